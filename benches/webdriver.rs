@@ -0,0 +1,86 @@
+#![feature(slicing_syntax)]
+#![feature(phase)]
+
+extern crate hyper;
+extern crate serialize;
+extern crate test;
+#[phase(plugin, link)] extern crate webdriver;
+
+use hyper::method::{Get, Post};
+use serialize::json;
+use serialize::json::ToJson;
+use test::Bencher;
+
+use webdriver::common::{WebDriverError, ErrorStatus, WebElement};
+use webdriver::messagebuilder::get_builder;
+use webdriver::response::{WebDriverResponse, ValueResponse, ElementResponse};
+
+static SESSION: &'static str = "/session/1b2e5f5c-8c57-4b6e-9d2c-0f3e7a1d4c9b";
+
+// About the size of a screenshot of a 1280x1024 page
+fn screenshot() -> String {
+    "iVBORw0KGgoAAAANSUhEUgAA".repeat(64 * 1024)
+}
+
+#[bench]
+fn route_first(b: &mut Bencher) {
+    let builder = get_builder();
+    b.iter(|| builder.from_http(Post, "/session", "{}"))
+}
+
+#[bench]
+fn route_element(b: &mut Bencher) {
+    let builder = get_builder();
+    let path = format!("{}/element/7d2a9b1c-4e5f-4a6b-8c7d-9e0f1a2b3c4d/attribute/href",
+                       SESSION);
+    b.iter(|| builder.from_http(Get, path[], ""))
+}
+
+#[bench]
+fn route_unknown(b: &mut Bencher) {
+    let builder = get_builder();
+    b.iter(|| builder.from_http(Get, "/no/such/path", ""))
+}
+
+#[bench]
+fn parse_find_element(b: &mut Bencher) {
+    let builder = get_builder();
+    let path = format!("{}/element", SESSION);
+    let body = r#"{"using": "css selector", "value": "#main > ul li:nth-child(2) a"}"#;
+    b.iter(|| builder.from_http(Post, path[], body))
+}
+
+#[bench]
+fn parse_execute_script(b: &mut Bencher) {
+    let builder = get_builder();
+    let path = format!("{}/execute/sync", SESSION);
+    let args: Vec<json::Json> = range(0u, 100).map(|x| format!("argument {}", x).to_json()).collect();
+    let body = format!(r#"{{"script": "return arguments.length", "args": {}}}"#,
+                       args.to_json());
+    b.iter(|| builder.from_http(Post, path[], body[]))
+}
+
+#[bench]
+fn serialize_element(b: &mut Bencher) {
+    b.iter(|| {
+        let element = WebElement::new("7d2a9b1c-4e5f-4a6b-8c7d-9e0f1a2b3c4d".to_string());
+        json::encode(&WebDriverResponse::Element(ElementResponse::new(element)).into_json())
+    })
+}
+
+#[bench]
+fn serialize_screenshot(b: &mut Bencher) {
+    let data = screenshot();
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let response = WebDriverResponse::Generic(ValueResponse::new(data.to_json()));
+        json::encode(&response.into_json())
+    })
+}
+
+#[bench]
+fn serialize_error(b: &mut Bencher) {
+    b.iter(|| {
+        WebDriverError::new(ErrorStatus::NoSuchElement, "Unable to locate element").to_json_string()
+    })
+}
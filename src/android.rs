@@ -0,0 +1,174 @@
+use serialize::json::Json;
+use std::collections::TreeMap;
+use std::io::process::{Command, ProcessExit};
+use std::io::{IoResult, IoError, IoErrorKind};
+
+use webdriver::common::{WebDriverResult, WebDriverError, ErrorStatus};
+use webdriver::params::{as_string, join_path};
+use firefox::Runner;
+use profile::Profile;
+
+static DEFAULT_ACTIVITY: &'static str = "org.mozilla.gecko.BrowserApp";
+static DEVICE_PROFILE_ROOT: &'static str = "/mnt/sdcard/wires";
+
+#[deriving(PartialEq, Show)]
+pub struct AndroidOptions {
+    pub package: String,
+    pub activity: Option<String>,
+    pub serial: Option<String>
+}
+
+impl AndroidOptions {
+    pub fn from_json(path: &str,
+                     options: &TreeMap<String, Json>) -> WebDriverResult<Option<AndroidOptions>> {
+        let package = match options.get("androidPackage") {
+            Some(x) => try!(as_string(join_path(path, "androidPackage")[], x)).to_string(),
+            None => return Ok(None)
+        };
+        let activity = match options.get("androidActivity") {
+            Some(x) => Some(try!(as_string(join_path(path, "androidActivity")[], x)).to_string()),
+            None => None
+        };
+        let serial = match options.get("androidDeviceSerial") {
+            Some(x) => {
+                Some(try!(as_string(join_path(path, "androidDeviceSerial")[], x)).to_string())
+            },
+            None => None
+        };
+        Ok(Some(AndroidOptions {
+            package: package,
+            activity: activity,
+            serial: serial
+        }))
+    }
+}
+
+struct Adb {
+    serial: Option<String>
+}
+
+impl Adb {
+    fn run(&self, args: &[&str]) -> IoResult<String> {
+        let mut command = Command::new("adb");
+        match self.serial {
+            Some(ref x) => {
+                command.arg("-s").arg(x[]);
+            },
+            None => {}
+        }
+        command.args(args);
+        debug!("Running {}", command);
+        let output = try!(command.output());
+        if !output.status.success() {
+            return Err(IoError {
+                kind: IoErrorKind::OtherIoError,
+                desc: "adb command failed",
+                detail: Some(String::from_utf8_lossy(output.error[]).into_owned())
+            })
+        }
+        Ok(String::from_utf8_lossy(output.output[]).into_owned())
+    }
+}
+
+// The browser runs on the device with marionette listening on a device port, which
+// adb forwards to a port on this machine.
+pub struct AndroidRunner {
+    adb: Adb,
+    package: String,
+    host_port: u16,
+    device_profile: String,
+    pub profile: Profile
+}
+
+impl AndroidRunner {
+    pub fn start(options: &AndroidOptions, profile: Profile, env: &[(String, String)],
+                 host_port: u16, device_port: u16) -> WebDriverResult<AndroidRunner> {
+        let adb = Adb {
+            serial: options.serial.clone()
+        };
+        let device_profile = format!("{}/profile-{}", DEVICE_PROFILE_ROOT, host_port);
+        //adb takes paths as strings, so a profile it can't be given can't be used
+        let local_profile = try_opt!(profile.path().as_str(),
+                                     ErrorStatus::InvalidArgument,
+                                     format!("Profile path {} is not valid UTF-8",
+                                             profile.path().display())).to_string();
+        match AndroidRunner::launch(&adb, options, env, local_profile[], device_profile[],
+                                    host_port, device_port) {
+            Ok(_) => {},
+            Err(e) => return Err(WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                format!("Failed to start {} on device: {}", options.package, e)).with_source(e))
+        }
+
+        Ok(AndroidRunner {
+            adb: adb,
+            package: options.package.clone(),
+            host_port: host_port,
+            device_profile: device_profile,
+            profile: profile
+        })
+    }
+
+    fn launch(adb: &Adb, options: &AndroidOptions, env: &[(String, String)],
+              local_profile: &str, device_profile: &str,
+              host_port: u16, device_port: u16) -> IoResult<()> {
+        let activity = match options.activity {
+            Some(ref x) => x[],
+            None => DEFAULT_ACTIVITY
+        };
+
+        try!(adb.run(&["forward",
+                       format!("tcp:{}", host_port)[],
+                       format!("tcp:{}", device_port)[]]));
+        try!(adb.run(&["shell", "am", "force-stop", options.package[]]));
+        //The profile of an earlier session on this port may still be there
+        try!(adb.run(&["shell", "rm", "-rf", device_profile]));
+        try!(adb.run(&["push", local_profile, device_profile]));
+        let component = format!("{}/{}", options.package, activity);
+        let browser_args = format!("-marionette -profile {}", device_profile);
+        let mut args = vec!["shell", "am", "start", "-W",
+                            "-n", component[],
+                            "-a", "android.intent.action.VIEW",
+                            "--es", "args", browser_args[]];
+        //The browser reads its environment from extras named env0, env1, ...
+        let env_extras: Vec<(String, String)> = env.iter().enumerate().map(|(i, &(ref name, ref value))| {
+            (format!("env{}", i), format!("{}={}", name, value))
+        }).collect();
+        for &(ref key, ref value) in env_extras.iter() {
+            args.push_all(&["--es", key[], value[]]);
+        }
+        try!(adb.run(args[]));
+        Ok(())
+    }
+}
+
+impl Runner for AndroidRunner {
+    fn set_session_id(&self, _: &str) {}
+
+    //There's no cheap way to watch a process on the device
+    fn exit_status(&mut self) -> Option<ProcessExit> {
+        None
+    }
+
+    fn stop(&mut self) -> IoResult<()> {
+        try!(self.adb.run(&["shell", "am", "force-stop", self.package[]]));
+        try!(self.adb.run(&["forward", "--remove", format!("tcp:{}", self.host_port)[]]));
+        try!(self.adb.run(&["shell", "rm", "-rf", self.device_profile[]]));
+        Ok(())
+    }
+
+    //The app restarts in place, and reconnecting will wait for it
+    fn wait_for_restart(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+
+    //Dumps are written to the profile on the device, which isn't read back
+    fn collect_minidumps(&mut self) -> Vec<Path> {
+        vec![]
+    }
+
+    //Output goes to logcat rather than to us
+    fn recent_output(&self) -> Vec<String> {
+        vec![]
+    }
+}
@@ -0,0 +1,410 @@
+use std::io::TempDir;
+use std::io::process::ProcessExit;
+use serialize::json::ToJson;
+
+use webdriver::command::{WebDriverMessage, WebDriverCommand, UploadFileParameters};
+use webdriver::common::{WebDriverResult, WebDriverError, ErrorStatus, Capabilities};
+use webdriver::response::{WebDriverResponse, ValueResponse};
+
+use android::AndroidRunner;
+use events;
+use firefox::{FirefoxOptions, FirefoxRunner, Runner, required_prefs, safe_mode_prefs,
+              jsdebugger_prefs, console_prefs, check_headless};
+use marionette::{MarionetteConnection, MarionetteSettings, DEFAULT_HOST, DEFAULT_PORT,
+                 get_free_port};
+use profile::{Profile, Pref};
+use zip;
+
+// What a worker runs its session's commands against. The worker deals with the
+// dispatcher and the client, so a backend only has to talk to its browser.
+pub trait Backend {
+    // Gets a browser ready for a New Session command, which is sent next
+    fn start(&mut self, msg: &WebDriverMessage) -> WebDriverResult<()>;
+    // Connects to a session that is already running in a browser wires didn't start
+    fn attach(&mut self, session_id: &str) -> WebDriverResult<()>;
+    fn is_alive(&self) -> bool;
+    fn handle_command(&mut self,
+                      msg: &WebDriverMessage) -> WebDriverResult<Option<WebDriverResponse>>;
+    fn session_id(&self) -> Option<String>;
+    fn capabilities(&self) -> Option<Capabilities>;
+    // Ends the session, if there is one, and gets rid of the browser
+    fn teardown(&mut self);
+}
+
+// Makes the backend for a new worker, from the binary to use and the settings
+pub type BackendFactory = fn(Option<Path>, MarionetteSettings) -> Box<Backend + Send>;
+
+// Launches Firefox, or connects to one that is already running, and forwards commands
+// to it over marionette
+pub struct MarionetteBackend {
+    connection: Option<MarionetteConnection>,
+    browser: Option<Box<Runner + Send>>,
+    binary: Option<Path>,
+    settings: MarionetteSettings,
+    //The session's marionette timeout, if its capabilities set one
+    response_timeout: Option<u64>,
+    //Files clients uploaded, removed along with the session
+    uploads: Vec<TempDir>
+}
+
+pub fn new_marionette_backend(binary: Option<Path>,
+                              settings: MarionetteSettings) -> Box<Backend + Send> {
+    box MarionetteBackend {
+        connection: None,
+        browser: None,
+        binary: binary,
+        settings: settings,
+        response_timeout: None,
+        uploads: vec![]
+    } as Box<Backend + Send>
+}
+
+impl Backend for MarionetteBackend {
+    fn start(&mut self, msg: &WebDriverMessage) -> WebDriverResult<()> {
+        let options = match msg.command {
+            WebDriverCommand::NewSession(ref x) => {
+                try!(FirefoxOptions::from_capabilities(&x.capabilities))
+            },
+            _ => FirefoxOptions::new()
+        };
+        self.response_timeout = options.response_timeout;
+        let (host, port) = if self.settings.connect_existing {
+            (self.settings.marionette_host.clone(),
+             self.settings.marionette_port.unwrap_or(DEFAULT_PORT))
+        } else {
+            (DEFAULT_HOST.to_string(), try!(self.start_browser(&options)))
+        };
+        match self.create_connection(host[], port, None) {
+            Err(e) => {
+                error!("{}", e.message);
+                Err(self.startup_error(e))
+            },
+            Ok(_) => Ok(())
+        }
+    }
+
+    fn attach(&mut self, session_id: &str) -> WebDriverResult<()> {
+        let host = self.settings.marionette_host.clone();
+        let port = self.settings.marionette_port.unwrap_or(DEFAULT_PORT);
+        self.create_connection(host[], port, Some(session_id.to_string()))
+    }
+
+    fn is_alive(&self) -> bool {
+        self.connection.as_ref().map_or(false, |x| x.is_alive())
+    }
+
+    fn handle_command(&mut self,
+                      msg: &WebDriverMessage) -> WebDriverResult<Option<WebDriverResponse>> {
+        let resp = match msg.command {
+            WebDriverCommand::Restart => {
+                self.restart().map(|_| Some(WebDriverResponse::Void))
+            },
+            WebDriverCommand::UploadFile(ref x) => self.upload_file(x),
+            _ => self.send_message(msg)
+        };
+        let resp = match resp {
+            Err(e) => match self.browser_exit_status() {
+                Some(status) => {
+                    error!("Browser exited unexpectedly with {}", status);
+                    let err = self.crash_error(status);
+                    self.teardown();
+                    Err(err)
+                },
+                None => Err(e)
+            },
+            x => x
+        };
+        match msg.command {
+            WebDriverCommand::NewSession(_) => {
+                let resp = self.check_new_session(resp);
+                match (&resp, &self.browser, &self.connection) {
+                    (&Ok(_), &Some(ref browser), &Some(ref conn)) => {
+                        browser.set_session_id(conn.session.session_id[])
+                    },
+                    _ => {}
+                }
+                resp
+            },
+            _ => resp
+        }
+    }
+
+    fn session_id(&self) -> Option<String> {
+        match self.connection {
+            Some(ref conn) if !conn.session.session_id.is_empty() => {
+                Some(conn.session.session_id.clone())
+            },
+            _ => None
+        }
+    }
+
+    fn capabilities(&self) -> Option<Capabilities> {
+        self.connection.as_ref().and_then(|x| x.session.capabilities.clone())
+    }
+
+    fn teardown(&mut self) {
+        match self.browser.take() {
+            Some(mut browser) => {
+                match self.connection {
+                    Some(ref mut conn) => conn.quit(),
+                    None => {}
+                }
+                match browser.stop() {
+                    Ok(_) => {},
+                    Err(e) => error!("Failed to stop browser: {}", e)
+                }
+            },
+            None => {}
+        }
+        self.connection = None;
+        self.uploads.clear();
+    }
+}
+
+impl MarionetteBackend {
+    // Unpacks the zip into a directory of its own and returns the path to use in a file
+    // input: the file itself when the archive holds just one, otherwise the directory
+    fn upload_file(&mut self, params: &UploadFileParameters)
+                   -> WebDriverResult<Option<WebDriverResponse>> {
+        let dir = match TempDir::new("wires-upload") {
+            Ok(x) => x,
+            Err(e) => return Err(wd_err!(ErrorStatus::UnknownError,
+                                         "Failed to create upload directory: {}", e))
+        };
+        let files = try!(zip::extract(params.file[], dir.path()));
+        let path = match files[] {
+            [ref x] => x.display().to_string(),
+            _ => dir.path().display().to_string()
+        };
+        debug!("Extracted {} uploaded files to {}", files.len(), dir.path().display());
+        match self.connection {
+            Some(ref mut conn) => conn.session.uploaded_files.push_all(files[]),
+            None => {}
+        }
+        self.uploads.push(dir);
+        Ok(Some(WebDriverResponse::Generic(ValueResponse::new(path.to_json()))))
+    }
+
+    fn browser_exit_status(&mut self) -> Option<ProcessExit> {
+        match self.browser {
+            Some(ref mut x) => x.exit_status(),
+            None => None
+        }
+    }
+
+    // Don't hand out a session unless the browser is actually usable, otherwise the
+    // failure only shows up as some confusing error on the next command
+    fn check_new_session(&mut self, resp: WebDriverResult<Option<WebDriverResponse>>)
+                         -> WebDriverResult<Option<WebDriverResponse>> {
+        let alive = match self.connection {
+            Some(ref conn) => conn.is_alive(),
+            None => false
+        };
+        match resp {
+            Ok(Some(WebDriverResponse::NewSession(_))) if !alive => Err(self.startup_error(
+                WebDriverError::new(ErrorStatus::SessionNotCreated,
+                                    "Marionette connection closed after creating the session"))),
+            Ok(Some(WebDriverResponse::NewSession(x))) => Ok(Some(WebDriverResponse::NewSession(x))),
+            Ok(_) => Err(self.startup_error(
+                WebDriverError::new(ErrorStatus::SessionNotCreated,
+                                    "Marionette didn't return the new session"))),
+            Err(e) => Err(self.startup_error(e))
+        }
+    }
+
+    // Add whatever the browser said on the way down to a failure to start a session, and
+    // get rid of the browser. Errors that don't say what went wrong become session not
+    // created; the rest, like invalid arguments or timeouts, keep their status.
+    fn startup_error(&mut self, err: WebDriverError) -> WebDriverError {
+        let output = match self.browser {
+            Some(ref x) => x.recent_output(),
+            None => vec![]
+        };
+        let mut message = err.message.into_owned();
+        if !output.is_empty() {
+            message.push_str("\nBrowser output:\n");
+            message.push_str(output.connect("\n")[]);
+        }
+        let status = match err.status {
+            ErrorStatus::UnknownError => ErrorStatus::SessionNotCreated,
+            x => x
+        };
+        let mut rv = WebDriverError::new(status, message);
+        rv.stack = err.stack;
+        rv.source = err.source;
+        self.teardown();
+        rv
+    }
+
+    fn crash_error(&mut self, status: ProcessExit) -> WebDriverError {
+        let dumps = match self.browser {
+            Some(ref mut x) => x.collect_minidumps(),
+            None => vec![]
+        };
+        let mut message = format!("Browser crashed ({})", status);
+        if !dumps.is_empty() {
+            let paths: Vec<String> = dumps.iter().map(|x| x.display().to_string()).collect();
+            for path in paths.iter() {
+                error!("Crash dump written to {}", path);
+            }
+            message.push_str(format!(", minidumps: {}", paths.connect(", "))[]);
+        }
+        WebDriverError::new(ErrorStatus::UnknownError, message)
+    }
+
+    // The same connection is used for the whole session. If it has dropped while the
+    // browser is still running, it is replaced, and a message that never made it to
+    // marionette is sent once more on the new connection.
+    fn send_message(&mut self,
+                    msg: &WebDriverMessage) -> WebDriverResult<Option<WebDriverResponse>> {
+        let alive = try_opt!(self.connection.as_ref(),
+                             ErrorStatus::InvalidSessionId,
+                             "Browser went away: not connected to marionette").is_alive();
+        if !alive && self.browser_exit_status().is_none() {
+            info!("Marionette connection closed, reconnecting");
+            try!(self.reconnect());
+        }
+        let resp = self.connection.as_mut().unwrap().send_message(msg);
+        match resp {
+            Err(e) => {
+                if !self.connection.as_ref().unwrap().last_message_unsent() ||
+                    self.browser_exit_status().is_some() {
+                    return Err(e)
+                }
+                info!("Failed to send to marionette ({}), reconnecting", e.message);
+                try!(self.reconnect());
+                self.connection.as_mut().unwrap().send_message(msg)
+            },
+            x => x
+        }
+    }
+
+    // Makes a new connection to the same marionette, and picks up the session again
+    // with everything wires knew about it
+    fn reconnect(&mut self) -> WebDriverResult<()> {
+        let old = try_opt!(self.connection.take(),
+                           ErrorStatus::InvalidSessionId,
+                           "Browser went away: not connected to marionette");
+        try!(self.create_connection(old.host[], old.port,
+                                    Some(old.session.session_id.clone())));
+        self.connection.as_mut().unwrap().resume_session(old.session)
+    }
+
+    // Ask the browser to restart itself, then reconnect to it and pick up the existing
+    // session again
+    fn restart(&mut self) -> WebDriverResult<()> {
+        try_opt!(self.connection.as_mut(),
+                 ErrorStatus::InvalidSessionId,
+                 "Browser went away: not connected to marionette").restart();
+        match self.browser {
+            Some(ref mut browser) => match browser.wait_for_restart() {
+                Ok(_) => {},
+                Err(e) => return Err(WebDriverError::new(
+                    ErrorStatus::UnknownError,
+                    format!("Browser didn't exit to restart: {}", e)).with_source(e))
+            },
+            None => {}
+        }
+        self.reconnect()
+    }
+
+    fn start_browser(&mut self, options: &FirefoxOptions) -> WebDriverResult<u16> {
+        let keep_profile = self.settings.keep_profile || options.keep_profile;
+        let profile = match Profile::new(self.settings.profile_root.as_ref(), keep_profile) {
+            Ok(x) => {
+                if keep_profile {
+                    info!("Profile {} will be kept after the session", x.path().display());
+                }
+                x
+            },
+            Err(e) => return Err(WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                format!("Failed to create profile: {}", e)).with_source(e))
+        };
+        let port = match self.settings.marionette_port {
+            Some(x) => x,
+            None => match get_free_port() {
+                Ok(x) => x,
+                Err(e) => return Err(WebDriverError::new(
+                    ErrorStatus::SessionNotCreated,
+                    format!("Failed to find a free port for marionette: {}", e)).with_source(e))
+            }
+        };
+        //On android marionette listens on the device, by default on its usual port,
+        //and adb forwards the port here to it
+        let device_port = match options.android {
+            Some(_) => self.settings.marionette_port.unwrap_or(DEFAULT_PORT),
+            None => port
+        };
+        let safe_mode = self.settings.safe_mode || options.is_safe_mode();
+        let mut prefs = required_prefs();
+        prefs.push(("marionette.defaultPrefs.port".to_string(), Pref::Int(device_port as i64)));
+        if safe_mode {
+            prefs.push_all(safe_mode_prefs()[]);
+        }
+        if self.settings.jsdebugger {
+            prefs.push_all(jsdebugger_prefs()[]);
+        }
+        if events::is_enabled() {
+            prefs.push_all(console_prefs()[]);
+        }
+        prefs.push_all(options.prefs[]);
+        match profile.write_prefs(prefs[]) {
+            Ok(_) => {},
+            Err(e) => return Err(WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                format!("Failed to write profile preferences: {}", e)).with_source(e))
+        }
+        let runner = match options.android {
+            Some(ref android) => {
+                box try!(AndroidRunner::start(android, profile, options.env[], port,
+                                              device_port)) as Box<Runner + Send>
+            },
+            None => {
+                let binary = match options.binary.as_ref().or(self.binary.as_ref()) {
+                    Some(x) => x.clone(),
+                    None => return Err(WebDriverError::new(
+                        ErrorStatus::SessionNotCreated,
+                        "Unable to find a Firefox binary, set 'binary' in moz:firefoxOptions"))
+                };
+                debug!("Using Firefox binary {}", binary.display());
+                let mut args = options.args.clone();
+                let mut env = options.env.clone();
+                if self.settings.headless || options.is_headless() {
+                    try!(check_headless(&binary));
+                    if !options.is_headless() {
+                        args.push("-headless".to_string());
+                    }
+                    env.push(("MOZ_HEADLESS".to_string(), "1".to_string()));
+                }
+                if self.settings.safe_mode && !options.is_safe_mode() {
+                    args.push("-safe-mode".to_string());
+                }
+                if self.settings.jsdebugger {
+                    args.push("-jsdebugger".to_string());
+                }
+                match FirefoxRunner::start(&binary, profile, args[], env[],
+                                           self.settings.browser_log.as_ref()) {
+                    Ok(x) => box x as Box<Runner + Send>,
+                    Err(e) => return Err(WebDriverError::new(
+                        ErrorStatus::SessionNotCreated,
+                        format!("Failed to start {}: {}", binary.display(), e)).with_source(e))
+                }
+            }
+        };
+        self.browser = Some(runner);
+        Ok(port)
+    }
+
+    fn create_connection(&mut self, host: &str, port: u16,
+                         session_id: Option<String>) -> WebDriverResult<()> {
+        let response_timeout = self.response_timeout.or(self.settings.response_timeout);
+        let mut connection = MarionetteConnection::new(host, port, session_id, response_timeout);
+        connection.driver_implicit_wait = self.settings.driver_implicit_wait;
+        try!(connection.connect(self.settings.startup_timeout));
+        debug!("Connected to marionette using protocol version {}",
+               connection.protocol_version);
+        self.connection = Some(connection);
+        Ok(())
+    }
+}
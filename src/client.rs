@@ -0,0 +1,119 @@
+use serialize::json;
+use serialize::json::{Json, ToJson};
+use std::collections::TreeMap;
+use std::io::TcpStream;
+
+use common::{WebDriverResult, WebDriverError, ErrorStatus, WebElement, LocatorStrategy};
+
+// A small client for a WebDriver server, which also shows how the protocol is used.
+// Requests go over HTTP/1.0, so the server closes each connection once it has answered.
+pub struct Client {
+    host: String,
+    port: u16,
+    session_id: Option<String>
+}
+
+impl Client {
+    pub fn new(host: &str, port: u16) -> Client {
+        Client {
+            host: host.to_string(),
+            port: port,
+            session_id: None
+        }
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_ref().map(|x| x[])
+    }
+
+    // Starts a session and returns the capabilities the server reported for it
+    pub fn new_session(&mut self, capabilities: Json) -> WebDriverResult<Json> {
+        let mut body = TreeMap::new();
+        body.insert("capabilities".to_string(), capabilities);
+        let value = try!(self.command("POST", "/session", Some(&Json::Object(body))));
+        let session_id = try_opt!(value.find("sessionId").and_then(|x| x.as_string()),
+                                  ErrorStatus::UnknownError,
+                                  "New session response has no session id");
+        self.session_id = Some(session_id.to_string());
+        Ok(value.find("capabilities").map_or(Json::Null, |x| x.clone()))
+    }
+
+    pub fn get(&self, url: &str) -> WebDriverResult<()> {
+        let mut body = TreeMap::new();
+        body.insert("url".to_string(), url.to_json());
+        let path = try!(self.session_path("/url"));
+        try!(self.command("POST", path[], Some(&Json::Object(body))));
+        Ok(())
+    }
+
+    pub fn current_url(&self) -> WebDriverResult<String> {
+        let path = try!(self.session_path("/url"));
+        let value = try!(self.command("GET", path[], None));
+        Ok(try_opt!(value.as_string(),
+                    ErrorStatus::UnknownError,
+                    "Current URL was not a string").to_string())
+    }
+
+    pub fn find_element(&self, using: LocatorStrategy, value: &str) -> WebDriverResult<WebElement> {
+        let mut body = TreeMap::new();
+        body.insert("using".to_string(), using.to_json());
+        body.insert("value".to_string(), value.to_json());
+        let path = try!(self.session_path("/element"));
+        WebElement::from_json(&try!(self.command("POST", path[], Some(&Json::Object(body)))))
+    }
+
+    // Ends the session, if there is one
+    pub fn quit(&mut self) -> WebDriverResult<()> {
+        match self.session_id.take() {
+            Some(session_id) => {
+                try!(self.command("DELETE", format!("/session/{}", session_id)[], None));
+                Ok(())
+            },
+            None => Ok(())
+        }
+    }
+
+    // Sends a command and returns the value it was answered with. Error responses
+    // become a WebDriverError with the status and message the server gave.
+    pub fn command(&self, method: &str, path: &str, body: Option<&Json>) -> WebDriverResult<Json> {
+        let (status, data) = try!(self.request(method, path, body));
+        let value = data.find("value").map_or(Json::Null, |x| x.clone());
+        if status == 200 {
+            return Ok(value)
+        }
+        let error = value.find("error").and_then(|x| ErrorStatus::from_json(x))
+            .unwrap_or(ErrorStatus::UnknownError);
+        let message = value.find("message").and_then(|x| x.as_string()).unwrap_or("");
+        Err(WebDriverError::new(error, message.to_string()))
+    }
+
+    // Sends a request and returns the HTTP status and the body
+    pub fn request(&self, method: &str, path: &str,
+                   body: Option<&Json>) -> WebDriverResult<(int, Json)> {
+        let mut stream = try!(TcpStream::connect((self.host[], self.port)));
+        let body = body.map_or("".to_string(), |x| json::encode(x));
+        let mut request = format!("{} {} HTTP/1.0\r\nHost: {}:{}\r\n",
+                                  method, path, self.host, self.port);
+        if !body.is_empty() {
+            request.push_str("Content-Type: application/json; charset=utf-8\r\n");
+        }
+        request.push_str(format!("Content-Length: {}\r\n\r\n", body.len())[]);
+        request.push_str(body[]);
+        try!(stream.write_str(request[]));
+        let response = try!(stream.read_to_string());
+        let body_start = try_opt!(response[].find_str("\r\n\r\n"),
+                                  ErrorStatus::UnknownError,
+                                  "Response has no body") + 4;
+        let status = try_opt!(response[].split(' ').nth(1).and_then(|x| from_str::<int>(x)),
+                              ErrorStatus::UnknownError,
+                              "Response has no status");
+        Ok((status, try!(json::from_str(response[body_start..]))))
+    }
+
+    fn session_path(&self, path: &str) -> WebDriverResult<String> {
+        let session_id = try_opt!(self.session_id.as_ref(),
+                                  ErrorStatus::InvalidSessionId,
+                                  "No session has been started");
+        Ok(format!("/session/{}{}", session_id, path))
+    }
+}
@@ -1,16 +1,22 @@
 use std::collections::TreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+use serialize::base64::{FromBase64, ToBase64, STANDARD};
 use serialize::json;
 use serialize::json::{ToJson, Json};
-use regex::Captures;
 
-use common::{WebDriverResult, WebDriverError, ErrorStatus, Nullable, WebElement, FrameId, LocatorStrategy};
+use common::{WebDriverResult, WebDriverError, ErrorStatus, Nullable, WebElement, FrameId, LocatorStrategy,
+             SameSite, Capabilities, CapabilitiesDialect, convert_references, MAX_SAFE_INTEGER};
+use params::{as_array, as_boolean, as_object, as_string, as_i64_in_range, as_u64_in_range,
+             get_string, get_u64_in_range, get_value, index_path, invalid, join_path};
+use keys;
 use response::Date; //TODO: Put all these types in a specific file
-use messagebuilder::MatchType;
+use messagebuilder::{MatchType, PathParams, parse_command};
 
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub enum WebDriverCommand {
-    NewSession,
+    NewSession(NewSessionParameters),
     DeleteSession,
     Get(GetParameters),
     GetCurrentUrl,
@@ -23,6 +29,8 @@ pub enum WebDriverCommand {
     Close,
     SetWindowSize(WindowSizeParameters),
     GetWindowSize,
+    SetWindowRect(WindowRectParameters),
+    GetWindowRect,
     MaximizeWindow,
 //    FullscreenWindow // Not supported in marionette
     SwitchToWindow(SwitchToWindowParameters),
@@ -32,8 +40,8 @@ pub enum WebDriverCommand {
     FindElements(LocatorParameters),
     IsDisplayed(WebElement),
     IsSelected(WebElement),
-    GetElementAttribute(WebElement, String),
-    GetCSSValue(WebElement, String),
+    GetElementAttribute(GetElementAttributeParameters),
+    GetCSSValue(GetCSSValueParameters),
     GetElementText(WebElement),
     GetElementTagName(WebElement),
     GetElementRect(WebElement),
@@ -43,175 +51,111 @@ pub enum WebDriverCommand {
     GetCookie(GetCookieParameters),
     AddCookie(AddCookieParameters),
     SetTimeouts(TimeoutsParameters),
-    //Actions(ActionsParameters),
+    PerformActions(ActionsParameters),
+    ReleaseActions,
     ElementClick(WebElement),
     ElementTap(WebElement),
     ElementClear(WebElement),
-    ElementSendKeys(WebElement, SendKeysParameters),
+    ElementSendKeys(SendKeysParameters),
     DismissAlert,
     AcceptAlert,
     GetAlertText,
     SendAlertText(SendAlertTextParameters),
-    TakeScreenshot(TakeScreenshotParameters)
+    TakeScreenshot(TakeScreenshotParameters),
+    GetContext,
+    SetContext(SetContextParameters),
+    InstallAddon(InstallAddonParameters),
+    UninstallAddon(UninstallAddonParameters),
+    Restart,
+    GetDriverLog,
+    GetLogTypes,
+    GetLog(GetLogParameters),
+    UploadFile(UploadFileParameters),
+    TouchClick(TouchElementParameters),
+    TouchDown(TouchPointParameters),
+    TouchUp(TouchPointParameters),
+    TouchMove(TouchPointParameters),
+    TouchScroll(TouchScrollParameters),
+    TouchFlick(TouchFlickParameters),
+    TouchLongClick(TouchElementParameters),
+    GetPref(PrefParameters),
+    SetPref(SetPrefParameters),
+    ResetPref(PrefParameters),
+    Status,
+    GetSessions,
+    Extension(ExtensionParameters)
 }
 
-#[deriving(PartialEq)]
+impl WebDriverCommand {
+    // The name of the variant, without its parameters
+    pub fn name(&self) -> String {
+        match *self {
+            WebDriverCommand::Extension(ref x) => x.name.clone(),
+            _ => {
+                let name = format!("{}", self);
+                name[].split('(').next().unwrap_or("").to_string()
+            }
+        }
+    }
+
+    // Commands whose parameters can hold things like typed passwords or session
+    // cookies, so only their name gets logged
+    pub fn is_sensitive(&self) -> bool {
+        match *self {
+            WebDriverCommand::ElementSendKeys(_) | WebDriverCommand::SendAlertText(_) |
+            WebDriverCommand::ExecuteScript(_) | WebDriverCommand::ExecuteAsyncScript(_) |
+            WebDriverCommand::AddCookie(_) | WebDriverCommand::UploadFile(_) |
+            WebDriverCommand::PerformActions(_) => true,
+            _ => false
+        }
+    }
+}
+
+// Builds the parameters of an extension command from the path and the request body
+pub type ExtensionParser = fn(&PathParams, &Json) -> WebDriverResult<Json>;
+
+#[deriving(PartialEq, Show)]
 pub struct WebDriverMessage {
     pub session_id: Option<String>,
-    pub command: WebDriverCommand
+    pub command: WebDriverCommand,
+    //Id of the HTTP request the message came from, for matching up log lines
+    pub request_id: Option<String>,
+    //Window a legacy route addressed, which has to be the current one
+    pub window_handle: Option<String>
 }
 
 impl WebDriverMessage {
     pub fn new(session_id: Option<String>, command: WebDriverCommand) -> WebDriverMessage {
         WebDriverMessage {
             session_id: session_id,
-            command: command
+            command: command,
+            request_id: None,
+            window_handle: None
         }
     }
 
-    pub fn from_http(match_type: MatchType, params: &Captures, body: &str) -> WebDriverResult<WebDriverMessage> {
+    pub fn from_http(match_type: MatchType, params: &PathParams, body: &Json) -> WebDriverResult<WebDriverMessage> {
         let session_id = WebDriverMessage::get_session_id(params);
-        let body_data = if body != "" {
-            debug!("Got request body {}", body);
-            match json::from_str(body) {
-                Ok(x) => x,
-                Err(_) => return Err(WebDriverError::new(ErrorStatus::UnknownError,
-                                                         format!("Failed to decode request body as json: {}", body).as_slice()))
-            }
-        } else {
-            json::Null
+        let command = try!(parse_command(match_type, params, body));
+        let mut message = WebDriverMessage::new(session_id, command);
+        message.window_handle = match params.name("windowHandle") {
+            "" | "current" => None,
+            x => Some(x.to_string())
         };
-        let command = match match_type {
-            MatchType::NewSession => WebDriverCommand::NewSession,
-            MatchType::DeleteSession => WebDriverCommand::DeleteSession,
-            MatchType::Get => {
-                let parameters: GetParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::Get(parameters)
-            },
-            MatchType::GetCurrentUrl => WebDriverCommand::GetCurrentUrl,
-            MatchType::GoBack => WebDriverCommand::GoBack,
-            MatchType::GoForward => WebDriverCommand::GoForward,
-            MatchType::Refresh => WebDriverCommand::Refresh,
-            MatchType::GetTitle => WebDriverCommand::GetTitle,
-            MatchType::GetWindowHandle => WebDriverCommand::GetWindowHandle,
-            MatchType::GetWindowHandles => WebDriverCommand::GetWindowHandles,
-            MatchType::Close => WebDriverCommand::Close,
-            MatchType::SetTimeouts => {
-                let parameters: TimeoutsParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::SetTimeouts(parameters)
-            },
-            MatchType::SetWindowSize => {
-                let parameters: WindowSizeParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::SetWindowSize(parameters)
-            },
-            MatchType::GetWindowSize => WebDriverCommand::GetWindowSize,
-            MatchType::MaximizeWindow => WebDriverCommand::MaximizeWindow,
-            MatchType::SwitchToWindow => {
-                let parameters: SwitchToWindowParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::SwitchToWindow(parameters)
-            }
-            MatchType::SwitchToFrame => {
-                let parameters: SwitchToFrameParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::SwitchToFrame(parameters)
-            },
-            MatchType::SwitchToParentFrame => WebDriverCommand::SwitchToParentFrame,
-            MatchType::FindElement => {
-                let parameters: LocatorParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::FindElement(parameters)
-            },
-            MatchType::FindElements => {
-                let parameters: LocatorParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::FindElements(parameters)
-            },
-            MatchType::IsDisplayed => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::IsDisplayed(element)
-            },
-            MatchType::IsSelected => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::IsSelected(element)
-            },
-            MatchType::GetElementAttribute => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                let attr = params.name("name").to_string();
-                WebDriverCommand::GetElementAttribute(element, attr)
-            },
-            MatchType::GetCSSValue => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                let property = params.name("propertyName").to_string();
-                WebDriverCommand::GetCSSValue(element, property)
-            },
-            MatchType::GetElementText => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::GetElementText(element)
-            },
-            MatchType::GetElementTagName => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::GetElementTagName(element)
-            },
-            MatchType::GetElementRect => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::GetElementRect(element)
-            },
-            MatchType::IsEnabled => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::IsEnabled(element)
-            },
-            MatchType::ElementClick => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::ElementClick(element)
-            },
-            MatchType::ElementTap => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::ElementTap(element)
-            },
-            MatchType::ElementClear => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                WebDriverCommand::ElementClear(element)
-            },
-            MatchType::ElementSendKeys => {
-                let element = WebElement::new(params.name("elementId").to_string());
-                let parameters: SendKeysParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::ElementSendKeys(element, parameters)
-            },
-            MatchType::ExecuteScript => {
-                let parameters: JavascriptCommandParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::ExecuteScript(parameters)
-            },
-            MatchType::ExecuteAsyncScript => {
-                let parameters: JavascriptCommandParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::ExecuteAsyncScript(parameters)
-            },
-            MatchType::GetCookie => {
-                let parameters: GetCookieParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::GetCookie(parameters)
-            },
-            MatchType::AddCookie => {
-                let parameters: AddCookieParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::AddCookie(parameters)
-            },
-            MatchType::DismissAlert => {
-                WebDriverCommand::DismissAlert
-            },
-            MatchType::AcceptAlert => {
-                WebDriverCommand::AcceptAlert
-            },
-            MatchType::GetAlertText => {
-                WebDriverCommand::GetAlertText
-            },
-            MatchType::SendAlertText => {
-                let parameters: SendAlertTextParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::SendAlertText(parameters)
-            }
-            MatchType::TakeScreenshot => {
-                let parameters: TakeScreenshotParameters = try!(Parameters::from_json(&body_data));
-                WebDriverCommand::TakeScreenshot(parameters)
-            }
+        Ok(message)
+    }
+
+    pub fn from_extension(name: &str, parser: ExtensionParser,
+                          params: &PathParams, body: &Json) -> WebDriverResult<WebDriverMessage> {
+        let session_id = WebDriverMessage::get_session_id(params);
+        let parameters = ExtensionParameters {
+            name: name.to_string(),
+            parameters: try!(parser(params, body))
         };
-        Ok(WebDriverMessage::new(session_id, command))
+        Ok(WebDriverMessage::new(session_id, WebDriverCommand::Extension(parameters)))
     }
 
-    fn get_session_id(params: &Captures) -> Option<String> {
+    fn get_session_id(params: &PathParams) -> Option<String> {
         match params.name("sessionId") {
             "" => None,
             x => Some(x.to_string())
@@ -223,35 +167,57 @@ impl ToJson for WebDriverMessage {
     fn to_json(&self) -> json::Json {
         let mut data = TreeMap::new();
         let parameters = match self.command {
-            WebDriverCommand::NewSession |
             WebDriverCommand::DeleteSession | WebDriverCommand::GetCurrentUrl |
             WebDriverCommand::GoBack | WebDriverCommand::GoForward | WebDriverCommand::Refresh |
             WebDriverCommand::GetTitle | WebDriverCommand::GetWindowHandle |
             WebDriverCommand::GetWindowHandles | WebDriverCommand::Close |
-            WebDriverCommand::GetWindowSize | WebDriverCommand::MaximizeWindow |
+            WebDriverCommand::GetWindowSize | WebDriverCommand::GetWindowRect |
+            WebDriverCommand::MaximizeWindow |
             WebDriverCommand::SwitchToParentFrame | WebDriverCommand::IsDisplayed(_) |
-            WebDriverCommand::IsSelected(_) | WebDriverCommand::GetElementAttribute(_, _) |
-            WebDriverCommand::GetCSSValue(_, _) | WebDriverCommand::GetElementText(_) |
+            WebDriverCommand::IsSelected(_) | WebDriverCommand::GetElementAttribute(_) |
+            WebDriverCommand::GetCSSValue(_) | WebDriverCommand::GetElementText(_) |
             WebDriverCommand::GetElementTagName(_) | WebDriverCommand::GetElementRect(_) |
             WebDriverCommand::IsEnabled(_) | WebDriverCommand::AddCookie(_) |
             WebDriverCommand::DismissAlert | WebDriverCommand::AcceptAlert |
             WebDriverCommand::GetAlertText | WebDriverCommand::ElementClick(_) |
-            WebDriverCommand::ElementTap(_) | WebDriverCommand::ElementClear(_) => {
+            WebDriverCommand::ElementTap(_) | WebDriverCommand::ElementClear(_) |
+            WebDriverCommand::GetContext | WebDriverCommand::Restart |
+            WebDriverCommand::GetDriverLog | WebDriverCommand::GetLogTypes |
+            WebDriverCommand::ReleaseActions | WebDriverCommand::Status |
+            WebDriverCommand::GetSessions => {
                 None
             },
+            WebDriverCommand::NewSession(ref x) => Some(x.to_json()),
             WebDriverCommand::Get(ref x) => Some(x.to_json()),
             WebDriverCommand::SetTimeouts(ref x) => Some(x.to_json()),
             WebDriverCommand::SetWindowSize(ref x) => Some(x.to_json()),
+            WebDriverCommand::SetWindowRect(ref x) => Some(x.to_json()),
             WebDriverCommand::SwitchToWindow(ref x) => Some(x.to_json()),
             WebDriverCommand::SwitchToFrame(ref x) => Some(x.to_json()),
             WebDriverCommand::FindElement(ref x) => Some(x.to_json()),
             WebDriverCommand::FindElements(ref x) => Some(x.to_json()),
-            WebDriverCommand::ElementSendKeys(_, ref x) => Some(x.to_json()),
+            WebDriverCommand::ElementSendKeys(ref x) => Some(x.to_json()),
             WebDriverCommand::ExecuteScript(ref x) |
             WebDriverCommand::ExecuteAsyncScript(ref x) => Some(x.to_json()),
             WebDriverCommand::GetCookie(ref x) => Some(x.to_json()),
             WebDriverCommand::SendAlertText(ref x) => Some(x.to_json()),
-            WebDriverCommand::TakeScreenshot(ref x) => Some(x.to_json())
+            WebDriverCommand::TakeScreenshot(ref x) => Some(x.to_json()),
+            WebDriverCommand::SetContext(ref x) => Some(x.to_json()),
+            WebDriverCommand::InstallAddon(ref x) => Some(x.to_json()),
+            WebDriverCommand::UninstallAddon(ref x) => Some(x.to_json()),
+            WebDriverCommand::GetLog(ref x) => Some(x.to_json()),
+            WebDriverCommand::UploadFile(ref x) => Some(x.to_json()),
+            WebDriverCommand::TouchClick(ref x) | WebDriverCommand::TouchLongClick(ref x) => {
+                Some(x.to_json())
+            },
+            WebDriverCommand::TouchDown(ref x) | WebDriverCommand::TouchUp(ref x) |
+            WebDriverCommand::TouchMove(ref x) => Some(x.to_json()),
+            WebDriverCommand::TouchScroll(ref x) => Some(x.to_json()),
+            WebDriverCommand::TouchFlick(ref x) => Some(x.to_json()),
+            WebDriverCommand::PerformActions(ref x) => Some(x.to_json()),
+            WebDriverCommand::GetPref(_) | WebDriverCommand::ResetPref(_) => None,
+            WebDriverCommand::SetPref(ref x) => Some(x.to_json()),
+            WebDriverCommand::Extension(ref x) => Some(x.to_json())
         };
         if parameters.is_some() {
             data.insert("parameters".to_string(), parameters.unwrap());
@@ -260,25 +226,173 @@ impl ToJson for WebDriverMessage {
     }
 }
 
-trait Parameters {
+//Whether parameter parsers reject members they don't know about
+static STRICT_PARAMETERS: AtomicBool = INIT_ATOMIC_BOOL;
+
+pub fn set_strict_parameters(enabled: bool) {
+    STRICT_PARAMETERS.store(enabled, SeqCst);
+}
+
+fn check_members(data: &TreeMap<String, Json>, allowed: &[&str]) -> WebDriverResult<()> {
+    if !STRICT_PARAMETERS.load(SeqCst) {
+        return Ok(())
+    }
+    for key in data.keys() {
+        if !allowed.contains(&key[]) {
+            return Err(wd_err!(ErrorStatus::InvalidArgument, "Unexpected parameter {}", key));
+        }
+    }
+    Ok(())
+}
+
+pub trait Parameters {
     fn from_json(body: &json::Json) -> WebDriverResult<Self>;
 }
 
-#[deriving(PartialEq)]
+// The body is parsed once, before the route's parser sees it, and only the parsed
+// value is passed on from there
+pub fn parse_json_body(body: &str) -> WebDriverResult<Json> {
+    if body == "" {
+        return Ok(json::Null)
+    }
+    //Bodies can hold typed passwords, so only the parsed command gets logged
+    debug!("Got request body of {} bytes", body.len());
+    json::from_str(body).map_err(
+        |e| wd_err!(ErrorStatus::UnknownError,
+                    "Failed to decode request body as json: {}", e))
+}
+
+pub fn parse_parameters<T: Parameters>(body: &Json) -> WebDriverResult<T> {
+    Parameters::from_json(body)
+}
+
+// The element a route's {elementId} refers to
+pub fn element_param(params: &PathParams) -> WebElement {
+    WebElement::new(params.name("elementId").to_string())
+}
+
+#[deriving(PartialEq, Show)]
+pub struct NewSessionParameters {
+    pub dialect: CapabilitiesDialect,
+    //What the session is created with, after merging the separate sets the client sent
+    pub capabilities: Capabilities
+}
+
+impl Parameters for NewSessionParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<NewSessionParameters> {
+        if body.is_null() {
+            return Ok(NewSessionParameters {
+                dialect: CapabilitiesDialect::Legacy,
+                capabilities: Capabilities::new()
+            })
+        }
+        let data = try_opt!(body.as_object(), ErrorStatus::UnknownError,
+                            "Message body was not an object");
+        try!(check_members(data, &["desiredCapabilities", "requiredCapabilities", "capabilities"]));
+        //Clients that speak both send both, and the spec form wins
+        let (dialect, merged) = match data.get("capabilities") {
+            Some(x) => (CapabilitiesDialect::W3C, try!(NewSessionParameters::process_w3c(x))),
+            None => (CapabilitiesDialect::Legacy, try!(NewSessionParameters::process_legacy(
+                data.get("desiredCapabilities"), data.get("requiredCapabilities"))))
+        };
+        return Ok(NewSessionParameters {
+            dialect: dialect,
+            capabilities: try!(Capabilities::from_json(&Json::Object(merged)))
+        })
+    }
+}
+
+impl NewSessionParameters {
+    //alwaysMatch combined with the first firstMatch entry that could be Firefox. Each
+    //object is checked before they are merged, so errors point at the right one.
+    fn process_w3c(capabilities: &Json) -> WebDriverResult<TreeMap<String, Json>> {
+        let capabilities = try!(as_object("capabilities", capabilities));
+        let always_match = match capabilities.get("alwaysMatch") {
+            Some(x) => {
+                try!(Capabilities::from_json_at("capabilities.alwaysMatch", x));
+                try!(as_object("capabilities.alwaysMatch", x)).clone()
+            },
+            None => TreeMap::new()
+        };
+        let first_match = match capabilities.get("firstMatch") {
+            Some(x) => try!(as_array("capabilities.firstMatch", x)).clone(),
+            None => vec![Json::Object(TreeMap::new())]
+        };
+        for (i, entry) in first_match.iter().enumerate() {
+            let path = index_path("capabilities.firstMatch", i);
+            try!(Capabilities::from_json_at(path[], entry));
+            let mut merged = always_match.clone();
+            for (key, value) in try!(as_object(path[], entry)).iter() {
+                if merged.contains_key(key) {
+                    return Err(wd_err!(ErrorStatus::InvalidArgument,
+                                       "Capability {} is in both alwaysMatch and firstMatch",
+                                       key));
+                }
+                merged.insert(key.clone(), value.clone());
+            }
+            let matches = match merged.get("browserName") {
+                Some(x) => x.as_string() == Some("firefox"),
+                None => true
+            };
+            if matches {
+                return Ok(merged)
+            }
+        }
+        Err(WebDriverError::new(ErrorStatus::SessionNotCreated,
+                                "No firstMatch capabilities could be matched"))
+    }
+
+    //Required capabilities take precedence over desired ones
+    fn process_legacy(desired: Option<&Json>,
+                      required: Option<&Json>) -> WebDriverResult<TreeMap<String, Json>> {
+        let mut merged = match desired {
+            Some(x) => {
+                try!(Capabilities::from_json_at("desiredCapabilities", x));
+                try!(as_object("desiredCapabilities", x)).clone()
+            },
+            None => TreeMap::new()
+        };
+        match required {
+            Some(x) => {
+                try!(Capabilities::from_json_at("requiredCapabilities", x));
+                for (key, value) in try!(as_object("requiredCapabilities", x)).iter() {
+                    merged.insert(key.clone(), value.clone());
+                }
+            },
+            None => {}
+        }
+        Ok(merged)
+    }
+}
+
+impl ToJson for NewSessionParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        match self.dialect {
+            CapabilitiesDialect::W3C => {
+                let mut capabilities = TreeMap::new();
+                capabilities.insert("alwaysMatch".to_string(), self.capabilities.to_json());
+                data.insert("capabilities".to_string(), Json::Object(capabilities));
+            },
+            CapabilitiesDialect::Legacy => {
+                data.insert("desiredCapabilities".to_string(), self.capabilities.to_json());
+            }
+        }
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
 pub struct GetParameters {
-    url: String
+    pub url: String
 }
 
 impl Parameters for GetParameters {
     fn from_json(body: &json::Json) -> WebDriverResult<GetParameters> {
         let data = try_opt!(body.as_object(), ErrorStatus::UnknownError,
                             "Message body was not an object");
-        let url = try_opt!(
-            try_opt!(data.get("url"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'url' parameter").as_string(),
-            ErrorStatus::InvalidArgument,
-            "'url' not a string");
+        try!(check_members(data, &["url"]));
+        let url = try!(get_string(data, "", "url"));
         return Ok(GetParameters {
             url: url.to_string()
         })
@@ -293,29 +407,19 @@ impl ToJson for GetParameters {
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub struct TimeoutsParameters {
-    type_: String,
-    ms: u64
+    pub type_: String,
+    pub ms: u64
 }
 
 impl Parameters for TimeoutsParameters {
     fn from_json(body: &json::Json) -> WebDriverResult<TimeoutsParameters> {
         let data = try_opt!(body.as_object(), ErrorStatus::UnknownError,
                             "Message body was not an object");
-        let type_ = try_opt!(
-            try_opt!(data.get("type"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'type' parameter").as_string(),
-            ErrorStatus::InvalidArgument,
-            "'type' not a string");
-
-        let ms = try_opt!(
-            try_opt!(data.get("ms"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'ms' parameter").as_u64(),
-            ErrorStatus::InvalidArgument,
-            "'ms' not an integer");
+        try!(check_members(data, &["type", "ms"]));
+        let type_ = try!(get_string(data, "", "type"));
+        let ms = try!(get_u64_in_range(data, "", "ms", 0, MAX_SAFE_INTEGER));
         return Ok(TimeoutsParameters {
             type_: type_.to_string(),
             ms: ms
@@ -332,7 +436,7 @@ impl ToJson for TimeoutsParameters {
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub struct WindowSizeParameters {
     width: u64,
     height: u64
@@ -342,18 +446,11 @@ impl Parameters for WindowSizeParameters {
     fn from_json(body: &json::Json) -> WebDriverResult<WindowSizeParameters> {
         let data = try_opt!(body.as_object(), ErrorStatus::UnknownError,
                             "Message body was not an object");
-        let height = try_opt!(
-            try_opt!(data.get("height"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'height' parameter").as_u64(),
-            ErrorStatus::InvalidArgument,
-            "'height' is not a positive integer");
-        let width = try_opt!(
-            try_opt!(data.get("width"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing width parameter").as_u64(),
-            ErrorStatus::InvalidArgument,
-            "'width' is not a positive integer");
+        try!(check_members(data, &["width", "height"]));
+        let height = try!(get_u64_in_range(data, "", "height", 0,
+                                           MAX_WINDOW_COORDINATE as u64));
+        let width = try!(get_u64_in_range(data, "", "width", 0,
+                                          MAX_WINDOW_COORDINATE as u64));
         return Ok(WindowSizeParameters {
             height: height,
             width: width
@@ -370,7 +467,60 @@ impl ToJson for WindowSizeParameters {
     }
 }
 
-#[deriving(PartialEq)]
+//Window coordinates and sizes are signed 32 bit integers on every platform
+static MAX_WINDOW_COORDINATE: i64 = 2147483647;
+
+// Members that are missing or null are left as they are. Coordinates can be negative,
+// for monitors that are above or to the left of the primary one.
+#[deriving(PartialEq, Show)]
+pub struct WindowRectParameters {
+    pub x: Nullable<i64>,
+    pub y: Nullable<i64>,
+    pub width: Nullable<u64>,
+    pub height: Nullable<u64>
+}
+
+impl Parameters for WindowRectParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<WindowRectParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["x", "y", "width", "height"]));
+        let coordinate = |key: &str| -> WebDriverResult<Nullable<i64>> {
+            match data.get(key) {
+                Some(x) if !x.is_null() => Ok(Nullable::Value(try!(as_i64_in_range(
+                    key, x, -MAX_WINDOW_COORDINATE - 1, MAX_WINDOW_COORDINATE)))),
+                _ => Ok(Nullable::Null)
+            }
+        };
+        let size = |key: &str| -> WebDriverResult<Nullable<u64>> {
+            match data.get(key) {
+                Some(x) if !x.is_null() => Ok(Nullable::Value(try!(as_u64_in_range(
+                    key, x, 0, MAX_WINDOW_COORDINATE as u64)))),
+                _ => Ok(Nullable::Null)
+            }
+        };
+        return Ok(WindowRectParameters {
+            x: try!(coordinate("x")),
+            y: try!(coordinate("y")),
+            width: try!(size("width")),
+            height: try!(size("height"))
+        })
+    }
+}
+
+impl ToJson for WindowRectParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("x".to_string(), self.x.to_json());
+        data.insert("y".to_string(), self.y.to_json());
+        data.insert("width".to_string(), self.width.to_json());
+        data.insert("height".to_string(), self.height.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
 pub struct SwitchToWindowParameters {
     handle: String
 }
@@ -379,12 +529,8 @@ impl Parameters for SwitchToWindowParameters {
     fn from_json(body: &json::Json) -> WebDriverResult<SwitchToWindowParameters> {
         let data = try_opt!(body.as_object(), ErrorStatus::UnknownError,
                             "Message body was not an object");
-        let handle = try_opt!(
-            try_opt!(data.get("handle"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'handle' parameter").as_string(),
-            ErrorStatus::InvalidArgument,
-            "'handle' not a string");
+        try!(check_members(data, &["handle"]));
+        let handle = try!(get_string(data, "", "handle"));
         return Ok(SwitchToWindowParameters {
             handle: handle.to_string()
         })
@@ -399,9 +545,9 @@ impl ToJson for SwitchToWindowParameters {
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub struct LocatorParameters {
-    using: LocatorStrategy,
+    pub using: LocatorStrategy,
     value: String
 }
 
@@ -409,18 +555,10 @@ impl Parameters for LocatorParameters {
     fn from_json(body: &json::Json) -> WebDriverResult<LocatorParameters> {
         let data = try_opt!(body.as_object(), ErrorStatus::UnknownError,
                             "Message body was not an object");
+        try!(check_members(data, &["using", "value"]));
 
-        let using = try!(LocatorStrategy::from_json(
-            try_opt!(data.get("using"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'using' parameter")));
-
-        let value = try_opt!(
-            try_opt!(data.get("value"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'using' parameter").as_string(),
-            ErrorStatus::InvalidArgument,
-            "Could not convert using to string").into_string();
+        let using = try!(LocatorStrategy::from_json(try!(get_value(data, "", "using"))));
+        let value = try!(get_string(data, "", "value")).to_string();
 
         return Ok(LocatorParameters {
             using: using,
@@ -438,7 +576,7 @@ impl ToJson for LocatorParameters {
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub struct SwitchToFrameParameters {
     pub id: FrameId
 }
@@ -448,9 +586,8 @@ impl Parameters for SwitchToFrameParameters {
         let data = try_opt!(body.as_object(),
                             ErrorStatus::UnknownError,
                             "Message body was not an object");
-        let id = try!(FrameId::from_json(try_opt!(data.get("id"),
-                                                  ErrorStatus::UnknownError,
-                                                  "Missing 'id' parameter")));
+        try!(check_members(data, &["id"]));
+        let id = try!(FrameId::from_json(try!(get_value(data, "", "id"))));
 
         Ok(SwitchToFrameParameters {
             id: id
@@ -466,23 +603,62 @@ impl ToJson for SwitchToFrameParameters {
     }
 }
 
+#[deriving(PartialEq, Show)]
+pub struct GetElementAttributeParameters {
+    pub element: WebElement,
+    pub name: String
+}
+
+impl GetElementAttributeParameters {
+    pub fn from_path(params: &PathParams) -> GetElementAttributeParameters {
+        GetElementAttributeParameters {
+            element: element_param(params),
+            name: params.name("name").to_string()
+        }
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct GetCSSValueParameters {
+    pub element: WebElement,
+    pub property: String
+}
+
+impl GetCSSValueParameters {
+    pub fn from_path(params: &PathParams) -> GetCSSValueParameters {
+        GetCSSValueParameters {
+            element: element_param(params),
+            property: params.name("propertyName").to_string()
+        }
+    }
+}
+
 #[deriving(PartialEq)]
 pub struct SendKeysParameters {
+    pub element: WebElement,
     pub value: String
 }
 
-impl Parameters for SendKeysParameters {
-    fn from_json(body: &json::Json) -> WebDriverResult<SendKeysParameters> {
+//What gets typed is often a password, so it stays out of the log
+impl fmt::Show for SendKeysParameters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SendKeysParameters {{ element: {}, value: <{} chars> }}",
+               self.element, self.value.char_len())
+    }
+}
+
+impl SendKeysParameters {
+    pub fn from_http(params: &PathParams, body: &Json) -> WebDriverResult<SendKeysParameters> {
         let data = try_opt!(body.as_object(),
                             ErrorStatus::InvalidArgument,
                             "Message body was not an object");
-        let value = try_opt!(try_opt!(data.get("value"),
-                                      ErrorStatus::InvalidArgument,
-                                      "Missing 'value' parameter").as_string(),
-                             ErrorStatus::InvalidArgument,
-                             "'value' not a string").into_string();
+        try!(check_members(data, &["value"]));
+        let value = try!(get_string(data, "", "value")).to_string();
+        //Fail on unknown key codepoints before the browser has typed half the text
+        try!(keys::check(value[]));
 
         Ok(SendKeysParameters {
+            element: element_param(params),
             value: value
         })
     }
@@ -502,34 +678,44 @@ pub struct JavascriptCommandParameters {
     args: Nullable<Vec<json::Json>>
 }
 
+impl fmt::Show for JavascriptCommandParameters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JavascriptCommandParameters {{ script: <{} chars>, args: <{} bytes> }}",
+               self.script[].chars().count(), self.args.to_json().to_string().len())
+    }
+}
+
+impl JavascriptCommandParameters {
+    pub fn new(script: String, args: Vec<json::Json>) -> JavascriptCommandParameters {
+        JavascriptCommandParameters {
+            script: script,
+            args: Nullable::Value(args)
+        }
+    }
+}
+
 impl Parameters for JavascriptCommandParameters {
     fn from_json(body: &json::Json) -> WebDriverResult<JavascriptCommandParameters> {
         let data = try_opt!(body.as_object(),
                             ErrorStatus::InvalidArgument,
                             "Message body was not an object");
-
-        let args_json = try_opt!(data.get("args"),
-                                 ErrorStatus::InvalidArgument,
-                                 "Missing args parameter");
+        try!(check_members(data, &["script", "args"]));
 
         let args = try!(Nullable::from_json(
-            args_json,
+            try!(get_value(data, "", "args")),
             |x| {
-                Ok((try_opt!(x.as_array(),
-                             ErrorStatus::InvalidArgument,
-                             "Failed to convert args to Array")).clone())
+                let args = try!(as_array("args", x));
+                let mut rv = vec![];
+                for arg in args.iter() {
+                    rv.push(try!(convert_references(arg)));
+                }
+                Ok(rv)
             }));
 
-         //TODO: Look for WebElements in args?
-        let script = try_opt!(
-            try_opt!(data.get("script"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing script parameter").as_string(),
-            ErrorStatus::InvalidArgument,
-            "Failed to convert script to String");
+        let script = try!(get_string(data, "", "script"));
         Ok(JavascriptCommandParameters {
             script: script.to_string(),
-            args: args.clone()
+            args: args
         })
     }
 }
@@ -547,7 +733,7 @@ impl ToJson for JavascriptCommandParameters {
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub struct GetCookieParameters {
     name: Nullable<String>
 }
@@ -556,16 +742,10 @@ impl Parameters for GetCookieParameters {
     fn from_json(body: &json::Json) -> WebDriverResult<GetCookieParameters> {
         let data = try_opt!(body.as_object(), ErrorStatus::InvalidArgument,
                             "Message body was not an object");
-        let name_json = try_opt!(data.get("name"),
-                                 ErrorStatus::InvalidArgument,
-                                 "Missing 'name' parameter");
+        try!(check_members(data, &["name"]));
         let name = try!(Nullable::from_json(
-            name_json,
-            |x| {
-                Ok(try_opt!(x.as_string(),
-                            ErrorStatus::InvalidArgument,
-                            "Failed to convert name to String").into_string())
-            }));
+            try!(get_value(data, "", "name")),
+            |x| Ok(try!(as_string("name", x)).to_string())));
         return Ok(GetCookieParameters {
             name: name
         })
@@ -580,7 +760,7 @@ impl ToJson for GetCookieParameters {
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub struct AddCookieParameters {
     pub name: String,
     pub value: String,
@@ -589,7 +769,8 @@ pub struct AddCookieParameters {
     pub expiry: Nullable<Date>,
     pub maxAge: Nullable<Date>,
     pub secure: bool,
-    pub httpOnly: bool
+    pub httpOnly: bool,
+    pub sameSite: Nullable<SameSite>
 }
 
 impl Parameters for AddCookieParameters {
@@ -597,87 +778,46 @@ impl Parameters for AddCookieParameters {
         let data = try_opt!(body.as_object(),
                             ErrorStatus::InvalidArgument,
                             "Message body was not an object");
-        let name = try_opt!(
-            try_opt!(data.get("name"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'name' parameter").as_string(),
-            ErrorStatus::InvalidArgument,
-            "'name' is not a string").into_string();
-
-        let value = try_opt!(
-            try_opt!(data.get("value"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'value' parameter").as_string(),
-            ErrorStatus::InvalidArgument,
-            "'value' is not a string").into_string();
-
-        let path = match data.get("path") {
-            Some(path_json) => {
-                try!(Nullable::from_json(
-                    path_json,
-                    |x| {
-                        Ok(try_opt!(x.as_string(),
-                                    ErrorStatus::InvalidArgument,
-                                    "Failed to convert path to String").into_string())
-                    }))
-            },
-            None => Nullable::Null
-        };
+        try!(check_members(data, &["name", "value", "path", "domain", "expiry", "maxAge", "secure",
+                                   "httpOnly", "sameSite"]));
+        let name = try!(get_string(data, "", "name")).to_string();
+        let value = try!(get_string(data, "", "value")).to_string();
 
-        let domain = match data.get("domain") {
-            Some(domain_json) => {
-                try!(Nullable::from_json(
-                    domain_json,
-                    |x| {
-                        Ok(try_opt!(x.as_string(),
-                                    ErrorStatus::InvalidArgument,
-                                    "Failed to convert domain to String").into_string())
-                    }))
-            },
-            None => Nullable::Null
+        let string = |key: &str| -> WebDriverResult<Nullable<String>> {
+            match data.get(key) {
+                Some(x) => Nullable::from_json(x, |x| Ok(try!(as_string(key, x)).to_string())),
+                None => Ok(Nullable::Null)
+            }
         };
+        let path = try!(string("path"));
+        let domain = try!(string("domain"));
 
         //TODO: This is supposed to support some text format
-        let expiry = match data.get("expiry") {
-            Some(expiry_json) => {
-                try!(Nullable::from_json(
-                    expiry_json,
-                    |x| {
-                        Ok(Date::new(try_opt!(x.as_u64(),
-                                              ErrorStatus::InvalidArgument,
-                                              "Failed to convert expiry to Date")))
-                    }))
-            },
-            None => Nullable::Null
-        };
-
-        let max_age = match data.get("maxAge") {
-            Some(max_age_json) => {
-                try!(Nullable::from_json(
-                    max_age_json,
-                    |x| {
-                        Ok(Date::new(try_opt!(x.as_u64(),
-                                              ErrorStatus::InvalidArgument,
-                                              "Failed to convert expiry to Date")))
-                    }))
-            },
-            None => Nullable::Null
+        let date = |key: &str| -> WebDriverResult<Nullable<Date>> {
+            match data.get(key) {
+                Some(x) => Nullable::from_json(
+                    x, |x| Ok(Date::new(try!(as_u64_in_range(key, x, 0, MAX_SAFE_INTEGER))))),
+                None => Ok(Nullable::Null)
+            }
         };
+        let expiry = try!(date("expiry"));
+        let max_age = try!(date("maxAge"));
 
         let secure = match data.get("secure") {
-            Some(x) => try_opt!(x.as_boolean(),
-                                ErrorStatus::InvalidArgument,
-                                "Failed to convert secure to boolean"),
+            Some(x) => try!(as_boolean("secure", x)),
             None => false
         };
 
         let http_only = match data.get("httpOnly") {
-            Some(x) => try_opt!(x.as_boolean(),
-                                ErrorStatus::InvalidArgument,
-                                "Failed to convert httpOnly to boolean"),
+            Some(x) => try!(as_boolean("httpOnly", x)),
             None => false
         };
 
+        let same_site = match data.get("sameSite") {
+            Some(x) => try!(Nullable::from_json(x, |x| SameSite::from_json(x))),
+            None => Nullable::Null
+        };
+
         return Ok(AddCookieParameters {
             name: name,
             value: value,
@@ -686,7 +826,8 @@ impl Parameters for AddCookieParameters {
             expiry: expiry,
             maxAge: max_age,
             secure: secure,
-            httpOnly: http_only
+            httpOnly: http_only,
+            sameSite: same_site
         })
     }
 }
@@ -702,11 +843,12 @@ impl ToJson for AddCookieParameters {
         data.insert("maxAge".to_string(), self.maxAge.to_json());
         data.insert("secure".to_string(), self.secure.to_json());
         data.insert("httpOnly".to_string(), self.httpOnly.to_json());
+        data.insert("sameSite".to_string(), self.sameSite.to_json());
         json::Object(data)
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub struct SendAlertTextParameters {
     keysToSend: String
 }
@@ -715,14 +857,11 @@ impl Parameters for SendAlertTextParameters {
     fn from_json(body: &json::Json) -> WebDriverResult<SendAlertTextParameters> {
         let data = try_opt!(body.as_object(), ErrorStatus::InvalidArgument,
                             "Message body was not an object");
-        let keys = try_opt!(
-            try_opt!(data.get("keysToSend"),
-                     ErrorStatus::InvalidArgument,
-                     "Missing 'handle' parameter").as_string(),
-            ErrorStatus::InvalidArgument,
-            "'keysToSend' not a string").into_string();
+        try!(check_members(data, &["keysToSend"]));
+        let text = try!(get_string(data, "", "keysToSend")).to_string();
+        try!(keys::check(text[]));
         return Ok(SendAlertTextParameters {
-            keysToSend: keys
+            keysToSend: text
         })
     }
 }
@@ -735,9 +874,13 @@ impl ToJson for SendAlertTextParameters {
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub struct TakeScreenshotParameters {
-    pub element: Nullable<WebElement>
+    pub element: Nullable<WebElement>,
+    //Capture the whole document rather than what is in the viewport
+    pub full: bool,
+    //Capture the browser window, UI included, rather than the page
+    pub chrome: bool
 }
 
 impl Parameters for TakeScreenshotParameters {
@@ -745,6 +888,7 @@ impl Parameters for TakeScreenshotParameters {
         let data = try_opt!(body.as_object(),
                             ErrorStatus::InvalidArgument,
                             "Message body was not an object");
+        try!(check_members(data, &["element"]));
         let element = match data.get("element") {
             Some(element_json) => try!(Nullable::from_json(
                 element_json,
@@ -755,7 +899,9 @@ impl Parameters for TakeScreenshotParameters {
         };
 
         return Ok(TakeScreenshotParameters {
-            element: element
+            element: element,
+            full: false,
+            chrome: false
         })
     }
 }
@@ -764,6 +910,809 @@ impl ToJson for TakeScreenshotParameters {
     fn to_json(&self) -> json::Json {
         let mut data = TreeMap::new();
         data.insert("element".to_string(), self.element.to_json());
+        if self.full {
+            data.insert("full".to_string(), true.to_json());
+        }
+        if self.chrome {
+            data.insert("chrome".to_string(), true.to_json());
+        }
         json::Object(data)
     }
 }
+
+#[deriving(PartialEq, Clone, Show)]
+pub enum Context {
+    Chrome,
+    Content
+}
+
+impl Context {
+    pub fn from_json(body: &json::Json) -> WebDriverResult<Context> {
+        match try!(as_string("context", body)) {
+            "chrome" => Ok(Context::Chrome),
+            "content" => Ok(Context::Content),
+            _ => Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                         "Unknown context"))
+        }
+    }
+}
+
+impl ToJson for Context {
+    fn to_json(&self) -> json::Json {
+        json::Json::String(match *self {
+            Context::Chrome => "chrome",
+            Context::Content => "content"
+        }.into_string())
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct SetContextParameters {
+    pub context: Context
+}
+
+impl Parameters for SetContextParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<SetContextParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["context"]));
+        let context = try!(Context::from_json(
+            try_opt!(data.get("context"),
+                     ErrorStatus::InvalidArgument,
+                     "Missing 'context' parameter")));
+        return Ok(SetContextParameters {
+            context: context
+        })
+    }
+}
+
+impl ToJson for SetContextParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("context".to_string(), self.context.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq)]
+pub enum AddonSource {
+    Path(String),
+    Data(Vec<u8>)
+}
+
+//Add-ons can be megabytes, which don't belong in the log
+impl fmt::Show for AddonSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AddonSource::Path(ref x) => write!(f, "Path({})", x),
+            AddonSource::Data(ref x) => write!(f, "Data(<{} bytes>)", x.len())
+        }
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct InstallAddonParameters {
+    pub source: AddonSource,
+    pub temporary: bool
+}
+
+impl Parameters for InstallAddonParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<InstallAddonParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["path", "addon", "temporary"]));
+        let source = match (data.get("path"), data.get("addon")) {
+            (Some(path), None) => {
+                AddonSource::Path(try!(as_string("path", path)).to_string())
+            },
+            (None, Some(addon)) => {
+                let encoded = try!(as_string("addon", addon));
+                match encoded.from_base64() {
+                    Ok(x) => AddonSource::Data(x),
+                    Err(_) => return Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                                             "'addon' is not valid base64"))
+                }
+            },
+            _ => return Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                                "Expected exactly one of 'path' or 'addon'"))
+        };
+        let temporary = match data.get("temporary") {
+            Some(x) => try!(as_boolean("temporary", x)),
+            None => false
+        };
+        return Ok(InstallAddonParameters {
+            source: source,
+            temporary: temporary
+        })
+    }
+}
+
+impl ToJson for InstallAddonParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        match self.source {
+            AddonSource::Path(ref x) => {
+                data.insert("path".to_string(), x.to_json());
+            },
+            AddonSource::Data(ref x) => {
+                data.insert("addon".to_string(), x[].to_base64(STANDARD).to_json());
+            }
+        }
+        data.insert("temporary".to_string(), self.temporary.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct UninstallAddonParameters {
+    pub id: String
+}
+
+impl Parameters for UninstallAddonParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<UninstallAddonParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["id"]));
+        let id = try!(get_string(data, "", "id")).to_string();
+        return Ok(UninstallAddonParameters {
+            id: id
+        })
+    }
+}
+
+impl ToJson for UninstallAddonParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("id".to_string(), self.id.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct GetLogParameters {
+    pub log_type: String
+}
+
+impl Parameters for GetLogParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<GetLogParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["type"]));
+        let log_type = try!(get_string(data, "", "type")).to_string();
+        return Ok(GetLogParameters {
+            log_type: log_type
+        })
+    }
+}
+
+impl ToJson for GetLogParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("type".to_string(), self.log_type.to_json());
+        json::Object(data)
+    }
+}
+
+// A zip archive with the files to put on the machine the browser runs on
+#[deriving(PartialEq)]
+pub struct UploadFileParameters {
+    pub file: Vec<u8>
+}
+
+impl fmt::Show for UploadFileParameters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UploadFileParameters {{ file: <{} bytes> }}", self.file.len())
+    }
+}
+
+impl Parameters for UploadFileParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<UploadFileParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["file"]));
+        let encoded = try!(get_string(data, "", "file"));
+        match encoded.from_base64() {
+            Ok(x) => Ok(UploadFileParameters {
+                file: x
+            }),
+            Err(_) => Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                              "'file' is not valid base64"))
+        }
+    }
+}
+
+impl ToJson for UploadFileParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("file".to_string(), self.file[].to_base64(STANDARD).to_json());
+        json::Object(data)
+    }
+}
+
+// The JSON wire protocol's touch commands, which name elements by their bare id and
+// put points in page coordinates
+#[deriving(PartialEq, Show)]
+pub struct TouchElementParameters {
+    pub element: WebElement
+}
+
+impl Parameters for TouchElementParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<TouchElementParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["element"]));
+        Ok(TouchElementParameters {
+            element: WebElement::new(try!(get_string(data, "", "element")).to_string())
+        })
+    }
+}
+
+impl ToJson for TouchElementParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("element".to_string(), self.element.id.to_json());
+        json::Object(data)
+    }
+}
+
+fn touch_offset(data: &TreeMap<String, Json>, key: &str) -> WebDriverResult<i64> {
+    as_i64_in_range(key, try!(get_value(data, "", key)),
+                    -MAX_WINDOW_COORDINATE - 1, MAX_WINDOW_COORDINATE)
+}
+
+fn flick_speed(data: &TreeMap<String, Json>, key: &str) -> WebDriverResult<i64> {
+    as_i64_in_range(key, try!(get_value(data, "", key)), -MAX_FLICK_SPEED, MAX_FLICK_SPEED)
+}
+
+// In f64, since the squares of offsets can be too large for an i64
+fn distance(x: i64, y: i64) -> f64 {
+    let (x, y) = (x as f64, y as f64);
+    (x * x + y * y).sqrt()
+}
+
+fn touch_element(data: &TreeMap<String, Json>) -> WebDriverResult<Option<WebElement>> {
+    match data.get("element") {
+        Some(x) if !x.is_null() => {
+            Ok(Some(WebElement::new(try!(as_string("element", x)).to_string())))
+        },
+        _ => Ok(None)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct TouchPointParameters {
+    pub x: i64,
+    pub y: i64
+}
+
+impl Parameters for TouchPointParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<TouchPointParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["x", "y"]));
+        Ok(TouchPointParameters {
+            x: try!(touch_offset(data, "x")),
+            y: try!(touch_offset(data, "y"))
+        })
+    }
+}
+
+impl ToJson for TouchPointParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("x".to_string(), self.x.to_json());
+        data.insert("y".to_string(), self.y.to_json());
+        json::Object(data)
+    }
+}
+
+// Without an element the scroll starts wherever the last touch was
+#[deriving(PartialEq, Show)]
+pub struct TouchScrollParameters {
+    pub element: Option<WebElement>,
+    pub xoffset: i64,
+    pub yoffset: i64
+}
+
+impl Parameters for TouchScrollParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<TouchScrollParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["element", "xoffset", "yoffset"]));
+        Ok(TouchScrollParameters {
+            element: try!(touch_element(data)),
+            xoffset: try!(touch_offset(data, "xoffset")),
+            yoffset: try!(touch_offset(data, "yoffset"))
+        })
+    }
+}
+
+impl ToJson for TouchScrollParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("element".to_string(),
+                    self.element.as_ref().map(|x| x.id.clone()).to_json());
+        data.insert("xoffset".to_string(), self.xoffset.to_json());
+        data.insert("yoffset".to_string(), self.yoffset.to_json());
+        json::Object(data)
+    }
+}
+
+//How long a flick given only as a speed lasts, in ms
+static FLICK_DURATION: i64 = 100;
+
+//In pixels per second, which is off any screen well within a flick
+static MAX_FLICK_SPEED: i64 = 1000000;
+
+// A flick is either a distance from an element covered at some speed, or just a speed
+// from wherever the last touch was. The second form is turned into the first, with the
+// distance the finger covers in a short flick at that speed.
+#[deriving(PartialEq, Show)]
+pub struct TouchFlickParameters {
+    pub element: Option<WebElement>,
+    pub xoffset: i64,
+    pub yoffset: i64,
+    //In pixels per second
+    pub speed: f64
+}
+
+impl Parameters for TouchFlickParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<TouchFlickParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["element", "xoffset", "yoffset", "speed",
+                                   "xspeed", "yspeed"]));
+        if data.contains_key("xspeed") || data.contains_key("yspeed") {
+            let xspeed = try!(flick_speed(data, "xspeed"));
+            let yspeed = try!(flick_speed(data, "yspeed"));
+            let speed = distance(xspeed, yspeed);
+            if speed == 0.0 {
+                return Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                               "Invalid parameters xspeed and yspeed: \
+                                                expected a speed, got 0 for both"))
+            }
+            return Ok(TouchFlickParameters {
+                element: None,
+                xoffset: xspeed * FLICK_DURATION / 1000,
+                yoffset: yspeed * FLICK_DURATION / 1000,
+                speed: speed
+            })
+        }
+        let speed = try!(get_value(data, "", "speed"));
+        let speed = match speed.as_f64() {
+            Some(x) if x > 0.0 => x,
+            _ => return Err(invalid("speed", "a positive number", speed))
+        };
+        Ok(TouchFlickParameters {
+            element: try!(touch_element(data)),
+            xoffset: try!(touch_offset(data, "xoffset")),
+            yoffset: try!(touch_offset(data, "yoffset")),
+            speed: speed
+        })
+    }
+}
+
+impl TouchFlickParameters {
+    // How long the finger takes to cover the distance, in ms
+    pub fn duration(&self) -> u64 {
+        (distance(self.xoffset, self.yoffset) * 1000.0 / self.speed) as u64
+    }
+}
+
+impl ToJson for TouchFlickParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("element".to_string(),
+                    self.element.as_ref().map(|x| x.id.clone()).to_json());
+        data.insert("xoffset".to_string(), self.xoffset.to_json());
+        data.insert("yoffset".to_string(), self.yoffset.to_json());
+        data.insert("speed".to_string(), self.speed.to_json());
+        json::Object(data)
+    }
+}
+
+// Actions are sequences of ticks, one sequence per input source. A source only takes
+// the actions that make sense for it, besides pause, which they all take.
+#[deriving(PartialEq, Show)]
+pub struct ActionsParameters {
+    pub actions: Vec<ActionSequence>
+}
+
+impl Parameters for ActionsParameters {
+    fn from_json(body: &json::Json) -> WebDriverResult<ActionsParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["actions"]));
+        let sequences = try!(as_array("actions", try!(get_value(data, "", "actions"))));
+        let mut actions = vec![];
+        for (i, x) in sequences.iter().enumerate() {
+            actions.push(try!(ActionSequence::from_json(index_path("actions", i)[], x)));
+        }
+        Ok(ActionsParameters {
+            actions: actions
+        })
+    }
+}
+
+impl ToJson for ActionsParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("actions".to_string(), self.actions.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct ActionSequence {
+    pub id: String,
+    pub source: ActionSource,
+    pub actions: Vec<Action>
+}
+
+impl ActionSequence {
+    fn from_json(path: &str, value: &Json) -> WebDriverResult<ActionSequence> {
+        let data = try!(as_object(path, value));
+        let source = match try!(get_string(data, path, "type")) {
+            "none" => ActionSource::Null,
+            "key" => ActionSource::Key,
+            "pointer" => ActionSource::Pointer(try!(pointer_type(path, data))),
+            "wheel" => ActionSource::Wheel,
+            x => return Err(wd_err!(ErrorStatus::InvalidArgument,
+                                    "Invalid parameter {}: expected one of none, key, pointer \
+                                     or wheel, got {}", join_path(path, "type"), x))
+        };
+        let actions_path = join_path(path, "actions");
+        let items = try!(as_array(actions_path[], try!(get_value(data, path, "actions"))));
+        let mut actions = vec![];
+        for (i, x) in items.iter().enumerate() {
+            actions.push(try!(Action::from_json(&source, index_path(actions_path[], i)[], x)));
+        }
+        Ok(ActionSequence {
+            id: try!(get_string(data, path, "id")).to_string(),
+            source: source,
+            actions: actions
+        })
+    }
+}
+
+impl ToJson for ActionSequence {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("type".to_string(), self.source.name().to_json());
+        data.insert("id".to_string(), self.id.to_json());
+        match self.source {
+            ActionSource::Pointer(ref x) => {
+                let mut parameters = TreeMap::new();
+                parameters.insert("pointerType".to_string(), x.name().to_json());
+                data.insert("parameters".to_string(), json::Object(parameters));
+            },
+            _ => {}
+        }
+        data.insert("actions".to_string(), self.actions.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub enum ActionSource {
+    Null,
+    Key,
+    Pointer(PointerType),
+    Wheel
+}
+
+impl ActionSource {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ActionSource::Null => "none",
+            ActionSource::Key => "key",
+            ActionSource::Pointer(_) => "pointer",
+            ActionSource::Wheel => "wheel"
+        }
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub enum PointerType {
+    Mouse,
+    Pen,
+    Touch
+}
+
+impl PointerType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PointerType::Mouse => "mouse",
+            PointerType::Pen => "pen",
+            PointerType::Touch => "touch"
+        }
+    }
+}
+
+fn pointer_type(path: &str, data: &TreeMap<String, Json>) -> WebDriverResult<PointerType> {
+    let path = join_path(path, "parameters");
+    let parameters = match data.get("parameters") {
+        Some(x) => try!(as_object(path[], x)),
+        None => return Ok(PointerType::Mouse)
+    };
+    let member = join_path(path[], "pointerType");
+    match parameters.get("pointerType") {
+        Some(x) => match try!(as_string(member[], x)) {
+            "mouse" => Ok(PointerType::Mouse),
+            "pen" => Ok(PointerType::Pen),
+            "touch" => Ok(PointerType::Touch),
+            x => Err(wd_err!(ErrorStatus::InvalidArgument,
+                             "Invalid parameter {}: expected one of mouse, pen or touch, got {}",
+                             member, x))
+        },
+        None => Ok(PointerType::Mouse)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub enum Action {
+    //For how long, in ms, or a tick as long as the other sources' actions in it
+    Pause(Option<u64>),
+    KeyDown(String),
+    KeyUp(String),
+    PointerDown(u64),
+    PointerUp(u64),
+    PointerMove(PointerMoveAction),
+    Scroll(ScrollAction)
+}
+
+impl Action {
+    fn from_json(source: &ActionSource, path: &str, value: &Json) -> WebDriverResult<Action> {
+        let data = try!(as_object(path, value));
+        match (source, try!(get_string(data, path, "type"))) {
+            (_, "pause") => Ok(Action::Pause(try!(action_duration(path, data)))),
+            (&ActionSource::Key, "keyDown") => Ok(Action::KeyDown(try!(action_key(path, data)))),
+            (&ActionSource::Key, "keyUp") => Ok(Action::KeyUp(try!(action_key(path, data)))),
+            (&ActionSource::Pointer(_), "pointerDown") => {
+                Ok(Action::PointerDown(try!(action_button(path, data))))
+            },
+            (&ActionSource::Pointer(_), "pointerUp") => {
+                Ok(Action::PointerUp(try!(action_button(path, data))))
+            },
+            (&ActionSource::Pointer(_), "pointerMove") => {
+                Ok(Action::PointerMove(PointerMoveAction {
+                    duration: try!(action_duration(path, data)),
+                    origin: try!(ActionOrigin::from_json(path, data, true)),
+                    x: try!(action_offset(path, data, "x")),
+                    y: try!(action_offset(path, data, "y"))
+                }))
+            },
+            (&ActionSource::Wheel, "scroll") => {
+                Ok(Action::Scroll(ScrollAction {
+                    duration: try!(action_duration(path, data)),
+                    origin: try!(ActionOrigin::from_json(path, data, false)),
+                    x: try!(action_offset(path, data, "x")),
+                    y: try!(action_offset(path, data, "y")),
+                    delta_x: try!(action_offset(path, data, "deltaX")),
+                    delta_y: try!(action_offset(path, data, "deltaY"))
+                }))
+            },
+            (_, x) => Err(wd_err!(ErrorStatus::InvalidArgument,
+                                  "Invalid parameter {}: {} is not an action of {} input sources",
+                                  join_path(path, "type"), x, source.name()))
+        }
+    }
+}
+
+impl ToJson for Action {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        let (type_, duration) = match *self {
+            Action::Pause(duration) => ("pause", duration),
+            Action::KeyDown(ref x) => {
+                data.insert("value".to_string(), x.to_json());
+                ("keyDown", None)
+            },
+            Action::KeyUp(ref x) => {
+                data.insert("value".to_string(), x.to_json());
+                ("keyUp", None)
+            },
+            Action::PointerDown(x) => {
+                data.insert("button".to_string(), x.to_json());
+                ("pointerDown", None)
+            },
+            Action::PointerUp(x) => {
+                data.insert("button".to_string(), x.to_json());
+                ("pointerUp", None)
+            },
+            Action::PointerMove(ref x) => {
+                data.insert("origin".to_string(), x.origin.to_json());
+                data.insert("x".to_string(), x.x.to_json());
+                data.insert("y".to_string(), x.y.to_json());
+                ("pointerMove", x.duration)
+            },
+            Action::Scroll(ref x) => {
+                data.insert("origin".to_string(), x.origin.to_json());
+                data.insert("x".to_string(), x.x.to_json());
+                data.insert("y".to_string(), x.y.to_json());
+                data.insert("deltaX".to_string(), x.delta_x.to_json());
+                data.insert("deltaY".to_string(), x.delta_y.to_json());
+                ("scroll", x.duration)
+            }
+        };
+        data.insert("type".to_string(), type_.to_json());
+        match duration {
+            Some(x) => {
+                data.insert("duration".to_string(), x.to_json());
+            },
+            None => {}
+        }
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct PointerMoveAction {
+    pub duration: Option<u64>,
+    pub origin: ActionOrigin,
+    pub x: i64,
+    pub y: i64
+}
+
+// x and y are where the scroll happens, relative to the origin, and the deltas are how
+// far the wheel scrolls, in CSS pixels
+#[deriving(PartialEq, Show)]
+pub struct ScrollAction {
+    pub duration: Option<u64>,
+    pub origin: ActionOrigin,
+    pub x: i64,
+    pub y: i64,
+    pub delta_x: i64,
+    pub delta_y: i64
+}
+
+// What the coordinates of a move or a scroll are relative to. Wheels have no position
+// of their own, so scrolls can't be relative to the pointer.
+#[deriving(PartialEq, Show)]
+pub enum ActionOrigin {
+    Viewport,
+    Pointer,
+    Element(WebElement)
+}
+
+impl ActionOrigin {
+    fn from_json(path: &str, data: &TreeMap<String, Json>,
+                 pointer: bool) -> WebDriverResult<ActionOrigin> {
+        let expected = if pointer {
+            "viewport, pointer or an element"
+        } else {
+            "viewport or an element"
+        };
+        match data.get("origin") {
+            None => Ok(ActionOrigin::Viewport),
+            Some(&Json::String(ref x)) if x[] == "viewport" => Ok(ActionOrigin::Viewport),
+            Some(&Json::String(ref x)) if x[] == "pointer" && pointer => Ok(ActionOrigin::Pointer),
+            Some(x @ &Json::Object(_)) => Ok(ActionOrigin::Element(try!(WebElement::from_json(x)))),
+            Some(x) => Err(invalid(join_path(path, "origin")[], expected, x))
+        }
+    }
+}
+
+impl ToJson for ActionOrigin {
+    fn to_json(&self) -> json::Json {
+        match *self {
+            ActionOrigin::Viewport => "viewport".to_json(),
+            ActionOrigin::Pointer => "pointer".to_json(),
+            ActionOrigin::Element(ref x) => x.to_json()
+        }
+    }
+}
+
+fn action_duration(path: &str, data: &TreeMap<String, Json>) -> WebDriverResult<Option<u64>> {
+    match data.get("duration") {
+        Some(x) => Ok(Some(try!(as_u64_in_range(join_path(path, "duration")[], x,
+                                                0, MAX_SAFE_INTEGER)))),
+        None => Ok(None)
+    }
+}
+
+fn action_offset(path: &str, data: &TreeMap<String, Json>, key: &str) -> WebDriverResult<i64> {
+    as_i64_in_range(join_path(path, key)[], try!(get_value(data, path, key)),
+                    -MAX_WINDOW_COORDINATE - 1, MAX_WINDOW_COORDINATE)
+}
+
+fn action_button(path: &str, data: &TreeMap<String, Json>) -> WebDriverResult<u64> {
+    as_u64_in_range(join_path(path, "button")[], try!(get_value(data, path, "button")),
+                    0, MAX_SAFE_INTEGER)
+}
+
+// A key action is for a single key, given as a character or one of the special
+// codepoints. The key isn't repeated in the error, in case it is part of a password.
+fn action_key(path: &str, data: &TreeMap<String, Json>) -> WebDriverResult<String> {
+    let value = try!(get_string(data, path, "value"));
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if keys::key_value(c).is_some() => Ok(value.to_string()),
+        _ => Err(wd_err!(ErrorStatus::InvalidArgument,
+                         "Invalid parameter {}: expected a single key", join_path(path, "value")))
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct PrefParameters {
+    pub name: String
+}
+
+impl PrefParameters {
+    pub fn from_path(params: &PathParams) -> PrefParameters {
+        PrefParameters {
+            name: params.name("prefName").to_string()
+        }
+    }
+}
+
+// Preferences are booleans, 32 bit integers or strings, and the type of a preference
+// that already has a value can't be changed
+#[deriving(PartialEq, Show)]
+pub struct SetPrefParameters {
+    pub name: String,
+    pub value: Json
+}
+
+impl SetPrefParameters {
+    pub fn from_http(params: &PathParams, body: &Json) -> WebDriverResult<SetPrefParameters> {
+        let data = try_opt!(body.as_object(),
+                            ErrorStatus::InvalidArgument,
+                            "Message body was not an object");
+        try!(check_members(data, &["value"]));
+        let value = try!(get_value(data, "", "value"));
+        match *value {
+            Json::Boolean(_) | Json::String(_) => {},
+            _ => {
+                try!(as_i64_in_range("value", value, -2147483648, 2147483647));
+            }
+        }
+        Ok(SetPrefParameters {
+            name: params.name("prefName").to_string(),
+            value: value.clone()
+        })
+    }
+}
+
+impl ToJson for SetPrefParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("value".to_string(), self.value.clone());
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct ExtensionParameters {
+    pub name: String,
+    pub parameters: Json
+}
+
+impl ToJson for ExtensionParameters {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("name".to_string(), self.name.to_json());
+        data.insert("parameters".to_string(), self.parameters.clone());
+        json::Object(data)
+    }
+}
+
+// Parser for extensions that take the request body as their parameters
+pub fn body_parameters(_: &PathParams, body: &Json) -> WebDriverResult<Json> {
+    match *body {
+        Json::Null => Ok(Json::Object(TreeMap::new())),
+        Json::Object(_) => Ok(body.clone()),
+        _ => Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                     "Message body was not an object"))
+    }
+}
@@ -1,13 +1,24 @@
 use core::u16;
+use hyper::HttpError;
 use serialize::{json, Encodable, Encoder};
 use serialize::json::{ToJson, ParserError};
 use std::collections::TreeMap;
+use std::borrow::IntoCow;
 use std::error::{Error, FromError};
+use std::fmt;
+use std::io::IoError;
+use std::str::FromStr;
+use std::string::CowString;
+use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+
+use params::{as_object, as_string, as_string_array, as_u64_in_range, get_string, join_path};
 
 #[deriving(PartialEq, Show)]
 pub enum ErrorStatus {
     ElementNotSelectable,
     ElementNotVisible,
+    //The server won't take requests from this client or page
+    Forbidden,
     InvalidArgument,
     InvalidCookieDomain,
     InvalidElementCoordinates,
@@ -32,28 +43,175 @@ pub enum ErrorStatus {
     UnsupportedOperation,
 }
 
+impl ErrorStatus {
+    // Marionette sends either the legacy numeric codes or the error names
+    pub fn from_json(value: &json::Json) -> Option<ErrorStatus> {
+        match *value {
+            json::Json::U64(x) => ErrorStatus::from_code(x),
+            json::Json::I64(x) if x >= 0 => ErrorStatus::from_code(x as u64),
+            json::Json::String(ref x) => from_str(x[]),
+            _ => None
+        }
+    }
+
+    pub fn from_code(code: u64) -> Option<ErrorStatus> {
+        let status = match code {
+            6 => ErrorStatus::InvalidSessionId,
+            7 => ErrorStatus::NoSuchElement,
+            8 => ErrorStatus::NoSuchFrame,
+            9 => ErrorStatus::UnsupportedOperation,
+            10 => ErrorStatus::StaleElementReference,
+            11 => ErrorStatus::ElementNotVisible,
+            12 => ErrorStatus::InvalidElementState,
+            13 => ErrorStatus::UnknownError,
+            15 => ErrorStatus::ElementNotSelectable,
+            17 => ErrorStatus::JavascriptError,
+            19 | 51 | 52 => ErrorStatus::InvalidSelector,
+            21 => ErrorStatus::Timeout,
+            23 => ErrorStatus::NoSuchWindow,
+            24 => ErrorStatus::InvalidCookieDomain,
+            25 => ErrorStatus::UnableToSetCookie,
+            26 => ErrorStatus::UnexpectedAlertOpen,
+            27 => ErrorStatus::NoSuchAlert,
+            28 => ErrorStatus::ScriptTimeout,
+            29 => ErrorStatus::InvalidElementCoordinates,
+            32 => ErrorStatus::InvalidSelector,
+            33 => ErrorStatus::SessionNotCreated,
+            34 => ErrorStatus::MoveTargetOutOfBounds,
+            405 => ErrorStatus::UnsupportedOperation,
+            _ => return None
+        };
+        Some(status)
+    }
+
+    // The numeric status of the JSON wire protocol that older clients expect
+    pub fn code(&self) -> u64 {
+        match *self {
+            ErrorStatus::InvalidSessionId => 6,
+            ErrorStatus::NoSuchElement => 7,
+            ErrorStatus::NoSuchFrame => 8,
+            ErrorStatus::UnknownPath | ErrorStatus::UnknownMethod |
+            ErrorStatus::UnsupportedOperation => 9,
+            ErrorStatus::StaleElementReference => 10,
+            ErrorStatus::ElementNotVisible => 11,
+            ErrorStatus::InvalidElementState => 12,
+            ErrorStatus::UnknownError | ErrorStatus::InvalidArgument |
+            ErrorStatus::Forbidden => 13,
+            ErrorStatus::ElementNotSelectable => 15,
+            ErrorStatus::JavascriptError => 17,
+            ErrorStatus::Timeout => 21,
+            ErrorStatus::NoSuchWindow => 23,
+            ErrorStatus::InvalidCookieDomain => 24,
+            ErrorStatus::UnableToSetCookie => 25,
+            ErrorStatus::UnexpectedAlertOpen => 26,
+            ErrorStatus::NoSuchAlert => 27,
+            ErrorStatus::ScriptTimeout => 28,
+            ErrorStatus::InvalidElementCoordinates => 29,
+            ErrorStatus::InvalidSelector => 32,
+            ErrorStatus::SessionNotCreated => 33,
+            ErrorStatus::MoveTargetOutOfBounds => 34
+        }
+    }
+}
+
+impl FromStr for ErrorStatus {
+    // Accepts the names from the spec as well as ones older Marionettes used
+    fn from_str(s: &str) -> Option<ErrorStatus> {
+        let status = match s {
+            "element not selectable" => ErrorStatus::ElementNotSelectable,
+            "element not visible" | "element not interactable" => ErrorStatus::ElementNotVisible,
+            "invalid argument" => ErrorStatus::InvalidArgument,
+            "invalid cookie domain" => ErrorStatus::InvalidCookieDomain,
+            "invalid element coordinates" => ErrorStatus::InvalidElementCoordinates,
+            "invalid element state" => ErrorStatus::InvalidElementState,
+            "invalid selector" | "invalid xpath selector" |
+            "invalid xpath selector return typer" => ErrorStatus::InvalidSelector,
+            "invalid session id" | "no such session" => ErrorStatus::InvalidSessionId,
+            "javascript error" => ErrorStatus::JavascriptError,
+            "move target out of bounds" => ErrorStatus::MoveTargetOutOfBounds,
+            "no such alert" | "no alert open" => ErrorStatus::NoSuchAlert,
+            "no such element" => ErrorStatus::NoSuchElement,
+            "no such frame" => ErrorStatus::NoSuchFrame,
+            "no such window" => ErrorStatus::NoSuchWindow,
+            "script timeout" => ErrorStatus::ScriptTimeout,
+            "session not created" => ErrorStatus::SessionNotCreated,
+            "stale element reference" => ErrorStatus::StaleElementReference,
+            "timeout" => ErrorStatus::Timeout,
+            "unable to set cookie" => ErrorStatus::UnableToSetCookie,
+            "unexpected alert open" => ErrorStatus::UnexpectedAlertOpen,
+            "unknown error" => ErrorStatus::UnknownError,
+            //A command the browser doesn't know is one we can't support, not a bad path
+            "unknown command" | "unsupported operation" => ErrorStatus::UnsupportedOperation,
+            _ => return None
+        };
+        Some(status)
+    }
+}
+
 pub type WebDriverResult<T> = Result<T, WebDriverError>;
 
-#[deriving(Show)]
 pub struct WebDriverError {
     pub status: ErrorStatus,
-    pub message: String
+    pub message: CowString<'static>,
+    pub stack: Option<String>,
+    //The I/O or parse error this was caused by, if any
+    pub source: Option<Box<Error + Send>>
 }
 
 impl WebDriverError {
-    pub fn new(status: ErrorStatus, message: &str) -> WebDriverError {
+    // Fixed messages are kept as they are, only formatted ones are allocated
+    pub fn new<M: IntoCow<'static, String, str>>(status: ErrorStatus,
+                                                  message: M) -> WebDriverError {
         WebDriverError {
             status: status,
-            message: message.to_string().clone()
+            message: message.into_cow(),
+            stack: None,
+            source: None
         }
     }
 
-    pub fn status_code(&self) -> &str {
-    // This expands to status_code<'a>(&'a self) -> &'a str; consider
-    // status_code(&self) -> &'static str.
+    pub fn from_status(status: ErrorStatus) -> WebDriverError {
+        let message = match status {
+            ErrorStatus::ElementNotSelectable => "Element can't be selected",
+            ErrorStatus::ElementNotVisible => "Element is not visible",
+            ErrorStatus::Forbidden => "Request is not allowed",
+            ErrorStatus::InvalidArgument => "Invalid argument",
+            ErrorStatus::InvalidCookieDomain => "Cookie domain doesn't match the current page",
+            ErrorStatus::InvalidElementCoordinates => "Element coordinates are invalid",
+            ErrorStatus::InvalidElementState => "Element is in an invalid state",
+            ErrorStatus::InvalidSelector => "Selector is invalid",
+            ErrorStatus::InvalidSessionId => "Session id is not known",
+            ErrorStatus::JavascriptError => "Script threw an error",
+            ErrorStatus::MoveTargetOutOfBounds => "Move target is out of bounds",
+            ErrorStatus::NoSuchAlert => "No alert is open",
+            ErrorStatus::NoSuchElement => "Element not found",
+            ErrorStatus::NoSuchFrame => "Frame not found",
+            ErrorStatus::NoSuchWindow => "Window not found",
+            ErrorStatus::ScriptTimeout => "Script timed out",
+            ErrorStatus::SessionNotCreated => "Session could not be created",
+            ErrorStatus::StaleElementReference => "Element is no longer attached to the page",
+            ErrorStatus::Timeout => "Timed out",
+            ErrorStatus::UnableToSetCookie => "Cookie could not be set",
+            ErrorStatus::UnexpectedAlertOpen => "An alert is open",
+            ErrorStatus::UnknownError => "Unknown error",
+            ErrorStatus::UnknownPath => "Unknown path",
+            ErrorStatus::UnknownMethod => "Method not allowed for this path",
+            ErrorStatus::UnsupportedOperation => "Operation is not supported"
+        };
+        WebDriverError::new(status, message)
+    }
+
+    pub fn with_source<E: Error + Send>(mut self, source: E) -> WebDriverError {
+        self.source = Some(box source as Box<Error + Send>);
+        self
+    }
+
+    pub fn status_code(&self) -> &'static str {
         match self.status {
             ErrorStatus::ElementNotSelectable => "element not selectable",
             ErrorStatus::ElementNotVisible => "element not visible",
+            //WebDriver has no error for this, the HTTP status says what happened
+            ErrorStatus::Forbidden => "unknown error",
             ErrorStatus::InvalidArgument => "invalid argument",
             ErrorStatus::InvalidCookieDomain => "invalid cookie domain",
             ErrorStatus::InvalidElementCoordinates => "invalid element coordinates",
@@ -81,6 +239,7 @@ impl WebDriverError {
 
     pub fn http_status(&self) -> int {
         match self.status {
+            ErrorStatus::Forbidden => 403,
             ErrorStatus::UnknownPath => 404,
             ErrorStatus::UnknownMethod => 405,
             _ => 500
@@ -90,13 +249,30 @@ impl WebDriverError {
     pub fn to_json_string(&self) -> String {
         self.to_json().to_string()
     }
+
+    pub fn to_jsonwire_string(&self, session_id: Option<String>) -> String {
+        let mut value = TreeMap::new();
+        value.insert("message".to_string(), (*self.message).to_json());
+        let mut data = TreeMap::new();
+        data.insert("status".to_string(), self.status.code().to_json());
+        data.insert("sessionId".to_string(), session_id.to_json());
+        data.insert("value".to_string(), json::Object(value));
+        json::Object(data).to_string()
+    }
 }
 
 impl ToJson for WebDriverError {
     fn to_json(&self) -> json::Json {
+        let mut value = TreeMap::new();
+        value.insert("error".to_string(), self.status_code().to_json());
+        value.insert("message".to_string(), (*self.message).to_json());
+        value.insert("stacktrace".to_string(), match self.stack {
+            Some(ref x) => x.to_json(),
+            None => "".to_json()
+        });
+        //The same envelope as successful responses
         let mut data = TreeMap::new();
-        data.insert("status".to_string(), self.status_code().to_json());
-        data.insert("error".to_string(), self.message.to_json());
+        data.insert("value".to_string(), json::Object(value));
         json::Object(data)
     }
 }
@@ -107,18 +283,42 @@ impl Error for WebDriverError {
     }
 
     fn detail(&self) -> Option<String> {
-        Some(self.message.clone())
+        Some((*self.message).to_string())
     }
 
     fn cause(&self) -> Option<&Error> {
-        None
+        self.source.as_ref().map(|x| &**x as &Error)
+    }
+}
+
+impl fmt::Show for WebDriverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}: {}", self.status_code(), self.message));
+        match self.source {
+            Some(ref x) => write!(f, " (caused by {})", x.description()),
+            None => Ok(())
+        }
+    }
+}
+
+impl FromError<IoError> for WebDriverError {
+    fn from_error(err: IoError) -> WebDriverError {
+        let msg = format!("{}", err);
+        WebDriverError::new(ErrorStatus::UnknownError, msg).with_source(err)
+    }
+}
+
+impl FromError<HttpError> for WebDriverError {
+    fn from_error(err: HttpError) -> WebDriverError {
+        let msg = format!("{}", err);
+        WebDriverError::new(ErrorStatus::UnknownError, msg).with_source(err)
     }
 }
 
 impl FromError<ParserError> for WebDriverError {
     fn from_error(err: ParserError) -> WebDriverError {
         let msg = format!("{}", err);
-        WebDriverError::new(ErrorStatus::UnknownError, msg.as_slice())
+        WebDriverError::new(ErrorStatus::UnknownError, msg).with_source(err)
     }
 }
 
@@ -142,6 +342,34 @@ impl<T: ToJson> Nullable<T> {
             Nullable::Null => false
         }
     }
+
+    pub fn from_option(value: Option<T>) -> Nullable<T> {
+        match value {
+            Some(x) => Nullable::Value(x),
+            None => Nullable::Null
+        }
+    }
+
+    pub fn into_option(self) -> Option<T> {
+        match self {
+            Nullable::Value(x) => Some(x),
+            Nullable::Null => None
+        }
+    }
+
+    pub fn as_ref(&self) -> Option<&T> {
+        match *self {
+            Nullable::Value(ref x) => Some(x),
+            Nullable::Null => None
+        }
+    }
+
+    pub fn map<U: ToJson, F: FnOnce(T) -> U>(self, f: F) -> Nullable<U> {
+        match self {
+            Nullable::Value(x) => Nullable::Value(f(x)),
+            Nullable::Null => Nullable::Null
+        }
+    }
 }
 
 impl<T: ToJson> Nullable<T> {
@@ -173,7 +401,17 @@ impl<S: Encoder<E>, E, T: ToJson> Encodable<S, E> for Nullable<T> {
     }
 }
 
-#[deriving(PartialEq)]
+pub static ELEMENT_KEY: &'static str = "element-6066-11e4-a52e-4f735466cecf";
+pub static LEGACY_ELEMENT_KEY: &'static str = "ELEMENT";
+
+//Whether elements are sent with LEGACY_ELEMENT_KEY as well
+static LEGACY_ELEMENT_KEYS: AtomicBool = INIT_ATOMIC_BOOL;
+
+pub fn set_legacy_element_keys(enabled: bool) {
+    LEGACY_ELEMENT_KEYS.store(enabled, SeqCst);
+}
+
+#[deriving(PartialEq, Show)]
 pub struct WebElement {
     pub id: String
 }
@@ -186,41 +424,289 @@ impl WebElement {
     }
 
     pub fn from_json(data: &json::Json) -> WebDriverResult<WebElement> {
-        Ok(WebElement::new(
-            try_opt!(
-                try_opt!(
-                    try_opt!(data.as_object(),
-                             ErrorStatus::InvalidArgument,
-                             "Could not convert webelement to object").get(
-                        "element-6066-11e4-a52e-4f735466cecf"),
-                    ErrorStatus::InvalidArgument,
-                    "Could not find webelement key").as_string(),
-                ErrorStatus::InvalidArgument,
-                "Could not convert web element to string").into_string()))
-        // Not very readable...
-        let object = try_opt!(data.as_object(),
-                              ErrorStatus::InvalidArgument,
-                              "Could not convert webelement to object");
-        let key_value = try_opt!(object.get("element-6066-11e4-a52e-4f735466cecf"),
-                                 ErrorStatus::InvalidArgument,
-                                 "Could not find webelement key");
-        let key = try_opt!(key_value.as_string(),
-                           ErrorStatus::InvalidArgument,
-                           "Could not convert web element to string").into_string();
-        Ok(WebElement::new(key))
+        let object = try!(as_object("element", data));
+        //Older Selenium bindings only know the key from the JSON wire protocol
+        let key = if object.contains_key(ELEMENT_KEY) {
+            ELEMENT_KEY
+        } else {
+            LEGACY_ELEMENT_KEY
+        };
+        Ok(WebElement::new(try!(get_string(object, "element", key)).to_string()))
     }
 }
 
 impl ToJson for WebElement {
     fn to_json(&self) -> json::Json {
         let mut data = TreeMap::new();
-        data.insert("element-6066-11e4-a52e-4f735466cecf".to_string(), self.id.to_json());
-                    // ^ constant!
+        data.insert(ELEMENT_KEY.to_string(), self.id.to_json());
+        if LEGACY_ELEMENT_KEYS.load(SeqCst) {
+            data.insert(LEGACY_ELEMENT_KEY.to_string(), self.id.to_json());
+        }
         json::Object(data)
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Clone, Show)]
+pub struct Capabilities {
+    pub browser_name: Option<String>,
+    pub browser_version: Option<String>,
+    pub platform_name: Option<String>,
+    pub timeouts: Option<TreeMap<String, json::Json>>,
+    pub proxy: Option<TreeMap<String, json::Json>>,
+    //Everything else, including vendor extensions like moz:firefoxOptions
+    pub extensions: TreeMap<String, json::Json>
+}
+
+impl Capabilities {
+    pub fn new() -> Capabilities {
+        Capabilities {
+            browser_name: None,
+            browser_version: None,
+            platform_name: None,
+            timeouts: None,
+            proxy: None,
+            extensions: TreeMap::new()
+        }
+    }
+
+    pub fn from_json(data: &json::Json) -> WebDriverResult<Capabilities> {
+        Capabilities::from_json_at("capabilities", data)
+    }
+
+    // Older clients and browsers use version and platform rather than the spec names.
+    // path is where the capabilities were in the request, for error messages.
+    pub fn from_json_at(path: &str, data: &json::Json) -> WebDriverResult<Capabilities> {
+        let data = try!(as_object(path, data));
+        let mut rv = Capabilities::new();
+        for (key, value) in data.iter() {
+            let member = join_path(path, key[]);
+            match key[] {
+                "browserName" => {
+                    rv.browser_name = Some(try!(as_string(member[], value)).to_string())
+                },
+                "browserVersion" | "version" => {
+                    rv.browser_version = Some(try!(as_string(member[], value)).to_string())
+                },
+                "platformName" | "platform" => {
+                    rv.platform_name = Some(try!(as_string(member[], value)).to_string())
+                },
+                "timeouts" => rv.timeouts = Some(try!(timeouts_capability(member[], value))),
+                "proxy" => rv.proxy = Some(try!(proxy_capability(member[], value))),
+                _ => {
+                    rv.extensions.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        Ok(rv)
+    }
+}
+
+// Which form of New Session the client used, which also decides the form of the response
+#[deriving(PartialEq, Clone, Show)]
+pub enum CapabilitiesDialect {
+    W3C,
+    Legacy
+}
+
+//Timeouts are in ms, and have to fit in a JavaScript number
+pub static MAX_SAFE_INTEGER: u64 = 9007199254740991;
+
+fn timeouts_capability(path: &str,
+                       value: &json::Json) -> WebDriverResult<TreeMap<String, json::Json>> {
+    let timeouts = try!(as_object(path, value));
+    for (key, value) in timeouts.iter() {
+        try!(as_u64_in_range(join_path(path, key[])[], value, 0, MAX_SAFE_INTEGER));
+    }
+    Ok(timeouts.clone())
+}
+
+// Members the spec doesn't define are left for the browser to make sense of. Legacy
+// clients write the proxy type in capitals.
+fn proxy_capability(path: &str,
+                    value: &json::Json) -> WebDriverResult<TreeMap<String, json::Json>> {
+    let proxy = try!(as_object(path, value));
+    for (key, value) in proxy.iter() {
+        let member = join_path(path, key[]);
+        match key[] {
+            "proxyType" => match try!(as_string(member[], value)).to_ascii_lower()[] {
+                "pac" | "direct" | "autodetect" | "system" | "manual" => {},
+                x => return Err(wd_err!(ErrorStatus::InvalidArgument,
+                                        "Invalid parameter {}: expected one of pac, direct, \
+                                         autodetect, system or manual, got {}", member, x))
+            },
+            "proxyAutoconfigUrl" | "ftpProxy" | "httpProxy" | "sslProxy" | "socksProxy" => {
+                try!(as_string(member[], value));
+            },
+            "socksVersion" => {
+                try!(as_u64_in_range(member[], value, 0, 255));
+            },
+            "noProxy" => {
+                try!(as_string_array(member[], value));
+            },
+            _ => {}
+        }
+    }
+    Ok(proxy.clone())
+}
+
+impl ToJson for Capabilities {
+    fn to_json(&self) -> json::Json {
+        let mut data = self.extensions.clone();
+        match self.browser_name {
+            Some(ref x) => {
+                data.insert("browserName".to_string(), x.to_json());
+            },
+            None => {}
+        }
+        match self.browser_version {
+            Some(ref x) => {
+                data.insert("browserVersion".to_string(), x.to_json());
+            },
+            None => {}
+        }
+        match self.platform_name {
+            Some(ref x) => {
+                data.insert("platformName".to_string(), x.to_json());
+            },
+            None => {}
+        }
+        match self.timeouts {
+            Some(ref x) => {
+                data.insert("timeouts".to_string(), json::Json::Object(x.clone()));
+            },
+            None => {}
+        }
+        match self.proxy {
+            Some(ref x) => {
+                data.insert("proxy".to_string(), json::Json::Object(x.clone()));
+            },
+            None => {}
+        }
+        json::Json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Clone, Show)]
+pub enum SameSite {
+    Lax,
+    Strict,
+    None
+}
+
+impl SameSite {
+    pub fn from_json(data: &json::Json) -> WebDriverResult<SameSite> {
+        match try_opt!(data.as_string(),
+                       ErrorStatus::InvalidArgument,
+                       "Failed to convert sameSite to String") {
+            "Lax" => Ok(SameSite::Lax),
+            "Strict" => Ok(SameSite::Strict),
+            "None" => Ok(SameSite::None),
+            x => Err(wd_err!(ErrorStatus::InvalidArgument,
+                             "sameSite must be Lax, Strict or None, got {}", x))
+        }
+    }
+}
+
+impl ToJson for SameSite {
+    fn to_json(&self) -> json::Json {
+        match *self {
+            SameSite::Lax => "Lax",
+            SameSite::Strict => "Strict",
+            SameSite::None => "None"
+        }.to_json()
+    }
+}
+
+impl<S: Encoder<E>, E> Encodable<S, E> for SameSite {
+    fn encode(&self, s: &mut S) -> Result<(), E> {
+        self.to_json().encode(s)
+    }
+}
+
+pub static WINDOW_KEY: &'static str = "window-fcc6-11e5-b4f8-330a88ab9d7f";
+pub static FRAME_KEY: &'static str = "frame-075b-4da1-b6ba-e579c2d3230a";
+
+#[deriving(PartialEq, Show)]
+pub struct WebWindow {
+    pub id: String
+}
+
+impl WebWindow {
+    pub fn new(id: String) -> WebWindow {
+        WebWindow {
+            id: id
+        }
+    }
+
+    pub fn from_json(data: &json::Json) -> WebDriverResult<WebWindow> {
+        let object = try!(as_object("window", data));
+        Ok(WebWindow::new(try!(get_string(object, "window", WINDOW_KEY)).to_string()))
+    }
+}
+
+impl ToJson for WebWindow {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert(WINDOW_KEY.to_string(), self.id.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub struct WebFrame {
+    pub id: String
+}
+
+impl WebFrame {
+    pub fn new(id: String) -> WebFrame {
+        WebFrame {
+            id: id
+        }
+    }
+
+    pub fn from_json(data: &json::Json) -> WebDriverResult<WebFrame> {
+        let object = try!(as_object("frame", data));
+        Ok(WebFrame::new(try!(get_string(object, "frame", FRAME_KEY)).to_string()))
+    }
+}
+
+impl ToJson for WebFrame {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert(FRAME_KEY.to_string(), self.id.to_json());
+        json::Object(data)
+    }
+}
+
+// Script arguments and results can have window and frame references anywhere inside
+// them, which are checked and written out again without any extra keys
+pub fn convert_references(value: &json::Json) -> WebDriverResult<json::Json> {
+    match *value {
+        json::Json::Array(ref x) => {
+            let mut rv = vec![];
+            for item in x.iter() {
+                rv.push(try!(convert_references(item)));
+            }
+            Ok(json::Json::Array(rv))
+        },
+        json::Json::Object(ref x) if x.contains_key(WINDOW_KEY) => {
+            Ok(try!(WebWindow::from_json(value)).to_json())
+        },
+        json::Json::Object(ref x) if x.contains_key(FRAME_KEY) => {
+            Ok(try!(WebFrame::from_json(value)).to_json())
+        },
+        json::Json::Object(ref x) => {
+            let mut rv = TreeMap::new();
+            for (key, item) in x.iter() {
+                rv.insert(key.clone(), try!(convert_references(item)));
+            }
+            Ok(json::Json::Object(rv))
+        },
+        _ => Ok(value.clone())
+    }
+}
+
+#[deriving(PartialEq, Show)]
 pub enum FrameId {
     Short(u16),
     Element(WebElement),
@@ -229,26 +715,14 @@ pub enum FrameId {
 
 impl FrameId {
     pub fn from_json(data: &json::Json) -> WebDriverResult<FrameId> {
-      match data {
-          // indentation
-            &json::Json::U64(x) => {
-                if x <= u16::MAX as u64 {
-                    Ok(FrameId::Short(x as u16))
-                } else {
-                    Err(WebDriverError::new(ErrorStatus::NoSuchFrame,
-                                            "frame id out of range"))
-                }
-                // Or... use std::num::ToPrimitive;
-                match x.to_u16() {
-                    Some(x) => Ok(FrameId::Short(x)),
-                    None => Err(WebDriverError::new(ErrorStatus::NoSuchFrame,
-                                                    "frame id out of range")),
-                }
+        match *data {
+            json::Json::U64(_) | json::Json::I64(_) => {
+                Ok(FrameId::Short(try!(as_u64_in_range("id", data, 0, u16::MAX as u64)) as u16))
             },
-          &json::Json::Null => Ok(FrameId::Null),
-          &json::Json::String(ref x) => Ok(FrameId::Element(WebElement::new(x.clone()))),
-          _ => Err(WebDriverError::new(ErrorStatus::NoSuchFrame,
-                                       "frame id has unexpected type"))
+            json::Json::Null => Ok(FrameId::Null),
+            json::Json::String(ref x) => Ok(FrameId::Element(WebElement::new(x.clone()))),
+            _ => Err(WebDriverError::new(ErrorStatus::NoSuchFrame,
+                                         "frame id has unexpected type"))
         }
     }
 }
@@ -269,12 +743,14 @@ impl ToJson for FrameId {
     }
 }
 
-#[deriving(PartialEq)]
+#[deriving(PartialEq, Show)]
 pub enum LocatorStrategy {
     CSSSelector,
     LinkText,
     PartialLinkText,
-    XPath
+    XPath,
+    Anon,
+    AnonAttribute
 }
 
 impl LocatorStrategy {
@@ -286,10 +762,20 @@ impl LocatorStrategy {
             "link text" => Ok(LocatorStrategy::LinkText),
             "partial link text" => Ok(LocatorStrategy::PartialLinkText),
             "xpath" => Ok(LocatorStrategy::XPath),
+            "anon" => Ok(LocatorStrategy::Anon),
+            "anon attribute" => Ok(LocatorStrategy::AnonAttribute),
             _ => Err(WebDriverError::new(ErrorStatus::InvalidArgument,
                                          "Unknown locator strategy"))
         }
     }
+
+    // Anonymous content only exists in the browser's own UI
+    pub fn is_chrome_only(&self) -> bool {
+        match *self {
+            LocatorStrategy::Anon | LocatorStrategy::AnonAttribute => true,
+            _ => false
+        }
+    }
 }
 
 impl ToJson for LocatorStrategy {
@@ -298,7 +784,9 @@ impl ToJson for LocatorStrategy {
             LocatorStrategy::CSSSelector => "css selector",
             LocatorStrategy::LinkText => "link text",
             LocatorStrategy::PartialLinkText => "partial link text",
-            LocatorStrategy::XPath => "xpath"
+            LocatorStrategy::XPath => "xpath",
+            LocatorStrategy::Anon => "anon",
+            LocatorStrategy::AnonAttribute => "anon attribute"
         }.into_string())
     }
 }
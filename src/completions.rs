@@ -0,0 +1,88 @@
+use getopts::{OptGroup, HasArg};
+
+// Completion scripts are generated from the option table, so they can't get out of date
+pub fn generate(shell: &str, prog: &str, opts: &[OptGroup]) -> Result<String, String> {
+    match shell {
+        "bash" => Ok(bash(prog, opts)),
+        "zsh" => Ok(zsh(prog, opts)),
+        "fish" => Ok(fish(prog, opts)),
+        _ => Err(format!("can't generate completions for {}, expected bash, zsh or fish", shell))
+    }
+}
+
+fn names(opt: &OptGroup) -> Vec<String> {
+    let mut rv = vec![];
+    if !opt.short_name.is_empty() {
+        rv.push(format!("-{}", opt.short_name));
+    }
+    if !opt.long_name.is_empty() {
+        rv.push(format!("--{}", opt.long_name));
+    }
+    rv
+}
+
+fn takes_path(opt: &OptGroup) -> bool {
+    opt.hasarg != HasArg::No && opt.hint[] == "PATH"
+}
+
+fn bash(prog: &str, opts: &[OptGroup]) -> String {
+    let words: Vec<String> = opts.iter().flat_map(|x| names(x).into_iter()).collect();
+    let with_args: Vec<String> = opts.iter()
+        .filter(|x| x.hasarg != HasArg::No)
+        .flat_map(|x| names(x).into_iter())
+        .collect();
+    format!("_{prog}() {{
+    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"
+    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+    case \"$prev\" in
+        {with_args})
+            COMPREPLY=($(compgen -f -- \"$cur\"))
+            return;;
+    esac
+    COMPREPLY=($(compgen -W \"{words}\" -- \"$cur\"))
+}}
+complete -o default -F _{prog} {prog}
+", prog=prog, with_args=with_args.connect("|"), words=words.connect(" "))
+}
+
+fn zsh_escape(s: &str) -> String {
+    s.replace("'", "'\\''").replace("[", "\\[").replace("]", "\\]").replace(":", "\\:")
+}
+
+fn zsh(prog: &str, opts: &[OptGroup]) -> String {
+    let mut rv = format!("#compdef {}\n\n_arguments \\\n", prog);
+    for opt in opts.iter() {
+        let arg = if opt.hasarg == HasArg::No {
+            "".to_string()
+        } else if takes_path(opt) {
+            format!(":{}:_files", opt.hint)
+        } else {
+            format!(":{}:", opt.hint)
+        };
+        for name in names(opt).iter() {
+            rv.push_str(format!("    '{}[{}]{}' \\\n", name, zsh_escape(opt.desc[]), arg)[]);
+        }
+    }
+    rv.push_str("    '*:address:'\n");
+    rv
+}
+
+fn fish(prog: &str, opts: &[OptGroup]) -> String {
+    let mut rv = String::new();
+    for opt in opts.iter() {
+        let mut line = format!("complete -c {}", prog);
+        if !opt.short_name.is_empty() {
+            line.push_str(format!(" -s {}", opt.short_name)[]);
+        }
+        if !opt.long_name.is_empty() {
+            line.push_str(format!(" -l {}", opt.long_name)[]);
+        }
+        if opt.hasarg != HasArg::No {
+            line.push_str(if takes_path(opt) { " -r" } else { " -x" });
+        }
+        line.push_str(format!(" -d '{}'", opt.desc.replace("'", "\\'"))[]);
+        rv.push_str(line[]);
+        rv.push('\n');
+    }
+    rv
+}
@@ -0,0 +1,133 @@
+use getopts::{Matches, OptGroup};
+use std::collections::TreeMap;
+use std::io::File;
+use std::os;
+use toml;
+
+// Values for long options that come from somewhere other than the command line
+pub struct ConfigLayer {
+    values: TreeMap<String, String>
+}
+
+impl ConfigLayer {
+    // Keys are the long option names, e.g. port = 4444 or log-level = "debug". Lists
+    // like allow-hosts can be given as arrays.
+    pub fn from_file(path: &Path, opts: &[OptGroup]) -> Result<ConfigLayer, String> {
+        let data = match File::open(path).read_to_string() {
+            Ok(x) => x,
+            Err(e) => return Err(format!("failed to read {}: {}", path.display(), e))
+        };
+        let mut parser = toml::Parser::new(data[]);
+        let table = match parser.parse() {
+            Some(x) => x,
+            None => {
+                let msg = match parser.errors.iter().next() {
+                    Some(e) => e.desc.clone(),
+                    None => "unknown error".to_string()
+                };
+                return Err(format!("failed to parse {}: {}", path.display(), msg))
+            }
+        };
+        let mut values = TreeMap::new();
+        for (key, value) in table.into_iter() {
+            let known = key[] == "address" || opts.iter().any(|x| x.long_name == key);
+            if key[] == "config" || !known {
+                return Err(format!("unknown setting in {}: {}", path.display(), key));
+            }
+            let value = match value_to_string(&value) {
+                Some(x) => x,
+                None => return Err(format!("illegal value in {} for {}", path.display(), key))
+            };
+            values.insert(key, value);
+        }
+        Ok(ConfigLayer {
+            values: values
+        })
+    }
+
+    // WIRES_PORT for --port, WIRES_LOG_LEVEL for --log-level, etc., and WIRES_ADDRESS
+    // for ADDRESS
+    pub fn from_env(opts: &[OptGroup]) -> ConfigLayer {
+        let mut values = TreeMap::new();
+        let names = opts.iter()
+            .map(|x| x.long_name.clone())
+            .filter(|x| !x.is_empty())
+            .chain(Some("address".to_string()).into_iter());
+        for name in names {
+            let var = format!("WIRES_{}", name[].to_ascii_upper().replace("-", "_"));
+            match os::getenv(var[]) {
+                Some(x) => {
+                    values.insert(name, x);
+                },
+                None => {}
+            }
+        }
+        ConfigLayer {
+            values: values
+        }
+    }
+}
+
+fn value_to_string(value: &toml::Value) -> Option<String> {
+    match *value {
+        toml::Value::String(ref x) => Some(x.clone()),
+        toml::Value::Integer(x) => Some(x.to_string()),
+        toml::Value::Boolean(x) => Some(x.to_string()),
+        toml::Value::Array(ref x) => {
+            let mut items = vec![];
+            for item in x.iter() {
+                match value_to_string(item) {
+                    Some(x) => items.push(x),
+                    None => return None
+                }
+            }
+            Some(items.connect(","))
+        },
+        _ => None
+    }
+}
+
+// The command line, falling back to each layer in turn
+pub struct Options<'a> {
+    matches: &'a Matches,
+    layers: Vec<ConfigLayer>
+}
+
+impl<'a> Options<'a> {
+    pub fn new(matches: &'a Matches) -> Options<'a> {
+        Options {
+            matches: matches,
+            layers: vec![]
+        }
+    }
+
+    // Layers added later have lower priority
+    pub fn add_layer(&mut self, layer: ConfigLayer) {
+        self.layers.push(layer);
+    }
+
+    pub fn opt_str(&self, name: &str) -> Option<String> {
+        self.matches.opt_str(name).or_else(|| self.layer_value(name))
+    }
+
+    pub fn opt_present(&self, name: &str) -> bool {
+        if self.matches.opt_present(name) {
+            return true;
+        }
+        match self.layer_value(name) {
+            //Flags can be turned off with false
+            Some(x) => x[] != "false",
+            None => false
+        }
+    }
+
+    fn layer_value(&self, name: &str) -> Option<String> {
+        for layer in self.layers.iter() {
+            match layer.values.get(&name.to_string()) {
+                Some(x) => return Some(x.clone()),
+                None => {}
+            }
+        }
+        None
+    }
+}
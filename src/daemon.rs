@@ -0,0 +1,58 @@
+use libc;
+use std::io::{File, IoResult};
+use std::io::fs;
+use std::os;
+
+// Only the calling thread survives the fork, so this has to happen before any other
+// tasks are started
+#[cfg(unix)]
+pub fn daemonize() -> Result<(), String> {
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(format!("failed to fork: {}", os::last_os_error())),
+            0 => {},
+            _ => libc::exit(0)
+        }
+        if libc::setsid() == -1 {
+            return Err(format!("failed to detach from the terminal: {}", os::last_os_error()));
+        }
+        let null = "/dev/null".to_c_str();
+        let fd = libc::open(null.as_ptr(), libc::O_RDWR, 0);
+        if fd == -1 {
+            return Err(format!("failed to open /dev/null: {}", os::last_os_error()));
+        }
+        for target in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].iter() {
+            libc::dup2(fd, *target);
+        }
+        if fd > libc::STDERR_FILENO {
+            libc::close(fd);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> Result<(), String> {
+    Err("--daemon is only supported on unix".to_string())
+}
+
+// Removed again when wires exits
+pub struct PidFile {
+    path: Path
+}
+
+impl PidFile {
+    pub fn create(path: Path) -> IoResult<PidFile> {
+        let mut file = try!(File::create(&path));
+        try!(file.write_line(os::getpid().to_string()[]));
+        Ok(PidFile {
+            path: path
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::unlink(&self.path);
+    }
+}
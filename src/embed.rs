@@ -0,0 +1,162 @@
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+
+use webdriver::httpserver::{ServerSettings, ListenAddress};
+
+use backend::{BackendFactory, new_marionette_backend};
+use marionette::{MarionetteSettings, DEFAULT_HOST, get_free_port};
+use proxy::{serve, RunningServer};
+use {RunError, DEFAULT_MAX_BODY_SIZE, DEFAULT_STARTUP_TIMEOUT, parse_log_level, set_log_level};
+
+// Runs wires inside another program, e.g. a test harness, rather than as its own
+// process. The defaults are the same as the command line ones, except that the server
+// listens on a free port unless told otherwise.
+pub struct WiresBuilder {
+    address: Option<ListenAddress>,
+    log_level: Option<String>,
+    settings: MarionetteSettings,
+    server_settings: ServerSettings,
+    new_backend: BackendFactory
+}
+
+impl WiresBuilder {
+    pub fn new() -> WiresBuilder {
+        WiresBuilder {
+            address: None,
+            log_level: None,
+            settings: MarionetteSettings {
+                binary: None,
+                connect_existing: false,
+                marionette_host: DEFAULT_HOST.to_string(),
+                marionette_port: None,
+                headless: false,
+                safe_mode: false,
+                jsdebugger: false,
+                keep_profile: false,
+                profile_root: None,
+                startup_timeout: DEFAULT_STARTUP_TIMEOUT * 1000,
+                response_timeout: None,
+                request_timeout: None,
+                idle_timeout: None,
+                driver_implicit_wait: false,
+                marionette_passthrough: false,
+                browser_log: None
+            },
+            server_settings: ServerSettings {
+                max_body_size: DEFAULT_MAX_BODY_SIZE,
+                check_content_type: true,
+                jsonwire_responses: false,
+                allowed_hosts: vec![],
+                allowed_origins: vec![],
+                allowed_ips: vec![],
+                client_timeout: None
+            },
+            new_backend: new_marionette_backend
+        }
+    }
+
+    pub fn address(mut self, address: SocketAddr) -> WiresBuilder {
+        self.address = Some(ListenAddress::Tcp(address));
+        self
+    }
+
+    pub fn unix_socket(mut self, path: Path) -> WiresBuilder {
+        self.address = Some(ListenAddress::Unix(path));
+        self
+    }
+
+    pub fn binary(mut self, binary: Path) -> WiresBuilder {
+        self.settings.binary = Some(binary);
+        self
+    }
+
+    // One of the levels --log-level takes. The log crate only reads the level once,
+    // so this has no effect if anything in the process has logged already.
+    pub fn log_level(mut self, level: &str) -> WiresBuilder {
+        self.log_level = Some(level.to_string());
+        self
+    }
+
+    pub fn marionette_port(mut self, port: u16) -> WiresBuilder {
+        self.settings.marionette_port = Some(port);
+        self
+    }
+
+    // Use a browser that is already running rather than launching one
+    pub fn connect_existing(mut self, host: &str, port: u16) -> WiresBuilder {
+        self.settings.connect_existing = true;
+        self.settings.marionette_host = host.to_string();
+        self.settings.marionette_port = Some(port);
+        self
+    }
+
+    pub fn headless(mut self, headless: bool) -> WiresBuilder {
+        self.settings.headless = headless;
+        self
+    }
+
+    // Timeouts are in ms
+    pub fn startup_timeout(mut self, timeout: u64) -> WiresBuilder {
+        self.settings.startup_timeout = timeout;
+        self
+    }
+
+    pub fn response_timeout(mut self, timeout: u64) -> WiresBuilder {
+        self.settings.response_timeout = Some(timeout);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: u64) -> WiresBuilder {
+        self.settings.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn backend(mut self, new_backend: BackendFactory) -> WiresBuilder {
+        self.new_backend = new_backend;
+        self
+    }
+
+    // Starts the server on background tasks and returns once it is accepting connections
+    pub fn start(self) -> Result<Wires, RunError> {
+        match self.log_level {
+            Some(ref x) => set_log_level(try!(parse_log_level(x[]).map_err(RunError::Usage))),
+            None => {}
+        }
+        let address = match self.address {
+            Some(x) => x,
+            None => match get_free_port() {
+                Ok(port) => ListenAddress::Tcp(SocketAddr { ip: Ipv4Addr(127, 0, 0, 1),
+                                                            port: port }),
+                Err(e) => return Err(RunError::Internal(
+                    format!("failed to find a port for the http server: {}", e)))
+            }
+        };
+        let socket_address = match address {
+            ListenAddress::Tcp(x) | ListenAddress::Tls(x, _, _) => Some(x),
+            ListenAddress::Unix(_) => None
+        };
+        let server = try!(serve(address, self.settings, self.server_settings,
+                                self.new_backend));
+        Ok(Wires {
+            address: socket_address,
+            server: server
+        })
+    }
+}
+
+// An embedded server, which runs until it is shut down
+pub struct Wires {
+    address: Option<SocketAddr>,
+    server: RunningServer
+}
+
+impl Wires {
+    // Where the server listens, unless it is on a unix socket
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+
+    // Stops taking requests and ends every session
+    pub fn shutdown(self) {
+        self.server.stop()
+    }
+}
@@ -0,0 +1,83 @@
+use serialize::json;
+use serialize::json::{Json, ToJson};
+use std::collections::TreeMap;
+use std::mem;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+use time;
+
+// Events are published from wherever they happen, like log lines, and handed to every
+// subscriber as an encoded {"method": ..., "params": ..., "timestamp": ...} message.
+static mut SUBSCRIBERS: *const Mutex<Vec<Sender<String>>> = 0 as *const Mutex<Vec<Sender<String>>>;
+static SUBSCRIBERS_INIT: Once = ONCE_INIT;
+//Set once an event server is running, so nothing is prepared for events that can't be seen
+static ENABLED: AtomicBool = INIT_ATOMIC_BOOL;
+
+fn subscribers() -> &'static Mutex<Vec<Sender<String>>> {
+    unsafe {
+        SUBSCRIBERS_INIT.doit(|| {
+            SUBSCRIBERS = mem::transmute(box Mutex::new(Vec::<Sender<String>>::new()));
+        });
+        &*SUBSCRIBERS
+    }
+}
+
+pub fn enable() {
+    ENABLED.store(true, SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(SeqCst)
+}
+
+pub fn subscribe() -> Receiver<String> {
+    let (send, recv) = channel();
+    subscribers().lock().push(send);
+    recv
+}
+
+pub fn publish(method: &str, params: TreeMap<String, Json>) {
+    let mut subscribers = subscribers().lock();
+    if subscribers.is_empty() {
+        return
+    }
+    let now = time::get_time();
+    let mut data = TreeMap::new();
+    data.insert("method".to_string(), method.to_json());
+    data.insert("params".to_string(), Json::Object(params));
+    data.insert("timestamp".to_string(),
+                (now.sec as u64 * 1000 + now.nsec as u64 / 1000000).to_json());
+    let message = json::encode(&Json::Object(data));
+    //Subscribers that have gone away are dropped
+    subscribers.retain(|x| x.send_opt(message.clone()).is_ok());
+}
+
+pub fn session_started(session_id: &str) {
+    let mut params = TreeMap::new();
+    params.insert("sessionId".to_string(), session_id.to_json());
+    publish("session.started", params);
+}
+
+pub fn session_ended(session_id: &str, reason: &str) {
+    let mut params = TreeMap::new();
+    params.insert("sessionId".to_string(), session_id.to_json());
+    params.insert("reason".to_string(), reason.to_json());
+    publish("session.ended", params);
+}
+
+pub fn navigation_completed(session_id: &str, url: Option<&str>) {
+    let mut params = TreeMap::new();
+    params.insert("sessionId".to_string(), session_id.to_json());
+    params.insert("url".to_string(), url.map(|x| x.to_string()).to_json());
+    publish("navigation.completed", params);
+}
+
+// A console message from a page, as the browser printed it. source is the session
+// id once there is one.
+pub fn console_entry(source: &str, level: &str, text: &str) {
+    let mut params = TreeMap::new();
+    params.insert("source".to_string(), source.to_json());
+    params.insert("level".to_string(), level.to_json());
+    params.insert("text".to_string(), text.to_json());
+    publish("log.entryAdded", params);
+}
@@ -0,0 +1,392 @@
+use std::collections::RingBuf;
+use std::io;
+use std::io::fs;
+use std::io::process::{Command, ProcessExit};
+use std::io::{BufferedReader, File, IoResult, IoErrorKind, Append, Write};
+use std::os;
+use std::sync::{Arc, Mutex};
+
+use android::AndroidOptions;
+use events;
+use logging;
+use webdriver::common::{WebDriverResult, WebDriverError, ErrorStatus, Capabilities,
+                        MAX_SAFE_INTEGER};
+use webdriver::params::{as_boolean, as_object, as_string, as_string_array, as_u64_in_range,
+                        join_path};
+use process::Child;
+use profile::{Profile, Pref};
+
+pub static FIREFOX_OPTIONS_KEY: &'static str = "moz:firefoxOptions";
+//Time in ms that the browser gets to exit by itself before it is killed
+static SHUTDOWN_TIMEOUT: u64 = 5000;
+static MIN_HEADLESS_VERSION: u64 = 56;
+//Lines of browser output kept around to explain failures
+static OUTPUT_LINES: uint = 20;
+
+#[deriving(PartialEq, Show)]
+pub struct FirefoxOptions {
+    pub binary: Option<Path>,
+    pub android: Option<AndroidOptions>,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub prefs: Vec<(String, Pref)>,
+    //Leave the generated profile on disk when the session ends, like --keep-profile
+    pub keep_profile: bool,
+    //Time in ms to wait for marionette to respond to a command, like --marionette-timeout
+    pub response_timeout: Option<u64>
+}
+
+impl FirefoxOptions {
+    pub fn new() -> FirefoxOptions {
+        FirefoxOptions {
+            binary: None,
+            android: None,
+            args: vec![],
+            env: vec![],
+            prefs: vec![],
+            keep_profile: false,
+            response_timeout: None
+        }
+    }
+
+    pub fn from_capabilities(capabilities: &Capabilities) -> WebDriverResult<FirefoxOptions> {
+        let mut rv = FirefoxOptions::new();
+        //The capabilities have been merged by now, so paths start at the options
+        let path = FIREFOX_OPTIONS_KEY;
+        let options = match capabilities.extensions.get(FIREFOX_OPTIONS_KEY) {
+            Some(x) => try!(as_object(path, x)),
+            None => return Ok(rv)
+        };
+
+        match options.get("binary") {
+            Some(x) => {
+                let binary = Path::new(try!(as_string(join_path(path, "binary")[], x)));
+                try!(check_binary(&binary));
+                rv.binary = Some(binary);
+            },
+            None => {}
+        }
+
+        rv.android = try!(AndroidOptions::from_json(path, options));
+
+        match options.get("args") {
+            Some(x) => rv.args = try!(as_string_array(join_path(path, "args")[], x)),
+            None => {}
+        }
+
+        match options.get("env") {
+            Some(x) => {
+                let env_path = join_path(path, "env");
+                for (name, value) in try!(as_object(env_path[], x)).iter() {
+                    let value = try!(as_string(join_path(env_path[], name[])[], value));
+                    rv.env.push((name.clone(), value.to_string()));
+                }
+            },
+            None => {}
+        }
+
+        match options.get("prefs") {
+            Some(x) => {
+                let prefs = try!(as_object(join_path(path, "prefs")[], x));
+                for (name, value) in prefs.iter() {
+                    rv.prefs.push((name.clone(), try!(Pref::from_json(name[], value))));
+                }
+            },
+            None => {}
+        }
+
+        match options.get("keepProfile") {
+            Some(x) => rv.keep_profile = try!(as_boolean(join_path(path, "keepProfile")[], x)),
+            None => {}
+        }
+
+        match options.get("marionetteTimeout") {
+            Some(x) => rv.response_timeout = Some(try!(as_u64_in_range(
+                join_path(path, "marionetteTimeout")[], x, 1, MAX_SAFE_INTEGER))),
+            None => {}
+        }
+
+        Ok(rv)
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.args.iter().any(|x| x[] == "-headless" || x[] == "--headless")
+    }
+
+    pub fn is_safe_mode(&self) -> bool {
+        self.args.iter().any(|x| x[] == "-safe-mode" || x[] == "--safe-mode")
+    }
+}
+
+pub fn check_binary(path: &Path) -> WebDriverResult<()> {
+    let stat = match fs::stat(path) {
+        Ok(x) => x,
+        Err(_) => return Err(wd_err!(ErrorStatus::InvalidArgument,
+                                     "Firefox binary {} does not exist", path.display()))
+    };
+    if stat.kind != io::FileType::RegularFile ||
+        !stat.perm.intersects(io::USER_EXECUTE | io::GROUP_EXECUTE | io::OTHER_EXECUTE) {
+        return Err(wd_err!(ErrorStatus::InvalidArgument,
+                           "Firefox binary {} is not an executable file", path.display()))
+    }
+    Ok(())
+}
+
+// Used when no binary is given. Anything called firefox on the PATH wins, after which
+// the usual install locations for the platform are tried.
+pub fn find_binary() -> Option<Path> {
+    let name = if cfg!(windows) { "firefox.exe" } else { "firefox" };
+    let mut candidates = match os::getenv("PATH") {
+        Some(x) => os::split_paths(x[]).into_iter().map(|dir| dir.join(name)).collect(),
+        None => vec![]
+    };
+    candidates.push_all(platform_binaries()[]);
+    candidates.into_iter().find(|x| check_binary(x).is_ok())
+}
+
+#[cfg(target_os = "macos")]
+fn platform_binaries() -> Vec<Path> {
+    let mut dirs = vec![Path::new("/Applications")];
+    match os::homedir() {
+        Some(x) => dirs.push(x.join("Applications")),
+        None => {}
+    }
+    let mut rv = vec![];
+    for dir in dirs.iter() {
+        for app in ["Firefox.app", "Firefox Nightly.app", "FirefoxNightly.app"].iter() {
+            rv.push(dir.join(*app).join("Contents/MacOS/firefox-bin"));
+        }
+    }
+    rv
+}
+
+//Installers also record the location in the registry, but Program Files is where it
+//ends up unless someone went out of their way to change it
+#[cfg(windows)]
+fn platform_binaries() -> Vec<Path> {
+    ["ProgramFiles", "ProgramFiles(x86)", "ProgramW6432"].iter()
+        .filter_map(|x| os::getenv(*x))
+        .map(|x| Path::new(x).join("Mozilla Firefox").join("firefox.exe"))
+        .collect()
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn platform_binaries() -> Vec<Path> {
+    vec![]
+}
+
+// `firefox --version` prints something like "Mozilla Firefox 56.0"
+pub fn check_headless(binary: &Path) -> WebDriverResult<()> {
+    let output = match Command::new(binary).arg("--version").output() {
+        Ok(x) => x,
+        Err(e) => return Err(wd_err!(ErrorStatus::SessionNotCreated,
+                                     "Failed to get the version of {}: {}", binary.display(), e))
+    };
+    let version = String::from_utf8_lossy(output.output[]).into_owned();
+    let major = version.trim().split(' ').last()
+        .and_then(|x| x.split('.').next())
+        .and_then(|x| from_str::<u64>(x));
+    match major {
+        Some(x) if x >= MIN_HEADLESS_VERSION => Ok(()),
+        Some(x) => Err(wd_err!(ErrorStatus::SessionNotCreated,
+                               "Headless mode requires Firefox {} or later, but {} is version {}",
+                               MIN_HEADLESS_VERSION, binary.display(), x)),
+        None => Err(wd_err!(ErrorStatus::SessionNotCreated,
+                            "Unable to tell whether {} supports headless mode from version {}",
+                            binary.display(), version.trim()))
+    }
+}
+
+pub fn required_prefs() -> Vec<(String, Pref)> {
+    vec![("marionette.defaultPrefs.enabled".to_string(), Pref::Bool(true)),
+         ("browser.shell.checkDefaultBrowser".to_string(), Pref::Bool(false)),
+         ("browser.startup.page".to_string(), Pref::Int(0))]
+}
+
+// Prints page console messages to stdout, where they are picked up as events
+pub fn console_prefs() -> Vec<(String, Pref)> {
+    vec![("devtools.console.stdout.content".to_string(), Pref::Bool(true))]
+}
+
+// Safe mode normally asks whether to start in safe mode or refresh the profile, and
+// the prompt blocks startup until someone answers it
+pub fn safe_mode_prefs() -> Vec<(String, Pref)> {
+    vec![("browser.safemode.prompt".to_string(), Pref::Bool(false)),
+         ("toolkit.startup.max_resumed_crashes".to_string(), Pref::Int(-1)),
+         ("browser.disableResetPrompt".to_string(), Pref::Bool(true))]
+}
+
+// Lets the Browser Toolbox attach without asking, and makes marionette wait for a click
+// before it starts, so breakpoints can be set first
+pub fn jsdebugger_prefs() -> Vec<(String, Pref)> {
+    vec![("devtools.debugger.remote-enabled".to_string(), Pref::Bool(true)),
+         ("devtools.chrome.enabled".to_string(), Pref::Bool(true)),
+         ("devtools.debugger.prompt-connection".to_string(), Pref::Bool(false)),
+         ("marionette.debugging.clicktostart".to_string(), Pref::Bool(true))]
+}
+
+pub trait Runner {
+    fn set_session_id(&self, session_id: &str);
+    fn exit_status(&mut self) -> Option<ProcessExit>;
+    fn stop(&mut self) -> IoResult<()>;
+    fn wait_for_restart(&mut self) -> IoResult<()>;
+    // Paths of any crash dumps the browser wrote, which are kept after the session ends
+    fn collect_minidumps(&mut self) -> Vec<Path>;
+    fn recent_output(&self) -> Vec<String>;
+}
+
+pub struct FirefoxRunner {
+    child: Child,
+    //Set once the browser has restarted itself into a process that isn't our child
+    restarted: bool,
+    log_tag: Arc<Mutex<String>>,
+    output: Arc<Mutex<RingBuf<String>>>,
+    pub profile: Profile
+}
+
+impl FirefoxRunner {
+    pub fn start(binary: &Path, profile: Profile, args: &[String], env: &[(String, String)],
+                 log_path: Option<&Path>) -> IoResult<FirefoxRunner> {
+        debug!("Starting {} with profile {}", binary.display(), profile.path().display());
+        let mut command = Command::new(binary);
+        command.arg("-marionette")
+            .arg("-no-remote")
+            .arg("-profile")
+            .arg(profile.path());
+        for arg in args.iter() {
+            command.arg(arg[]);
+        }
+        for &(ref name, ref value) in env.iter() {
+            command.env(name[], value[]);
+        }
+        let mut child = try!(Child::spawn(&mut command));
+
+        let log_file = match log_path {
+            Some(path) => Some(Arc::new(Mutex::new(try!(File::open_mode(path, Append, Write))))),
+            None => None
+        };
+        //Until there is a session the output can only be attributed to the process
+        let log_tag = Arc::new(Mutex::new(format!("pid {}", child.id())));
+        let output = Arc::new(Mutex::new(RingBuf::with_capacity(OUTPUT_LINES)));
+        match child.process().stdout.take() {
+            Some(x) => capture_output(x, "stdout", log_tag.clone(), log_file.clone(),
+                                      output.clone()),
+            None => {}
+        }
+        match child.process().stderr.take() {
+            Some(x) => capture_output(x, "stderr", log_tag.clone(), log_file.clone(),
+                                      output.clone()),
+            None => {}
+        }
+
+        Ok(FirefoxRunner {
+            child: child,
+            restarted: false,
+            log_tag: log_tag,
+            output: output,
+            profile: profile
+        })
+    }
+
+    pub fn kill(&mut self) -> IoResult<()> {
+        self.child.kill()
+    }
+}
+
+impl Runner for FirefoxRunner {
+    fn set_session_id(&self, session_id: &str) {
+        *self.log_tag.lock() = session_id.to_string();
+    }
+
+    fn exit_status(&mut self) -> Option<ProcessExit> {
+        if self.restarted {
+            return None
+        }
+        self.child.try_wait()
+    }
+
+    // Wait for a browser that has been asked to quit, killing it if it hangs around.
+    // Its other processes, or the copy of it a restart started, can outlive the process
+    // wires started, so those get the same chance to exit before they are killed.
+    fn stop(&mut self) -> IoResult<()> {
+        match self.child.wait_timeout(Some(SHUTDOWN_TIMEOUT)) {
+            Ok(x) => debug!("Browser exited with {}", x),
+            Err(ref e) if e.kind == IoErrorKind::TimedOut => {
+                debug!("Browser didn't exit after {}ms, killing it", SHUTDOWN_TIMEOUT);
+                return self.kill()
+            },
+            Err(e) => return Err(e)
+        }
+        if !self.child.wait_for_group(SHUTDOWN_TIMEOUT) {
+            debug!("Browser processes still running after {}ms, killing them", SHUTDOWN_TIMEOUT);
+            try!(self.kill());
+        }
+        Ok(())
+    }
+
+    // Firefox restarts by launching a new copy of itself with the same arguments and
+    // then exiting, so all that can be done here is wait for the original to go away
+    fn wait_for_restart(&mut self) -> IoResult<()> {
+        if self.restarted {
+            return Ok(())
+        }
+        let status = try!(self.child.wait_timeout(Some(SHUTDOWN_TIMEOUT)));
+        debug!("Browser exited with {} to restart", status);
+        self.restarted = true;
+        Ok(())
+    }
+
+    fn collect_minidumps(&mut self) -> Vec<Path> {
+        let dumps = self.profile.minidumps();
+        if !dumps.is_empty() {
+            self.profile.keep();
+        }
+        dumps
+    }
+
+    fn recent_output(&self) -> Vec<String> {
+        self.output.lock().iter().map(|x| x.clone()).collect()
+    }
+}
+
+// The browser blocks if nobody drains its output pipes, so this has to keep reading
+// for as long as the process is around
+fn capture_output<R: Reader + Send>(stream: R, name: &'static str, tag: Arc<Mutex<String>>,
+                                    log_file: Option<Arc<Mutex<File>>>,
+                                    recent: Arc<Mutex<RingBuf<String>>>) {
+    spawn(proc() {
+        logging::init();
+        let mut reader = BufferedReader::new(stream);
+        for line in reader.lines() {
+            let line = match line {
+                Ok(x) => x,
+                Err(_) => break
+            };
+            let line = line.trim_right();
+            let tag = tag.lock().clone();
+            info!("[{}] browser {}: {}", tag, name, line);
+            //With devtools.console.stdout.content set, page console calls are printed
+            //as "console.LEVEL: message"
+            if line.starts_with("console.") {
+                match line.find(':') {
+                    Some(index) => events::console_entry(tag[], line["console.".len()..index],
+                                                         line[index + 1..].trim()),
+                    None => {}
+                }
+            }
+            {
+                let mut recent = recent.lock();
+                if recent.len() == OUTPUT_LINES {
+                    recent.pop_front();
+                }
+                recent.push_back(format!("{}: {}", name, line));
+            }
+            match log_file {
+                Some(ref file) => {
+                    let _ = file.lock().write_line(format!("[{}] {}: {}", tag, name, line)[]);
+                },
+                None => {}
+            }
+        }
+    });
+}
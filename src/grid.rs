@@ -0,0 +1,187 @@
+use serialize::json;
+use serialize::json::{Json, ToJson};
+use std::collections::TreeMap;
+use std::io::{IoResult, IoError, IoErrorKind, TcpStream};
+use std::io::timer::Timer;
+use std::time::Duration;
+
+use logging;
+
+//Time in ms between checks that the hub still knows about this node
+static REGISTER_CYCLE: u64 = 5000;
+//Time in ms that a request to the hub may take
+static HUB_TIMEOUT: u64 = 10000;
+static DEFAULT_HUB_PORT: u16 = 4444;
+
+pub struct GridSettings {
+    //The hub to register with, as http://HOST[:PORT]
+    pub hub: String,
+    //Where the hub can reach this node, as http://HOST:PORT
+    pub node_url: String,
+    pub max_sessions: uint
+}
+
+// Registers with a Selenium Grid hub the way Selenium's own nodes do: the registration
+// is repeated whenever the hub turns out not to know about the node, e.g. because the
+// hub was restarted, and withdrawn when the node stops.
+pub struct GridNode {
+    stop: Sender<()>,
+    done: Receiver<()>
+}
+
+impl GridNode {
+    pub fn start(settings: GridSettings) -> Result<GridNode, String> {
+        let (host, port) = try!(parse_http_url(settings.hub[], DEFAULT_HUB_PORT));
+        let hub = Hub {
+            host: host,
+            port: port
+        };
+        let (stop_send, stop_recv) = channel();
+        let (done_send, done_recv) = channel();
+        spawn(proc() {
+            logging::init();
+            let mut timer = Timer::new().unwrap();
+            let ticks = timer.periodic(Duration::milliseconds(REGISTER_CYCLE as i64));
+            hub.register(&settings);
+            loop {
+                select! {
+                    () = ticks.recv() => {
+                        if !hub.is_registered(&settings) {
+                            hub.register(&settings)
+                        }
+                    },
+                    _ = stop_recv.recv_opt() => break
+                }
+            }
+            hub.unregister(&settings);
+            let _ = done_send.send_opt(());
+        });
+        Ok(GridNode {
+            stop: stop_send,
+            done: done_recv
+        })
+    }
+
+    // Takes the node out of the grid, so the hub stops sending it sessions
+    pub fn stop(self) {
+        let _ = self.stop.send_opt(());
+        let _ = self.done.recv_opt();
+    }
+}
+
+struct Hub {
+    host: String,
+    port: u16
+}
+
+impl Hub {
+    fn register(&self, settings: &GridSettings) {
+        let body = json::encode(&registration(settings, self));
+        match self.request("POST", "/grid/register", body[]) {
+            Ok((200, _)) => info!("Registered with the grid hub at {}", settings.hub),
+            Ok((status, body)) => error!("Grid hub refused the registration with {}: {}",
+                                         status, body),
+            Err(e) => error!("Failed to register with the grid hub at {}: {}", settings.hub, e)
+        }
+    }
+
+    fn is_registered(&self, settings: &GridSettings) -> bool {
+        let path = format!("/grid/api/proxy?id={}", query_encode(settings.node_url[]));
+        match self.request("GET", path[], "") {
+            Ok((200, body)) => match json::from_str(body[]) {
+                Ok(x) => x.find("success").and_then(|x| x.as_boolean()) == Some(true),
+                Err(_) => false
+            },
+            Ok(_) => false,
+            Err(e) => {
+                debug!("Failed to check the registration with the grid hub: {}", e);
+                false
+            }
+        }
+    }
+
+    fn unregister(&self, settings: &GridSettings) {
+        let path = format!("/grid/unregister?id={}", query_encode(settings.node_url[]));
+        match self.request("GET", path[], "") {
+            Ok((200, _)) => info!("Unregistered from the grid hub at {}", settings.hub),
+            Ok((status, _)) => error!("Grid hub refused to unregister the node with {}",
+                                      status),
+            Err(e) => error!("Failed to unregister from the grid hub at {}: {}",
+                             settings.hub, e)
+        }
+    }
+
+    // Sends a request over HTTP/1.0 and returns the status and the body
+    fn request(&self, method: &str, path: &str, body: &str) -> IoResult<(int, String)> {
+        let mut stream = try!(TcpStream::connect((self.host[], self.port)));
+        stream.set_timeout(Some(HUB_TIMEOUT));
+        let mut request = format!("{} {} HTTP/1.0\r\nHost: {}:{}\r\n",
+                                  method, path, self.host, self.port);
+        if !body.is_empty() {
+            request.push_str("Content-Type: application/json; charset=utf-8\r\n");
+        }
+        request.push_str(format!("Content-Length: {}\r\n\r\n", body.len())[]);
+        request.push_str(body);
+        try!(stream.write_str(request[]));
+        let response = try!(stream.read_to_string());
+        let status = response[].split(' ').nth(1).and_then(|x| from_str::<int>(x));
+        match (status, response[].find_str("\r\n\r\n")) {
+            (Some(status), Some(index)) => Ok((status, response[index + 4..].to_string())),
+            _ => Err(IoError {
+                kind: IoErrorKind::InvalidInput,
+                desc: "malformed response from the grid hub",
+                detail: None
+            })
+        }
+    }
+}
+
+fn registration(settings: &GridSettings, hub: &Hub) -> Json {
+    let mut capabilities = TreeMap::new();
+    capabilities.insert("browserName".to_string(), "firefox".to_json());
+    capabilities.insert("platform".to_string(), "ANY".to_json());
+    capabilities.insert("maxInstances".to_string(), settings.max_sessions.to_json());
+    capabilities.insert("seleniumProtocol".to_string(), "WebDriver".to_json());
+    let mut configuration = TreeMap::new();
+    configuration.insert("role".to_string(), "node".to_json());
+    configuration.insert("hubHost".to_string(), hub.host.to_json());
+    configuration.insert("hubPort".to_string(), hub.port.to_json());
+    configuration.insert("remoteHost".to_string(), settings.node_url.to_json());
+    configuration.insert("maxSession".to_string(), settings.max_sessions.to_json());
+    configuration.insert("register".to_string(), true.to_json());
+    configuration.insert("registerCycle".to_string(), REGISTER_CYCLE.to_json());
+    let mut data = TreeMap::new();
+    data.insert("class".to_string(), "org.openqa.grid.common.RegistrationRequest".to_json());
+    data.insert("capabilities".to_string(), Json::Array(vec![Json::Object(capabilities)]));
+    data.insert("configuration".to_string(), Json::Object(configuration));
+    Json::Object(data)
+}
+
+// Splits http://HOST[:PORT][/] into the host and port
+pub fn parse_http_url(url: &str, default_port: u16) -> Result<(String, u16), String> {
+    if !url.starts_with("http://") {
+        return Err(format!("{} is not an http:// URL", url))
+    }
+    let rest = url["http://".len()..].trim_right_chars('/');
+    if rest.is_empty() || rest.contains("/") {
+        return Err(format!("{} should be of the form http://HOST:PORT", url))
+    }
+    match rest.rfind(':') {
+        Some(index) => match from_str::<u16>(rest[index + 1..]) {
+            Some(port) => Ok((rest[..index].to_string(), port)),
+            None => Err(format!("illegal port in {}", url))
+        },
+        None => Ok((rest.to_string(), default_port))
+    }
+}
+
+fn query_encode(value: &str) -> String {
+    let mut rv = String::new();
+    for byte in value.bytes() {
+        match byte as char {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '.' | '_' | '~' => rv.push(byte as char),
+            _ => rv.push_str(format!("%{:02X}", byte)[])
+        }
+    }
+    rv
+}
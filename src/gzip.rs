@@ -0,0 +1,51 @@
+use flate::deflate_bytes;
+
+// gzip is a raw deflate stream wrapped in a header and a trailer with the CRC-32 and
+// length of the uncompressed data
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let deflated = match deflate_bytes(data) {
+        Some(x) => x,
+        None => return None
+    };
+    //Magic number, deflate, no flags, no modification time, no extra flags, unknown OS
+    let mut rv = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    rv.push_all(deflated.as_slice());
+    push_u32_le(&mut rv, crc32(data));
+    push_u32_le(&mut rv, data.len() as u32);
+    Some(rv)
+}
+
+// Whether gzip is among the encodings in an Accept-Encoding header, and not refused
+// with q=0
+pub fn accepted(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|encoding| {
+        let mut parts = encoding.split(';').map(|x| x.trim());
+        match parts.next() {
+            Some(x) if x.eq_ignore_ascii_case("gzip") => {
+                !parts.any(|x| x.replace(" ", "")[] == "q=0" || x.replace(" ", "")[] == "q=0.0")
+            },
+            _ => false
+        }
+    })
+}
+
+fn push_u32_le(data: &mut Vec<u8>, value: u32) {
+    for i in range(0u, 4) {
+        data.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data.iter() {
+        crc = crc ^ (*byte as u32);
+        for _ in range(0u, 8) {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -1,185 +1,530 @@
-use std::io::net::ip::IpAddr;
-use std::sync::Mutex;
+use serialize::Encodable;
+use serialize::json;
+use serialize::json::Json;
+use std::error::{Error, FromError};
+use std::io::{ConnectionRefused, Listener, PathAlreadyExists};
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::io::net::tcp::TcpListener;
+use std::io::util::LimitReader;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use time::{get_time, precise_time_ns};
 
-use hyper::header::common::ContentLength;
-use hyper::method::Post;
-use hyper::server::{Server, Handler, Request, Response};
+use hyper::header::common::{Connection, ContentLength};
+use hyper::header::common::connection::ConnectionOption;
+use hyper::method::{Method, Get, Head, Post};
+use hyper::net::HttpListener;
+use hyper::server::{Server, Handler, Request, Response, Listening};
 use hyper::uri::AbsolutePath;
+use hyper::version::HttpVersion;
 
-use response::WebDriverResponse;
-use messagebuilder::{get_builder, MessageBuilder};
-use marionette::MarionetteConnection;
 use command::WebDriverMessage;
-use common::WebDriverResult;
-
-enum DispatchMessage {
-    HandleWebDriver(WebDriverMessage, Sender<WebDriverResult<Option<WebDriverResponse>>>),
-    Quit
-}
-
-struct Dispatcher {
-    connection: Option<MarionetteConnection>
-}
-
-impl Dispatcher {
-    fn new() -> Dispatcher {
-        Dispatcher {
-            connection: None
-        }
-    }
-
-    fn run(&mut self, msg_chan: Receiver<DispatchMessage>) {
-        loop {
-            match msg_chan.recv() {
-                DispatchMessage::HandleWebDriver(msg, resp_chan) => {
-                    match msg.session_id {
-                        Some(ref x) => {
-                            match self.connection {
-                                Some(ref conn) => {
-                                    if conn.session.session_id != *x {
-                                        error!("Got unexpected session id {} expected {}",
-                                               x, conn.session.session_id);
-                                        continue
-                                    }
-                                },
-                                None => {
-                                    match self.create_connection(Some(x.clone())) {
-                                        Err(msg) => {
-                                            error!("{}", msg);
-                                            continue
-                                        },
-                                        Ok(_) => {}
-                                    }
-                                }
-                            }
-                        },
-                        None => {
-                            if self.connection.is_some() {
-                                error!("Missing session id for established connection");
-                                continue;
-                            }
-                            match self.create_connection(None) {
-                                Err(msg) => {
-                                    error!("{}", msg);
-                                    continue
-                                },
-                                Ok(_) => {}
-                            }
-                        }
-                    };
-                    let resp = {
-                        let mut connection = self.connection.as_mut().unwrap();
-                        connection.send_message(&msg)
-                    };
-                    debug!("{}", resp);
-                    match resp {
-                        Ok(Some(WebDriverResponse::DeleteSession)) => {
-                            debug!("Deleting session");
-                            self.connection = None;
-                        },
-                        _ => {}
-                    }
-                    resp_chan.send(resp);
-                },
-                DispatchMessage::Quit => {
-                    break;
-                }
+use common::{WebDriverResult, WebDriverError, ErrorStatus};
+use messagebuilder::MessageBuilder;
+use metrics;
+use response::WebDriverResponse;
+use gzip;
+use listener::{ServerListener, ServerAcceptor, bind_unix, unix_address};
+
+static HTTP_THREADS: uint = 8;
+//Smaller responses aren't worth compressing
+static MIN_GZIP_SIZE: uint = 1024;
+//Responses at least this big are encoded straight onto the connection
+static MIN_STREAM_SIZE: uint = 1024 * 1024;
+//Longest X-Request-Id from a client that is used rather than replaced
+static MAX_REQUEST_ID_SIZE: uint = 128;
+
+// What the server does with the commands it parses. The server's tasks share it, so
+// handle_command can be running for several requests at once.
+pub trait WebDriverHandler: Send + Sync {
+    fn handle_command(&self, msg: WebDriverMessage) -> WebDriverResult<Option<WebDriverResponse>>;
+
+    // An HTML page to answer GET / with, if any
+    fn status_page(&self) -> Option<String> {
+        None
+    }
+
+    // Called on each of the server's tasks before it handles a request, e.g. to set up
+    // the task's logger
+    fn init_task(&self) {}
+}
+
+#[deriving(Clone)]
+pub struct ServerSettings {
+    //Largest HTTP request body that will be read
+    pub max_body_size: uint,
+    //Older clients don't always label their bodies as JSON
+    pub check_content_type: bool,
+    //Values of the Host and Origin headers to accept, besides the local ones
+    pub allowed_hosts: Vec<String>,
+    pub allowed_origins: Vec<String>,
+    //Remote addresses that may send commands, besides loopback ones. Empty allows any.
+    pub allowed_ips: Vec<IpAddr>,
+    //Answer with the JSON wire protocol's envelope and numeric statuses
+    pub jsonwire_responses: bool,
+    //Time in ms that a unix socket client may leave its connection idle, if any
+    pub client_timeout: Option<u64>
+}
+
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    //Address, certificate and private key
+    Tls(SocketAddr, Path, Path),
+    Unix(Path)
+}
+
+pub enum ServerError {
+    Address(String),
+    AddressInUse(String),
+    Internal(String)
+}
+
+// Big values, like screenshots, are written to the connection as they are encoded
+// rather than built up as a string first
+enum ResponseBody {
+    Text(String),
+    Streamed(Json)
+}
+
+struct HttpHandler<H> {
+    //Built once at startup and only ever read, so all the server threads share it
+    builder: MessageBuilder,
+    handler: H,
+    settings: ServerSettings,
+    //Request ids are the server's start time followed by a counter, so they stay
+    //unique across restarts
+    id_prefix: String,
+    next_request_id: AtomicUint
+}
+
+impl<H: WebDriverHandler> HttpHandler<H> {
+    fn new(builder: MessageBuilder, handler: H, settings: ServerSettings) -> HttpHandler<H> {
+        HttpHandler {
+            builder: builder,
+            handler: handler,
+            settings: settings,
+            id_prefix: format!("{:x}", get_time().sec),
+            next_request_id: INIT_ATOMIC_UINT
+        }
+    }
+}
+
+impl<H: WebDriverHandler> HttpHandler<H> {
+    // Any web page can make the browser send requests here, and DNS rebinding can
+    // even make them look same origin, so only expected hosts and origins are let in
+    fn check_origin(&self, req: &Request) -> WebDriverResult<()> {
+        let host = match req.headers.get_raw("host") {
+            Some(x) if x.len() == 1 => Some(String::from_utf8_lossy(x[0][]).into_owned()),
+            _ => None
+        };
+        let origin = req.headers.get_raw("origin")
+            .map(|x| String::from_utf8_lossy(x[0][]).into_owned());
+        check_host_and_origin(host.as_ref().map(|x| x[]), origin.as_ref().map(|x| x[]),
+                              self.settings.allowed_hosts[], self.settings.allowed_origins[])
+    }
+
+    // A client that already tags its requests keeps its own ids
+    fn request_id(&self, req: &Request) -> String {
+        match req.headers.get_raw("x-request-id") {
+            Some(x) if x.len() == 1 && !x[0].is_empty() && x[0].len() <= MAX_REQUEST_ID_SIZE &&
+                x[0].iter().all(|x| *x > b' ' && *x < 0x7F) => {
+                String::from_utf8_lossy(x[0][]).into_owned()
+            },
+            _ => format!("{}-{}", self.id_prefix, self.next_request_id.fetch_add(1, SeqCst))
+        }
+    }
+
+    // Bodies without a Content-Length are only read up to the limit, so a client can't
+    // make us buffer an arbitrary amount of data. Failures come with the HTTP status to
+    // answer with, since a body that is too large is a 413 rather than a bad request.
+    fn read_body(&self, req: &mut Request) -> Result<String, (int, WebDriverError)> {
+        let too_large = (413, WebDriverError::new(
+            ErrorStatus::InvalidArgument,
+            format!("Request body is larger than {} bytes", self.settings.max_body_size)));
+        match req.headers.get::<ContentLength>() {
+            Some(&ContentLength(x)) if x > self.settings.max_body_size => return Err(too_large),
+            _ => {}
+        }
+        let limit = self.settings.max_body_size + 1;
+        let data = match LimitReader::new(req.by_ref(), limit).read_to_end() {
+            Ok(x) => x,
+            Err(e) => {
+                let err: WebDriverError = FromError::from_error(e);
+                return Err((err.http_status(), err))
             }
+        };
+        if data.len() > self.settings.max_body_size {
+            return Err(too_large)
+        }
+        match String::from_utf8(data) {
+            Ok(x) => Ok(x),
+            Err(_) => Err((400, WebDriverError::new(ErrorStatus::InvalidArgument,
+                                                    "Request body is not valid UTF-8")))
         }
     }
 
-    fn create_connection(&mut self, session_id: Option<String>) -> Result<(), String> {
-        let mut connection = MarionetteConnection::new(session_id);
-        if connection.connect().is_err() {
-            return Err("Failed to start marionette connection".to_string());
+    // Command bodies have to be application/json, optionally with a UTF-8 charset
+    fn check_content_type(&self, req: &Request, body: &str) -> WebDriverResult<()> {
+        if !self.settings.check_content_type || body.is_empty() {
+            return Ok(())
+        }
+        let content_type = match req.headers.get_raw("content-type") {
+            Some(x) if x.len() == 1 => String::from_utf8_lossy(x[0][]).into_owned(),
+            _ => return Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                                "Missing Content-Type, expected application/json"))
+        };
+        let lower = content_type.as_slice().to_ascii_lower();
+        let mut parts = lower.split(';').map(|x| x.trim());
+        let valid = parts.next() == Some("application/json") && parts.all(|param| {
+            match param.split('=').map(|x| x.trim()).collect::<Vec<&str>>()[] {
+                ["charset", charset] => charset == "utf-8" || charset == "\"utf-8\"",
+                _ => true
+            }
+        });
+        if valid {
+            Ok(())
+        } else {
+            Err(WebDriverError::new(
+                ErrorStatus::InvalidArgument,
+                format!("Unsupported Content-Type {}, expected application/json",
+                        content_type)))
         }
-        self.connection = Some(connection);
-        Ok(())
     }
-}
 
-struct MarionetteHandler {
-    chan: Mutex<Sender<DispatchMessage>>,
-    builder: Mutex<MessageBuilder>
-}
+    fn handle_path(&self, method: Method, path: &str, body: &str,
+                   request_id: &str) -> (int, ResponseBody) {
+        let start = precise_time_ns();
+        let msg_result = self.builder.from_http(method, path, body);
+        let (command, status, resp_body) = match msg_result {
+            Ok(mut message) => {
+                message.request_id = Some(request_id.to_string());
+                if message.command.is_sensitive() {
+                    debug!("Parsed {} (parameters not logged)", message.command.name());
+                } else {
+                    debug!("Parsed {}", message);
+                }
+                let command = message.command.name();
+                let session_id = message.session_id.clone();
+                let (status, resp_body) = match self.handler.handle_command(message) {
+                    Ok(None) => (200, self.response_body(WebDriverResponse::Void, session_id)),
+                    Ok(Some(response)) => {
+                        (200, self.response_body(response, session_id))
+                    },
+                    Err(err) => {
+                        debug_sources(&err);
+                        metrics::record_error(err.status_code());
+                        (err.http_status(), ResponseBody::Text(self.error_body(&err, session_id)))
+                    },
+                };
+                (command, status, resp_body)
+            },
+            Err(err) => {
+                metrics::record_error(err.status_code());
+                ("Unknown".to_string(), err.http_status(),
+                 ResponseBody::Text(self.error_body(&err, None)))
+            }
+        };
+        metrics::record_request(command[], status, (precise_time_ns() - start) / 1000000);
+        (status, resp_body)
+    }
+
+    fn response_body(&self, response: WebDriverResponse,
+                     session_id: Option<String>) -> ResponseBody {
+        let data = if self.settings.jsonwire_responses {
+            response.into_jsonwire(session_id)
+        } else {
+            response.into_json()
+        };
+        if json_size_hint(&data) >= MIN_STREAM_SIZE {
+            ResponseBody::Streamed(data)
+        } else {
+            ResponseBody::Text(json::encode(&data))
+        }
+    }
 
-impl MarionetteHandler {
-    fn new(builder: MessageBuilder, chan: Sender<DispatchMessage>) -> MarionetteHandler {
-        MarionetteHandler {
-            chan: Mutex::new(chan),
-            builder: Mutex::new(builder)
+    fn error_body(&self, err: &WebDriverError, session_id: Option<String>) -> String {
+        if self.settings.jsonwire_responses {
+            err.to_jsonwire_string(session_id)
+        } else {
+            err.to_json_string()
         }
     }
 }
 
-impl Handler for MarionetteHandler {
+impl<H: WebDriverHandler> Handler for HttpHandler<H> {
     fn handle(&self, req: Request, res: Response) {
+        //This runs on one of hyper's tasks
+        self.handler.init_task();
         let mut req = req;
         let mut res = res;
+        let start = precise_time_ns();
+        let request_id = self.request_id(&req);
 
-        let body = match req.method {
-            Post => req.read_to_string().unwrap(),
-            _ => "".to_string()
+        // The whole body has to be read even if it isn't used, otherwise it would be
+        // taken for the start of the next request on the connection
+        let body = match self.check_origin(&req) {
+            Ok(_) => self.read_body(&mut req),
+            Err(err) => Err((err.http_status(), err))
+        };
+        //Whatever is left of a rejected body is still sitting on the connection
+        let body_error = body.is_err();
+        debug!("Got request {} {} [{}]", req.method, req.uri, request_id);
+        //HEAD is answered like GET, but without sending the body
+        let head = req.method == Head;
+        let method = if head { Get } else { req.method.clone() };
+        let mut content_type = "application/json; charset=utf-8";
+        let status_page = match req.uri {
+            AbsolutePath(ref path) if path[] == "/" && method == Get => self.handler.status_page(),
+            _ => None
+        };
+        let metrics_page = match req.uri {
+            AbsolutePath(ref path) => path[] == "/metrics" && method == Get,
+            _ => false
+        };
+        let (status, resp_body) = match (body, &req.uri) {
+            (Err((status, err)), _) => (status, ResponseBody::Text(self.error_body(&err, None))),
+            (Ok(_), _) if status_page.is_some() => {
+                content_type = "text/html; charset=utf-8";
+                (200, ResponseBody::Text(status_page.unwrap()))
+            },
+            (Ok(_), _) if metrics_page => {
+                content_type = "text/plain; version=0.0.4; charset=utf-8";
+                (200, ResponseBody::Text(metrics::render()))
+            },
+            (Ok(body), &AbsolutePath(ref path)) => {
+                let body = match method {
+                    Post => body,
+                    _ => "".to_string()
+                };
+                match self.check_content_type(&req, body[]) {
+                    Ok(_) => self.handle_path(method, path[], body[], request_id[]),
+                    Err(err) => (err.http_status(),
+                                 ResponseBody::Text(self.error_body(&err, None)))
+                }
+            },
+            (Ok(_), _) => {
+                let err = WebDriverError::new(ErrorStatus::UnknownPath,
+                                              format!("{} is not a path", req.uri));
+                (err.http_status(), ResponseBody::Text(self.error_body(&err, None)))
+            }
+        };
+        let accept_gzip = match req.headers.get_raw("accept-encoding") {
+            Some(x) => x.iter().any(|x| gzip::accepted(String::from_utf8_lossy(x[])[])),
+            None => false
+        };
+        let resp_body = match resp_body {
+            //Compressing needs the whole body anyway, and HEAD needs its length for the
+            //Content-Length it would have had
+            ResponseBody::Streamed(data) if accept_gzip || head => {
+                ResponseBody::Text(json::encode(&data))
+            },
+            x => x
+        };
+        match resp_body {
+            ResponseBody::Text(ref x) if status != 200 => {
+                error!("Returning body {} [{}]", x, request_id)
+            },
+            ResponseBody::Text(ref x) => debug!("Returning body {} [{}]", x, request_id),
+            ResponseBody::Streamed(_) => debug!("Streaming response body")
+        }
+        //HTTP/1.1 connections are kept open unless the client says otherwise
+        let close = body_error || match req.headers.get::<Connection>() {
+            Some(&Connection(ref options)) => options.contains(&ConnectionOption::Close),
+            None => req.version == HttpVersion::Http10
         };
-        debug!("Got request {} {}", req.method, req.uri);
-        match req.uri {
-            AbsolutePath(path) => {
-                let msg_result = {
-                    // The fact that this locks for basically the whole request doesn't
-                    // matter as long as we are only handling one request at a time.
-                    let builder = self.builder.lock();
-                    builder.from_http(req.method, path[], body[])
+        {
+            let status_code = res.status_mut();
+            *status_code = FromPrimitive::from_int(status).unwrap();
+        }
+        res.headers_mut().set_raw("Vary", vec![b"Accept-Encoding".to_vec()]);
+        res.headers_mut().set_raw("X-Request-Id", vec![request_id.clone().into_bytes()]);
+        res.headers_mut().set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+        //Streamed bodies go without a Content-Length, so hyper sends them chunked
+        let (resp_bytes, streamed) = match resp_body {
+            ResponseBody::Text(body) => {
+                let compressed = if accept_gzip && body.len() >= MIN_GZIP_SIZE {
+                    gzip::compress(body.as_bytes())
+                } else {
+                    None
                 };
-                let (status, resp_body) = match msg_result {
-                    Ok(message) => {
-                        let (send_res, recv_res) = channel();
-                        {
-                            let c = self.chan.lock();
-                            c.send(DispatchMessage::HandleWebDriver(message, send_res));
-                        }
-                        match recv_res.recv() {
-                            Ok(None) => return,
-                            Ok(Some(response)) => {
-                                (200, response.to_json_string())
-                            },
-                            Err(err) => (err.http_status(), err.to_json_string()),
-                        }
+                let bytes = match compressed {
+                    Some(x) => {
+                        res.headers_mut().set_raw("Content-Encoding", vec![b"gzip".to_vec()]);
+                        x
                     },
-                    Err(err) => {
-                        (err.http_status(), err.to_json_string())
-                    }
+                    None => body.into_bytes()
                 };
-                if status != 200 {
-                    error!("Returning status code {}", status);
-                    error!("Returning body {}", resp_body);
-                } else {
-                    debug!("Returning status code {}", status);
-                    debug!("Returning body {}", resp_body);
-                }
-                {
-                    let status_code = res.status_mut();
-                    *status_code = FromPrimitive::from_int(status).unwrap();
+                res.headers_mut().set(ContentLength(bytes.len()));
+                (bytes, None)
+            },
+            ResponseBody::Streamed(data) => (vec![], Some(data))
+        };
+        if status == 405 {
+            match req.uri {
+                AbsolutePath(ref path) => {
+                    let mut allowed = self.builder.allowed_methods(path[]);
+                    if allowed.contains(&Get) {
+                        allowed.push(Head);
+                    }
+                    let allowed: Vec<String> = allowed.iter().map(|x| x.to_string()).collect();
+                    res.headers_mut().set_raw("Allow", vec![allowed.connect(", ").into_bytes()]);
+                },
+                _ => {}
+            }
+        }
+        res.headers_mut().set(Connection(vec![if close {
+            ConnectionOption::Close
+        } else {
+            ConnectionOption::KeepAlive
+        }]));
+        let mut stream = res.start();
+        let size = match streamed {
+            _ if head => "0B".to_string(),
+            Some(data) => {
+                let mut encoder = json::Encoder::new(&mut stream as &mut Writer);
+                match data.encode(&mut encoder) {
+                    Ok(_) => {},
+                    Err(e) => error!("Failed to write response: {}", e)
                 }
-                res.headers_mut().set(ContentLength(resp_body.len()));
-                let mut stream = res.start();
-                stream.write_str(resp_body.as_slice()).unwrap();
-                stream.unwrap().end().unwrap();
+                "streamed".to_string()
             },
-            _ => {}
+            None => {
+                stream.write(resp_bytes.as_slice()).unwrap();
+                format!("{}B", resp_bytes.len())
+            }
+        };
+        stream.unwrap().end().unwrap();
+        info!("{} {} {} {} {} {}ms [{}]", req.remote_addr.ip, req.method, req.uri, status,
+              size, (precise_time_ns() - start) / 1000000, request_id);
+    }
+}
+
+// A lower bound on the encoded size, which is enough to spot the big responses
+fn json_size_hint(data: &Json) -> uint {
+    match *data {
+        Json::String(ref x) => x.len(),
+        Json::Array(ref x) => x.iter().fold(0, |total, x| total + json_size_hint(x)),
+        Json::Object(ref x) => x.iter().fold(0, |total, (key, value)| {
+            total + key.len() + json_size_hint(value)
+        }),
+        _ => 1
+    }
+}
+
+fn debug_sources(err: &Error) {
+    let mut source = err.cause();
+    loop {
+        source = match source {
+            Some(x) => {
+                debug!("Caused by {}: {}", x.description(), x.detail().unwrap_or("".to_string()));
+                x.cause()
+            },
+            None => break
         }
     }
 }
 
-pub fn start(ip_address: IpAddr, port: u16) {
-    let server = Server::http(ip_address, port);
-    let mut dispatcher = Dispatcher::new();
+// The Host header values a server listening on ip accepts without being told to
+pub fn local_hosts(ip: IpAddr) -> Vec<String> {
+    let mut rv = vec!["localhost".to_string(), "127.0.0.1".to_string(), "[::1]".to_string()];
+    match ip {
+        Ipv4Addr(0, 0, 0, 0) | Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0) => {},
+        Ipv6Addr(..) => rv.push(format!("[{}]", ip)),
+        Ipv4Addr(..) => rv.push(ip.to_string())
+    }
+    rv
+}
 
-    let (msg_send, msg_recv) = channel();
+// Checks a request's Host header, which is None if it was missing or sent more than
+// once, and its Origin header, if any. Anything else that talks HTTP to browsers, like
+// the event stream, goes through this too.
+pub fn check_host_and_origin(host: Option<&str>, origin: Option<&str>,
+                             allowed_hosts: &[String],
+                             allowed_origins: &[String]) -> WebDriverResult<()> {
+    let host = try_opt!(host, ErrorStatus::Forbidden, "Missing or duplicate Host header");
+    let host_lower = host.to_ascii_lower();
+    //Strip the port, taking care not to split up an IPv6 address
+    let name = if host_lower.starts_with("[") {
+        match host_lower.find(']') {
+            Some(x) => host_lower[..x + 1],
+            None => host_lower[]
+        }
+    } else {
+        host_lower.split(':').next().unwrap_or("")
+    };
+    if !allowed_hosts.iter().any(|x| x[] == name) {
+        return Err(WebDriverError::new(ErrorStatus::Forbidden,
+                                       format!("Host {} is not allowed", host)))
+    }
+    match origin {
+        Some(origin) if !allowed_origins.iter().any(|x| x[] == origin) => {
+            Err(WebDriverError::new(ErrorStatus::Forbidden,
+                                    format!("Origin {} is not allowed", origin)))
+        },
+        _ => Ok(())
+    }
+}
+
+// hyper's errors don't say why listening failed, so this tries first
+fn check_address(ip_address: IpAddr, port: u16) -> Result<(), ServerError> {
+    match TcpListener::bind((ip_address, port)).and_then(|x| x.listen()) {
+        Ok(_) => Ok(()),
+        //EADDRINUSE is reported as ConnectionRefused
+        Err(ref e) if e.kind == ConnectionRefused => Err(ServerError::AddressInUse(
+            format!("{}:{} is already in use", ip_address, port))),
+        Err(e) => Err(ServerError::Address(
+            format!("failed to listen on {}:{}: {}", ip_address, port, e)))
+    }
+}
+
+#[cfg(feature = "tls")]
+fn https_listener(cert: Path, key: Path,
+                  allowed_ips: Vec<IpAddr>) -> Result<ServerListener, ServerError> {
+    Ok(ServerListener::Http(HttpListener::Https(cert, key), allowed_ips))
+}
 
-    spawn(proc() {
-        dispatcher.run(msg_recv);
-    });
-    let builder = get_builder();
-    let handler = MarionetteHandler::new(builder, msg_send.clone());
-    server.listen(handler).unwrap();
+#[cfg(not(feature = "tls"))]
+fn https_listener(_: Path, _: Path, _: Vec<IpAddr>) -> Result<ServerListener, ServerError> {
+    Err(ServerError::Address("TLS is not supported by this build".to_string()))
+}
+
+fn unix_listener(path: &Path, client_timeout: Option<u64>) -> Result<ServerListener, ServerError> {
+    bind_unix(path, client_timeout).map_err(|e| {
+        let msg = format!("failed to listen on {}: {}", path.display(), e);
+        //EADDRINUSE is reported as ConnectionRefused here too
+        if e.kind == ConnectionRefused || e.kind == PathAlreadyExists {
+            ServerError::AddressInUse(msg)
+        } else {
+            ServerError::Address(msg)
+        }
+    })
+}
+
+// Starts serving on address, which is a TCP port, with or without TLS, or a unix socket
+pub fn start<H: WebDriverHandler>(address: ListenAddress, settings: ServerSettings,
+                                  builder: MessageBuilder,
+                                  handler: H) -> Result<Listening<ServerAcceptor>, ServerError> {
+    let (socket_address, listener) = match address {
+        ListenAddress::Tcp(x) => {
+            try!(check_address(x.ip, x.port));
+            (x, ServerListener::Http(HttpListener::Http, settings.allowed_ips.clone()))
+        },
+        ListenAddress::Tls(x, ref cert, ref key) => {
+            try!(check_address(x.ip, x.port));
+            (x, try!(https_listener(cert.clone(), key.clone(), settings.allowed_ips.clone())))
+        },
+        ListenAddress::Unix(ref path) => {
+            (unix_address(), try!(unix_listener(path, settings.client_timeout)))
+        }
+    };
+    let mut settings = settings;
+    //The unix socket's stand-in address is one of the local ones anyway
+    settings.allowed_hosts.push_all(local_hosts(socket_address.ip)[]);
+    let address_name = match address {
+        ListenAddress::Unix(ref path) => path.display().to_string(),
+        _ => socket_address.to_string()
+    };
+    let server = Server::with_listener(socket_address.ip, socket_address.port, listener);
+    let handler = HttpHandler::new(builder, handler, settings);
+    server.listen_threads(handler, HTTP_THREADS).map_err(|e| ServerError::Internal(
+        format!("failed to listen on {}: {}", address_name, e)))
 }
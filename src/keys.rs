@@ -0,0 +1,100 @@
+use common::{WebDriverResult, WebDriverError, ErrorStatus};
+
+// The codepoints WebDriver uses for keys that aren't characters, with the key value a
+// browser reports for each. The private use range from U+E000 to U+E05D is reserved for
+// these, so any other codepoint in it is an error rather than a character to type.
+static FIRST_KEY: char = '\uE000';
+static LAST_KEY: char = '\uE05D';
+
+static KEYS: [(char, &'static str), ..70] = [
+    ('\uE000', "Unidentified"),
+    ('\uE001', "Cancel"),
+    ('\uE002', "Help"),
+    ('\uE003', "Backspace"),
+    ('\uE004', "Tab"),
+    ('\uE005', "Clear"),
+    ('\uE006', "Enter"),
+    ('\uE007', "Enter"),
+    ('\uE008', "Shift"),
+    ('\uE009', "Control"),
+    ('\uE00A', "Alt"),
+    ('\uE00B', "Pause"),
+    ('\uE00C', "Escape"),
+    ('\uE00D', " "),
+    ('\uE00E', "PageUp"),
+    ('\uE00F', "PageDown"),
+    ('\uE010', "End"),
+    ('\uE011', "Home"),
+    ('\uE012', "ArrowLeft"),
+    ('\uE013', "ArrowUp"),
+    ('\uE014', "ArrowRight"),
+    ('\uE015', "ArrowDown"),
+    ('\uE016', "Insert"),
+    ('\uE017', "Delete"),
+    ('\uE018', ";"),
+    ('\uE019', "="),
+    ('\uE01A', "0"),
+    ('\uE01B', "1"),
+    ('\uE01C', "2"),
+    ('\uE01D', "3"),
+    ('\uE01E', "4"),
+    ('\uE01F', "5"),
+    ('\uE020', "6"),
+    ('\uE021', "7"),
+    ('\uE022', "8"),
+    ('\uE023', "9"),
+    ('\uE024', "*"),
+    ('\uE025', "+"),
+    ('\uE026', ","),
+    ('\uE027', "-"),
+    ('\uE028', "."),
+    ('\uE029', "/"),
+    ('\uE031', "F1"),
+    ('\uE032', "F2"),
+    ('\uE033', "F3"),
+    ('\uE034', "F4"),
+    ('\uE035', "F5"),
+    ('\uE036', "F6"),
+    ('\uE037', "F7"),
+    ('\uE038', "F8"),
+    ('\uE039', "F9"),
+    ('\uE03A', "F10"),
+    ('\uE03B', "F11"),
+    ('\uE03C', "F12"),
+    ('\uE03D', "Meta"),
+    ('\uE040', "ZenkakuHankaku"),
+    ('\uE050', "Shift"),
+    ('\uE051', "Control"),
+    ('\uE052', "Alt"),
+    ('\uE053', "Meta"),
+    ('\uE054', "PageUp"),
+    ('\uE055', "PageDown"),
+    ('\uE056', "End"),
+    ('\uE057', "Home"),
+    ('\uE058', "ArrowLeft"),
+    ('\uE059', "ArrowUp"),
+    ('\uE05A', "ArrowRight"),
+    ('\uE05B', "ArrowDown"),
+    ('\uE05C', "Insert"),
+    ('\uE05D', "Delete")
+];
+
+// The key value for a codepoint, which is the character itself for anything outside
+// the special range, or None for an unassigned codepoint in it
+pub fn key_value(c: char) -> Option<String> {
+    if c < FIRST_KEY || c > LAST_KEY {
+        return Some(c.to_string())
+    }
+    KEYS.iter().find(|&&(key, _)| key == c)
+        .map(|&(_, value)| value.to_string())
+}
+
+// Text sent to an element or an alert is typed by the browser, which gets it as it is,
+// so all that is left to do here is refusing codepoints that aren't keys
+pub fn check(text: &str) -> WebDriverResult<()> {
+    match text.chars().find(|x| key_value(*x).is_none()) {
+        Some(c) => Err(wd_err!(ErrorStatus::InvalidArgument,
+                               "Unknown key codepoint U+{:04X}", c as u32)),
+        None => Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+#![feature(slicing_syntax)]
+#![feature(phase)]
+#![feature(macro_rules)]
+#![feature(unboxed_closures)]
+
+extern crate core;
+extern crate flate;
+extern crate hyper;
+#[phase(plugin, link)] extern crate log;
+extern crate serialize;
+extern crate time;
+
+#[macro_export]
+macro_rules! try_opt {
+    ($expr:expr, $err_type:expr, $err_msg:expr) => ({
+        match $expr {
+            Some(x) => x,
+            None => return Err(WebDriverError::new($err_type, $err_msg))
+        }
+    })
+}
+
+// Builds a WebDriverError with a formatted message
+#[macro_export]
+macro_rules! wd_err {
+    ($status:expr, $($arg:tt)+) => (
+        WebDriverError::new($status, format!($($arg)+))
+    )
+}
+
+pub mod client;
+pub mod command;
+pub mod common;
+mod gzip;
+pub mod httpserver;
+pub mod keys;
+pub mod listener;
+pub mod messagebuilder;
+pub mod metrics;
+pub mod params;
+pub mod response;
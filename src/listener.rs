@@ -0,0 +1,163 @@
+use std::io::fs;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddr};
+use std::io::net::pipe::{UnixAcceptor, UnixListener, UnixStream};
+use std::io::{Acceptor, IoError, IoErrorKind, IoResult, Listener};
+
+use hyper::net::{NetworkListener, NetworkAcceptor, NetworkStream,
+                 HttpListener, HttpAcceptor, HttpStream};
+
+// hyper only knows how to listen on TCP, so the server hands it one of these, which is
+// either hyper's own listener or a unix socket. Either way hyper accepts connections
+// and reads requests from them itself.
+pub enum ServerListener {
+    //Along with the remote addresses that may connect, besides loopback ones. Empty
+    //allows any.
+    Http(HttpListener, Vec<IpAddr>),
+    //The socket is bound up front, so a path that is taken is reported as such, along
+    //with the time in ms a client may leave its connection idle
+    Unix(Path, Option<UnixAcceptor>, Option<u64>)
+}
+
+#[deriving(Clone)]
+pub enum ServerAcceptor {
+    Http(HttpAcceptor, Vec<IpAddr>),
+    Unix(Path, UnixAcceptor, Option<u64>)
+}
+
+#[deriving(Clone)]
+pub enum ServerStream {
+    Http(HttpStream),
+    Unix(UnixStream, Option<u64>)
+}
+
+// Clients of the unix socket don't have an address, but they are on this machine
+pub fn unix_address() -> SocketAddr {
+    SocketAddr {
+        ip: Ipv4Addr(127, 0, 0, 1),
+        port: 0
+    }
+}
+
+fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        Ipv4Addr(127, _, _, _) | Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1) => true,
+        _ => false
+    }
+}
+
+fn is_allowed(allowed_ips: &[IpAddr], ip: IpAddr) -> bool {
+    allowed_ips.is_empty() || is_loopback(ip) || allowed_ips.contains(&ip)
+}
+
+pub fn bind_unix(path: &Path, client_timeout: Option<u64>) -> IoResult<ServerListener> {
+    let acceptor = try!(UnixListener::bind(path).and_then(|x| x.listen()));
+    Ok(ServerListener::Unix(path.clone(), Some(acceptor), client_timeout))
+}
+
+impl NetworkListener<ServerStream, ServerAcceptor> for ServerListener {
+    fn listen<To: ToSocketAddr>(&mut self, addr: To) -> IoResult<ServerAcceptor> {
+        match *self {
+            ServerListener::Http(ref mut x, ref allowed_ips) => {
+                Ok(ServerAcceptor::Http(try!(x.listen(addr)), allowed_ips.clone()))
+            },
+            ServerListener::Unix(ref path, ref mut acceptor, timeout) => match acceptor.take() {
+                Some(x) => Ok(ServerAcceptor::Unix(path.clone(), x, timeout)),
+                None => Err(IoError {
+                    kind: IoErrorKind::OtherIoError,
+                    desc: "Unix socket is already listening",
+                    detail: Some(path.display().to_string())
+                })
+            }
+        }
+    }
+}
+
+impl NetworkAcceptor<ServerStream> for ServerAcceptor {
+    // Connections from addresses that aren't allowed are closed straight away, before
+    // hyper reads anything from them
+    fn accept(&mut self) -> IoResult<ServerStream> {
+        match *self {
+            ServerAcceptor::Http(ref mut x, ref allowed_ips) => loop {
+                let mut stream = try!(x.accept());
+                match stream.peer_name() {
+                    Ok(addr) if is_allowed(allowed_ips[], addr.ip) => {
+                        return Ok(ServerStream::Http(stream))
+                    },
+                    Ok(addr) => info!("Refused connection from {}", addr.ip),
+                    Err(e) => debug!("Refused connection without a peer address: {}", e)
+                }
+            },
+            ServerAcceptor::Unix(_, ref mut x, timeout) => {
+                x.accept().map(|stream| ServerStream::Unix(stream, timeout))
+            }
+        }
+    }
+
+    fn socket_name(&self) -> IoResult<SocketAddr> {
+        match *self {
+            ServerAcceptor::Http(ref x, _) => x.socket_name(),
+            ServerAcceptor::Unix(..) => Ok(unix_address())
+        }
+    }
+
+    // The socket file goes away along with the socket
+    fn close(&mut self) -> IoResult<()> {
+        match *self {
+            ServerAcceptor::Http(ref mut x, _) => x.close(),
+            ServerAcceptor::Unix(ref path, ref mut x, _) => {
+                try!(x.close_accept());
+                fs::unlink(path)
+            }
+        }
+    }
+}
+
+impl Reader for ServerStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        match *self {
+            ServerStream::Http(ref mut x) => x.read(buf),
+            ServerStream::Unix(ref mut x, timeout) => {
+                //Timeouts are deadlines, so pushing this one back before every read makes
+                //it a limit on how long the client can go quiet
+                x.set_read_timeout(timeout);
+                let rv = x.read(buf);
+                match rv {
+                    Err(ref e) if e.kind == IoErrorKind::TimedOut => {
+                        debug!("Closing unix socket connection after {}ms without data",
+                               timeout.unwrap_or(0))
+                    },
+                    _ => {}
+                }
+                rv
+            }
+        }
+    }
+}
+
+impl Writer for ServerStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        match *self {
+            ServerStream::Http(ref mut x) => x.write(buf),
+            ServerStream::Unix(ref mut x, timeout) => {
+                x.set_write_timeout(timeout);
+                x.write(buf)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match *self {
+            ServerStream::Http(ref mut x) => x.flush(),
+            ServerStream::Unix(ref mut x, _) => x.flush()
+        }
+    }
+}
+
+impl NetworkStream for ServerStream {
+    fn peer_name(&mut self) -> IoResult<SocketAddr> {
+        match *self {
+            ServerStream::Http(ref mut x) => x.peer_name(),
+            ServerStream::Unix(..) => Ok(unix_address())
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use log;
+use log::{Logger, LogRecord};
+use serialize::json;
+use serialize::json::{Json, ToJson};
+use std::collections::TreeMap;
+use std::io;
+use std::io::{File, IoResult, Append, Write};
+use std::mem;
+use std::collections::RingBuf;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+use time;
+
+use signal;
+
+#[deriving(Clone, PartialEq)]
+pub enum LogFormat {
+    Text,
+    //One JSON object per line, for log aggregators
+    Json
+}
+
+struct Output {
+    console: bool,
+    format: LogFormat,
+    path: Option<Path>,
+    file: Option<File>
+}
+
+// The most recent lines logged by a task, so they can be handed to clients
+pub type LogBuffer = Arc<Mutex<RingBuf<String>>>;
+
+static MAX_BUFFERED_LINES: uint = 1000;
+
+// Shared by the loggers of all tasks
+static mut OUTPUT: *const Mutex<Output> = 0 as *const Mutex<Output>;
+static OUTPUT_INIT: Once = ONCE_INIT;
+
+fn output() -> &'static Mutex<Output> {
+    unsafe {
+        OUTPUT_INIT.doit(|| {
+            OUTPUT = mem::transmute(box Mutex::new(Output {
+                console: true,
+                format: LogFormat::Text,
+                path: None,
+                file: None
+            }));
+        });
+        &*OUTPUT
+    }
+}
+
+// Lines look like "1417000000.123 marionette DEBUG message", tab separated, so
+// harnesses can pick them apart. In the JSON format they are objects with timestamp
+// (in ms), level, component, sessionId and message members instead.
+struct WiresLogger {
+    buffer: Option<LogBuffer>,
+    //The session the task works for, once it has one
+    session_id: Option<String>
+}
+
+impl Logger for WiresLogger {
+    fn log(&mut self, record: &LogRecord) {
+        let now = time::get_time();
+        let level = match record.level.0 {
+            1 => "ERROR",
+            2 => "WARN",
+            3 => "INFO",
+            4 => "DEBUG",
+            _ => "TRACE"
+        };
+        let message = format!("{}", record.args);
+        let line = format!("{}.{:03u}\t{}\t{}\t{}",
+                           now.sec, now.nsec / 1000000,
+                           component(record.module_path),
+                           level, message);
+        match self.buffer {
+            Some(ref buffer) => {
+                let mut lines = buffer.lock();
+                lines.push_back(line.clone());
+                if lines.len() > MAX_BUFFERED_LINES {
+                    lines.pop_front();
+                }
+            },
+            None => {}
+        }
+        let mut output = output().lock();
+        //Whoever rotated the log sends SIGHUP once the old file has been moved away
+        if signal::take_hangup() {
+            output.file = match output.path {
+                Some(ref path) => File::open_mode(path, Append, Write).ok(),
+                None => None
+            };
+        }
+        let line = match output.format {
+            LogFormat::Text => line,
+            LogFormat::Json => {
+                let mut data = TreeMap::new();
+                data.insert("timestamp".to_string(),
+                            (now.sec as u64 * 1000 + now.nsec as u64 / 1000000).to_json());
+                data.insert("level".to_string(), level.to_json());
+                data.insert("component".to_string(), component(record.module_path).to_json());
+                data.insert("sessionId".to_string(), self.session_id.to_json());
+                data.insert("message".to_string(), message.to_json());
+                json::encode(&Json::Object(data))
+            }
+        };
+        if output.console {
+            let _ = io::stderr().write_line(line[]);
+        }
+        match output.file {
+            Some(ref mut x) => {
+                let _ = x.write_line(line[]);
+            },
+            None => {}
+        }
+    }
+}
+
+fn component(module_path: &str) -> &'static str {
+    match module_path.split_str("::").nth(1) {
+        Some("httpserver") | Some("messagebuilder") | Some("unixsocket") => "httpd",
+        Some("marionette") => "marionette",
+        Some("firefox") | Some("android") | Some("profile") => "session",
+        Some(_) => "wires",
+        None => "external"
+    }
+}
+
+// Loggers belong to a task, so every task that logs has to call this first
+pub fn init() {
+    log::set_logger(box WiresLogger {
+        buffer: None,
+        session_id: None
+    } as Box<Logger + Send>);
+}
+
+// Like init, but also keeps the task's recent lines in buffer
+pub fn init_buffered(buffer: LogBuffer) {
+    log::set_logger(box WiresLogger {
+        buffer: Some(buffer),
+        session_id: None
+    } as Box<Logger + Send>);
+}
+
+// Replaces the logger of a task that has been initialized with init_buffered, once the
+// task knows which session it works for
+pub fn set_session(buffer: LogBuffer, session_id: String) {
+    log::set_logger(box WiresLogger {
+        buffer: Some(buffer),
+        session_id: Some(session_id)
+    } as Box<Logger + Send>);
+}
+
+pub fn new_buffer() -> LogBuffer {
+    Arc::new(Mutex::new(RingBuf::new()))
+}
+
+pub fn configure(path: Option<Path>, console: bool) -> IoResult<()> {
+    let file = match path {
+        Some(ref x) => Some(try!(File::open_mode(x, Append, Write))),
+        None => None
+    };
+    let mut output = output().lock();
+    output.console = console;
+    output.path = path;
+    output.file = file;
+    Ok(())
+}
+
+pub fn set_format(format: LogFormat) {
+    output().lock().format = format;
+}
+
+pub fn flush() {
+    let mut output = output().lock();
+    match output.file {
+        Some(ref mut x) => {
+            let _ = x.flush();
+        },
+        None => {}
+    }
+}
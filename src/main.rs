@@ -3,37 +3,111 @@
 #![feature(macro_rules)]
 #![feature(unboxed_closures)]
 
-extern crate core;
+extern crate flate;
 extern crate getopts;
 extern crate hyper;
+extern crate libc;
 #[phase(plugin, link)] extern crate log;
-extern crate regex;
 extern crate serialize;
+extern crate time;
+extern crate toml;
+extern crate uuid;
+#[phase(plugin, link)] extern crate webdriver;
 
-use getopts::{usage,optflag, getopts, OptGroup};
-use httpserver::start;
-use std::io::net::ip::SocketAddr;
+use getopts::{usage,optflag, optopt, getopts, OptGroup};
+use backend::new_marionette_backend;
+use proxy::start;
+use webdriver::common::set_legacy_element_keys;
+use webdriver::command::set_strict_parameters;
+use webdriver::httpserver::{ServerSettings, ListenAddress};
+use webdriver::messagebuilder::routes;
+use config::{ConfigLayer, Options};
+use daemon::{daemonize, PidFile};
+use firefox::check_binary;
+use grid::{GridSettings, parse_http_url};
+use logging::LogFormat;
+use marionette::{MarionetteSettings, DEFAULT_HOST, MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION};
+use serialize::json;
+use serialize::json::ToJson;
+use std::cmp::min;
+use std::collections::TreeMap;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::io;
+use std::io::fs::PathExtensions;
 use std::os;
 
-macro_rules! try_opt {
-    ($expr:expr, $err_type:expr, $err_msg:expr) => ({
-        match $expr {
-            Some(x) => x,
-            None => return Err(WebDriverError::new($err_type, $err_msg))
-        }
-    })
+// Protocol level logging that is too noisy even for debug!
+macro_rules! trace {
+    ($($arg:tt)*) => (log!(5, $($arg)*))
 }
 
-mod command;
-mod common;
-mod httpserver;
+#[cfg(feature = "android")]
+mod android;
+#[cfg(not(feature = "android"))]
+#[path = "no_android.rs"]
+mod android;
+mod backend;
+mod completions;
+mod config;
+mod daemon;
+mod embed;
+mod events;
+mod firefox;
+mod grid;
+mod logging;
 mod marionette;
-mod messagebuilder;
-mod response;
+#[cfg(test)]
+mod mock_marionette;
+mod process;
+mod profile;
+mod proxy;
+mod signal;
+#[cfg(test)]
+mod tests;
+mod websocket;
+mod zip;
 
 static DEFAULT_ADDR: &'static str = "127.0.0.1:4444";
-static VERSION: &'static str = include_str!("../.version");
+static DEFAULT_LISTEN_HOST: &'static str = "127.0.0.1";
+static DEFAULT_LISTEN_PORT: u16 = 4444;
+static DEFAULT_LOG_LEVEL: u32 = 3;
+static WARN_LEVEL: u32 = 2;
+static DEFAULT_STARTUP_TIMEOUT: u64 = 60;
+//Startup waits for someone to click through the debugger
+static JSDEBUGGER_STARTUP_TIMEOUT: u64 = 60 * 60;
+//Add-ons are sent base64 encoded, so this has to allow for big ones
+static DEFAULT_MAX_BODY_SIZE: uint = 64 * 1024 * 1024;
+pub static VERSION: &'static str = include_str!("../.version");
+static COMMIT: &'static str = include_str!("../.commit");
+static BUILD_DATE: &'static str = include_str!("../.build-date");
+
+// Each kind of failure gets its own exit code, so wrappers can tell them apart
+pub enum RunError {
+    Usage(String),
+    Address(String),
+    AddressInUse(String),
+    BrowserLaunch(String),
+    Internal(String)
+}
+
+impl RunError {
+    pub fn exit_code(&self) -> int {
+        match *self {
+            RunError::Usage(_) => 64,
+            RunError::Address(_) => 65,
+            RunError::AddressInUse(_) => 66,
+            RunError::BrowserLaunch(_) => 67,
+            RunError::Internal(_) => 70
+        }
+    }
+
+    pub fn message(self) -> String {
+        match self {
+            RunError::Usage(x) | RunError::Address(x) | RunError::AddressInUse(x) |
+            RunError::BrowserLaunch(x) | RunError::Internal(x) => x
+        }
+    }
+}
 
 fn err(msg: String) {
     let prog = os::args()[0].clone(); // shouldn't need to clone()
@@ -50,51 +124,278 @@ fn print_usage(opts: &[OptGroup]) {
     // Prefer &*msg over msg.as_slice()
 }
 
-// Valid addresses to parse are "HOST:PORT" or ":PORT".
-// If the host isn't specified, 127.0.0.1 will be assumed.
-fn parse_addr(s: String) -> Result<SocketAddr, String> {
-// Take &str rather than String
-    let mut parts: Vec<&str> = s.as_slice().splitn(1, ':').collect();
-    if parts.len() == 2 {
-        // "If the host isn't specified"?
-        parts[0] = "127.0.0.1";
+fn print_version(as_json: bool) {
+    let endpoints: Vec<String> = routes().iter()
+        .map(|&(ref method, path, _)| format!("{} {}", method, path))
+        .collect();
+    if as_json {
+        let mut data = TreeMap::new();
+        data.insert("version".to_string(), VERSION.to_json());
+        data.insert("commit".to_string(), COMMIT.to_json());
+        data.insert("buildDate".to_string(), BUILD_DATE.to_json());
+        data.insert("marionetteProtocol".to_string(),
+                    vec![MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION].to_json());
+        data.insert("endpoints".to_string(), endpoints.to_json());
+        println!("{}", json::Object(data).to_pretty_str());
+    } else {
+        println!("wires version {} ({} built {})", VERSION, COMMIT, BUILD_DATE);
+        println!("Marionette protocol versions {} to {}",
+                 MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION);
+        println!("{} WebDriver endpoints:", endpoints.len());
+        for endpoint in endpoints.iter() {
+            println!("    {}", endpoint);
+        }
     }
-    let full_addr = parts.connect(":");
+}
+
+// Valid addresses to parse are "HOST:PORT", "[IPV6]:PORT" or ":PORT".
+// If the host isn't specified, all interfaces are listened on.
+fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    let full_addr = if s.starts_with(":") {
+        format!("0.0.0.0{}", s)
+    } else {
+        s.to_string()
+    };
     match from_str::<SocketAddr>(full_addr.as_slice()) {
         Some(addr) => Ok(addr),
+        None if s.split(':').count() > 2 && !s.starts_with("[") => {
+            Err(format!("illegal address: {}, IPv6 addresses must be written as [ADDRESS]:PORT", s))
+        },
         None => Err(format!("illegal address: {}", s))
     }
 }
 
-fn run(args: Vec<String>) -> int {
-// -> Result<(), ()> would be more idiomatic, I think
+// The log crate has no fatal or config levels, so those are treated as error and info.
+fn parse_log_level(level: &str) -> Result<u32, String> {
+    match level {
+        "fatal" | "error" => Ok(1u32),
+        "warn" => Ok(WARN_LEVEL),
+        "info" | "config" => Ok(3),
+        "debug" => Ok(4),
+        "trace" => Ok(5),
+        _ => Err(format!("illegal log level: {}", level))
+    }
+}
+
+// The level to log at, if it is to be set at all. An explicit RUST_LOG wins over the
+// default, but not over the options. Quiet only ever removes output, so
+// --log-level error -q still only shows errors.
+fn log_level_from_options(level: Option<&str>, quiet: bool,
+                          rust_log: bool) -> Result<Option<u32>, String> {
+    let level = match level {
+        Some(x) => Some(try!(parse_log_level(x))),
+        None if !rust_log => Some(DEFAULT_LOG_LEVEL),
+        None => None
+    };
+    Ok(if quiet {
+        Some(min(level.unwrap_or(WARN_LEVEL), WARN_LEVEL))
+    } else {
+        level
+    })
+}
+
+fn parse_log_format(format: &str) -> Result<LogFormat, String> {
+    match format {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _ => Err(format!("illegal log format: {}", format))
+    }
+}
+
+// The crates that log through the level set here. A directive applies to every module
+// whose path starts with it, so one per crate covers the modules split out into it.
+static LOG_CRATES: &'static [&'static str] = &["wires", "webdriver"];
+
+// The log crate reads its configuration from RUST_LOG the first time anything is
+// logged, so this has to happen before that.
+fn set_log_level(level: u32) {
+    os::setenv("RUST_LOG", log_directives(level)[]);
+}
+
+fn log_directives(level: u32) -> String {
+    let directives: Vec<String> = LOG_CRATES.iter()
+        .map(|x| format!("{}={}", x, level))
+        .collect();
+    directives.connect(",")
+}
+
+fn split_list(value: Option<String>) -> Vec<String> {
+    match value {
+        Some(x) => x.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect(),
+        None => vec![]
+    }
+}
+
+fn run(args: Vec<String>) -> Result<int, RunError> {
     let opts = [
-        optflag("q", "", "make the program quiet, only printing warnings"),
-        optflag("v", "", "show version information"),
+        optflag("q", "quiet",
+                "make the program quiet, only printing warnings and errors even if --log-level \
+                 asks for more"),
+        optopt("", "config",
+               "TOML file with defaults for the other long options, and for ADDRESS as address",
+               "PATH"),
+        optopt("", "log-level",
+               "one of fatal, error, warn, info, config, debug or trace", "LEVEL"),
+        optopt("", "log-format", "text (the default) or json, for one JSON object per line",
+               "FORMAT"),
+        optopt("", "log-file", "also write the log to this file, reopening it on SIGHUP", "PATH"),
+        optflag("", "log-file-only", "don't write the log to the console when --log-file is given"),
+        optflag("", "daemon", "run in the background, logging only to --log-file"),
+        optopt("", "pidfile", "write the process id to this file", "PATH"),
+        optflag("v", "version", "show version information"),
+        optflag("", "json", "with -v, print the version information as JSON"),
         optflag("h", "", "show this message"),
+        optopt("b", "binary", "path to the Firefox binary to launch", "PATH"),
+        optopt("", "host", "IP address to listen on, instead of giving ADDRESS", "HOST"),
+        optopt("", "port", "port to listen on, instead of giving ADDRESS", "PORT"),
+        optflag("", "connect-existing",
+                "connect to an already running Firefox instead of launching one"),
+        optflag("", "headless", "run the browser without a visible UI"),
+        optflag("", "safe-mode", "start the browser in safe mode, with add-ons disabled"),
+        optflag("", "jsdebugger",
+                "open the Browser Toolbox when launching Firefox, and wait for it before starting \
+                 marionette"),
+        optflag("", "keep-profile", "don't delete generated profiles when sessions end"),
+        optopt("", "profile-root",
+               "directory to create profiles in, instead of the system temporary directory",
+               "PATH"),
+        optopt("", "startup-timeout",
+               "seconds to wait for the browser to accept marionette connections", "SECONDS"),
+        optopt("", "marionette-timeout",
+               "seconds to wait for marionette to respond to a command", "SECONDS"),
+        optopt("", "request-timeout",
+               "seconds that handling a request may take before it fails with a timeout",
+               "SECONDS"),
+        optopt("", "client-timeout",
+               "seconds a unix socket client may go without sending or receiving data",
+               "SECONDS"),
+        optopt("", "idle-timeout",
+               "seconds a session may go without a command before it is ended", "SECONDS"),
+        optopt("", "browser-log", "also write the browser's output to this file", "PATH"),
+        optflag("", "driver-implicit-wait",
+                "keep finding elements until the implicit wait runs out, for browsers that \
+                 don't wait themselves"),
+        optflag("", "marionette-passthrough",
+                "for debugging, serve /session/{sessionId}/moz/marionette to send any \
+                 marionette command"),
+        optopt("", "max-body-size", "largest request body to accept", "BYTES"),
+        optopt("", "unix-socket", "listen on a unix domain socket instead of ADDRESS", "PATH"),
+        optopt("", "tls-cert", "serve HTTPS using this PEM certificate", "PATH"),
+        optopt("", "tls-key", "private key for the certificate given by --tls-cert", "PATH"),
+        optopt("", "allow-hosts",
+               "comma separated host names to accept in the Host header, besides localhost",
+               "HOSTS"),
+        optopt("", "allow-origins",
+               "comma separated origins of web pages that may send commands", "ORIGINS"),
+        optopt("", "allowed-ips",
+               "comma separated addresses that may connect, besides loopback ones", "ADDRESSES"),
+        optflag("", "legacy-element-key",
+                "also send elements with the ELEMENT key older Selenium bindings expect"),
+        optflag("", "jsonwire",
+                "answer in the JSON wire protocol format that pre-W3C Selenium bindings expect"),
+        optflag("", "strict-parameters",
+                "reject command bodies with members the command doesn't use"),
+        optflag("", "any-content-type",
+                "accept command bodies without an application/json Content-Type"),
+        optopt("", "events-port",
+               "stream session, navigation and console events over a WebSocket at \
+                ws://127.0.0.1:PORT/events", "PORT"),
+        optopt("", "grid-hub",
+               "register with the Selenium Grid hub at this http://HOST:PORT", "URL"),
+        optopt("", "grid-node-url",
+               "http://HOST:PORT the grid hub should use to reach wires, instead of ADDRESS",
+               "URL"),
+        optopt("", "grid-max-sessions",
+               "number of sessions to offer to the grid hub, 1 by default", "COUNT"),
+        optopt("", "marionette-host",
+               "host of an already running Firefox to connect to, implies --connect-existing",
+               "HOST"),
+        optopt("", "marionette-port",
+               "port for marionette to listen on, instead of a free one for launched browsers \
+                or 2828 for existing ones", "PORT"),
     ];
+    //Deliberately not in the option table, so it doesn't show up in the usage
+    if args.len() > 1 && args[1][] == "--generate-completions" {
+        let shell = match args.get(2) {
+            Some(x) => x.clone(),
+            None => return Err(RunError::Usage(
+                "--generate-completions needs one of bash, zsh or fish".to_string()))
+        };
+        match completions::generate(shell[], "wires", &opts) {
+            Ok(x) => print!("{}", x),
+            Err(e) => return Err(RunError::Usage(e))
+        }
+        return Ok(0);
+    }
+
     let matches = match getopts(args.tail(), &opts) {
         Ok(m) => m,
         Err(f) => {
-            err(format!("{}", f));
-            return 0;
+            return Err(RunError::Usage(f.to_string()));
         }
     };
 
     if matches.opt_present("v") {
-        println!("wires version {}", VERSION);
-        return 0;
+        print_version(matches.opt_present("json"));
+        return Ok(0);
     } else if matches.opt_present("h") {
         print_usage(&opts);
-        return 127;
+        return Ok(127);
+    }
+
+    //The command line wins over the environment, which wins over the config file
+    let mut options = Options::new(&matches);
+    options.add_layer(ConfigLayer::from_env(&opts));
+    match options.opt_str("config") {
+        Some(x) => match ConfigLayer::from_file(&Path::new(x), &opts) {
+            Ok(layer) => options.add_layer(layer),
+            Err(e) => {
+                return Err(RunError::Usage(e));
+            }
+        },
+        None => {}
+    }
+
+    let log_level = options.opt_str("log-level");
+    match log_level_from_options(log_level.as_ref().map(|x| x[]), options.opt_present("quiet"),
+                                 os::getenv("RUST_LOG").is_some()) {
+        Ok(Some(x)) => set_log_level(x),
+        Ok(None) => {},
+        Err(e) => {
+            return Err(RunError::Usage(e));
+        }
+    }
+
+    match options.opt_str("log-format") {
+        Some(x) => match parse_log_format(x[]) {
+            Ok(x) => logging::set_format(x),
+            Err(e) => {
+                return Err(RunError::Usage(e));
+            }
+        },
+        None => {}
+    }
+
+    match options.opt_str("log-file") {
+        Some(x) => {
+            let path = Path::new(x);
+            match logging::configure(Some(path.clone()), !options.opt_present("log-file-only")) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(RunError::Internal(format!("failed to open log file {}: {}",
+                                                          path.display(), e)));
+                }
+            }
+        },
+        None => {}
     }
 
     let addr_str = if matches.free.len() == 1 {
         matches.free[0].clone()
     } else if matches.free.len() > 1 {
-        err(format!("got {} positional arguments, expected 1", matches.free.len()));
         print_usage(&opts);
-        return 1;
+        return Err(RunError::Usage(format!("got {} positional arguments, expected 1",
+                                               matches.free.len())));
     } else {
         DEFAULT_ADDR.to_string()
     };
@@ -104,26 +405,338 @@ fn run(args: Vec<String>) -> int {
         [] => DEFAULT_ADDR.to_string(),
         [m] => m.clone(),
         _ => {
-            err(format!("got {} positional arguments, expected 1", matches.free.len()));
             print_usage(&opts);
-            return 1;
+            return Err(RunError::Usage(format!("got {} positional arguments, expected 1",
+                                                   matches.free.len())));
         }
     };
 
-    let addr = match parse_addr(addr_str) {
+    if !matches.free.is_empty() && (matches.opt_present("host") || matches.opt_present("port")) {
+        return Err(RunError::Usage(
+            "ADDRESS can't be combined with --host or --port".to_string()));
+    }
+
+    //Anything on the command line beats the config file
+    let addr_str = if !matches.free.is_empty() {
+        addr_str
+    } else if options.opt_present("host") || options.opt_present("port") {
+        let host = options.opt_str("host").unwrap_or(DEFAULT_LISTEN_HOST.to_string());
+        let port = match options.opt_str("port") {
+            Some(x) => match from_str::<u16>(x[]) {
+                Some(x) => x,
+                None => {
+                    return Err(RunError::Address(format!("illegal port: {}", x)));
+                }
+            },
+            None => DEFAULT_LISTEN_PORT
+        };
+        if host[].contains(":") && !host[].starts_with("[") {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        }
+    } else {
+        options.opt_str("address").unwrap_or(addr_str)
+    };
+
+    let addr = match parse_addr(addr_str[]) {
         Ok(x) => x,
         Err(e) => {
-            err(format!("{}", e));
-            return 1;
+            return Err(RunError::Address(e));
         }
     };
 
-    start(addr.ip, addr.port);
-    return 0;
+    let startup_timeout = match options.opt_str("startup-timeout") {
+        Some(x) => match from_str::<u64>(x[]) {
+            Some(x) => x,
+            None => {
+                return Err(RunError::Usage(format!("illegal startup timeout: {}", x)));
+            }
+        },
+        None if options.opt_present("jsdebugger") => JSDEBUGGER_STARTUP_TIMEOUT,
+        None => DEFAULT_STARTUP_TIMEOUT
+    };
+
+    let response_timeout = match options.opt_str("marionette-timeout") {
+        Some(x) => match from_str::<u64>(x[]) {
+            Some(x) => Some(x * 1000),
+            None => {
+                return Err(RunError::Usage(format!("illegal marionette timeout: {}", x)));
+            }
+        },
+        None => None
+    };
+
+    let request_timeout = match options.opt_str("request-timeout") {
+        Some(x) => match from_str::<u64>(x[]) {
+            Some(x) => Some(x * 1000),
+            None => {
+                return Err(RunError::Usage(format!("illegal request timeout: {}", x)));
+            }
+        },
+        None => None
+    };
+
+    let client_timeout = match options.opt_str("client-timeout") {
+        Some(x) => match from_str::<u64>(x[]) {
+            Some(x) => Some(x * 1000),
+            None => {
+                return Err(RunError::Usage(format!("illegal client timeout: {}", x)));
+            }
+        },
+        None => None
+    };
+
+    let idle_timeout = match options.opt_str("idle-timeout") {
+        Some(x) => match from_str::<u64>(x[]) {
+            Some(x) if x > 0 => Some(x * 1000),
+            _ => {
+                return Err(RunError::Usage(format!("illegal idle timeout: {}", x)));
+            }
+        },
+        None => None
+    };
+
+    let marionette_port = match options.opt_str("marionette-port") {
+        Some(x) => match from_str::<u16>(x[]) {
+            Some(x) => Some(x),
+            None => {
+                return Err(RunError::Usage(format!("illegal marionette port: {}", x)));
+            }
+        },
+        None => None
+    };
+
+    let max_body_size = match options.opt_str("max-body-size") {
+        Some(x) => match from_str::<uint>(x[]) {
+            Some(x) => x,
+            None => {
+                return Err(RunError::Usage(format!("illegal maximum body size: {}", x)));
+            }
+        },
+        None => DEFAULT_MAX_BODY_SIZE
+    };
+
+    let binary = match options.opt_str("binary") {
+        Some(x) => {
+            let path = Path::new(x);
+            match check_binary(&path) {
+                Ok(_) => Some(path),
+                Err(e) => {
+                    return Err(RunError::BrowserLaunch(e.message.into_owned()));
+                }
+            }
+        },
+        None => None
+    };
+
+    let profile_root = match options.opt_str("profile-root") {
+        Some(x) => {
+            let path = Path::new(x);
+            if !path.is_dir() {
+                return Err(RunError::Usage(format!("profile root {} is not a directory",
+                                                   path.display())));
+            }
+            Some(path)
+        },
+        None => None
+    };
+
+    let mut allowed_ips = vec![];
+    for x in split_list(options.opt_str("allowed-ips")).iter() {
+        match from_str::<IpAddr>(x[]) {
+            Some(ip) => allowed_ips.push(ip),
+            None => {
+                return Err(RunError::Usage(format!("illegal IP address: {}", x)));
+            }
+        }
+    }
+
+    set_legacy_element_keys(options.opt_present("legacy-element-key"));
+    set_strict_parameters(options.opt_present("strict-parameters"));
+
+    let settings = MarionetteSettings {
+        binary: binary,
+        connect_existing: options.opt_present("connect-existing") ||
+            options.opt_present("marionette-host"),
+        marionette_host: options.opt_str("marionette-host")
+            .unwrap_or(DEFAULT_HOST.to_string()),
+        marionette_port: marionette_port,
+        headless: options.opt_present("headless"),
+        safe_mode: options.opt_present("safe-mode"),
+        jsdebugger: options.opt_present("jsdebugger"),
+        keep_profile: options.opt_present("keep-profile"),
+        profile_root: profile_root,
+        startup_timeout: startup_timeout * 1000,
+        //Without its own timeout the worker would stay stuck on a command that the
+        //client has already been told timed out
+        response_timeout: response_timeout.or(request_timeout),
+        request_timeout: request_timeout,
+        idle_timeout: idle_timeout,
+        driver_implicit_wait: options.opt_present("driver-implicit-wait"),
+        marionette_passthrough: options.opt_present("marionette-passthrough"),
+        browser_log: options.opt_str("browser-log").map(|x| Path::new(x))
+    };
+
+    let server_settings = ServerSettings {
+        max_body_size: max_body_size,
+        check_content_type: !options.opt_present("any-content-type"),
+        jsonwire_responses: options.opt_present("jsonwire"),
+        allowed_hosts: split_list(options.opt_str("allow-hosts")),
+        allowed_origins: split_list(options.opt_str("allow-origins")),
+        allowed_ips: allowed_ips,
+        client_timeout: client_timeout
+    };
+
+    let address = match (options.opt_str("unix-socket"),
+                         options.opt_str("tls-cert"),
+                         options.opt_str("tls-key")) {
+        (Some(x), None, None) => ListenAddress::Unix(Path::new(x)),
+        (Some(_), _, _) => {
+            return Err(RunError::Usage("--unix-socket can't be used with TLS".to_string()));
+        },
+        (None, Some(cert), Some(key)) => ListenAddress::Tls(addr, Path::new(cert), Path::new(key)),
+        (None, None, None) => ListenAddress::Tcp(addr),
+        (None, _, _) => {
+            return Err(RunError::Usage(
+                "--tls-cert and --tls-key have to be given together".to_string()));
+        }
+    };
+
+    let grid = match options.opt_str("grid-hub") {
+        Some(hub) => {
+            match parse_http_url(hub[], DEFAULT_LISTEN_PORT) {
+                Ok(_) => {},
+                Err(e) => return Err(RunError::Usage(e))
+            }
+            let node_url = match options.opt_str("grid-node-url") {
+                Some(x) => match parse_http_url(x[], DEFAULT_LISTEN_PORT) {
+                    Ok(_) => x,
+                    Err(e) => return Err(RunError::Usage(e))
+                },
+                //The hub can't connect to an address that means all interfaces
+                None => match address {
+                    ListenAddress::Tcp(SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), .. }) |
+                    ListenAddress::Tcp(SocketAddr { ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0), .. }) => {
+                        return Err(RunError::Usage(
+                            "--grid-hub needs --grid-node-url when listening on all \
+                             interfaces".to_string()))
+                    },
+                    ListenAddress::Tcp(x) => format!("http://{}", x),
+                    _ => return Err(RunError::Usage(
+                        "--grid-hub needs --grid-node-url unless listening on plain TCP"
+                            .to_string()))
+                }
+            };
+            let max_sessions = match options.opt_str("grid-max-sessions") {
+                Some(x) => match from_str::<uint>(x[]) {
+                    Some(x) if x > 0 => x,
+                    _ => return Err(RunError::Usage(format!("illegal session count: {}", x)))
+                },
+                None => 1
+            };
+            Some(GridSettings {
+                hub: hub,
+                node_url: node_url,
+                max_sessions: max_sessions
+            })
+        },
+        None => None
+    };
+
+    let events_port = match options.opt_str("events-port") {
+        Some(x) => match from_str::<u16>(x[]) {
+            Some(x) => Some(x),
+            None => {
+                return Err(RunError::Usage(format!("illegal events port: {}", x)));
+            }
+        },
+        None => None
+    };
+
+    if options.opt_present("daemon") {
+        if options.opt_str("log-file").is_none() {
+            return Err(RunError::Usage("--daemon needs --log-file".to_string()));
+        }
+        match daemonize() {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(RunError::Internal(e));
+            }
+        }
+    }
+
+    let _pidfile = match options.opt_str("pidfile") {
+        Some(x) => {
+            let path = Path::new(x);
+            match PidFile::create(path.clone()) {
+                Ok(x) => Some(x),
+                Err(e) => return Err(RunError::Internal(
+                    format!("failed to write pidfile {}: {}", path.display(), e)))
+            }
+        },
+        None => None
+    };
+
+    match events_port {
+        Some(port) => {
+            let events_address = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: port };
+            match websocket::start(events_address, server_settings.allowed_hosts.clone(),
+                                   server_settings.allowed_origins.clone()) {
+                Ok(_) => {},
+                Err(e) => return Err(RunError::Address(
+                    format!("failed to listen on {}: {}", events_address, e)))
+            }
+        },
+        None => {}
+    }
+
+    start(address, settings, server_settings, grid, new_marionette_backend)
 }
 
 fn main() {
+    logging::init();
     let args = os::args();
-    let s = run(args);
+    let s = match run(args) {
+        Ok(x) => x,
+        Err(e) => {
+            let code = e.exit_code();
+            err(e.message());
+            code
+        }
+    };
+    logging::flush();
     os::set_exit_status(s);
 }
+
+#[cfg(test)]
+mod log_level_tests {
+    use super::{log_level_from_options, DEFAULT_LOG_LEVEL, WARN_LEVEL};
+
+    #[test]
+    fn log_level_is_used() {
+        assert_eq!(log_level_from_options(Some("debug"), false, false), Ok(Some(4)));
+        //Even over RUST_LOG
+        assert_eq!(log_level_from_options(Some("trace"), false, true), Ok(Some(5)));
+        assert!(log_level_from_options(Some("loud"), false, false).is_err());
+    }
+
+    #[test]
+    fn default_unless_rust_log_is_set() {
+        assert_eq!(log_level_from_options(None, false, false), Ok(Some(DEFAULT_LOG_LEVEL)));
+        assert_eq!(log_level_from_options(None, false, true), Ok(None));
+    }
+
+    #[test]
+    fn quiet_caps_log_level() {
+        assert_eq!(log_level_from_options(Some("trace"), true, false), Ok(Some(WARN_LEVEL)));
+        assert_eq!(log_level_from_options(None, true, false), Ok(Some(WARN_LEVEL)));
+        assert_eq!(log_level_from_options(None, true, true), Ok(Some(WARN_LEVEL)));
+    }
+
+    #[test]
+    fn quiet_keeps_quieter_log_level() {
+        assert_eq!(log_level_from_options(Some("error"), true, false), Ok(Some(1)));
+        assert_eq!(log_level_from_options(Some("fatal"), true, true), Ok(Some(1)));
+    }
+}
@@ -1,31 +1,193 @@
 use serialize::json::{Json, ToJson};
 use serialize::json;
 use std::collections::TreeMap;
-use std::io::{IoResult, TcpStream, IoError};
+use std::cmp;
+use std::io::{BufferedReader, BufferedWriter, File, IoResult, TcpStream, TcpListener, IoError,
+              IoErrorKind, TempDir};
+use std::io::fs::PathExtensions;
+use std::io::timer::{sleep, Timer};
+use std::mem;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use time::precise_time_ns;
+use uuid::Uuid;
 
-use command::{WebDriverMessage};
-use command::WebDriverCommand::{NewSession, DeleteSession, Get, GetCurrentUrl,
+use webdriver::command::{WebDriverMessage, WebDriverCommand};
+use webdriver::command::WebDriverCommand::{NewSession, DeleteSession, Get, GetCurrentUrl,
                                 GoBack, GoForward, Refresh, GetTitle, GetWindowHandle,
                                 GetWindowHandles, Close, SetWindowSize,
-                                GetWindowSize, MaximizeWindow, SwitchToWindow, SwitchToFrame,
+                                GetWindowSize, SetWindowRect, GetWindowRect, MaximizeWindow,
+                                SwitchToWindow, SwitchToFrame,
                                 SwitchToParentFrame, FindElement, FindElements, IsDisplayed,
                                 IsSelected, GetElementAttribute, GetCSSValue, GetElementText,
                                 GetElementTagName, GetElementRect, IsEnabled, ElementClick,
                                 ElementTap, ElementClear, ElementSendKeys, ExecuteScript,
                                 ExecuteAsyncScript, GetCookie, AddCookie, SetTimeouts,
                                 DismissAlert, AcceptAlert, GetAlertText, SendAlertText,
-                                TakeScreenshot};
-use command::{GetParameters, WindowSizeParameters, SwitchToWindowParameters,
+                                TakeScreenshot, GetContext, SetContext, InstallAddon,
+                                UninstallAddon, Restart, GetDriverLog, GetLogTypes, GetLog,
+                                UploadFile, TouchClick, TouchDown, TouchUp, TouchMove,
+                                TouchScroll, TouchFlick, TouchLongClick, GetPref, SetPref,
+                                ResetPref, PerformActions, ReleaseActions, Status,
+                                GetSessions, Extension};
+use webdriver::command::{GetParameters, WindowSizeParameters, WindowRectParameters,
+              SwitchToWindowParameters,
+              GetElementAttributeParameters, GetCSSValueParameters, SendKeysParameters,
               SwitchToFrameParameters, LocatorParameters, JavascriptCommandParameters,
               GetCookieParameters, AddCookieParameters, TimeoutsParameters,
-              TakeScreenshotParameters};
-use response::{WebDriverResponse, NewSessionResponse, ValueResponse, WindowSizeResponse,
-               ElementRectResponse, CookieResponse, Date, Cookie};
-use common::{WebDriverResult, WebDriverError, ErrorStatus, Nullable, WebElement, FrameId};
+              TakeScreenshotParameters, SetContextParameters, InstallAddonParameters,
+              UninstallAddonParameters, GetLogParameters, AddonSource, Context,
+              ActionsParameters};
+use webdriver::response::{WebDriverResponse, NewSessionResponse, ValueResponse, WindowRectResponse,
+               ElementRectResponse, CookiesResponse, ElementResponse, Date, Cookie};
+use webdriver::common::{WebDriverResult, WebDriverError, ErrorStatus, Nullable, WebElement, FrameId,
+             SameSite, Capabilities, convert_references};
+use webdriver::messagebuilder::PathParams;
+use webdriver::metrics;
+use webdriver::params::{as_object, get_string};
+use logging;
+
+pub static DEFAULT_HOST: &'static str = "127.0.0.1";
+pub static DEFAULT_PORT: u16 = 2828;
+//Name of the extension command behind --marionette-passthrough, which isn't a
+//marionette command itself
+pub static PASSTHROUGH_EXTENSION: &'static str = "wires:passthrough";
+pub static MIN_PROTOCOL_VERSION: u64 = 1;
+pub static MAX_PROTOCOL_VERSION: u64 = 3;
+//First protocol version that wraps messages in arrays
+static ARRAY_PROTOCOL_VERSION: u64 = 3;
+//First protocol version with getWindowRect and setWindowRect
+static WINDOW_RECT_PROTOCOL_VERSION: u64 = 3;
+static MAX_RETRY_DELAY: u64 = 2000;
+//Time in ms between attempts to find elements during an implicit wait
+static IMPLICIT_WAIT_INTERVAL: i64 = 100;
+//How long a touch has to stay down to count as a long press
+static LONG_PRESS_SECONDS: f64 = 1.0;
+//Screenshots of large pages can get big, but anything beyond this is a broken stream
+static MAX_MESSAGE_SIZE: uint = 256 * 1024 * 1024;
+//Size of the read and write buffers on the marionette socket
+static BUFFER_SIZE: uint = 64 * 1024;
+//Collects script errors and console API calls from pages, newer than arguments[0]
+static BROWSER_LOG_SCRIPT: &'static str = "\
+    let since = arguments[0]; \
+    let Cc = Components.classes, Ci = Components.interfaces; \
+    let entries = []; \
+    let messages = Cc['@mozilla.org/consoleservice;1'] \
+        .getService(Ci.nsIConsoleService).getMessageArray() || []; \
+    for (let msg of messages) { \
+        if (!(msg instanceof Ci.nsIScriptError) || msg.timeStamp <= since || \
+            /chrome/.test(msg.category)) { \
+            continue; \
+        } \
+        entries.push({ \
+            level: msg.flags & Ci.nsIScriptError.warningFlag ? 'WARNING' : 'SEVERE', \
+            message: msg.errorMessage + ' (' + msg.sourceName + ':' + msg.lineNumber + ')', \
+            timestamp: msg.timeStamp \
+        }); \
+    } \
+    let levels = {error: 'SEVERE', warn: 'WARNING', debug: 'DEBUG', trace: 'DEBUG'}; \
+    let events = Cc['@mozilla.org/consoleAPI-storage;1'] \
+        .getService(Ci.nsIConsoleAPIStorage).getEvents(); \
+    for (let event of events) { \
+        if (event.timeStamp <= since) { \
+            continue; \
+        } \
+        entries.push({ \
+            level: levels[event.level] || 'INFO', \
+            message: Array.prototype.map.call(event.arguments, String).join(' '), \
+            timestamp: event.timeStamp \
+        }); \
+    } \
+    entries.sort(function(a, b) { return a.timestamp - b.timestamp; }); \
+    return entries;";
+//Describes arguments[0] if it is a file input, or returns null for any other element
+static FILE_INPUT_SCRIPT: &'static str = "\
+    let el = arguments[0]; \
+    return el.localName == 'input' && el.type == 'file' ? {multiple: el.multiple} : null;";
+//Selects the files at the paths in arguments[1] in the file input arguments[0], which
+//needs the system sandbox, and fires the events a user picking them would
+static SET_FILES_SCRIPT: &'static str = "\
+    let [el, paths] = arguments; \
+    let win = el.ownerDocument.defaultView; \
+    el.mozSetFileNameArray(paths, paths.length); \
+    el.dispatchEvent(new win.Event('input', {bubbles: true})); \
+    el.dispatchEvent(new win.Event('change', {bubbles: true}));";
+//The value of the preference arguments[0], or null if it isn't set
+static GET_PREF_SCRIPT: &'static str = "\
+    let name = arguments[0]; \
+    let prefs = Components.classes['@mozilla.org/preferences-service;1'] \
+        .getService(Components.interfaces.nsIPrefBranch); \
+    switch (prefs.getPrefType(name)) { \
+        case prefs.PREF_STRING: return prefs.getCharPref(name); \
+        case prefs.PREF_INT: return prefs.getIntPref(name); \
+        case prefs.PREF_BOOL: return prefs.getBoolPref(name); \
+        default: return null; \
+    }";
+//Sets the preference arguments[0] to arguments[1], with the type of the value
+static SET_PREF_SCRIPT: &'static str = "\
+    let [name, value] = arguments; \
+    let prefs = Components.classes['@mozilla.org/preferences-service;1'] \
+        .getService(Components.interfaces.nsIPrefBranch); \
+    switch (typeof value) { \
+        case 'string': prefs.setCharPref(name, value); break; \
+        case 'number': prefs.setIntPref(name, value); break; \
+        case 'boolean': prefs.setBoolPref(name, value); break; \
+    }";
+//Puts the preference arguments[0] back to its default value
+static RESET_PREF_SCRIPT: &'static str = "\
+    Components.classes['@mozilla.org/preferences-service;1'] \
+        .getService(Components.interfaces.nsIPrefBranch).clearUserPref(arguments[0]);";
+
+#[deriving(Clone)]
+pub struct MarionetteSettings {
+    //Firefox to launch when the session doesn't ask for a particular one
+    pub binary: Option<Path>,
+    //Don't launch or otherwise manage the browser process, just connect to marionette
+    pub connect_existing: bool,
+    //Where to find marionette when connecting to an existing browser
+    pub marionette_host: String,
+    //Port that marionette listens on, if it shouldn't be picked automatically
+    pub marionette_port: Option<u16>,
+    pub headless: bool,
+    pub safe_mode: bool,
+    pub jsdebugger: bool,
+    //Leave generated profiles on disk after use, for debugging
+    pub keep_profile: bool,
+    pub profile_root: Option<Path>,
+    //Time in ms to keep trying to connect to a browser that is starting up
+    pub startup_timeout: u64,
+    //Time in ms to wait for marionette to respond to a command, if any
+    pub response_timeout: Option<u64>,
+    //Time in ms that handling an HTTP request may take, if any
+    pub request_timeout: Option<u64>,
+    //Time in ms that a session may go without a command before it is ended, if any
+    pub idle_timeout: Option<u64>,
+    //Poll for elements during implicit waits, for browsers that don't wait themselves
+    pub driver_implicit_wait: bool,
+    //Serve moz/marionette, which sends any command the client gives it to marionette
+    pub marionette_passthrough: bool,
+    //File that browser output is copied to, in addition to the log
+    pub browser_log: Option<Path>
+}
 
 pub struct MarionetteSession {
+    //The id clients use, which wires generates
     pub session_id: String,
-    pub to: String
+    //The id marionette gave the session, used in messages to the browser
+    pub marionette_session_id: String,
+    pub to: String,
+    pub context: Context,
+    //What the browser said it supports when the session started
+    pub capabilities: Option<Capabilities>,
+    //Timestamp of the newest browser log entry handed out so far
+    pub log_since: u64,
+    //Marionette's id for a touch that is still down, and where it last was
+    pub touch_id: Option<u64>,
+    pub touch_position: (i64, i64),
+    //Time in ms that finding elements keeps trying, when wires does the waiting
+    pub implicit_wait: u64,
+    //Files clients uploaded, which file inputs can refer to by name alone
+    pub uploaded_files: Vec<Path>
 }
 
 fn object_from_json(data: &str) -> WebDriverResult<TreeMap<String, Json>> {
@@ -38,13 +200,32 @@ impl MarionetteSession {
     pub fn new(session_id: Option<String>) -> MarionetteSession {
         let initital_id = session_id.unwrap_or("".to_string());
         MarionetteSession {
-            session_id: initital_id,
-            to: String::from_str("root")
+            session_id: initital_id.clone(),
+            marionette_session_id: initital_id,
+            to: String::from_str("root"),
+            context: Context::Content,
+            capabilities: None,
+            log_since: 0,
+            touch_id: None,
+            touch_position: (0, 0),
+            implicit_wait: 0,
+            uploaded_files: vec![]
         }
     }
 
-    pub fn msg_to_marionette(&self, msg: &WebDriverMessage) -> WebDriverResult<Json> {
-        let x = try!(msg.to_marionette());
+    pub fn msg_to_marionette(&self, msg: &WebDriverMessage,
+                             protocol_version: u64) -> WebDriverResult<Json> {
+        match msg.command {
+            FindElement(ref x) | FindElements(ref x) if x.using.is_chrome_only() &&
+                self.context != Context::Chrome => {
+                return Err(WebDriverError::new(
+                    ErrorStatus::InvalidSelector,
+                    "Anonymous content can only be found in the chrome context"))
+            },
+            _ => {}
+        }
+        let session_id = msg.session_id.as_ref().map(|_| self.marionette_session_id[]);
+        let x = try!(message_to_marionette(msg, session_id, protocol_version));
         let mut data = try_opt!(x.as_object(),
                                 ErrorStatus::UnknownError,
                                 "Message was not a JSON Object").clone(); // Why clone()?
@@ -54,14 +235,25 @@ impl MarionetteSession {
 
     pub fn update(&mut self, msg: &WebDriverMessage, resp: &TreeMap<String, Json>) -> WebDriverResult<()> {
         match msg.command {
-            NewSession => {
+            NewSession(_) => {
                 let session_id = try_opt!(
                     try_opt!(resp.get("sessionId"),
                              ErrorStatus::SessionNotCreated,
                              "Unable to get session id").as_string(),
                         ErrorStatus::SessionNotCreated,
                         "Unable to convert session id to string");
-                self.session_id = session_id.to_string().clone();
+                self.marionette_session_id = session_id.to_string();
+                self.session_id = Uuid::new_v4().to_hyphenated_str();
+                let value = try_opt!(resp.get("value"),
+                                     ErrorStatus::SessionNotCreated,
+                                     "Failed to find value field");
+                self.capabilities = Some(try!(Capabilities::from_json(value).map_err(|mut e| {
+                    e.status = ErrorStatus::SessionNotCreated;
+                    e
+                })));
+            },
+            SetContext(ref x) => {
+                self.context = x.context.clone();
             },
             _ => {}
         }
@@ -69,35 +261,11 @@ impl MarionetteSession {
     }
 
     pub fn response_from_json(&mut self, message: &WebDriverMessage,
-                              data: &str) -> WebDriverResult<Option<WebDriverResponse>> {
+                              mut json_data: TreeMap<String, Json>) -> WebDriverResult<Option<WebDriverResponse>> {
                                                              // Do you ever return None?
-        let json_data = try!(object_from_json(data));
-        if json_data.contains_key(&"error".to_string()) {
-            //TODO: convert the marionette error into the right webdriver error
-            let error = try_opt!(json_data.get("error").unwrap().as_object(),
-                                 ErrorStatus::UnknownError,
-                                 "Marionette error field was not an object");
-
-        // Or...
-        if let Some(error) = json_data.get("error") {
-            let error = try_opt!(error.as_object(),
-                                 ErrorStatus::UnknownError,
-                                 "Marionette error field was not an object");
-
-
-            let status_code = try_opt!(
-                try_opt!(error.get("status"),
-                         ErrorStatus::UnknownError,
-                         "Error dict doesn't have a status field").as_u64(),
-                ErrorStatus::UnknownError,
-                "Error status isn't an integer");
-            let status = self.error_from_code(status_code);
-            let default_msg = Json::String("Unknown error".into_string());
-            let err_msg = try_opt!(
-                error.get("message").unwrap_or(&default_msg).as_string(),
-                ErrorStatus::UnknownError,
-                "Error message was not a string");
-            return Err(WebDriverError::new(status, err_msg));
+        match json_data.get("error") {
+            Some(error) => return Err(try!(self.error_from_json(error, &json_data))),
+            None => {}
         }
 
         try!(self.update(message, &json_data));
@@ -109,20 +277,53 @@ impl MarionetteSession {
             SetWindowSize(_) | MaximizeWindow | SwitchToWindow(_) | SwitchToFrame(_) |
             SwitchToParentFrame | AddCookie(_) | DismissAlert | AcceptAlert |
             SendAlertText(_) | ElementClick(_) | ElementTap(_) | ElementClear(_) |
-            ElementSendKeys(_, _) => {
+            ElementSendKeys(_) | SetContext(_) | UninstallAddon(_) | Restart |
+            GetDriverLog | GetLogTypes | GetLog(_) |
+            UploadFile(_) | TouchClick(_) | TouchDown(_) | TouchUp(_) | TouchMove(_) |
+            TouchScroll(_) | TouchFlick(_) | TouchLongClick(_) | GetPref(_) | SetPref(_) |
+            ResetPref(_) | PerformActions(_) | ReleaseActions | Status | GetSessions => {
                 Ok(Some(WebDriverResponse::Void))
             },
-            //Things that simply return the contents of the marionette "value" property
+            //Things that simply return the contents of the marionette "value" property.
+            //It is moved rather than copied, since screenshots are large base64 strings
+            //that go to the client unchanged.
             GetCurrentUrl | GetTitle | GetWindowHandle | GetWindowHandles |
-            FindElement(_) | FindElements(_) | IsDisplayed(_) | IsSelected(_) |
-            GetElementAttribute(_, _) | GetCSSValue(_, _) | GetElementText(_) |
-            GetElementTagName(_) | IsEnabled(_) | ExecuteScript(_) | ExecuteAsyncScript(_) |
-            GetAlertText | TakeScreenshot(_) => {
+            IsDisplayed(_) | IsSelected(_) |
+            GetElementAttribute(_) | GetCSSValue(_) | GetElementText(_) |
+            GetElementTagName(_) | IsEnabled(_) | GetAlertText | TakeScreenshot(_) |
+            GetContext | InstallAddon(_) => {
+                let value = try_opt!(json_data.remove("value"),
+                                     ErrorStatus::UnknownError,
+                                     "Failed to find value field");
+                Ok(Some(WebDriverResponse::Generic(ValueResponse::new(value))))
+            },
+            FindElement(_) => {
+                let value = try_opt!(json_data.get("value"),
+                                     ErrorStatus::UnknownError,
+                                     "Failed to find value field");
+                Ok(Some(WebDriverResponse::Element(ElementResponse::new(
+                    try!(WebElement::from_json(value))))))
+            },
+            FindElements(_) | ExecuteScript(_) | ExecuteAsyncScript(_) => {
+                let value = try_opt!(json_data.get("value"),
+                                     ErrorStatus::UnknownError,
+                                     "Failed to find value field");
+                Ok(Some(WebDriverResponse::Generic(ValueResponse::new(
+                    try!(convert_references(value))))))
+            },
+            //Marionette answers setWindowRect with the rect the window ended up with
+            SetWindowRect(_) | GetWindowRect => {
                 let value = try_opt!(json_data.get("value"),
                                      ErrorStatus::UnknownError,
                                      "Failed to find value field");
-                //TODO: Convert webelement keys
-                Ok(Some(WebDriverResponse::Generic(ValueResponse::new(value.clone()))))
+                Ok(Some(WebDriverResponse::WindowRect(WindowRectResponse::with_position(
+                    try!(rect_member(value, "x")) as i64, try!(rect_member(value, "y")) as i64,
+                    try!(rect_member(value, "width")) as u64,
+                    try!(rect_member(value, "height")) as u64))))
+            },
+            Extension(_) => {
+                let value = json_data.get("value").map(|x| x.clone()).unwrap_or(Json::Null);
+                Ok(Some(WebDriverResponse::Generic(ValueResponse::new(value))))
             },
             GetWindowSize => {
                 let value = try_opt!(
@@ -146,7 +347,7 @@ impl MarionetteSession {
                     ErrorStatus::UnknownError,
                     "Failed to interpret width as integer");
 
-                Ok(Some(WebDriverResponse::WindowSize(WindowSizeResponse::new(width, height))))
+                Ok(Some(WebDriverResponse::WindowRect(WindowRectResponse::new(width, height))))
             },
             GetElementRect(_) => {
                 let value = try_opt!(
@@ -252,27 +453,23 @@ impl MarionetteSession {
                                             "Failed to interpret http_only as boolean"),
                         None => false
                     };
-                    Ok(Cookie::new(name, value, path, domain, expiry, max_age, secure, http_only))
+                    //Older browsers don't know about sameSite at all
+                    let same_site = match x.find("sameSite") {
+                        Some(x) => try!(Nullable::from_json(x, |x| SameSite::from_json(x))),
+                        None => Nullable::Null
+                    };
+                    Ok(Cookie::new(name, value, path, domain, expiry, max_age, secure, http_only,
+                                   same_site))
                 }).collect::<Result<Vec<_>, _>>());
-                Ok(Some(WebDriverResponse::Cookie(CookieResponse::new(cookies))))
+                Ok(Some(WebDriverResponse::Cookies(CookiesResponse::new(cookies))))
             },
-            NewSession => {
-                let session_id = try_opt!(
-                    try_opt!(json_data.get("sessionId"),
-                             ErrorStatus::InvalidSessionId,
-                             "Failed to find sessionId field").as_string(),
-                    ErrorStatus::InvalidSessionId,
-                    "sessionId was not a string").into_string();
-
-                let value = try_opt!(
-                    try_opt!(json_data.get("value"),
-                             ErrorStatus::SessionNotCreated,
-                             "Failed to find value field").as_object(),
-                    ErrorStatus::SessionNotCreated,
-                    "value field was not an Object");
-
+            NewSession(_) => {
+                //update() has already checked and recorded what marionette sent
+                let capabilities = try_opt!(self.capabilities.as_ref(),
+                                            ErrorStatus::SessionNotCreated,
+                                            "Failed to find value field");
                 Ok(Some(WebDriverResponse::NewSession(NewSessionResponse::new(
-                    session_id, json::Object(value.clone())))))
+                    self.session_id.clone(), capabilities))))
             }
             DeleteSession => {
                 Ok(Some(WebDriverResponse::DeleteSession))
@@ -280,218 +477,1058 @@ impl MarionetteSession {
         }
     }
 
-    pub fn error_from_code(&self, error_code: u64) -> ErrorStatus {
-        match error_code {
-            7 => ErrorStatus::NoSuchElement,
-            8 => ErrorStatus::NoSuchFrame,
-            9 => ErrorStatus::UnsupportedOperation,
-            10 => ErrorStatus::StaleElementReference,
-            11 => ErrorStatus::ElementNotVisible,
-            12 => ErrorStatus::InvalidElementState,
-            15 => ErrorStatus::ElementNotSelectable,
-            17 => ErrorStatus::JavascriptError,
-            21 => ErrorStatus::Timeout,
-            23 => ErrorStatus::NoSuchWindow,
-            24 => ErrorStatus::InvalidCookieDomain,
-            25 => ErrorStatus::UnableToSetCookie,
-            26 => ErrorStatus::UnexpectedAlertOpen,
-            27 => ErrorStatus::NoSuchAlert,
-            28 => ErrorStatus::ScriptTimeout,
-            29 => ErrorStatus::InvalidElementCoordinates,
-            32 => ErrorStatus::InvalidSelector,
-            34 => ErrorStatus::MoveTargetOutOfBounds,
-            405 => ErrorStatus::UnsupportedOperation,
-            13 | 19 | 51 | 52 | 53 | 54 | 55 | 56 | 500 | _ => ErrorStatus::UnknownError
-            // ^ why list those?
+    // Newer versions of marionette send the error name as a string, with the message
+    // and stacktrace alongside it. Older versions send an object containing those
+    // fields and a numeric status, like the JSON wire protocol.
+    pub fn error_from_json(&self, error: &Json,
+                       data: &TreeMap<String, Json>) -> WebDriverResult<WebDriverError> {
+        let (status, details) = match *error {
+            Json::String(_) => {
+                (ErrorStatus::from_json(error).unwrap_or(ErrorStatus::UnknownError), data)
+            },
+            Json::Object(ref x) => {
+                //Array based responses carry the error name in an "error" field
+                let status = x.get("status").or(x.get("error"))
+                    .and_then(|x| ErrorStatus::from_json(x))
+                    .unwrap_or(ErrorStatus::UnknownError);
+                (status, x)
+            },
+            _ => return Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                                "Marionette error field was not understood"))
+        };
+        let message = match details.get("message") {
+            Some(x) => try_opt!(x.as_string(),
+                                ErrorStatus::UnknownError,
+                                "Error message was not a string"),
+            None => "Unknown error"
+        };
+        let mut err = WebDriverError::new(status, message.to_string());
+        err.stack = match details.get("stacktrace") {
+            Some(&Json::String(ref x)) => Some(x.clone()),
+            _ => None
+        };
+        Ok(err)
+    }
+}
+
+struct ReaderState {
+    pending: TreeMap<u64, Sender<IoResult<Json>>>,
+    closed: bool
+}
 
+impl ReaderState {
+    // Closes the connection and fails every command still waiting for a response
+    fn fail_all(&mut self, err: IoError) {
+        self.closed = true;
+        let waiting = mem::replace(&mut self.pending, TreeMap::new());
+        for (_, sender) in waiting.into_iter() {
+            let _ = sender.send_opt(Err(err.clone()));
         }
     }
 }
 
 pub struct MarionetteConnection {
-    stream: IoResult<TcpStream>,
+    pub host: String,
+    pub port: u16,
+    response_timeout: Option<u64>,
+    //Writes go through a buffer, and reads through another on the reader task
+    stream: IoResult<BufferedWriter<TcpStream>>,
+    codec: MarionetteCodec,
+    next_id: u64,
+    reader: Arc<Mutex<ReaderState>>,
+    //Set when the last message couldn't be written, so marionette never saw it
+    unsent: bool,
+    //The client request being handled, which the protocol log lines are tagged with
+    request_id: Option<String>,
+    //Whether that request's parameters are kept out of the protocol log
+    sensitive: bool,
+    pub protocol_version: u64,
+    //Whether implicit waits are done here instead of by marionette
+    pub driver_implicit_wait: bool,
     pub session: MarionetteSession
 }
 
 impl MarionetteConnection {
-    pub fn new(session_id: Option<String>) -> MarionetteConnection {
-        let stream = TcpStream::connect("127.0.0.1:2828");
+    pub fn new(host: &str, port: u16, session_id: Option<String>,
+               response_timeout: Option<u64>) -> MarionetteConnection {
         MarionetteConnection {
-            stream: stream,
+            host: host.to_string(),
+            port: port,
+            response_timeout: response_timeout,
+            stream: Err(IoError {
+                kind: IoErrorKind::NotConnected,
+                desc: "Not connected to marionette",
+                detail: None
+            }),
+            codec: MarionetteCodec::new(MAX_MESSAGE_SIZE),
+            next_id: 0,
+            reader: Arc::new(Mutex::new(ReaderState {
+                pending: TreeMap::new(),
+                closed: false
+            })),
+            unsent: false,
+            request_id: None,
+            sensitive: false,
+            protocol_version: 0,
+            driver_implicit_wait: false,
             session: MarionetteSession::new(session_id)
         }
     }
 
-    pub fn connect(&mut self) -> Result<(), IoError> {
-        try!(self.read_resp());
-        //Would get traits and application type here
+    pub fn connect(&mut self, startup_timeout: u64) -> WebDriverResult<()> {
+        let stream = match self.connect_stream(startup_timeout) {
+            Ok(x) => x,
+            //It only gives up once the startup timeout is used up
+            Err(e) => return Err(WebDriverError::new(
+                ErrorStatus::Timeout,
+                format!("Failed to connect to marionette on {}:{}: {}",
+                        self.host, self.port, e)).with_source(e))
+        };
+        let mut incoming = BufferedReader::with_capacity(BUFFER_SIZE, stream.clone());
+        self.stream = Ok(BufferedWriter::with_capacity(BUFFER_SIZE, stream));
+        let hello = match self.codec.decode(&mut incoming) {
+            Ok(x) => x,
+            Err(e) => return Err(WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                format!("Failed to read marionette handshake: {}", e)).with_source(e))
+        };
+        trace!("<- handshake {}", hello);
+        try!(self.handshake(hello[]));
+        self.start_reader(incoming);
+
+        //Newer versions don't address messages to an actor
+        if self.protocol_version >= ARRAY_PROTOCOL_VERSION {
+            return Ok(())
+        }
         let mut msg = TreeMap::new();
         msg.insert("name".to_string(), "getMarionetteID".to_json());
         msg.insert("to".to_string(), "root".to_json());
-        match self.send(&msg.to_json()) {
-            Ok(resp) => {
-                let json_data = match object_from_json(resp.as_slice()) {
-                    Ok(x) => x,
-                    Err(_) => panic!("Failed to connect to marionette")
-                };
-                // ok().expect("Failed to connect to marionette")?
-                match json_data.get(&"id".to_string()) {
-                    Some(x) => match x.as_string() {
-                        Some(id) => self.session.to = id.to_string(),
-                        None => panic!("Failed to connect to marionette")
-                    },
-                    None => panic!("Failed to connect to marionette")
-                };
-                Ok(())
+        let json_data = try!(self.send(Json::Object(msg)));
+        let id = try_opt!(
+            try_opt!(json_data.get("id"),
+                     ErrorStatus::SessionNotCreated,
+                     "Failed to get marionette id").as_string(),
+            ErrorStatus::SessionNotCreated,
+            "Marionette id was not a string");
+        self.session.to = id.to_string();
+        Ok(())
+    }
+
+    // The browser takes a while to start listening after it is launched, so back off
+    // and retry refused connections until the timeout runs out
+    fn connect_stream(&self, timeout: u64) -> IoResult<TcpStream> {
+        let mut delay = 100;
+        let mut waited = 0;
+        loop {
+            match TcpStream::connect((self.host[], self.port)) {
+                Ok(x) => return Ok(x),
+                Err(e) => {
+                    if waited >= timeout {
+                        return Err(e)
+                    }
+                    debug!("Connecting to marionette failed ({}), retrying in {}ms", e, delay);
+                    sleep(Duration::milliseconds(delay as i64));
+                    waited += delay;
+                    delay = cmp::min(delay * 2, MAX_RETRY_DELAY);
+                }
             }
-            Err(_) => panic!("Failed to connect to marionette")
         }
     }
 
-    fn encode_msg(&self, msg:&Json) -> String {
-        let data = json::encode(msg);
-        /*let len = data.len().to_string();
-        let mut message = len;
-        message.push_str(":");
-        message.push_str(data.as_slice());
-        message*/
-        format!("{}:{}", data.len(), data)
+    fn handshake(&mut self, data: &str) -> WebDriverResult<()> {
+        let hello = try!(object_from_json(data));
+        let app_type = try_opt!(
+            try_opt!(hello.get("applicationType"),
+                     ErrorStatus::SessionNotCreated,
+                     "Marionette handshake is missing applicationType").as_string(),
+            ErrorStatus::SessionNotCreated,
+            "applicationType was not a string");
+        if app_type != "gecko" {
+            return Err(WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                format!("Unexpected application type {}", app_type)))
+        }
+        //Versions of marionette before the protocol was versioned don't send this
+        let version = match hello.get("marionetteProtocol") {
+            Some(x) => try_opt!(x.as_u64(),
+                                ErrorStatus::SessionNotCreated,
+                                "marionetteProtocol was not an integer"),
+            None => 1
+        };
+        if version < MIN_PROTOCOL_VERSION || version > MAX_PROTOCOL_VERSION {
+            return Err(WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                format!("Unsupported marionette protocol version {}, supported versions are {} to {}",
+                        version, MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION)))
+        }
+        self.protocol_version = version;
+        Ok(())
     }
 
     pub fn send_message(&mut self, msg: &WebDriverMessage) -> WebDriverResult<Option<WebDriverResponse>>  {
-        let resp = try!(self.session.msg_to_marionette(msg));
-        let resp = match self.send(&resp) {
-            Ok(resp_data) => self.session.response_from_json(msg, resp_data[]),
+        self.request_id = msg.request_id.clone();
+        self.sensitive = msg.command.is_sensitive();
+        match msg.window_handle {
+            Some(ref handle) => try!(self.check_current_window(handle[])),
+            None => {}
+        }
+        match msg.command {
+            GetLogTypes => return Ok(Some(WebDriverResponse::Generic(ValueResponse::new(
+                vec!["browser".to_string()].to_json())))),
+            GetLog(ref x) => return self.get_log(x),
+            TakeScreenshot(ref x) if x.chrome => return self.chrome_screenshot(x),
+            SetWindowRect(ref x) if self.protocol_version < WINDOW_RECT_PROTOCOL_VERSION => {
+                return self.set_window_rect(x)
+            },
+            GetWindowRect if self.protocol_version < WINDOW_RECT_PROTOCOL_VERSION => {
+                return self.get_window_rect()
+            },
+            SetTimeouts(ref x) if self.driver_implicit_wait && x.type_[] == "implicit" => {
+                self.session.implicit_wait = x.ms;
+                return Ok(Some(WebDriverResponse::Void))
+            },
+            FindElement(_) | FindElements(_) if self.driver_implicit_wait &&
+                self.session.implicit_wait > 0 => return self.find_with_wait(msg),
+            TouchDown(_) | TouchUp(_) | TouchMove(_) | TouchScroll(_) | TouchFlick(_) |
+            TouchLongClick(_) => return self.touch_command(&msg.command),
+            GetPref(_) | SetPref(_) | ResetPref(_) => return self.pref_command(&msg.command),
+            Extension(ref x) if x.name[] == PASSTHROUGH_EXTENSION => {
+                return self.passthrough(&x.parameters)
+            },
+            ElementSendKeys(ref x) if self.session.context == Context::Content &&
+                self.names_files(x.value[]) => {
+                match try!(self.file_input(x)) {
+                    Some(multiple) => return self.set_files(x, multiple),
+                    None => {}
+                }
+            },
+            _ => {}
+        }
+        let resp = try!(self.session.msg_to_marionette(msg, self.protocol_version));
+        let resp = match self.send(resp) {
+            Ok(resp_data) => self.session.response_from_json(msg, resp_data),
             Err(x) => Err(x)
         };
         resp
         // Or
-        let resp_data = try!(match self.send(&resp));
-        self.session.response_from_json(msg, resp_data[])
+        let resp_data = try!(match self.send(resp));
+        self.session.response_from_json(msg, resp_data)
     }
 
-    fn send(&mut self, msg: &Json) -> WebDriverResult<String> {
-        let data = self.encode_msg(msg);
-        debug!("Sending {}", data);
-        match self.stream.write_str(data.as_slice()) {
+    // After the handshake all reads happen on a separate task, which hands each
+    // response to whoever is waiting for the message id it carries.
+    fn start_reader(&mut self, mut stream: BufferedReader<TcpStream>) {
+        let codec = self.codec.clone();
+        let state = self.reader.clone();
+        spawn(proc() {
+            logging::init();
+            loop {
+                let resp = codec.decode(&mut stream);
+                let mut state = state.lock();
+                match resp {
+                    Ok(data) => {
+                        //Each response is parsed exactly once, here, and handed on as json
+                        let data = parse_response(data[]);
+                        let id = data.as_ref().ok().and_then(|x| message_id(x));
+                        match id {
+                            //A response to a command that timed out is dropped
+                            Some(x) => match state.pending.remove(&x) {
+                                Some(sender) => {
+                                    let _ = sender.send_opt(data);
+                                },
+                                None => trace!("<- unsolicited {}", data)
+                            },
+                            //Responses without an id are for the oldest outstanding
+                            //command. One that nobody is waiting for means responses
+                            //and commands no longer line up, so nothing after it can
+                            //be trusted either.
+                            None => match state.pending.keys().next().map(|x| *x) {
+                                Some(x) => {
+                                    let sender = state.pending.remove(&x).unwrap();
+                                    let _ = sender.send_opt(data);
+                                },
+                                None => {
+                                    debug!("Marionette reader stopping: unexpected response {}",
+                                           data);
+                                    state.fail_all(framing_error(
+                                        "response without a command waiting for it",
+                                        "".to_string()));
+                                    break;
+                                }
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        debug!("Marionette reader stopping: {}", e);
+                        state.fail_all(e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    fn send(&mut self, msg: Json) -> WebDriverResult<TreeMap<String, Json>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.unsent = false;
+        let (resp_send, resp_recv) = channel();
+        {
+            let mut state = self.reader.lock();
+            if state.closed {
+                self.unsent = true;
+                return Err(WebDriverError::new(ErrorStatus::InvalidSessionId,
+                                               "Browser went away: marionette connection closed"))
+            }
+            state.pending.insert(id, resp_send);
+        }
+
+        let data = try!(self.encode_message(id, msg));
+        let request = match self.request_id {
+            Some(ref x) => format!(" [{}]", x),
+            None => "".to_string()
+        };
+        if self.sensitive {
+            trace!("-> {}{} <{} bytes>", id, request, data.len());
+        } else {
+            trace!("-> {}{} {}", id, request, data);
+        }
+        let start = precise_time_ns();
+        match self.codec.write_to(&mut self.stream, data[]) {
             Ok(_) => {},
-            Err(_) => {
-                return Err(WebDriverError::new(ErrorStatus::UnknownError,
-                                               "Failed to write response to stream"))
+            Err(e) => {
+                let mut state = self.reader.lock();
+                state.pending.remove(&id);
+                state.closed = true;
+                self.unsent = true;
+                return Err(WebDriverError::new(
+                    ErrorStatus::UnknownError,
+                    format!("Failed to write to marionette: {}", e)).with_source(e))
             }
         }
-        match self.read_resp() {
-            Ok(resp) => {
-                debug!("Marionette response {}", resp);
-                Ok(resp)
+        let resp = match self.response_timeout {
+            Some(timeout) => {
+                let mut timer = try!(Timer::new());
+                let timeout_recv = timer.oneshot(Duration::milliseconds(timeout as i64));
+                select! {
+                    resp = resp_recv.recv_opt() => resp,
+                    () = timeout_recv.recv() => {
+                        let mut state = self.reader.lock();
+                        state.pending.remove(&id);
+                        //Without ids a late response would be taken for the answer to
+                        //the next command, so the connection can't be used any more
+                        if self.protocol_version < ARRAY_PROTOCOL_VERSION {
+                            state.fail_all(framing_error("timed out waiting for a response",
+                                                         "".to_string()));
+                        }
+                        return Err(WebDriverError::new(
+                            ErrorStatus::Timeout,
+                            format!("Timed out after {}ms waiting for marionette", timeout)))
+                    }
+                }
             },
+            None => resp_recv.recv_opt()
+        };
+        match resp {
+            Ok(Ok(resp)) => {
+                let elapsed = (precise_time_ns() - start) / 1000000;
+                metrics::observe("wires_marionette_roundtrip_milliseconds", &[], elapsed);
+                trace!("<- {}{} ({}ms) {}", id, request, elapsed, resp);
+                self.decode_message(resp)
+            },
+            Ok(Err(e)) => Err(WebDriverError::new(
+                ErrorStatus::UnknownError,
+                format!("Failed to decode response from marionette: {}", e)).with_source(e)),
             Err(_) => Err(WebDriverError::new(ErrorStatus::UnknownError,
-                                              "Failed to decode response from marionette"))
+                                              "Marionette reader went away"))
+        }
+    }
+
+    // Protocol 3 and later wrap commands as [0, id, name, parameters] and responses as
+    // [1, id, error, result]. Earlier versions send bare objects addressed with "to",
+    // and reply with {from, ok, value, error}.
+    // The message is taken apart rather than copied, since parameters can be large
+    fn encode_message(&self, id: u64, msg: Json) -> WebDriverResult<String> {
+        if self.protocol_version < ARRAY_PROTOCOL_VERSION {
+            return Ok(json::encode(&msg))
+        }
+        let mut msg = match msg {
+            Json::Object(x) => x,
+            _ => return Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                                "Message is not an object"))
+        };
+        let name = try_opt!(msg.remove("name"),
+                            ErrorStatus::UnknownError,
+                            "Message is missing a name");
+        let parameters = msg.remove("parameters").unwrap_or(Json::Object(TreeMap::new()));
+        Ok(json::encode(&Json::Array(vec![0u64.to_json(), id.to_json(), name, parameters])))
+    }
+
+    // Responses are turned into the legacy object form, which is what the rest of the
+    // code understands.
+    fn decode_message(&self, data: Json) -> WebDriverResult<TreeMap<String, Json>> {
+        let mut message = match data {
+            Json::Array(x) => x,
+            Json::Object(x) => return Ok(x),
+            _ => return Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                                "Expected a json object"))
+        };
+        if message.len() != 4 || message[0].as_u64() != Some(1) {
+            return Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                           "Marionette response was not understood"))
+        }
+        let result = message.pop().unwrap();
+        let error = message.pop().unwrap();
+        let mut rv = match result {
+            Json::Object(x) => x,
+            Json::Null => TreeMap::new(),
+            x => {
+                let mut rv = TreeMap::new();
+                rv.insert("value".to_string(), x);
+                rv
+            }
+        };
+        match error {
+            Json::Null => {},
+            x => {
+                rv.insert("error".to_string(), x);
+            }
+        }
+        Ok(rv)
+    }
+
+    // The browser may well close the connection before replying, so failures are
+    // only logged
+    pub fn quit(&mut self) {
+        self.quit_with_flags(&["eForceQuit"])
+    }
+
+    pub fn restart(&mut self) {
+        self.quit_with_flags(&["eForceQuit", "eRestart"])
+    }
+
+    fn quit_with_flags(&mut self, flags: &[&str]) {
+        self.request_id = None;
+        self.sensitive = false;
+        let mut params = TreeMap::new();
+        let flags: Vec<String> = flags.iter().map(|x| x.to_string()).collect();
+        params.insert("flags".to_string(), flags.to_json());
+        let mut msg = TreeMap::new();
+        msg.insert("name".to_string(), "quitApplication".to_json());
+        msg.insert("parameters".to_string(), params.to_json());
+        msg.insert("to".to_string(), self.session.to.to_json());
+        match self.send(Json::Object(msg)) {
+            Ok(_) => {},
+            Err(e) => debug!("Error sending quit to marionette: {}", e.message)
+        }
+    }
+
+    // Marionette keeps the id of a session that it is asked to create with one, so after
+    // a restart the client can carry on using the session it had before. The state wires
+    // keeps for the session moves over from the old connection, and a new browser is put
+    // back in the context the client had chosen.
+    pub fn resume_session(&mut self, session: MarionetteSession) -> WebDriverResult<()> {
+        self.session = session;
+        if self.session.marionette_session_id.is_empty() {
+            return Ok(())
+        }
+        let mut params = TreeMap::new();
+        params.insert("sessionId".to_string(), self.session.marionette_session_id.to_json());
+        let mut msg = TreeMap::new();
+        msg.insert("name".to_string(), "newSession".to_json());
+        msg.insert("parameters".to_string(), params.to_json());
+        msg.insert("to".to_string(), self.session.to.to_json());
+        let resp = try!(self.send(Json::Object(msg)));
+        match resp.get("error") {
+            Some(error) => return Err(try!(self.session.error_from_json(error, &resp))),
+            None => {}
+        }
+        if self.session.context == Context::Chrome {
+            try!(self.send_command("setContext", try!(SetContextParameters {
+                context: Context::Chrome
+            }.to_marionette())));
+        }
+        Ok(())
+    }
+
+    // Marionette has no command for the page console, so the entries are read from the
+    // browser's console services by a script run in the chrome context. Each call
+    // returns the entries that are newer than any handed out before.
+    fn get_log(&mut self,
+               params: &GetLogParameters) -> WebDriverResult<Option<WebDriverResponse>> {
+        if params.log_type[] != "browser" {
+            return Err(wd_err!(ErrorStatus::InvalidArgument,
+                               "Unknown log type {}", params.log_type))
+        }
+        let mut script = TreeMap::new();
+        script.insert("script".to_string(), BROWSER_LOG_SCRIPT.to_json());
+        script.insert("args".to_string(), vec![self.session.log_since].to_json());
+        script.insert("newSandbox".to_string(), true.to_json());
+        let mut resp = try!(self.send_in_chrome("executeScript", Json::Object(script)));
+        let entries = try_opt!(resp.remove("value"),
+                               ErrorStatus::UnknownError,
+                               "Failed to find value field");
+        for entry in try_opt!(entries.as_array(),
+                              ErrorStatus::UnknownError,
+                              "Failed to interpret value as array").iter() {
+            match entry.find("timestamp").and_then(|x| x.as_f64()) {
+                Some(x) => self.session.log_since = cmp::max(self.session.log_since, x as u64),
+                None => {}
+            }
+        }
+        Ok(Some(WebDriverResponse::Generic(ValueResponse::new(entries))))
+    }
+
+    // Older marionettes set the position and the size separately, and need both halves
+    // of each. The rect is read back from the browser afterwards rather than echoed,
+    // since the window manager may clamp the size, or keep the window on a monitor,
+    // instead of doing exactly what was asked.
+    fn set_window_rect(&mut self, params: &WindowRectParameters)
+                       -> WebDriverResult<Option<WebDriverResponse>> {
+        let (x, y, width, height) = try!(self.window_rect());
+        if params.x.is_value() || params.y.is_value() {
+            let mut position = TreeMap::new();
+            position.insert("x".to_string(), params.x.as_ref().map_or(x, |x| *x).to_json());
+            position.insert("y".to_string(), params.y.as_ref().map_or(y, |x| *x).to_json());
+            try!(self.send_command("setWindowPosition", Json::Object(position)));
+        }
+        if params.width.is_value() || params.height.is_value() {
+            let mut size = TreeMap::new();
+            size.insert("width".to_string(),
+                        params.width.as_ref().map_or(width, |x| *x).to_json());
+            size.insert("height".to_string(),
+                        params.height.as_ref().map_or(height, |x| *x).to_json());
+            try!(self.send_command("setWindowSize", Json::Object(size)));
+        }
+        self.get_window_rect()
+    }
+
+    fn get_window_rect(&mut self) -> WebDriverResult<Option<WebDriverResponse>> {
+        let (x, y, width, height) = try!(self.window_rect());
+        Ok(Some(WebDriverResponse::WindowRect(
+            WindowRectResponse::with_position(x, y, width, height))))
+    }
+
+    fn window_rect(&mut self) -> WebDriverResult<(i64, i64, u64, u64)> {
+        let mut position = try!(self.send_command("getWindowPosition",
+                                                  Json::Object(TreeMap::new())));
+        let mut size = try!(self.send_command("getWindowSize", Json::Object(TreeMap::new())));
+        let position = try_opt!(position.remove("value"),
+                                ErrorStatus::UnknownError,
+                                "Failed to find value field");
+        let size = try_opt!(size.remove("value"),
+                            ErrorStatus::UnknownError,
+                            "Failed to find value field");
+        Ok((try!(rect_member(&position, "x")) as i64, try!(rect_member(&position, "y")) as i64,
+            try!(rect_member(&size, "width")) as u64, try!(rect_member(&size, "height")) as u64))
+    }
+
+    fn check_current_window(&mut self, handle: &str) -> WebDriverResult<()> {
+        let mut resp = try!(self.send_command("getWindowHandle", Json::Object(TreeMap::new())));
+        let value = try_opt!(resp.remove("value"),
+                             ErrorStatus::UnknownError,
+                             "Failed to find value field");
+        if value.as_string() == Some(handle) {
+            Ok(())
+        } else {
+            Err(wd_err!(ErrorStatus::NoSuchWindow, "Window {} is not the current window", handle))
+        }
+    }
+
+    // Whether the element is a file input, and if so whether it takes several files
+    fn file_input(&mut self, params: &SendKeysParameters) -> WebDriverResult<Option<bool>> {
+        let mut script = TreeMap::new();
+        script.insert("script".to_string(), FILE_INPUT_SCRIPT.to_json());
+        script.insert("args".to_string(), vec![params.element.to_json()].to_json());
+        let mut resp = try!(self.send_command("executeScript", Json::Object(script)));
+        let value = try_opt!(resp.remove("value"),
+                             ErrorStatus::UnknownError,
+                             "Failed to find value field");
+        Ok(match value {
+            Json::Null => None,
+            x => Some(x.find("multiple").and_then(|x| x.as_boolean()).unwrap_or(false))
+        })
+    }
+
+    // Typing into a file input would only put the path in the page's hands, so the text
+    // is taken as the files to select instead, one path per line
+    fn set_files(&mut self, params: &SendKeysParameters,
+                 multiple: bool) -> WebDriverResult<Option<WebDriverResponse>> {
+        let mut paths = vec![];
+        for line in params.value[].split('\n').filter(|x| !x.is_empty()) {
+            paths.push(try!(self.resolve_file(line)));
+        }
+        if paths.is_empty() {
+            return Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                           "No files given for the file input"))
+        }
+        if paths.len() > 1 && !multiple {
+            return Err(WebDriverError::new(ErrorStatus::InvalidArgument,
+                                           "File input doesn't accept multiple files"))
+        }
+        let mut script = TreeMap::new();
+        script.insert("script".to_string(), SET_FILES_SCRIPT.to_json());
+        script.insert("args".to_string(),
+                      vec![params.element.to_json(), paths.to_json()].to_json());
+        script.insert("sandbox".to_string(), "system".to_json());
+        try!(self.send_command("executeScript", Json::Object(script)));
+        Ok(Some(WebDriverResponse::Void))
+    }
+
+    // Only text that names files is worth asking the page about, since any other text
+    // is typed whatever the element is
+    fn names_files(&self, text: &str) -> bool {
+        let lines: Vec<&str> = text.split('\n').filter(|x| !x.is_empty()).collect();
+        !lines.is_empty() && lines.iter().all(|x| self.resolve_file(*x).is_ok())
+    }
+
+    // Paths that don't exist are looked up by file name among the uploaded files, so a
+    // remote client can use the name of a file it uploaded
+    fn resolve_file(&self, name: &str) -> WebDriverResult<String> {
+        let path = Path::new(name);
+        if path.is_absolute() && path.is_file() {
+            return Ok(path.display().to_string())
+        }
+        match self.session.uploaded_files.iter().rev()
+            .find(|x| x.filename_str() == Some(name)) {
+            Some(x) => Ok(x.display().to_string()),
+            None => Err(wd_err!(ErrorStatus::InvalidArgument, "File not found: {}", name))
+        }
+    }
+
+    // Finds elements again until some turn up or the implicit wait runs out, for
+    // browsers that return straight away when there are none
+    fn find_with_wait(&mut self,
+                      msg: &WebDriverMessage) -> WebDriverResult<Option<WebDriverResponse>> {
+        let deadline = precise_time_ns() + self.session.implicit_wait * 1000000;
+        loop {
+            let data = try!(self.session.msg_to_marionette(msg, self.protocol_version));
+            let resp = match self.send(data) {
+                Ok(resp_data) => self.session.response_from_json(msg, resp_data),
+                Err(x) => Err(x)
+            };
+            let found = match resp {
+                Err(ref e) if e.status == ErrorStatus::NoSuchElement => false,
+                Ok(Some(WebDriverResponse::Generic(ref x))) => {
+                    x.value.as_array().map_or(true, |x| !x.is_empty())
+                },
+                _ => true
+            };
+            if found || precise_time_ns() >= deadline {
+                return resp
+            }
+            sleep(Duration::milliseconds(IMPLICIT_WAIT_INTERVAL));
+        }
+    }
+
+    // Preferences are only reachable from chrome code, so each of these runs a script
+    // in the chrome context
+    fn pref_command(&mut self,
+                    command: &WebDriverCommand) -> WebDriverResult<Option<WebDriverResponse>> {
+        let (script, args) = match *command {
+            GetPref(ref x) => (GET_PREF_SCRIPT, vec![x.name.to_json()]),
+            SetPref(ref x) => (SET_PREF_SCRIPT, vec![x.name.to_json(), x.value.clone()]),
+            ResetPref(ref x) => (RESET_PREF_SCRIPT, vec![x.name.to_json()]),
+            _ => return Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                                "Not a preference command"))
+        };
+        let mut params = TreeMap::new();
+        params.insert("script".to_string(), script.to_json());
+        params.insert("args".to_string(), args.to_json());
+        params.insert("newSandbox".to_string(), true.to_json());
+        let mut resp = try!(self.send_in_chrome("executeScript", Json::Object(params)));
+        match *command {
+            GetPref(_) => {
+                let value = resp.remove("value").unwrap_or(Json::Null);
+                Ok(Some(WebDriverResponse::Generic(ValueResponse::new(value))))
+            },
+            _ => Ok(Some(WebDriverResponse::Void))
+        }
+    }
+
+    // The JSON wire touch commands are run as marionette action chains. Marionette
+    // keeps a touch going from one chain to the next when it gets back the id the last
+    // one returned, which is how separate down, move and up commands make one gesture.
+    fn action_chain(&mut self, chain: Vec<Json>,
+                    keep_touch: bool) -> WebDriverResult<Option<WebDriverResponse>> {
+        let mut params = TreeMap::new();
+        params.insert("chain".to_string(), chain.to_json());
+        params.insert("nextId".to_string(), self.session.touch_id.to_json());
+        let mut resp = try!(self.send_command("actionChain", Json::Object(params)));
+        self.session.touch_id = if keep_touch {
+            resp.remove("value").and_then(|x| x.as_u64())
+        } else {
+            None
+        };
+        Ok(Some(WebDriverResponse::Void))
+    }
+
+    // Touches at page coordinates are made relative to the document element
+    fn root_element(&mut self) -> WebDriverResult<String> {
+        let mut params = TreeMap::new();
+        params.insert("using".to_string(), "css selector".to_json());
+        params.insert("value".to_string(), ":root".to_json());
+        let mut resp = try!(self.send_command("findElement", Json::Object(params)));
+        let value = try_opt!(resp.remove("value"),
+                             ErrorStatus::UnknownError,
+                             "Failed to find value field");
+        Ok(try!(WebElement::from_json(&value)).id)
+    }
+
+    // Presses on the element, or where the last touch was if there isn't one
+    fn touch_start(&mut self, element: Option<&WebElement>) -> WebDriverResult<Json> {
+        Ok(match element {
+            Some(x) => vec!["press".to_json(), x.id.to_json(), Json::Null, Json::Null],
+            None => {
+                let (x, y) = self.session.touch_position;
+                vec!["press".to_json(), try!(self.root_element()).to_json(),
+                     x.to_json(), y.to_json()]
+            }
+        }.to_json())
+    }
+
+    fn touch_move_to(&mut self, x: i64, y: i64) -> Json {
+        let (last_x, last_y) = self.session.touch_position;
+        self.session.touch_position = (x, y);
+        vec!["moveByOffset".to_json(), (x - last_x).to_json(), (y - last_y).to_json()].to_json()
+    }
+
+    fn touch_command(&mut self,
+                     command: &WebDriverCommand) -> WebDriverResult<Option<WebDriverResponse>> {
+        let release = vec!["release".to_json()].to_json();
+        match *command {
+            TouchDown(ref x) => {
+                let root = try!(self.root_element());
+                self.session.touch_position = (x.x, x.y);
+                self.action_chain(vec![vec!["press".to_json(), root.to_json(),
+                                            x.x.to_json(), x.y.to_json()].to_json()], true)
+            },
+            TouchMove(ref x) => {
+                let step = self.touch_move_to(x.x, x.y);
+                self.action_chain(vec![step], true)
+            },
+            TouchUp(ref x) => {
+                let step = self.touch_move_to(x.x, x.y);
+                self.action_chain(vec![step, release], false)
+            },
+            TouchScroll(ref x) => {
+                let start = try!(self.touch_start(x.element.as_ref()));
+                self.action_chain(vec![start, offset_step(x.xoffset, x.yoffset), release],
+                                  false)
+            },
+            //Marionette works out the speed of a flick from how long the move took
+            TouchFlick(ref x) => {
+                let start = try!(self.touch_start(x.element.as_ref()));
+                let wait = vec!["wait".to_json(),
+                                (x.duration() as f64 / 1000.0).to_json()].to_json();
+                self.action_chain(vec![start, wait, offset_step(x.xoffset, x.yoffset),
+                                       release], false)
+            },
+            TouchLongClick(ref x) => {
+                let start = try!(self.touch_start(Some(&x.element)));
+                let wait = vec!["wait".to_json(), LONG_PRESS_SECONDS.to_json()].to_json();
+                self.action_chain(vec![start, wait, release], false)
+            },
+            _ => Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                         "Not a touch command"))
         }
     }
 
-    fn read_resp(&mut self) -> Result<String, IoError> {
-        let mut bytes = 0 as uint;
-        // 0 as uint -> 0u
+    // In the chrome context a screenshot is of the whole browser window, UI included
+    fn chrome_screenshot(&mut self, params: &TakeScreenshotParameters)
+                         -> WebDriverResult<Option<WebDriverResponse>> {
+        let mut resp = try!(self.send_in_chrome("takeScreenshot", try!(params.to_marionette())));
+        let value = try_opt!(resp.remove("value"),
+                             ErrorStatus::UnknownError,
+                             "Failed to find value field");
+        Ok(Some(WebDriverResponse::Generic(ValueResponse::new(value))))
+    }
+
+    // Sends a command in the chrome context, and then switches back to the context the
+    // client had chosen, even if the command failed
+    fn send_in_chrome(&mut self, name: &str,
+                      params: Json) -> WebDriverResult<TreeMap<String, Json>> {
+        let context = self.session.context.clone();
+        if context == Context::Chrome {
+            return self.send_command(name, params)
+        }
+        try!(self.send_command("setContext", try!(SetContextParameters {
+            context: Context::Chrome
+        }.to_marionette())));
+        let resp = self.send_command(name, params);
+        try!(self.send_command("setContext", try!(SetContextParameters {
+            context: context
+        }.to_marionette())));
+        resp
+    }
+
+    // Sends whatever command the client asked for and hands back marionette's whole
+    // response, errors included, for trying out commands wires doesn't know about yet
+    fn passthrough(&mut self,
+                   params: &Json) -> WebDriverResult<Option<WebDriverResponse>> {
+        let mut msg = TreeMap::new();
+        msg.insert("name".to_string(), params.find("name").map_or(Json::Null, |x| x.clone()));
+        msg.insert("parameters".to_string(),
+                   params.find("parameters").map_or(Json::Null, |x| x.clone()));
+        msg.insert("sessionId".to_string(), self.session.marionette_session_id.to_json());
+        msg.insert("to".to_string(), self.session.to.to_json());
+        let resp = try!(self.send(Json::Object(msg)));
+        Ok(Some(WebDriverResponse::Generic(ValueResponse::new(Json::Object(resp)))))
+    }
+
+    // Sends a message that doesn't come from a client's command, in the current session
+    fn send_command(&mut self, name: &str,
+                    params: Json) -> WebDriverResult<TreeMap<String, Json>> {
+        let mut msg = TreeMap::new();
+        msg.insert("name".to_string(), name.to_json());
+        msg.insert("parameters".to_string(), params);
+        msg.insert("sessionId".to_string(), self.session.marionette_session_id.to_json());
+        msg.insert("to".to_string(), self.session.to.to_json());
+        let resp = try!(self.send(Json::Object(msg)));
+        match resp.get("error") {
+            Some(error) => return Err(try!(self.session.error_from_json(error, &resp))),
+            None => {}
+        }
+        Ok(resp)
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !self.reader.lock().closed
+    }
+
+    // Whether the last message failed without reaching marionette, in which case it
+    // can be sent again on a new connection without running the command twice
+    pub fn last_message_unsent(&self) -> bool {
+        self.unsent
+    }
+}
+
+fn offset_step(x: i64, y: i64) -> Json {
+    vec!["moveByOffset".to_json(), x.to_json(), y.to_json()].to_json()
+}
+
+// Any kind of number is accepted, in case the rect has fractional coordinates
+fn rect_member(value: &Json, key: &str) -> WebDriverResult<f64> {
+    match value.find(key).and_then(|x| x.as_f64()) {
+        Some(x) => Ok(x),
+        None => Err(wd_err!(ErrorStatus::UnknownError,
+                            "Failed to find {} field in the window rect", key))
+    }
+}
+
+fn parse_response(data: &str) -> IoResult<Json> {
+    json::from_str(data).map_err(|e| IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: "response was not valid json",
+        detail: Some(e.to_string())
+    })
+}
+
+// Responses using the array based format are [1, id, error, result]
+fn message_id(json: &Json) -> Option<u64> {
+    match json.as_array() {
+        Some(x) if x.len() == 4 && x[0].as_u64() == Some(1) => x[1].as_u64(),
+        _ => None
+    }
+}
+
+// Messages on the wire are of the form "len:payload" where len is the length of the
+// payload in bytes. Reads may return less than a whole message, so the decoder keeps
+// reading until it has the number of bytes that it was promised.
+#[deriving(Clone)]
+pub struct MarionetteCodec {
+    max_size: uint
+}
+
+impl MarionetteCodec {
+    pub fn new(max_size: uint) -> MarionetteCodec {
+        MarionetteCodec {
+            max_size: max_size
+        }
+    }
+
+    pub fn encode(&self, data: &str) -> String {
+        format!("{}:{}", data.len(), data)
+    }
+
+    // Writes the length and the message separately, so that big messages don't have to
+    // be copied, then flushes
+    pub fn write_to<W: Writer>(&self, writer: &mut W, data: &str) -> IoResult<()> {
+        try!(writer.write_str(format!("{}:", data.len())[]));
+        try!(writer.write_str(data));
+        writer.flush()
+    }
+
+    pub fn decode<R: Reader>(&self, reader: &mut R) -> IoResult<String> {
+        let mut bytes = 0u;
+        let mut digits = 0u;
         loop {
-            let byte = try!(self.stream.read_byte()) as char;
+            let byte = try!(reader.read_byte()) as char;
             match byte {
                 '0'...'9' => {
-                    bytes = bytes * 10;
-                    bytes += byte as uint - '0' as uint;
+                    bytes = bytes * 10 + (byte as uint - '0' as uint);
+                    digits += 1;
+                    if bytes > self.max_size {
+                        return Err(framing_error("Message exceeds maximum size",
+                                                 format!("at least {} bytes", bytes)));
+                    }
                 },
                 ':' => {
+                    if digits == 0 {
+                        return Err(framing_error("Message is missing its length",
+                                                 "".to_string()));
+                    }
                     break
-                }
-                _ => {}
+                },
+                x => return Err(framing_error("Unexpected character in message length",
+                                              format!("{}", x)))
             }
         }
-        let data = try!(self.stream.read_exact(bytes));
-        //Need to handle the error here
-        Ok(String::from_utf8(data).unwrap())
+        let data = try!(reader.read_exact(bytes));
+        match String::from_utf8(data) {
+            Ok(x) => Ok(x),
+            Err(_) => Err(framing_error("Message was not valid UTF-8", "".to_string()))
+        }
+    }
+}
+
+fn framing_error(desc: &'static str, detail: String) -> IoError {
+    IoError {
+        kind: IoErrorKind::InvalidInput,
+        desc: desc,
+        detail: if detail.is_empty() { None } else { Some(detail) }
     }
 }
 
+fn write_temp_file(prefix: &str, name: &str, data: &[u8]) -> IoResult<Path> {
+    let dir = try!(TempDir::new(prefix));
+    let path = dir.path().join(name);
+    try!(File::create(&path).write(data));
+    dir.unwrap();
+    Ok(path)
+}
+
+//The port is released again before this returns, so something else could grab it
+//before the browser binds it
+pub fn get_free_port() -> IoResult<u16> {
+    let listener = try!(TcpListener::bind("127.0.0.1:0"));
+    let addr = try!(listener.socket_name());
+    Ok(addr.port)
+}
+
+// Parser for the passthrough endpoint, whose body is {"name": ..., "parameters": {...}}
+pub fn passthrough_parameters(_: &PathParams, body: &Json) -> WebDriverResult<Json> {
+    let data = try_opt!(body.as_object(),
+                        ErrorStatus::InvalidArgument,
+                        "Message body was not an object");
+    let mut rv = TreeMap::new();
+    rv.insert("name".to_string(), try!(get_string(data, "", "name")).to_json());
+    let parameters = match data.get("parameters") {
+        Some(x) => Json::Object(try!(as_object("parameters", x)).clone()),
+        None => Json::Object(TreeMap::new())
+    };
+    rv.insert("parameters".to_string(), parameters);
+    Ok(Json::Object(rv))
+}
+
 trait ToMarionette {
     fn to_marionette(&self) -> WebDriverResult<Json>;
 }
 
-impl ToMarionette for WebDriverMessage {
-    fn to_marionette(&self) -> WebDriverResult<Json> {
-        let (opt_name, opt_parameters) = match self.command {
-            NewSession => (Some("newSession"), None),
-            DeleteSession => (Some("deleteSession"), None),
-            Get(ref x) => (Some("get"), Some(x.to_marionette())),
-            GetCurrentUrl => (Some("getCurrentUrl"), None),
-            GoBack => (Some("goBack"), None),
-            GoForward => (Some("goForward"), None),
-            Refresh => (Some("refresh"), None),
-            GetTitle => (Some("getTitle"), None),
-            GetWindowHandle => (Some("getWindowHandle"), None),
-            GetWindowHandles => (Some("getWindowHandles"), None),
-            Close => (Some("close"), None),
-            SetTimeouts(ref x) => (Some("timeouts"), Some(x.to_marionette())),
-            SetWindowSize(ref x) => (Some("setWindowSize"), Some(x.to_marionette())),
-            GetWindowSize => (Some("getWindowSize"), None),
-            MaximizeWindow => (Some("maximizeWindow"), None),
-            SwitchToWindow(ref x) => (Some("switchToWindow"), Some(x.to_marionette())),
-            SwitchToFrame(ref x) => (Some("switchToFrame"), Some(x.to_marionette())),
-            SwitchToParentFrame => (Some("switchToParentFrame"), None),
-            FindElement(ref x) => (Some("findElement"), Some(x.to_marionette())),
-            FindElements(ref x) => (Some("findElements"), Some(x.to_marionette())),
-            IsDisplayed(ref x) => (Some("isElementDisplayed"), Some(x.to_marionette())),
-            IsSelected(ref x) => (Some("isElementSelected"), Some(x.to_marionette())),
-            GetElementAttribute(ref e, ref x) => {
-                let mut data = TreeMap::new();
-                data.insert("id".to_string(), e.id.to_json());
-                data.insert("name".to_string(), x.to_json());
-                (Some("getElementAttribute"), Some(Ok(Json::Object(data))))
-            },
-            GetCSSValue(ref e, ref x) => {
-                let mut data = TreeMap::new();
-                data.insert("id".to_string(), e.id.to_json());
-                data.insert("name".to_string(), x.to_json());
-                (Some("getElementValueOfCSSProperty"), Some(Ok(Json::Object(data))))
-            },
-            GetElementText(ref x) => (Some("getElementText"), Some(x.to_marionette())),
-            GetElementTagName(ref x) => (Some("getElementTagName"), Some(x.to_marionette())),
-            GetElementRect(ref x) => (Some("getElementRect"), Some(x.to_marionette())),
-            IsEnabled(ref x) => (Some("isElementEnabled"), Some(x.to_marionette())),
-            ElementClick(ref x) => (Some("clickElement"), Some(x.to_marionette())),
-            ElementTap(ref x) => (Some("singleTap"), Some(x.to_marionette())),
-            ElementClear(ref x) => (Some("clearElement"), Some(x.to_marionette())),
-            ElementSendKeys(ref e, ref x) => {
-                let mut data = TreeMap::new();
-                data.insert("id".to_string(), e.id.to_json());
-                data.insert("value".to_string(), x.value.to_json());
-                (Some("sendKeysToElement"), Some(Ok(Json::Object(data))))
-            },
-            ExecuteScript(ref x) => (Some("executeScript"), Some(x.to_marionette())),
-            ExecuteAsyncScript(ref x) => (Some("executeAsyncScript"), Some(x.to_marionette())),
-            GetCookie(ref x) => (Some("getCookies"), Some(x.to_marionette())),
-            AddCookie(ref x) => (Some("addCookie"), Some(x.to_marionette())),
-            DismissAlert => (None, None), //Unsupported
-            AcceptAlert => (None, None), //Unsupported
-            GetAlertText => (None, None), //Unsupported
-            SendAlertText(ref x) => (None, None), //Unsupported
-            TakeScreenshot(ref x) => (Some("takeScreenshot"), Some(x.to_marionette())),
-        };
-
-        let name = try_opt!(opt_name,
-                            ErrorStatus::UnsupportedOperation,
-                            "Operation not supported");
+// The one place WebDriver commands are mapped onto marionette's names and parameters.
+// Where marionette renamed a command or changed its parameters, add an arm with a
+// guard on protocol_version ahead of the general one.
+fn marionette_command<'a>(command: &'a WebDriverCommand,
+                          protocol_version: u64) -> WebDriverResult<(&'a str, Json)> {
+    let (opt_name, opt_parameters) = match *command {
+        NewSession(_) => (Some("newSession"), None),
+        DeleteSession => (Some("deleteSession"), None),
+        Get(ref x) => (Some("get"), Some(x.to_marionette())),
+        GetCurrentUrl => (Some("getCurrentUrl"), None),
+        GoBack => (Some("goBack"), None),
+        GoForward => (Some("goForward"), None),
+        Refresh => (Some("refresh"), None),
+        GetTitle => (Some("getTitle"), None),
+        GetWindowHandle => (Some("getWindowHandle"), None),
+        GetWindowHandles => (Some("getWindowHandles"), None),
+        Close => (Some("close"), None),
+        SetTimeouts(ref x) => (Some("timeouts"), Some(x.to_marionette())),
+        SetWindowSize(ref x) => (Some("setWindowSize"), Some(x.to_marionette())),
+        GetWindowSize => (Some("getWindowSize"), None),
+        SetWindowRect(ref x) if protocol_version >= WINDOW_RECT_PROTOCOL_VERSION => {
+            (Some("setWindowRect"), Some(Ok(x.to_json())))
+        },
+        GetWindowRect if protocol_version >= WINDOW_RECT_PROTOCOL_VERSION => {
+            (Some("getWindowRect"), None)
+        },
+        //Older marionettes need the position and size set separately, which the
+        //connection does
+        SetWindowRect(_) | GetWindowRect => (None, None),
+        MaximizeWindow => (Some("maximizeWindow"), None),
+        SwitchToWindow(ref x) => (Some("switchToWindow"), Some(x.to_marionette())),
+        SwitchToFrame(ref x) => (Some("switchToFrame"), Some(x.to_marionette())),
+        SwitchToParentFrame => (Some("switchToParentFrame"), None),
+        FindElement(ref x) => (Some("findElement"), Some(x.to_marionette())),
+        FindElements(ref x) => (Some("findElements"), Some(x.to_marionette())),
+        IsDisplayed(ref x) => (Some("isElementDisplayed"), Some(x.to_marionette())),
+        IsSelected(ref x) => (Some("isElementSelected"), Some(x.to_marionette())),
+        GetElementAttribute(ref x) => (Some("getElementAttribute"), Some(x.to_marionette())),
+        GetCSSValue(ref x) => (Some("getElementValueOfCSSProperty"), Some(x.to_marionette())),
+        GetElementText(ref x) => (Some("getElementText"), Some(x.to_marionette())),
+        GetElementTagName(ref x) => (Some("getElementTagName"), Some(x.to_marionette())),
+        GetElementRect(ref x) => (Some("getElementRect"), Some(x.to_marionette())),
+        IsEnabled(ref x) => (Some("isElementEnabled"), Some(x.to_marionette())),
+        ElementClick(ref x) => (Some("clickElement"), Some(x.to_marionette())),
+        ElementTap(ref x) => (Some("singleTap"), Some(x.to_marionette())),
+        ElementClear(ref x) => (Some("clearElement"), Some(x.to_marionette())),
+        ElementSendKeys(ref x) => (Some("sendKeysToElement"), Some(x.to_marionette())),
+        ExecuteScript(ref x) => (Some("executeScript"), Some(x.to_marionette())),
+        ExecuteAsyncScript(ref x) => (Some("executeAsyncScript"), Some(x.to_marionette())),
+        GetCookie(ref x) => (Some("getCookies"), Some(x.to_marionette())),
+        AddCookie(ref x) => (Some("addCookie"), Some(x.to_marionette())),
+        DismissAlert => (None, None), //Unsupported
+        AcceptAlert => (None, None), //Unsupported
+        GetAlertText => (None, None), //Unsupported
+        SendAlertText(ref x) => (None, None), //Unsupported
+        TakeScreenshot(ref x) => (Some("takeScreenshot"), Some(x.to_marionette())),
+        GetContext => (Some("getContext"), None),
+        SetContext(ref x) => (Some("setContext"), Some(x.to_marionette())),
+        InstallAddon(ref x) => (Some("addon:install"), Some(x.to_marionette())),
+        UninstallAddon(ref x) => (Some("addon:uninstall"), Some(x.to_marionette())),
+        Restart => (None, None), //Handled by the dispatcher
+        GetDriverLog => (None, None), //Handled by the worker
+        GetLogTypes => (None, None), //Handled by the connection
+        GetLog(_) => (None, None), //Handled by the connection
+        UploadFile(_) => (None, None), //Handled by the backend
+        TouchClick(ref x) => (Some("singleTap"), Some(x.element.to_marionette())),
+        TouchDown(_) | TouchUp(_) | TouchMove(_) | TouchScroll(_) | TouchFlick(_) |
+        TouchLongClick(_) => (None, None), //Handled by the connection
+        GetPref(_) | SetPref(_) | ResetPref(_) => (None, None), //Handled by the connection
+        PerformActions(ref x) => (Some("performActions"), Some(x.to_marionette())),
+        ReleaseActions => (Some("releaseActions"), None),
+        Status => (None, None), //Handled by the dispatcher
+        GetSessions => (None, None), //Handled by the dispatcher
+        Extension(ref x) => (Some(x.name[]), Some(Ok(x.parameters.clone()))),
+    };
 
-        let parameters = try!(opt_parameters.unwrap_or(Ok(Json::Object(TreeMap::new()))));
+    let name = try_opt!(opt_name,
+                        ErrorStatus::UnsupportedOperation,
+                        "Operation not supported");
+    let parameters = try!(opt_parameters.unwrap_or(Ok(Json::Object(TreeMap::new()))));
+    Ok((name, parameters))
+}
 
-        let mut data = TreeMap::new();
-        data.insert("name".to_string(), name.to_json());
-        data.insert("parameters".to_string(), parameters.to_json());
-        match self.session_id {
-            Some(ref x) => data.insert("sessionId".to_string(), x.to_json()),
-            None => None
-        };
-        Ok(json::Object(data))
-    }
+fn message_to_marionette(msg: &WebDriverMessage, session_id: Option<&str>,
+                         protocol_version: u64) -> WebDriverResult<Json> {
+    let (name, parameters) = try!(marionette_command(&msg.command, protocol_version));
+    let mut data = TreeMap::new();
+    data.insert("name".to_string(), name.to_json());
+    data.insert("parameters".to_string(), parameters);
+    match session_id {
+        Some(x) => data.insert("sessionId".to_string(), x.to_json()),
+        None => None
+    };
+    Ok(json::Object(data))
 }
 
 impl ToMarionette for GetParameters {
@@ -532,6 +1569,33 @@ impl ToMarionette for SwitchToFrameParameters {
     }
 }
 
+impl ToMarionette for GetElementAttributeParameters {
+    fn to_marionette(&self) -> WebDriverResult<Json> {
+        let mut data = TreeMap::new();
+        data.insert("id".to_string(), self.element.id.to_json());
+        data.insert("name".to_string(), self.name.to_json());
+        Ok(json::Object(data))
+    }
+}
+
+impl ToMarionette for GetCSSValueParameters {
+    fn to_marionette(&self) -> WebDriverResult<Json> {
+        let mut data = TreeMap::new();
+        data.insert("id".to_string(), self.element.id.to_json());
+        data.insert("name".to_string(), self.property.to_json());
+        Ok(json::Object(data))
+    }
+}
+
+impl ToMarionette for SendKeysParameters {
+    fn to_marionette(&self) -> WebDriverResult<Json> {
+        let mut data = TreeMap::new();
+        data.insert("id".to_string(), self.element.id.to_json());
+        data.insert("value".to_string(), self.value.to_json());
+        Ok(json::Object(data))
+    }
+}
+
 impl ToMarionette for JavascriptCommandParameters {
     fn to_marionette(&self) -> WebDriverResult<Json> {
         Ok(self.to_json())
@@ -563,6 +1627,9 @@ impl ToMarionette for AddCookieParameters {
         }
         cookie.insert("secure".to_string(), self.secure.to_json());
         cookie.insert("httpOnly".to_string(), self.httpOnly.to_json());
+        if self.sameSite.is_value() {
+            cookie.insert("sameSite".to_string(), self.sameSite.to_json());
+        }
         let mut data = TreeMap::new();
         data.insert("cookie".into_string(), Json::Object(cookie));
         Ok(json::Object(data))
@@ -572,15 +1639,57 @@ impl ToMarionette for AddCookieParameters {
 impl ToMarionette for TakeScreenshotParameters {
     fn to_marionette(&self) -> WebDriverResult<Json> {
         let mut data = TreeMap::new();
-        let element = match self.element {
-            Nullable::Null => Json::Null,
-            Nullable::Value(ref x) => try!(x.to_marionette())
+        let element = match self.element.as_ref() {
+            Some(x) => try!(x.to_marionette()),
+            None => Json::Null
         };
         data.insert("element".into_string(), element);
+        //Marionette captures the whole document by default, not just the viewport
+        data.insert("full".into_string(), self.full.to_json());
         Ok(Json::Object(data))
     }
 }
 
+impl ToMarionette for SetContextParameters {
+    fn to_marionette(&self) -> WebDriverResult<Json> {
+        let mut data = TreeMap::new();
+        data.insert("value".to_string(), self.context.to_json());
+        Ok(json::Object(data))
+    }
+}
+
+impl ToMarionette for InstallAddonParameters {
+    fn to_marionette(&self) -> WebDriverResult<Json> {
+        let path = match self.source {
+            AddonSource::Path(ref x) => x.clone(),
+            AddonSource::Data(ref x) => {
+                //Marionette can only install from a file. Temporary add-ons are read
+                //from it for as long as they are loaded, so it is left behind.
+                let path = try!(write_temp_file("wires-addon", "addon.xpi", x[]));
+                try_opt!(path.as_str(),
+                         ErrorStatus::UnknownError,
+                         "Add-on path is not valid UTF-8").to_string()
+            }
+        };
+        let mut data = TreeMap::new();
+        data.insert("path".to_string(), path.to_json());
+        data.insert("temporary".to_string(), self.temporary.to_json());
+        Ok(json::Object(data))
+    }
+}
+
+impl ToMarionette for UninstallAddonParameters {
+    fn to_marionette(&self) -> WebDriverResult<Json> {
+        Ok(self.to_json())
+    }
+}
+
+impl ToMarionette for ActionsParameters {
+    fn to_marionette(&self) -> WebDriverResult<Json> {
+        Ok(self.to_json())
+    }
+}
+
 impl ToMarionette for WebElement {
     fn to_marionette(&self) -> WebDriverResult<Json> {
         let mut data = TreeMap::new();
@@ -1,184 +1,523 @@
-use regex::{Regex, Captures};
+use serialize::json::{Json, ToJson};
+use std::collections::TreeMap;
 
 use hyper::method::{Method, Get, Post, Delete};
 
-use command::{WebDriverMessage};
-use common::{WebDriverResult, WebDriverError, ErrorStatus};
+use command::{WebDriverMessage, WebDriverCommand, ExtensionParser, element_param,
+              parse_parameters, parse_json_body, JavascriptCommandParameters,
+              GetElementAttributeParameters, GetCSSValueParameters, SendKeysParameters,
+              TakeScreenshotParameters, PrefParameters, SetPrefParameters};
+use common::{WebDriverResult, WebDriverError, ErrorStatus, Nullable};
+use params::get_string;
+
+// Declares every endpoint once, as (method, path, match type, parser). This
+// generates the MatchType enum, the route list used by get_builder, and
+// parse_command, which builds the command for a matched route.
+macro_rules! webdriver_routes {
+    ($(($method:ident, $path:expr, $match_type:ident, $parser:expr)),+) => {
+        #[deriving(Clone)]
+        pub enum MatchType {
+            $($match_type,)+
+            // Index into the extensions registered with the MessageBuilder
+            Extension(uint)
+        }
+
+        pub fn routes() -> Vec<(Method, &'static str, MatchType)> {
+            vec![$(($method, $path, MatchType::$match_type)),+]
+        }
+
+        pub fn parse_command(match_type: MatchType, params: &PathParams,
+                             body: &Json) -> WebDriverResult<WebDriverCommand> {
+            match match_type {
+                $(MatchType::$match_type => {
+                    let parser: |&PathParams, &Json| -> WebDriverResult<WebDriverCommand> = $parser;
+                    parser(params, body)
+                },)+
+                MatchType::Extension(_) => {
+                    Err(WebDriverError::new(ErrorStatus::UnknownError,
+                                            "Extension commands must be built by their parser"))
+                }
+            }
+        }
+    }
+}
+
+webdriver_routes! {
+    (Get, "/status", Status,
+     |_, _| Ok(WebDriverCommand::Status)),
+    (Get, "/sessions", GetSessions,
+     |_, _| Ok(WebDriverCommand::GetSessions)),
+    (Post, "/session", NewSession,
+     |_, body| Ok(WebDriverCommand::NewSession(try!(parse_parameters(body))))),
+    (Delete, "/session/{sessionId}", DeleteSession,
+     |_, _| Ok(WebDriverCommand::DeleteSession)),
+    (Post, "/session/{sessionId}/url", Get,
+     |_, body| Ok(WebDriverCommand::Get(try!(parse_parameters(body))))),
+    (Get, "/session/{sessionId}/url", GetCurrentUrl,
+     |_, _| Ok(WebDriverCommand::GetCurrentUrl)),
+    (Post, "/session/{sessionId}/back", GoBack,
+     |_, _| Ok(WebDriverCommand::GoBack)),
+    (Post, "/session/{sessionId}/forward", GoForward,
+     |_, _| Ok(WebDriverCommand::GoForward)),
+    (Post, "/session/{sessionId}/refresh", Refresh,
+     |_, _| Ok(WebDriverCommand::Refresh)),
+    (Get, "/session/{sessionId}/title", GetTitle,
+     |_, _| Ok(WebDriverCommand::GetTitle)),
+    (Get, "/session/{sessionId}/window", GetWindowHandle,
+     |_, _| Ok(WebDriverCommand::GetWindowHandle)),
+    (Get, "/session/{sessionId}/window/handles", GetWindowHandles,
+     |_, _| Ok(WebDriverCommand::GetWindowHandles)),
+    (Delete, "/session/{sessionId}/window", Close,
+     |_, _| Ok(WebDriverCommand::Close)),
+    (Post, "/session/{sessionId}/window/size", SetWindowSize,
+     |_, body| Ok(WebDriverCommand::SetWindowSize(try!(parse_parameters(body))))),
+    (Get, "/session/{sessionId}/window/size", GetWindowSize,
+     |_, _| Ok(WebDriverCommand::GetWindowSize)),
+    (Post, "/session/{sessionId}/window/rect", SetWindowRect,
+     |_, body| Ok(WebDriverCommand::SetWindowRect(try!(parse_parameters(body))))),
+    (Get, "/session/{sessionId}/window/rect", GetWindowRect,
+     |_, _| Ok(WebDriverCommand::GetWindowRect)),
+    (Post, "/session/{sessionId}/window/maximize", MaximizeWindow,
+     |_, _| Ok(WebDriverCommand::MaximizeWindow)),
+    (Post, "/session/{sessionId}/window", SwitchToWindow,
+     |_, body| Ok(WebDriverCommand::SwitchToWindow(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/frame", SwitchToFrame,
+     |_, body| Ok(WebDriverCommand::SwitchToFrame(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/frame/parent", SwitchToParentFrame,
+     |_, _| Ok(WebDriverCommand::SwitchToParentFrame)),
+    (Post, "/session/{sessionId}/element", FindElement,
+     |_, body| Ok(WebDriverCommand::FindElement(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/elements", FindElements,
+     |_, body| Ok(WebDriverCommand::FindElements(try!(parse_parameters(body))))),
+    (Get, "/session/{sessionId}/element/{elementId}/displayed", IsDisplayed,
+     |params, _| Ok(WebDriverCommand::IsDisplayed(element_param(params)))),
+    (Get, "/session/{sessionId}/element/{elementId}/selected", IsSelected,
+     |params, _| Ok(WebDriverCommand::IsSelected(element_param(params)))),
+    (Get, "/session/{sessionId}/element/{elementId}/attribute/{name}", GetElementAttribute,
+     |params, _| {
+         Ok(WebDriverCommand::GetElementAttribute(GetElementAttributeParameters::from_path(params)))
+     }),
+    (Get, "/session/{sessionId}/element/{elementId}/css/{propertyName}", GetCSSValue,
+     |params, _| Ok(WebDriverCommand::GetCSSValue(GetCSSValueParameters::from_path(params)))),
+    (Get, "/session/{sessionId}/element/{elementId}/text", GetElementText,
+     |params, _| Ok(WebDriverCommand::GetElementText(element_param(params)))),
+    (Get, "/session/{sessionId}/element/{elementId}/name", GetElementTagName,
+     |params, _| Ok(WebDriverCommand::GetElementTagName(element_param(params)))),
+    (Get, "/session/{sessionId}/element/{elementId}/rect", GetElementRect,
+     |params, _| Ok(WebDriverCommand::GetElementRect(element_param(params)))),
+    (Get, "/session/{sessionId}/element/{elementId}/enabled", IsEnabled,
+     |params, _| Ok(WebDriverCommand::IsEnabled(element_param(params)))),
+    (Post, "/session/{sessionId}/execute/sync", ExecuteScript,
+     |_, body| Ok(WebDriverCommand::ExecuteScript(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/execute/async", ExecuteAsyncScript,
+     |_, body| Ok(WebDriverCommand::ExecuteAsyncScript(try!(parse_parameters(body))))),
+    (Get, "/session/{sessionId}/cookie", GetCookie,
+     |_, body| Ok(WebDriverCommand::GetCookie(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/cookie", AddCookie,
+     |_, body| Ok(WebDriverCommand::AddCookie(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/timeouts", SetTimeouts,
+     |_, body| Ok(WebDriverCommand::SetTimeouts(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/actions", PerformActions,
+     |_, body| Ok(WebDriverCommand::PerformActions(try!(parse_parameters(body))))),
+    (Delete, "/session/{sessionId}/actions", ReleaseActions,
+     |_, _| Ok(WebDriverCommand::ReleaseActions)),
+    (Post, "/session/{sessionId}/element/{elementId}/click", ElementClick,
+     |params, _| Ok(WebDriverCommand::ElementClick(element_param(params)))),
+    (Post, "/session/{sessionId}/element/{elementId}/tap", ElementTap,
+     |params, _| Ok(WebDriverCommand::ElementTap(element_param(params)))),
+    (Post, "/session/{sessionId}/element/{elementId}/clear", ElementClear,
+     |params, _| Ok(WebDriverCommand::ElementClear(element_param(params)))),
+    (Post, "/session/{sessionId}/element/{elementId}/sendKeys", ElementSendKeys,
+     |params, body| {
+         Ok(WebDriverCommand::ElementSendKeys(try!(SendKeysParameters::from_http(params, body))))
+     }),
+    (Post, "/session/{sessionId}/alert/dismiss", DismissAlert,
+     |_, _| Ok(WebDriverCommand::DismissAlert)),
+    (Post, "/session/{sessionId}/alert/accept", AcceptAlert,
+     |_, _| Ok(WebDriverCommand::AcceptAlert)),
+    (Get, "/session/{sessionId}/alert/text", GetAlertText,
+     |_, _| Ok(WebDriverCommand::GetAlertText)),
+    (Post, "/session/{sessionId}/alert/text", SendAlertText,
+     |_, body| Ok(WebDriverCommand::SendAlertText(try!(parse_parameters(body))))),
+    (Get, "/session/{sessionId}/screenshot", TakeScreenshot,
+     |_, body| Ok(WebDriverCommand::TakeScreenshot(try!(parse_parameters(body))))),
+    (Get, "/session/{sessionId}/moz/screenshot/full", TakeFullScreenshot,
+     |_, _| Ok(WebDriverCommand::TakeScreenshot(TakeScreenshotParameters {
+         element: Nullable::Null,
+         full: true,
+         chrome: false
+     }))),
+    (Get, "/session/{sessionId}/moz/screenshot/chrome", TakeChromeScreenshot,
+     |_, _| Ok(WebDriverCommand::TakeScreenshot(TakeScreenshotParameters {
+         element: Nullable::Null,
+         full: false,
+         chrome: true
+     }))),
+    (Get, "/session/{sessionId}/moz/context", GetContext,
+     |_, _| Ok(WebDriverCommand::GetContext)),
+    (Post, "/session/{sessionId}/moz/context", SetContext,
+     |_, body| Ok(WebDriverCommand::SetContext(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/moz/addon/install", InstallAddon,
+     |_, body| Ok(WebDriverCommand::InstallAddon(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/moz/addon/uninstall", UninstallAddon,
+     |_, body| Ok(WebDriverCommand::UninstallAddon(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/moz/restart", Restart,
+     |_, _| Ok(WebDriverCommand::Restart)),
+    (Get, "/session/{sessionId}/moz/pref/{prefName}", GetPref,
+     |params, _| Ok(WebDriverCommand::GetPref(PrefParameters::from_path(params)))),
+    (Post, "/session/{sessionId}/moz/pref/{prefName}", SetPref,
+     |params, body| {
+         Ok(WebDriverCommand::SetPref(try!(SetPrefParameters::from_http(params, body))))
+     }),
+    (Delete, "/session/{sessionId}/moz/pref/{prefName}", ResetPref,
+     |params, _| Ok(WebDriverCommand::ResetPref(PrefParameters::from_path(params)))),
+    (Get, "/session/{sessionId}/moz/log", GetDriverLog,
+     |_, _| Ok(WebDriverCommand::GetDriverLog)),
+    (Get, "/session/{sessionId}/log/types", GetLogTypes,
+     |_, _| Ok(WebDriverCommand::GetLogTypes)),
+    (Post, "/session/{sessionId}/log", GetLog,
+     |_, body| Ok(WebDriverCommand::GetLog(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/file", UploadFile,
+     |_, body| Ok(WebDriverCommand::UploadFile(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/touch/click", TouchClick,
+     |_, body| Ok(WebDriverCommand::TouchClick(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/touch/down", TouchDown,
+     |_, body| Ok(WebDriverCommand::TouchDown(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/touch/up", TouchUp,
+     |_, body| Ok(WebDriverCommand::TouchUp(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/touch/move", TouchMove,
+     |_, body| Ok(WebDriverCommand::TouchMove(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/touch/scroll", TouchScroll,
+     |_, body| Ok(WebDriverCommand::TouchScroll(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/touch/flick", TouchFlick,
+     |_, body| Ok(WebDriverCommand::TouchFlick(try!(parse_parameters(body))))),
+    (Post, "/session/{sessionId}/touch/longclick", TouchLongClick,
+     |_, body| Ok(WebDriverCommand::TouchLongClick(try!(parse_parameters(body))))),
+    (Get, "/session/{sessionId}/local_storage", GetLocalStorageKeys,
+     |_, _| storage_keys("localStorage")),
+    (Post, "/session/{sessionId}/local_storage", SetLocalStorageItem,
+     |_, body| storage_set_item("localStorage", body)),
+    (Delete, "/session/{sessionId}/local_storage", ClearLocalStorage,
+     |_, _| storage_clear("localStorage")),
+    (Get, "/session/{sessionId}/local_storage/key/{key}", GetLocalStorageItem,
+     |params, _| storage_get_item("localStorage", params.name("key"))),
+    (Delete, "/session/{sessionId}/local_storage/key/{key}", RemoveLocalStorageItem,
+     |params, _| storage_remove_item("localStorage", params.name("key"))),
+    (Get, "/session/{sessionId}/local_storage/size", GetLocalStorageSize,
+     |_, _| storage_size("localStorage")),
+    (Get, "/session/{sessionId}/session_storage", GetSessionStorageKeys,
+     |_, _| storage_keys("sessionStorage")),
+    (Post, "/session/{sessionId}/session_storage", SetSessionStorageItem,
+     |_, body| storage_set_item("sessionStorage", body)),
+    (Delete, "/session/{sessionId}/session_storage", ClearSessionStorage,
+     |_, _| storage_clear("sessionStorage")),
+    (Get, "/session/{sessionId}/session_storage/key/{key}", GetSessionStorageItem,
+     |params, _| storage_get_item("sessionStorage", params.name("key"))),
+    (Delete, "/session/{sessionId}/session_storage/key/{key}", RemoveSessionStorageItem,
+     |params, _| storage_remove_item("sessionStorage", params.name("key"))),
+    (Get, "/session/{sessionId}/session_storage/size", GetSessionStorageSize,
+     |_, _| storage_size("sessionStorage"))
+}
+
+// The JSON wire protocol's local_storage and session_storage endpoints are run as
+// scripts in the page, which see the storage object as storage
+fn storage_script(storage: &str, body: &str,
+                  args: Vec<Json>) -> WebDriverResult<WebDriverCommand> {
+    let script = format!("var storage = window.{}; {}", storage, body);
+    Ok(WebDriverCommand::ExecuteScript(JavascriptCommandParameters::new(script, args)))
+}
+
+fn storage_keys(storage: &str) -> WebDriverResult<WebDriverCommand> {
+    storage_script(storage, "var keys = []; \
+                             for (var i = 0; i < storage.length; i++) { \
+                                 keys.push(storage.key(i)); \
+                             } \
+                             return keys;", vec![])
+}
+
+fn storage_set_item(storage: &str, body: &Json) -> WebDriverResult<WebDriverCommand> {
+    let data = try_opt!(body.as_object(),
+                        ErrorStatus::InvalidArgument,
+                        "Message body was not an object");
+    let key = try!(get_string(data, "", "key"));
+    let value = try!(get_string(data, "", "value"));
+    storage_script(storage, "storage.setItem(arguments[0], arguments[1]);",
+                   vec![key.to_json(), value.to_json()])
+}
+
+fn storage_clear(storage: &str) -> WebDriverResult<WebDriverCommand> {
+    storage_script(storage, "storage.clear();", vec![])
+}
+
+fn storage_get_item(storage: &str, key: &str) -> WebDriverResult<WebDriverCommand> {
+    storage_script(storage, "return storage.getItem(arguments[0]);", vec![key.to_json()])
+}
+
+// Answers with the value that was removed, as Selenium's own drivers do
+fn storage_remove_item(storage: &str, key: &str) -> WebDriverResult<WebDriverCommand> {
+    storage_script(storage, "var value = storage.getItem(arguments[0]); \
+                             storage.removeItem(arguments[0]); \
+                             return value;", vec![key.to_json()])
+}
+
+fn storage_size(storage: &str) -> WebDriverResult<WebDriverCommand> {
+    storage_script(storage, "return storage.length;", vec![])
+}
+
+// Spellings of endpoints from the JSON wire protocol that older Selenium clients still
+// use, handled the same as the routes they stand in for. Commands only ever apply to
+// the current window, so a {windowHandle} has to be that one, or "current".
+pub fn aliases() -> Vec<(Method, &'static str, MatchType)> {
+    vec![(Get, "/session/{sessionId}/window_handle", MatchType::GetWindowHandle),
+         (Get, "/session/{sessionId}/window_handles", MatchType::GetWindowHandles),
+         (Delete, "/session/{sessionId}/window_handle", MatchType::Close),
+         (Post, "/session/{sessionId}/execute", MatchType::ExecuteScript),
+         (Post, "/session/{sessionId}/execute_async", MatchType::ExecuteAsyncScript),
+         (Post, "/session/{sessionId}/dismiss_alert", MatchType::DismissAlert),
+         (Post, "/session/{sessionId}/accept_alert", MatchType::AcceptAlert),
+         (Get, "/session/{sessionId}/alert_text", MatchType::GetAlertText),
+         (Post, "/session/{sessionId}/alert_text", MatchType::SendAlertText),
+         (Post, "/session/{sessionId}/element/{elementId}/value", MatchType::ElementSendKeys),
+         (Get, "/session/{sessionId}/window/{windowHandle}/size", MatchType::GetWindowSize),
+         (Post, "/session/{sessionId}/window/{windowHandle}/size", MatchType::SetWindowSize),
+         (Post, "/session/{sessionId}/window/{windowHandle}/maximize", MatchType::MaximizeWindow)]
+}
+
+// Selenium grids serve everything under this, so clients pointed at a grid use it
+static HUB_PREFIX: &'static str = "/wd/hub";
+
+fn strip_hub_prefix(path: &str) -> &str {
+    if path.starts_with(HUB_PREFIX) && (path.len() == HUB_PREFIX.len() ||
+                                        path[HUB_PREFIX.len()..].starts_with("/")) {
+        path[HUB_PREFIX.len()..]
+    } else {
+        path
+    }
+}
+
+// Parameters taken from {placeholders} in the path, and from the query string
+pub struct PathParams {
+    values: TreeMap<String, String>,
+    query: TreeMap<String, String>
+}
+
+impl PathParams {
+    // The empty string if the route has no such parameter
+    pub fn name(&self, name: &str) -> &str {
+        match self.values.get(name) {
+            Some(x) => x[],
+            None => ""
+        }
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(|x| x[])
+    }
+
+    fn decode(self) -> WebDriverResult<PathParams> {
+        let mut values = TreeMap::new();
+        for (key, value) in self.values.into_iter() {
+            values.insert(key, try!(percent_decode(value[])));
+        }
+        Ok(PathParams {
+            values: values,
+            query: self.query
+        })
+    }
+}
+
+// Splits the request target into the path, without any hub prefix, and the (possibly
+// empty) query string
+fn split_query(target: &str) -> (&str, &str) {
+    let (path, query) = match target.find('?') {
+        Some(index) => (target[..index], target[index + 1..]),
+        None => (target, "")
+    };
+    (strip_hub_prefix(path), query)
+}
+
+fn parse_query(query: &str) -> WebDriverResult<TreeMap<String, String>> {
+    let mut rv = TreeMap::new();
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(index) => (pair[..index], pair[index + 1..]),
+            None => (pair, "")
+        };
+        rv.insert(try!(percent_decode(key.replace("+", " ")[])),
+                  try!(percent_decode(value.replace("+", " ")[])));
+    }
+    Ok(rv)
+}
+
+fn percent_decode(input: &str) -> WebDriverResult<String> {
+    let mut bytes = vec![];
+    let mut iter = input.bytes();
+    loop {
+        match iter.next() {
+            Some(b'%') => {
+                let high = iter.next().and_then(|x| (x as char).to_digit(16));
+                let low = iter.next().and_then(|x| (x as char).to_digit(16));
+                match (high, low) {
+                    (Some(high), Some(low)) => bytes.push((high * 16 + low) as u8),
+                    _ => return Err(wd_err!(ErrorStatus::InvalidArgument,
+                                            "Invalid percent-encoding in path segment {}", input))
+                }
+            },
+            Some(x) => bytes.push(x),
+            None => break
+        }
+    }
+    String::from_utf8(bytes).map_err(
+        |_| wd_err!(ErrorStatus::InvalidArgument,
+                    "Path segment {} is not valid UTF-8 once decoded", input))
+}
 
 #[deriving(Clone)]
-pub enum MatchType {
-    NewSession,
-    DeleteSession,
-    Get,
-    GetCurrentUrl,
-    GoBack,
-    GoForward,
-    Refresh,
-    GetTitle,
-    GetWindowHandle,
-    GetWindowHandles,
-    Close,
-    SetWindowSize,
-    GetWindowSize,
-    MaximizeWindow,
-    SwitchToWindow,
-    SwitchToFrame,
-    SwitchToParentFrame,
-    FindElement,
-    FindElements,
-    IsDisplayed,
-    IsSelected,
-    GetElementAttribute,
-    GetCSSValue,
-    GetElementText,
-    GetElementTagName,
-    GetElementRect,
-    IsEnabled,
-    ExecuteScript,
-    ExecuteAsyncScript,
-    GetCookie,
-    AddCookie,
-    SetTimeouts,
-    //Actions XXX - once I understand the spec, perhaps
-    ElementClick,
-    ElementTap,
-    ElementClear,
-    ElementSendKeys,
-    DismissAlert,
-    AcceptAlert,
-    GetAlertText,
-    SendAlertText,
-    TakeScreenshot
+enum Segment {
+    Literal(String),
+    Param(String)
 }
 
 #[deriving(Clone)]
 pub struct RequestMatcher {
     method: Method,
-    path_regexp: Regex,
+    segments: Vec<Segment>,
     match_type: MatchType
 }
 
 impl RequestMatcher {
     pub fn new(method: Method, path: &str, match_type: MatchType) -> RequestMatcher {
-        let path_regexp = RequestMatcher::compile_path(path);
         RequestMatcher {
             method: method,
-            path_regexp: path_regexp,
+            segments: RequestMatcher::compile_path(path),
             match_type: match_type
         }
     }
 
-    pub fn get_match<'t>(&'t self, method: Method, path: &'t str) -> (bool, Option<Captures>) {
-        let captures = self.path_regexp.captures(path);
-        (method == self.method, captures)
+    // None if the path doesn't match, otherwise whether the method does as well
+    pub fn get_match(&self, method: &Method, path: &str) -> Option<(bool, PathParams)> {
+        let mut values = TreeMap::new();
+        // Some clients add a trailing slash, which we treat as insignificant
+        let path = if path.len() > 1 && path.ends_with("/") {
+            path[..path.len() - 1]
+        } else {
+            path
+        };
+        let mut parts = path.split('/');
+        for segment in self.segments.iter() {
+            let part = match parts.next() {
+                Some(x) => x,
+                None => return None
+            };
+            match *segment {
+                Segment::Literal(ref x) if x[] == part => {},
+                Segment::Literal(_) => return None,
+                Segment::Param(_) if part.is_empty() => return None,
+                Segment::Param(ref name) => {
+                    values.insert(name.clone(), part.to_string());
+                }
+            }
+        }
+        if parts.next().is_some() {
+            return None
+        }
+        Some((*method == self.method, PathParams {
+            values: values,
+            query: TreeMap::new()
+        }))
     }
 
-    fn compile_path(path: &str) -> Regex {
-        let mut rv = String::new();
-        rv.push_str("^");
-        let mut components = path.split('/');
-        for component in components {
+    fn compile_path(path: &str) -> Vec<Segment> {
+        path.split('/').map(|component| {
             if component.starts_with("{") {
                 if !component.ends_with("}") {
                     panic!("Invalid url pattern")
                 }
-                rv.push_str(format!("(?P<{}>[^/]+)/", component[1..component.len()-1])[]);
+                Segment::Param(component[1..component.len()-1].to_string())
             } else {
-                rv.push_str(format!("{}/", component)[]);
+                Segment::Literal(component.to_string())
             }
-        }
-        //Remove the trailing /
-        rv.pop();
-        rv.push_str("$");
-        //This will fail at runtime if the regexp is invalid
-        Regex::new(rv[]).unwrap()
+        }).collect()
     }
 }
 
+struct ExtensionRoute {
+    name: String,
+    parser: ExtensionParser
+}
+
 pub struct MessageBuilder {
-    http_matchers: Vec<(Method, RequestMatcher)>
+    http_matchers: Vec<(Method, RequestMatcher)>,
+    extensions: Vec<ExtensionRoute>
 }
 
 impl MessageBuilder {
     pub fn new() -> MessageBuilder {
         MessageBuilder {
-            http_matchers: vec![]
+            http_matchers: vec![],
+            extensions: vec![]
         }
     }
 
-    pub fn from_http(&self, method: Method, path: &str, body: &str) -> WebDriverResult<WebDriverMessage> {
+    pub fn from_http(&self, method: Method, target: &str, body: &str) -> WebDriverResult<WebDriverMessage> {
+        let (path, query) = split_query(target);
         let mut error = ErrorStatus::UnknownPath;
-        for &(ref match_method, ref matcher) in self.http_matchers.iter() {
-            if method == *match_method {
-                let (method_match, captures) = matcher.get_match(method.clone(), path);
-                if captures.is_some() {
-                    if method_match {
-                        return WebDriverMessage::from_http(matcher.match_type,
-                                                           &captures.unwrap(),
-                                                           body)
-                    } else {
-                        error = ErrorStatus::UnknownMethod;
+        for &(_, ref matcher) in self.http_matchers.iter() {
+            match matcher.get_match(&method, path) {
+                Some((true, mut params)) => {
+                    params.query = try!(parse_query(query));
+                    let params = try!(params.decode());
+                    let body = try!(parse_json_body(body));
+                    return match matcher.match_type {
+                        MatchType::Extension(index) => {
+                            let extension = &self.extensions[index];
+                            WebDriverMessage::from_extension(extension.name[], extension.parser,
+                                                             &params, &body)
+                        },
+                        match_type => WebDriverMessage::from_http(match_type, &params, &body)
                     }
-                }
+                },
+                Some((false, _)) => error = ErrorStatus::UnknownMethod,
+                None => {}
             }
         }
         Err(WebDriverError::new(error,
-                                format!("{} {} did not match a known command", method, path)[]))
+                                format!("{} {} did not match a known command", method, path)))
+    }
+
+    // For the Allow header when a path is requested with the wrong method
+    pub fn allowed_methods(&self, target: &str) -> Vec<Method> {
+        let (path, _) = split_query(target);
+        let mut rv = vec![];
+        for &(ref method, ref matcher) in self.http_matchers.iter() {
+            if matcher.get_match(method, path).is_some() && !rv.contains(method) {
+                rv.push(method.clone());
+            }
+        }
+        rv
     }
 
     pub fn add(&mut self, method: Method, path: &str, match_type: MatchType) {
         let http_matcher = RequestMatcher::new(method.clone(), path, match_type);
         self.http_matchers.push((method, http_matcher));
     }
+
+    // Register a vendor-specific route; name is the command sent to the browser
+    pub fn add_extension(&mut self, method: Method, path: &str, name: &str,
+                         parser: ExtensionParser) {
+        let index = self.extensions.len();
+        self.extensions.push(ExtensionRoute {
+            name: name.to_string(),
+            parser: parser
+        });
+        self.add(method, path, MatchType::Extension(index));
+    }
 }
 
 pub fn get_builder() -> MessageBuilder {
     let mut builder = MessageBuilder::new();
-    let matchers = vec![(Post, "/session", MatchType::NewSession),
-                        (Delete, "/session/{sessionId}", MatchType::DeleteSession),
-                        (Post, "/session/{sessionId}/url", MatchType::Get),
-                        (Get, "/session/{sessionId}/url", MatchType::GetCurrentUrl),
-                        (Post, "/session/{sessionId}/back", MatchType::GoBack),
-                        (Post, "/session/{sessionId}/forward", MatchType::GoForward),
-                        (Post, "/session/{sessionId}/refresh", MatchType::Refresh),
-                        (Get, "/session/{sessionId}/title", MatchType::GetTitle),
-                        (Get, "/session/{sessionId}/window_handle", MatchType::GetWindowHandle),
-                        (Get, "/session/{sessionId}/window_handles", MatchType::GetWindowHandles),
-                        (Delete, "/session/{sessionId}/window_handle", MatchType::Close),
-                        (Post, "/session/{sessionId}/window/size", MatchType::SetWindowSize),
-                        (Get, "/session/{sessionId}/window/size", MatchType::GetWindowSize),
-                        (Post, "/session/{sessionId}/window/maximize", MatchType::MaximizeWindow),
-                        (Post, "/session/{sessionId}/window", MatchType::SwitchToWindow),
-                        (Post, "/session/{sessionId}/frame", MatchType::SwitchToFrame),
-                        (Post, "/session/{sessionId}/frame/parent", MatchType::SwitchToParentFrame),
-                        (Post, "/session/{sessionId}/element", MatchType::FindElement),
-                        (Post, "/session/{sessionId}/elements", MatchType::FindElements),
-                        (Get, "/session/{sessionId}/element/{elementId}/displayed", MatchType::IsDisplayed),
-                        (Get, "/session/{sessionId}/element/{elementId}/selected", MatchType::IsSelected),
-                        (Get, "/session/{sessionId}/element/{elementId}/attribute/{name}", MatchType::GetElementAttribute),
-                        (Get, "/session/{sessionId}/element/{elementId}/css/{propertyName}", MatchType::GetCSSValue),
-                        (Get, "/session/{sessionId}/element/{elementId}/text", MatchType::GetElementText),
-                        (Get, "/session/{sessionId}/element/{elementId}/name", MatchType::GetElementTagName),
-                        (Get, "/session/{sessionId}/element/{elementId}/rect", MatchType::GetElementRect),
-                        (Get, "/session/{sessionId}/element/{elementId}/enabled", MatchType::IsEnabled),
-                        (Post, "/session/{sessionId}/execute", MatchType::ExecuteScript),
-                        (Post, "/session/{sessionId}/execute_async", MatchType::ExecuteAsyncScript),
-                        (Get, "/session/{sessionId}/cookie", MatchType::GetCookie),
-                        (Post, "/session/{sessionId}/cookie", MatchType::AddCookie),
-                        (Post, "/session/{sessionId}/timeouts", MatchType::SetTimeouts),
-                        //(Post, "/session/{sessionId}/actions", MatchType::Actions),
-                        (Post, "/session/{sessionId}/element/{elementId}/click", MatchType::ElementClick),
-                        (Post, "/session/{sessionId}/element/{elementId}/tap", MatchType::ElementTap),
-                        (Post, "/session/{sessionId}/element/{elementId}/clear", MatchType::ElementClear),
-                        (Post, "/session/{sessionId}/element/{elementId}/sendKeys", MatchType::ElementSendKeys),
-                        (Post, "/session/{sessionId}/dismiss_alert", MatchType::DismissAlert),
-                        (Post, "/session/{sessionId}/accept_alert", MatchType::AcceptAlert),
-                        (Get, "/session/{sessionId}/alert_text", MatchType::GetAlertText),
-                        (Post, "/session/{sessionId}/alert_text", MatchType::SendAlertText),
-                        (Get, "/session/{sessionId}/screenshot", MatchType::TakeScreenshot)
-                        ];
     debug!("Creating routes");
-    for &(ref method, ref url, ref match_type) in matchers.iter() {
+    for &(ref method, ref url, ref match_type) in routes().iter().chain(aliases().iter()) {
         builder.add(method.clone(), *url, *match_type);
     }
     builder
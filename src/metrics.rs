@@ -0,0 +1,165 @@
+use std::collections::TreeMap;
+use std::mem;
+use std::sync::{Mutex, Once, ONCE_INIT};
+
+// Counters, gauges and histograms for the whole process, served in Prometheus's text
+// format. Series are keyed by their metric name and their rendered labels.
+static mut REGISTRY: *const Mutex<Registry> = 0 as *const Mutex<Registry>;
+static REGISTRY_INIT: Once = ONCE_INIT;
+//Upper bounds of the histogram buckets, in ms
+static BUCKETS: [u64, ..12] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+struct Histogram {
+    //Observations in each bucket, not yet cumulative; the last one is +Inf
+    counts: Vec<u64>,
+    sum: u64,
+    count: u64
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: Vec::from_elem(BUCKETS.len() + 1, 0u64),
+            sum: 0,
+            count: 0
+        }
+    }
+
+    fn observe(&mut self, value: u64) {
+        let index = BUCKETS.iter().position(|x| value <= *x).unwrap_or(BUCKETS.len());
+        self.counts[index] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+struct Registry {
+    counters: TreeMap<String, TreeMap<String, u64>>,
+    gauges: TreeMap<String, TreeMap<String, i64>>,
+    histograms: TreeMap<String, TreeMap<String, Histogram>>
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    unsafe {
+        REGISTRY_INIT.doit(|| {
+            REGISTRY = mem::transmute(box Mutex::new(Registry {
+                counters: TreeMap::new(),
+                gauges: TreeMap::new(),
+                histograms: TreeMap::new()
+            }));
+        });
+        &*REGISTRY
+    }
+}
+
+pub fn increment(name: &str, labels: &[(&str, &str)]) {
+    let mut registry = registry().lock();
+    let series = entry(&mut registry.counters, name);
+    let key = render_labels(labels);
+    let value = series.get(&key).map(|x| *x).unwrap_or(0);
+    series.insert(key, value + 1);
+}
+
+pub fn set_gauge(name: &str, labels: &[(&str, &str)], value: i64) {
+    let mut registry = registry().lock();
+    entry(&mut registry.gauges, name).insert(render_labels(labels), value);
+}
+
+pub fn observe(name: &str, labels: &[(&str, &str)], value_ms: u64) {
+    let mut registry = registry().lock();
+    let series = entry(&mut registry.histograms, name);
+    let key = render_labels(labels);
+    if !series.contains_key(&key) {
+        series.insert(key.clone(), Histogram::new());
+    }
+    series.get_mut(&key).unwrap().observe(value_ms);
+}
+
+// Records a request the server answered, with the command it was parsed as
+pub fn record_request(command: &str, status: int, duration_ms: u64) {
+    let status = status.to_string();
+    increment("webdriver_requests_total", &[("command", command), ("status", status[])]);
+    observe("webdriver_request_duration_milliseconds", &[("command", command)], duration_ms);
+}
+
+// Records an error returned to a client, by its WebDriver error code
+pub fn record_error(error: &str) {
+    increment("webdriver_errors_total", &[("error", error)]);
+}
+
+pub fn render() -> String {
+    let registry = registry().lock();
+    let mut rv = String::new();
+    for (name, series) in registry.counters.iter() {
+        rv.push_str(format!("# TYPE {} counter\n", name)[]);
+        for (labels, value) in series.iter() {
+            rv.push_str(format!("{}{} {}\n", name, braced(labels[]), value)[]);
+        }
+    }
+    for (name, series) in registry.gauges.iter() {
+        rv.push_str(format!("# TYPE {} gauge\n", name)[]);
+        for (labels, value) in series.iter() {
+            rv.push_str(format!("{}{} {}\n", name, braced(labels[]), value)[]);
+        }
+    }
+    for (name, series) in registry.histograms.iter() {
+        rv.push_str(format!("# TYPE {} histogram\n", name)[]);
+        for (labels, histogram) in series.iter() {
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += *count;
+                let bound = match BUCKETS.get(i) {
+                    Some(x) => x.to_string(),
+                    None => "+Inf".to_string()
+                };
+                let le = format!("le=\"{}\"", bound);
+                let bucket_labels = if labels.is_empty() {
+                    le
+                } else {
+                    format!("{},{}", labels, le)
+                };
+                rv.push_str(format!("{}_bucket{{{}}} {}\n", name, bucket_labels, cumulative)[]);
+            }
+            rv.push_str(format!("{}_sum{} {}\n", name, braced(labels[]), histogram.sum)[]);
+            rv.push_str(format!("{}_count{} {}\n", name, braced(labels[]), histogram.count)[]);
+        }
+    }
+    rv
+}
+
+fn entry<'a, T>(map: &'a mut TreeMap<String, TreeMap<String, T>>,
+                name: &str) -> &'a mut TreeMap<String, T> {
+    let name = name.to_string();
+    if !map.contains_key(&name) {
+        map.insert(name.clone(), TreeMap::new());
+    }
+    map.get_mut(&name).unwrap()
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = labels.iter().map(|&(name, value)| {
+        format!("{}=\"{}\"", name, escape(value))
+    }).collect();
+    labels.connect(",")
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        "".to_string()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut rv = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => rv.push_str("\\\\"),
+            '"' => rv.push_str("\\\""),
+            '\n' => rv.push_str("\\n"),
+            _ => rv.push(c)
+        }
+    }
+    rv
+}
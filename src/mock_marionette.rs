@@ -0,0 +1,90 @@
+use serialize::json;
+use serialize::json::{Json, ToJson};
+use std::collections::TreeMap;
+use std::io::{Acceptor, Listener, TcpListener, TcpStream};
+
+use marionette::{MarionetteCodec, MAX_PROTOCOL_VERSION};
+
+// What the mock answers a command with
+#[deriving(Clone)]
+pub enum MockResponse {
+    Value(Json),
+    //Error name and message
+    Error(String, String)
+}
+
+// Stands in for a browser's marionette server. It sends the handshake, then answers
+// commands from a table of canned responses, and {} for anything not in the table.
+pub struct MockMarionette {
+    pub port: u16,
+    //Name and parameters of each command received, in order
+    received: Receiver<(String, Json)>
+}
+
+impl MockMarionette {
+    pub fn start(responses: TreeMap<String, MockResponse>) -> MockMarionette {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut acceptor = listener.listen().unwrap();
+        let (send_received, received) = channel();
+        spawn(proc() {
+            for stream in acceptor.incoming() {
+                match stream {
+                    Ok(stream) => serve(stream, &responses, &send_received),
+                    Err(_) => break
+                }
+            }
+        });
+        MockMarionette {
+            port: port,
+            received: received
+        }
+    }
+
+    // The names of the commands received so far
+    pub fn commands(&self) -> Vec<String> {
+        let mut rv = vec![];
+        loop {
+            match self.received.try_recv() {
+                Ok((name, _)) => rv.push(name),
+                Err(_) => return rv
+            }
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, responses: &TreeMap<String, MockResponse>,
+         received: &Sender<(String, Json)>) {
+    let codec = MarionetteCodec::new(1024 * 1024);
+    let hello = format!("{{\"applicationType\": \"gecko\", \"marionetteProtocol\": {}}}",
+                        MAX_PROTOCOL_VERSION);
+    if stream.write_str(codec.encode(hello[])[]).is_err() {
+        return
+    }
+    loop {
+        let data = match codec.decode(&mut stream) {
+            Ok(x) => x,
+            Err(_) => return
+        };
+        let message = json::from_str(data[]).unwrap();
+        let message = message.as_array().unwrap();
+        let id = message[1].clone();
+        let name = message[2].as_string().unwrap().to_string();
+        let _ = received.send_opt((name.clone(), message[3].clone()));
+        let (error, result) = match responses.get(&name) {
+            Some(&MockResponse::Value(ref x)) => (Json::Null, x.clone()),
+            Some(&MockResponse::Error(ref error, ref message)) => {
+                let mut data = TreeMap::new();
+                data.insert("error".to_string(), error.to_json());
+                data.insert("message".to_string(), message.to_json());
+                data.insert("stacktrace".to_string(), "".to_json());
+                (Json::Object(data), Json::Null)
+            },
+            None => (Json::Null, Json::Object(TreeMap::new()))
+        };
+        let resp = vec![1u64.to_json(), id, error, result].to_json();
+        if stream.write_str(codec.encode(json::encode(&resp)[])[]).is_err() {
+            return
+        }
+    }
+}
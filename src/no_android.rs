@@ -0,0 +1,60 @@
+use serialize::json::Json;
+use std::collections::TreeMap;
+use std::io::process::ProcessExit;
+use std::io::IoResult;
+
+use webdriver::common::{WebDriverResult, WebDriverError, ErrorStatus};
+use firefox::Runner;
+use profile::Profile;
+
+// Stands in for the android module in builds without the android feature, so that
+// sessions asking for a device fail cleanly.
+#[deriving(PartialEq, Show)]
+pub struct AndroidOptions {
+    pub package: String
+}
+
+impl AndroidOptions {
+    pub fn from_json(_path: &str,
+                     options: &TreeMap<String, Json>) -> WebDriverResult<Option<AndroidOptions>> {
+        match options.get("androidPackage") {
+            Some(_) => Err(WebDriverError::new(ErrorStatus::SessionNotCreated,
+                                               "Android is not supported by this build")),
+            None => Ok(None)
+        }
+    }
+}
+
+pub struct AndroidRunner;
+
+impl AndroidRunner {
+    pub fn start(_: &AndroidOptions, _: Profile, _: &[(String, String)],
+                 _: u16, _: u16) -> WebDriverResult<AndroidRunner> {
+        Err(WebDriverError::new(ErrorStatus::SessionNotCreated,
+                                "Android is not supported by this build"))
+    }
+}
+
+impl Runner for AndroidRunner {
+    fn set_session_id(&self, _: &str) {}
+
+    fn exit_status(&mut self) -> Option<ProcessExit> {
+        None
+    }
+
+    fn stop(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn wait_for_restart(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn collect_minidumps(&mut self) -> Vec<Path> {
+        vec![]
+    }
+
+    fn recent_output(&self) -> Vec<String> {
+        vec![]
+    }
+}
@@ -0,0 +1,117 @@
+use serialize::json::Json;
+use std::collections::TreeMap;
+
+use common::{WebDriverResult, WebDriverError, ErrorStatus};
+
+// Helpers for picking parameters out of command bodies. Each takes the JSON path of
+// the value it checks, e.g. capabilities.alwaysMatch.proxy, so errors can say exactly
+// which parameter was wrong, what it should have been and what it was instead.
+
+// The path of a member of the object at path, which is empty for the top level
+pub fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+pub fn index_path(path: &str, index: uint) -> String {
+    format!("{}[{}]", path, index)
+}
+
+pub fn type_name(value: &Json) -> &'static str {
+    match *value {
+        Json::Null => "null",
+        Json::Boolean(_) => "a boolean",
+        Json::I64(_) | Json::U64(_) | Json::F64(_) => "a number",
+        Json::String(_) => "a string",
+        Json::Array(_) => "an array",
+        Json::Object(_) => "an object"
+    }
+}
+
+pub fn invalid(path: &str, expected: &str, value: &Json) -> WebDriverError {
+    wd_err!(ErrorStatus::InvalidArgument, "Invalid parameter {}: expected {}, got {}",
+            path, expected, type_name(value))
+}
+
+pub fn get_value<'a>(obj: &'a TreeMap<String, Json>, path: &str,
+                     key: &str) -> WebDriverResult<&'a Json> {
+    match obj.get(key) {
+        Some(x) => Ok(x),
+        None => Err(wd_err!(ErrorStatus::InvalidArgument, "Missing parameter {}",
+                            join_path(path, key)))
+    }
+}
+
+pub fn as_object<'a>(path: &str, value: &'a Json) -> WebDriverResult<&'a TreeMap<String, Json>> {
+    match value.as_object() {
+        Some(x) => Ok(x),
+        None => Err(invalid(path, "an object", value))
+    }
+}
+
+pub fn as_array<'a>(path: &str, value: &'a Json) -> WebDriverResult<&'a Vec<Json>> {
+    match value.as_array() {
+        Some(x) => Ok(x),
+        None => Err(invalid(path, "an array", value))
+    }
+}
+
+pub fn as_string<'a>(path: &str, value: &'a Json) -> WebDriverResult<&'a str> {
+    match value.as_string() {
+        Some(x) => Ok(x),
+        None => Err(invalid(path, "a string", value))
+    }
+}
+
+pub fn as_boolean(path: &str, value: &Json) -> WebDriverResult<bool> {
+    match value.as_boolean() {
+        Some(x) => Ok(x),
+        None => Err(invalid(path, "a boolean", value))
+    }
+}
+
+pub fn as_string_array(path: &str, value: &Json) -> WebDriverResult<Vec<String>> {
+    let mut rv = vec![];
+    for (i, item) in try!(as_array(path, value)).iter().enumerate() {
+        rv.push(try!(as_string(index_path(path, i)[], item)).to_string());
+    }
+    Ok(rv)
+}
+
+pub fn as_u64_in_range(path: &str, value: &Json, min: u64, max: u64) -> WebDriverResult<u64> {
+    match value.as_u64() {
+        Some(x) if x >= min && x <= max => Ok(x),
+        Some(x) => Err(wd_err!(ErrorStatus::InvalidArgument,
+                               "Invalid parameter {}: expected an integer between {} and {}, \
+                                got {}", path, min, max, x)),
+        None => Err(invalid(path, format!("an integer between {} and {}", min, max)[], value))
+    }
+}
+
+pub fn as_i64_in_range(path: &str, value: &Json, min: i64, max: i64) -> WebDriverResult<i64> {
+    match value.as_i64() {
+        Some(x) if x >= min && x <= max => Ok(x),
+        Some(x) => Err(wd_err!(ErrorStatus::InvalidArgument,
+                               "Invalid parameter {}: expected an integer between {} and {}, \
+                                got {}", path, min, max, x)),
+        None => Err(invalid(path, format!("an integer between {} and {}", min, max)[], value))
+    }
+}
+
+pub fn get_object<'a>(obj: &'a TreeMap<String, Json>, path: &str,
+                      key: &str) -> WebDriverResult<&'a TreeMap<String, Json>> {
+    as_object(join_path(path, key)[], try!(get_value(obj, path, key)))
+}
+
+pub fn get_string<'a>(obj: &'a TreeMap<String, Json>, path: &str,
+                      key: &str) -> WebDriverResult<&'a str> {
+    as_string(join_path(path, key)[], try!(get_value(obj, path, key)))
+}
+
+pub fn get_u64_in_range(obj: &TreeMap<String, Json>, path: &str, key: &str,
+                        min: u64, max: u64) -> WebDriverResult<u64> {
+    as_u64_in_range(join_path(path, key)[], try!(get_value(obj, path, key)), min, max)
+}
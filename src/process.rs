@@ -0,0 +1,280 @@
+use libc::pid_t;
+use std::io::process::{Command, Process, ProcessExit};
+use std::io::{IoResult, IoErrorKind};
+use std::io::timer::sleep;
+use std::time::Duration;
+
+use self::imp::Group;
+
+// A browser is more than the process wires starts: it has content processes, plugin
+// processes, and after a restart a whole new copy of itself that isn't our child. So
+// the child is put in a group that takes all of those with it, its own process group on
+// unix and a job object on windows, and it is the group that gets killed.
+//
+// std reaps the child through its own SIGCHLD handling, but only once something waits
+// for it, so every way of getting rid of a child here ends in a wait; that is what
+// keeps exited browsers from being left around as zombies.
+pub struct Child {
+    process: Process,
+    group: Group,
+    //The exit status, once the child has been waited for
+    status: Option<ProcessExit>
+}
+
+impl Child {
+    pub fn spawn(command: &mut Command) -> IoResult<Child> {
+        //On unix this makes the child the leader of a new process group
+        command.detached();
+        let process = try!(command.spawn());
+        let group = Group::new(&process);
+        Ok(Child {
+            process: process,
+            group: group,
+            status: None
+        })
+    }
+
+    pub fn id(&self) -> pid_t {
+        self.process.id()
+    }
+
+    pub fn process(&mut self) -> &mut Process {
+        &mut self.process
+    }
+
+    // The exit status, without waiting if the child is still running
+    pub fn try_wait(&mut self) -> Option<ProcessExit> {
+        match self.wait_timeout(Some(0)) {
+            Ok(x) => Some(x),
+            Err(_) => None
+        }
+    }
+
+    // Waits for the child itself to exit; with a timeout, fails with TimedOut if it
+    // is still running when that is up
+    pub fn wait_timeout(&mut self, timeout: Option<u64>) -> IoResult<ProcessExit> {
+        match self.status {
+            Some(ref x) => return Ok(x.clone()),
+            None => {}
+        }
+        self.process.set_timeout(timeout);
+        let status = self.process.wait();
+        self.process.set_timeout(None);
+        let status = try!(status);
+        self.status = Some(status.clone());
+        Ok(status)
+    }
+
+    // Whether anything in the group is still running, which it can be after the child
+    // itself has exited
+    pub fn group_alive(&mut self) -> bool {
+        self.try_wait().is_none() || self.group.alive()
+    }
+
+    // Waits up to timeout ms for the whole group to go away
+    pub fn wait_for_group(&mut self, timeout: u64) -> bool {
+        let mut waited = 0;
+        while self.group_alive() {
+            if waited >= timeout {
+                return false
+            }
+            sleep(Duration::milliseconds(GROUP_POLL_INTERVAL as i64));
+            waited += GROUP_POLL_INTERVAL;
+        }
+        true
+    }
+
+    // Kills everything in the group, and reaps the child
+    pub fn kill(&mut self) -> IoResult<()> {
+        if self.group.kill() {
+            debug!("Killed the process group of {}", self.id());
+        } else {
+            match self.process.signal_kill() {
+                Ok(_) => {},
+                //The child has exited already, which is fine
+                Err(ref e) if e.kind == IoErrorKind::InvalidInput => {},
+                Err(e) => return Err(e)
+            }
+        }
+        self.wait_timeout(None).map(|_| ())
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if self.group_alive() {
+            match self.kill() {
+                Ok(_) => {},
+                Err(e) => error!("Failed to kill process {}: {}", self.id(), e)
+            }
+        }
+    }
+}
+
+//Time in ms between checks for processes left in a group
+static GROUP_POLL_INTERVAL: u64 = 100;
+
+#[cfg(unix)]
+mod imp {
+    use libc::{c_int, pid_t};
+    use std::io::process::Process;
+
+    static SIGKILL: c_int = 9;
+
+    extern {
+        fn kill(pid: pid_t, sig: c_int) -> c_int;
+    }
+
+    // The process group the child leads, which has the child's pid as its id
+    pub struct Group {
+        pgid: pid_t
+    }
+
+    impl Group {
+        pub fn new(process: &Process) -> Group {
+            Group {
+                pgid: process.id()
+            }
+        }
+
+        pub fn alive(&self) -> bool {
+            unsafe { kill(-self.pgid, 0) == 0 }
+        }
+
+        pub fn kill(&self) -> bool {
+            unsafe { kill(-self.pgid, SIGKILL) == 0 }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use libc::{c_int, c_void};
+    use std::io::process::Process;
+    use std::mem;
+    use std::ptr;
+
+    type Handle = *mut c_void;
+
+    static JOB_OBJECT_BASIC_ACCOUNTING_INFORMATION: c_int = 1;
+    static JOB_OBJECT_EXTENDED_LIMIT_INFORMATION: c_int = 9;
+    static JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x2000;
+    static PROCESS_SET_QUOTA: u32 = 0x0100;
+    static PROCESS_TERMINATE: u32 = 0x0001;
+
+    #[repr(C)]
+    struct BasicLimitInformation {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: uint,
+        maximum_working_set_size: uint,
+        active_process_limit: u32,
+        affinity: uint,
+        priority_class: u32,
+        scheduling_class: u32
+    }
+
+    #[repr(C)]
+    struct IoCounters {
+        read_operation_count: u64,
+        write_operation_count: u64,
+        other_operation_count: u64,
+        read_transfer_count: u64,
+        write_transfer_count: u64,
+        other_transfer_count: u64
+    }
+
+    #[repr(C)]
+    struct ExtendedLimitInformation {
+        basic_limit_information: BasicLimitInformation,
+        io_info: IoCounters,
+        process_memory_limit: uint,
+        job_memory_limit: uint,
+        peak_process_memory_used: uint,
+        peak_job_memory_used: uint
+    }
+
+    #[repr(C)]
+    struct BasicAccountingInformation {
+        total_user_time: i64,
+        total_kernel_time: i64,
+        this_period_total_user_time: i64,
+        this_period_total_kernel_time: i64,
+        total_page_fault_count: u32,
+        total_processes: u32,
+        active_processes: u32,
+        total_terminated_processes: u32
+    }
+
+    extern "system" {
+        fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> Handle;
+        fn SetInformationJobObject(job: Handle, class: c_int, info: *mut c_void,
+                                   length: u32) -> c_int;
+        fn QueryInformationJobObject(job: Handle, class: c_int, info: *mut c_void,
+                                     length: u32, return_length: *mut u32) -> c_int;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> c_int;
+        fn TerminateJobObject(job: Handle, exit_code: u32) -> c_int;
+        fn OpenProcess(access: u32, inherit: c_int, pid: u32) -> Handle;
+        fn CloseHandle(handle: Handle) -> c_int;
+    }
+
+    // A job object that is closed along with wires, at which point windows kills
+    // everything still in it. Processes the browser starts join the job too.
+    pub struct Group {
+        //The handle is kept as an integer so the group can move between tasks
+        job: uint
+    }
+
+    impl Group {
+        pub fn new(process: &Process) -> Group {
+            unsafe {
+                let job = CreateJobObjectW(ptr::null_mut(), ptr::null());
+                if job.is_null() {
+                    error!("Failed to create a job object for process {}", process.id());
+                    return Group { job: 0 }
+                }
+                let mut info: ExtendedLimitInformation = mem::zeroed();
+                info.basic_limit_information.limit_flags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+                SetInformationJobObject(job, JOB_OBJECT_EXTENDED_LIMIT_INFORMATION,
+                                        &mut info as *mut _ as *mut c_void,
+                                        mem::size_of::<ExtendedLimitInformation>() as u32);
+                let handle = OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, 0,
+                                         process.id() as u32);
+                if handle.is_null() || AssignProcessToJobObject(job, handle) == 0 {
+                    error!("Failed to add process {} to its job object", process.id());
+                }
+                if !handle.is_null() {
+                    CloseHandle(handle);
+                }
+                Group { job: job as uint }
+            }
+        }
+
+        pub fn alive(&self) -> bool {
+            if self.job == 0 {
+                return false
+            }
+            unsafe {
+                let mut info: BasicAccountingInformation = mem::zeroed();
+                QueryInformationJobObject(self.job as Handle,
+                                          JOB_OBJECT_BASIC_ACCOUNTING_INFORMATION,
+                                          &mut info as *mut _ as *mut c_void,
+                                          mem::size_of::<BasicAccountingInformation>() as u32,
+                                          ptr::null_mut()) != 0 && info.active_processes > 0
+            }
+        }
+
+        pub fn kill(&self) -> bool {
+            self.job != 0 && unsafe { TerminateJobObject(self.job as Handle, 1) != 0 }
+        }
+    }
+
+    impl Drop for Group {
+        fn drop(&mut self) {
+            if self.job != 0 {
+                unsafe { CloseHandle(self.job as Handle); }
+            }
+        }
+    }
+}
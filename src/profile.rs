@@ -0,0 +1,121 @@
+use serialize::json::{Json, ToJson};
+use std::io::fs::PathExtensions;
+use std::io::{fs, File, IoResult, TempDir};
+use std::num::ToPrimitive;
+
+use webdriver::common::{WebDriverResult, WebDriverError, ErrorStatus};
+
+#[deriving(PartialEq, Clone, Show)]
+pub enum Pref {
+    Bool(bool),
+    Int(i64),
+    String(String)
+}
+
+impl Pref {
+    pub fn from_json(name: &str, value: &Json) -> WebDriverResult<Pref> {
+        match *value {
+            Json::Boolean(x) => Ok(Pref::Bool(x)),
+            Json::I64(x) => Ok(Pref::Int(x)),
+            Json::U64(x) => {
+                match x.to_i64() {
+                    Some(x) => Ok(Pref::Int(x)),
+                    None => Err(wd_err!(ErrorStatus::InvalidArgument,
+                                        "Preference {} is out of range", name))
+                }
+            },
+            Json::String(ref x) => Ok(Pref::String(x.clone())),
+            _ => Err(wd_err!(ErrorStatus::InvalidArgument,
+                             "Preference {} must be a boolean, integer or string", name))
+        }
+    }
+
+    fn to_user_js(&self) -> String {
+        match *self {
+            Pref::Bool(x) => x.to_string(),
+            Pref::Int(x) => x.to_string(),
+            Pref::String(ref x) => x.to_json().to_string()
+        }
+    }
+}
+
+pub struct Profile {
+    path: Path,
+    //None if the profile is being kept around after use
+    temp_dir: Option<TempDir>
+}
+
+impl Profile {
+    // Without a root the profile goes in the system temporary directory
+    pub fn new(root: Option<&Path>, keep: bool) -> IoResult<Profile> {
+        let temp_dir = match root {
+            Some(x) => try!(TempDir::new_in(x, "wires")),
+            None => try!(TempDir::new("wires"))
+        };
+        let path = temp_dir.path().clone();
+        let temp_dir = if keep {
+            temp_dir.unwrap();
+            None
+        } else {
+            Some(temp_dir)
+        };
+        Ok(Profile {
+            path: path,
+            temp_dir: temp_dir
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Once the profile has something worth looking at, like crash dumps, it shouldn't
+    // be deleted along with the session
+    pub fn keep(&mut self) {
+        match self.temp_dir.take() {
+            Some(x) => {
+                x.unwrap();
+            },
+            None => {}
+        }
+    }
+
+    pub fn minidumps(&self) -> Vec<Path> {
+        let dir = self.path.join("minidumps");
+        if !dir.is_dir() {
+            return vec![]
+        }
+        match fs::readdir(&dir) {
+            Ok(x) => x.into_iter().filter(|x| x.extension_str() == Some("dmp")).collect(),
+            Err(e) => {
+                error!("Failed to read {}: {}", dir.display(), e);
+                vec![]
+            }
+        }
+    }
+
+    pub fn write_prefs(&self, prefs: &[(String, Pref)]) -> IoResult<()> {
+        let mut file = try!(File::create(&self.path().join("user.js")));
+        for &(ref name, ref value) in prefs.iter() {
+            try!(file.write_line(format!("user_pref({}, {});",
+                                         name.to_json(),
+                                         value.to_user_js())[]));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Profile {
+    fn drop(&mut self) {
+        match self.temp_dir.take() {
+            Some(dir) => {
+                debug!("Removing profile {}", self.path.display());
+                match dir.close() {
+                    Ok(_) => {},
+                    Err(e) => error!("Failed to remove profile {}: {}", self.path.display(), e)
+                }
+            },
+            None => info!("Keeping profile {}", self.path.display())
+        }
+    }
+}
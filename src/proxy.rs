@@ -0,0 +1,649 @@
+use serialize::json::{Json, ToJson};
+use std::cmp::min;
+use std::collections::{RingBuf, TreeMap};
+use std::io::timer::Timer;
+use std::sync::Mutex;
+use std::time::Duration;
+use time::precise_time_ns;
+
+use hyper::method::Post;
+use hyper::server::Listening;
+
+use webdriver::command::{WebDriverMessage, WebDriverCommand};
+use webdriver::common::{WebDriverResult, WebDriverError, ErrorStatus, Capabilities};
+use webdriver::httpserver::{WebDriverHandler, ServerSettings, ServerError, ListenAddress};
+use webdriver::httpserver;
+use webdriver::listener::ServerAcceptor;
+use webdriver::messagebuilder::get_builder;
+use webdriver::metrics;
+use webdriver::response::{WebDriverResponse, ValueResponse};
+
+use backend::{Backend, BackendFactory};
+use events;
+use firefox::find_binary;
+use grid::{GridNode, GridSettings};
+use marionette::{MarionetteSettings, PASSTHROUGH_EXTENSION, passthrough_parameters};
+use logging;
+use logging::LogBuffer;
+use signal;
+use {RunError, VERSION};
+
+//Longest time in ms between checks for idle sessions
+static IDLE_CHECK_INTERVAL: u64 = 10000;
+//How many ended sessions are remembered, so their ids can be reported as invalid
+static MAX_ENDED_SESSIONS: uint = 1000;
+
+// The dispatcher routes each command to the worker that owns the session it is for.
+// Workers own the backend that talks to the browser, so a slow command only holds up
+// its own session. A worker takes its commands one at a time, in the order they
+// arrived, so concurrent requests for a session never interleave on its connection.
+enum DispatchMessage {
+    HandleWebDriver(WebDriverMessage, Sender<WebDriverResult<Option<WebDriverResponse>>>),
+    SessionStarted(String, Sender<WorkerMessage>, Option<Capabilities>),
+    //Session id, and why it ended
+    SessionEnded(String, String),
+    ListSessions(Sender<Vec<String>>),
+    //Ends sessions that have been idle for longer than the idle timeout
+    ReapIdle,
+    //Replies once every session has been cleaned up
+    Quit(Sender<()>)
+}
+
+enum WorkerMessage {
+    HandleWebDriver(WebDriverMessage, Sender<WebDriverResult<Option<WebDriverResponse>>>),
+    Quit(Sender<()>)
+}
+
+// What the dispatcher knows about each running session
+struct SessionEntry {
+    worker: Sender<WorkerMessage>,
+    capabilities: Option<Capabilities>,
+    //precise_time_ns() when the session started and when it was last sent a command
+    started: u64,
+    last_activity: u64,
+    last_command: Option<String>
+}
+
+struct Dispatcher {
+    sessions: TreeMap<String, SessionEntry>,
+    //Ids of sessions that have ended and the reason, oldest first
+    ended_sessions: RingBuf<(String, String)>,
+    binary: Option<Path>,
+    settings: MarionetteSettings,
+    new_backend: BackendFactory
+}
+
+impl Dispatcher {
+    fn new(settings: MarionetteSettings, new_backend: BackendFactory) -> Dispatcher {
+        let binary = settings.binary.clone().or_else(find_binary);
+        match binary {
+            Some(ref x) => info!("Using Firefox binary {}", x.display()),
+            None => info!("No Firefox binary found, sessions will need to specify one")
+        }
+        Dispatcher {
+            sessions: TreeMap::new(),
+            ended_sessions: RingBuf::new(),
+            binary: binary,
+            settings: settings,
+            new_backend: new_backend
+        }
+    }
+
+    fn run(&mut self, msg_chan: Receiver<DispatchMessage>, self_chan: Sender<DispatchMessage>) {
+        loop {
+            match msg_chan.recv() {
+                DispatchMessage::HandleWebDriver(msg, resp_chan) => {
+                    match msg.command {
+                        WebDriverCommand::Status => {
+                            let _ = resp_chan.send_opt(Ok(Some(self.status())));
+                            continue
+                        },
+                        WebDriverCommand::GetSessions => {
+                            let _ = resp_chan.send_opt(Ok(Some(self.list_sessions())));
+                            continue
+                        },
+                        _ => {}
+                    }
+                    let worker = match msg.session_id {
+                        Some(ref x) => match self.session_worker(x, &msg.command, &self_chan) {
+                            Ok(worker) => worker,
+                            Err(e) => {
+                                let _ = resp_chan.send_opt(Err(e));
+                                continue
+                            }
+                        },
+                        None => {
+                            //Only starting a session doesn't need one; don't launch a
+                            //browser for anything else
+                            match msg.command {
+                                WebDriverCommand::NewSession(_) => {},
+                                _ => {
+                                    let _ = resp_chan.send_opt(Err(WebDriverError::new(
+                                        ErrorStatus::InvalidSessionId,
+                                        "Command requires a session id")));
+                                    continue
+                                }
+                            }
+                            if self.single_session() && !self.sessions.is_empty() {
+                                let _ = resp_chan.send_opt(Err(WebDriverError::new(
+                                    ErrorStatus::SessionNotCreated,
+                                    "Session already started")));
+                                continue
+                            }
+                            self.start_worker(&self_chan)
+                        }
+                    };
+                    match worker.send_opt(WorkerMessage::HandleWebDriver(msg, resp_chan)) {
+                        Ok(_) => {},
+                        Err(WorkerMessage::HandleWebDriver(_, resp_chan)) => {
+                            let _ = resp_chan.send_opt(Err(WebDriverError::new(
+                                ErrorStatus::InvalidSessionId,
+                                "Session has already ended")));
+                        },
+                        Err(_) => {}
+                    }
+                },
+                DispatchMessage::SessionStarted(session_id, worker, capabilities) => {
+                    debug!("Session {} started", session_id);
+                    let now = precise_time_ns();
+                    self.sessions.insert(session_id, SessionEntry {
+                        worker: worker,
+                        capabilities: capabilities,
+                        started: now,
+                        last_activity: now,
+                        last_command: Some("NewSession".to_string())
+                    });
+                    self.sessions_changed();
+                },
+                DispatchMessage::SessionEnded(session_id, reason) => {
+                    //Sessions that were reaped have already been removed
+                    match self.sessions.remove(&session_id) {
+                        Some(session) => {
+                            debug!("Session {} ended after {}s", session_id,
+                                   (precise_time_ns() - session.started) / 1000000000);
+                            self.add_ended_session(session_id, reason);
+                        },
+                        None => debug!("Session {} ended", session_id)
+                    }
+                },
+                DispatchMessage::ListSessions(resp_chan) => {
+                    let _ = resp_chan.send_opt(self.sessions.keys().map(|x| x.clone()).collect());
+                },
+                DispatchMessage::ReapIdle => self.reap_idle(),
+                DispatchMessage::Quit(done) => {
+                    let mut waiting = vec![];
+                    for (session_id, session) in self.sessions.iter() {
+                        debug!("Ending session {}", session_id);
+                        let (send_done, recv_done) = channel();
+                        if session.worker.send_opt(WorkerMessage::Quit(send_done)).is_ok() {
+                            waiting.push(recv_done);
+                        }
+                    }
+                    for x in waiting.iter() {
+                        let _ = x.recv_opt();
+                    }
+                    let _ = done.send_opt(());
+                    break;
+                }
+            }
+        }
+    }
+
+    // Finds the worker for a command's session, or says why there isn't one
+    fn session_worker(&mut self, session_id: &String, command: &WebDriverCommand,
+                      self_chan: &Sender<DispatchMessage>) -> WebDriverResult<Sender<WorkerMessage>> {
+        match self.sessions.get_mut(session_id) {
+            Some(session) => {
+                session.last_activity = precise_time_ns();
+                session.last_command = Some(command.name());
+                return Ok(session.worker.clone())
+            },
+            None => {}
+        }
+        match self.ended_sessions.iter().find(|&&(ref x, _)| x == session_id) {
+            Some(&(_, ref reason)) => return Err(WebDriverError::new(
+                ErrorStatus::InvalidSessionId,
+                format!("Session {} {}", session_id, reason))),
+            None => {}
+        }
+        //The session may belong to a browser that was already running
+        if self.settings.connect_existing && self.sessions.is_empty() {
+            Ok(self.start_worker(self_chan))
+        } else {
+            Err(WebDriverError::new(ErrorStatus::InvalidSessionId,
+                                    format!("Unknown session id {}", session_id)))
+        }
+    }
+
+    fn add_ended_session(&mut self, session_id: String, reason: String) {
+        self.ended_sessions.push_back((session_id, reason));
+        if self.ended_sessions.len() > MAX_ENDED_SESSIONS {
+            self.ended_sessions.pop_front();
+        }
+        self.sessions_changed();
+    }
+
+    fn sessions_changed(&self) {
+        metrics::set_gauge("wires_active_sessions", &[], self.sessions.len() as i64);
+    }
+
+    fn start_worker(&self, dispatcher: &Sender<DispatchMessage>) -> Sender<WorkerMessage> {
+        let (worker_send, worker_recv) = channel();
+        let backend = (self.new_backend)(self.binary.clone(), self.settings.clone());
+        let mut worker = Worker::new(backend, dispatcher.clone(), worker_send.clone());
+        spawn(proc() {
+            logging::init_buffered(worker.log.clone());
+            worker.run(worker_recv);
+        });
+        worker_send
+    }
+
+    fn reap_idle(&mut self) {
+        let timeout = match self.settings.idle_timeout {
+            Some(x) => x * 1000000,
+            None => return
+        };
+        let now = precise_time_ns();
+        let idle: Vec<String> = self.sessions.iter()
+            .filter(|&(_, session)| now - session.last_activity > timeout)
+            .map(|(session_id, _)| session_id.clone())
+            .collect();
+        for session_id in idle.into_iter() {
+            info!("Ending session {} after {}s without a command", session_id,
+                  timeout / 1000000000);
+            //Removed straight away so no more commands are sent to a browser that is quitting
+            match self.sessions.remove(&session_id) {
+                Some(session) => {
+                    let (send_done, _) = channel();
+                    let _ = session.worker.send_opt(WorkerMessage::Quit(send_done));
+                },
+                None => {}
+            }
+            self.add_ended_session(session_id, format!("was ended after {}s without a command",
+                                                       timeout / 1000000000));
+        }
+    }
+
+    // An existing browser can only have one session, and neither can a fixed marionette
+    // port be shared by several browsers
+    fn single_session(&self) -> bool {
+        self.settings.connect_existing || self.settings.marionette_port.is_some()
+    }
+
+    fn status(&self) -> WebDriverResponse {
+        let ready = !self.single_session() || self.sessions.is_empty();
+        let mut data = TreeMap::new();
+        data.insert("ready".to_string(), ready.to_json());
+        data.insert("message".to_string(),
+                    if ready { "" } else { "Session already started" }.to_json());
+        data.insert("sessions".to_string(), self.sessions.len().to_json());
+        data.insert("binary".to_string(), match self.binary {
+            Some(ref x) => x.display().to_string().to_json(),
+            None => Json::Null
+        });
+        WebDriverResponse::Generic(ValueResponse::new(Json::Object(data)))
+    }
+
+    // What is running, for people sharing a wires instance
+    fn list_sessions(&self) -> WebDriverResponse {
+        let now = precise_time_ns();
+        let sessions: Vec<Json> = self.sessions.iter().map(|(session_id, session)| {
+            let mut data = TreeMap::new();
+            data.insert("id".to_string(), session_id.to_json());
+            data.insert("capabilities".to_string(), match session.capabilities {
+                Some(ref x) => x.to_json(),
+                None => Json::Null
+            });
+            data.insert("uptime".to_string(), ((now - session.started) / 1000000000).to_json());
+            data.insert("idle".to_string(),
+                        ((now - session.last_activity) / 1000000000).to_json());
+            data.insert("lastCommand".to_string(), session.last_command.to_json());
+            Json::Object(data)
+        }).collect();
+        WebDriverResponse::Generic(ValueResponse::new(sessions.to_json()))
+    }
+}
+
+// A worker handles a single session, and goes away when the session does
+struct Worker {
+    backend: Box<Backend + Send>,
+    //Set once the session is registered with the dispatcher
+    session_id: Option<String>,
+    dispatcher: Sender<DispatchMessage>,
+    chan: Sender<WorkerMessage>,
+    //What the worker's task has logged recently
+    log: LogBuffer
+}
+
+impl Worker {
+    fn new(backend: Box<Backend + Send>, dispatcher: Sender<DispatchMessage>,
+           chan: Sender<WorkerMessage>) -> Worker {
+        Worker {
+            backend: backend,
+            session_id: None,
+            dispatcher: dispatcher,
+            chan: chan,
+            log: logging::new_buffer()
+        }
+    }
+
+    fn run(&mut self, msg_chan: Receiver<WorkerMessage>) {
+        loop {
+            match msg_chan.recv() {
+                WorkerMessage::HandleWebDriver(msg, resp_chan) => {
+                    match msg.session_id {
+                        Some(ref x) => {
+                            match self.session_id {
+                                Some(ref session_id) if session_id != x => {
+                                    error!("Got unexpected session id {} expected {}",
+                                           x, session_id);
+                                    let _ = resp_chan.send_opt(Err(WebDriverError::new(
+                                        ErrorStatus::InvalidSessionId,
+                                        format!("Unknown session id {}", x))));
+                                    continue
+                                },
+                                Some(_) => {},
+                                None => match self.backend.attach(x[]) {
+                                    Err(e) => {
+                                        error!("{}", e.message);
+                                        let _ = resp_chan.send_opt(Err(e));
+                                        break
+                                    },
+                                    Ok(_) => self.register()
+                                }
+                            }
+                            if !self.backend.is_alive() {
+                                self.end_session("ended when the browser went away");
+                                let _ = resp_chan.send_opt(Err(WebDriverError::new(
+                                    ErrorStatus::InvalidSessionId,
+                                    "Browser went away: marionette connection closed")));
+                                break
+                            }
+                        },
+                        None => match self.backend.start(&msg) {
+                            Err(e) => {
+                                let _ = resp_chan.send_opt(Err(e));
+                                break
+                            },
+                            Ok(_) => {}
+                        }
+                    };
+                    let resp = match msg.command {
+                        WebDriverCommand::GetDriverLog => {
+                            let lines: Vec<String> = self.log.lock().iter()
+                                .map(|x| x.clone()).collect();
+                            Ok(Some(WebDriverResponse::Generic(
+                                ValueResponse::new(lines.to_json()))))
+                        },
+                        _ => self.backend.handle_command(&msg)
+                    };
+                    debug!("{}", resp);
+                    match resp {
+                        Ok(Some(WebDriverResponse::NewSession(_))) => {
+                            //Has to happen before the client can send another command
+                            self.register();
+                        },
+                        Ok(Some(WebDriverResponse::DeleteSession)) => {
+                            debug!("Deleting session");
+                            self.end_session("has been deleted");
+                        },
+                        _ if !self.backend.is_alive() => {
+                            self.end_session("ended when the browser went away");
+                        },
+                        Ok(_) => self.navigated(&msg.command),
+                        _ => {}
+                    }
+                    let _ = resp_chan.send_opt(resp);
+                    if !self.backend.is_alive() {
+                        break
+                    }
+                },
+                WorkerMessage::Quit(done) => {
+                    self.end_session("was ended when wires shut down");
+                    let _ = done.send_opt(());
+                    break;
+                }
+            }
+        }
+    }
+
+    fn register(&mut self) {
+        self.session_id = self.backend.session_id();
+        match self.session_id {
+            Some(ref session_id) => {
+                logging::set_session(self.log.clone(), session_id.clone());
+                let _ = self.dispatcher.send_opt(DispatchMessage::SessionStarted(
+                    session_id.clone(), self.chan.clone(), self.backend.capabilities()));
+                events::session_started(session_id[]);
+            },
+            None => {}
+        }
+    }
+
+    fn end_session(&mut self, reason: &str) {
+        match self.session_id.take() {
+            Some(session_id) => {
+                events::session_ended(session_id[], reason);
+                let _ = self.dispatcher.send_opt(DispatchMessage::SessionEnded(
+                    session_id, reason.to_string()));
+            },
+            None => {}
+        }
+        self.backend.teardown();
+    }
+
+    // Marionette only answers navigation commands once the new page has loaded
+    fn navigated(&self, command: &WebDriverCommand) {
+        let session_id = match self.session_id {
+            Some(ref x) => x[],
+            None => return
+        };
+        match *command {
+            WebDriverCommand::Get(ref x) => events::navigation_completed(session_id,
+                                                                         Some(x.url[])),
+            WebDriverCommand::GoBack | WebDriverCommand::GoForward |
+            WebDriverCommand::Refresh => events::navigation_completed(session_id, None),
+            _ => {}
+        }
+    }
+}
+
+// Passes commands from the HTTP server on to the dispatcher
+struct MarionetteHandler {
+    chan: Mutex<Sender<DispatchMessage>>,
+    settings: MarionetteSettings,
+    //For the status page
+    address: String,
+    started: u64
+}
+
+impl MarionetteHandler {
+    fn new(chan: Sender<DispatchMessage>, settings: MarionetteSettings,
+           address: String) -> MarionetteHandler {
+        MarionetteHandler {
+            chan: Mutex::new(chan),
+            settings: settings,
+            address: address,
+            started: precise_time_ns()
+        }
+    }
+
+    // The worker may still be busy with the command after the deadline, but it drops
+    // the response if nobody is waiting for it any more
+    fn wait_for_response(&self, recv_res: Receiver<WebDriverResult<Option<WebDriverResponse>>>)
+                         -> WebDriverResult<Option<WebDriverResponse>> {
+        let timeout = match self.settings.request_timeout {
+            Some(x) => x,
+            None => return recv_res.recv()
+        };
+        let mut timer = try!(Timer::new());
+        let deadline = timer.oneshot(Duration::milliseconds(timeout as i64));
+        select! {
+            resp = recv_res.recv() => resp,
+            () = deadline.recv() => Err(WebDriverError::new(
+                ErrorStatus::Timeout,
+                format!("Handling the request took longer than {}ms", timeout)))
+        }
+    }
+
+    // A page for people checking in a browser that the server is up
+    fn status_html(&self) -> String {
+        let (send_sessions, recv_sessions) = channel();
+        {
+            let c = self.chan.lock();
+            c.send(DispatchMessage::ListSessions(send_sessions));
+        }
+        let sessions = recv_sessions.recv_opt().unwrap_or(vec![]);
+        let uptime = (precise_time_ns() - self.started) / 1000000000;
+        let mut sessions_html = String::new();
+        for session_id in sessions.iter() {
+            sessions_html.push_str(format!("<li>{}</li>", escape_html(session_id[]))[]);
+        }
+        format!("<!DOCTYPE html>
+<html>
+<head><title>wires</title></head>
+<body>
+<h1>wires {}</h1>
+<p>Listening on {}, up for {}h {}m {}s</p>
+<h2>Sessions ({})</h2>
+<ul>{}</ul>
+</body>
+</html>
+", escape_html(VERSION.trim()), escape_html(self.address[]),
+                uptime / 3600, uptime / 60 % 60, uptime % 60,
+                sessions.len(), sessions_html)
+    }
+}
+
+impl WebDriverHandler for MarionetteHandler {
+    fn handle_command(&self, msg: WebDriverMessage) -> WebDriverResult<Option<WebDriverResponse>> {
+        let (send_res, recv_res) = channel();
+        {
+            let c = self.chan.lock();
+            c.send(DispatchMessage::HandleWebDriver(msg, send_res));
+        }
+        self.wait_for_response(recv_res)
+    }
+
+    fn status_page(&self) -> Option<String> {
+        Some(self.status_html())
+    }
+
+    fn init_task(&self) {
+        logging::init();
+    }
+}
+
+fn escape_html(data: &str) -> String {
+    data.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+// Runs until the process is asked to stop, and returns the exit code to use
+pub fn start(address: ListenAddress, settings: MarionetteSettings,
+             server_settings: ServerSettings, grid: Option<GridSettings>,
+             new_backend: BackendFactory) -> Result<int, RunError> {
+    signal::install_handlers();
+    let server = try!(serve(address, settings, server_settings, new_backend));
+    let node = match grid {
+        Some(x) => match GridNode::start(x) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                server.stop();
+                return Err(RunError::Usage(e))
+            }
+        },
+        None => None
+    };
+    let signum = signal::wait_for_signal();
+    info!("Got signal {}, shutting down", signum);
+    //Leave the grid first, so the hub doesn't send any more sessions here
+    match node {
+        Some(x) => x.stop(),
+        None => {}
+    }
+    server.stop();
+    Ok(128 + signum as int)
+}
+
+// A server that is up and running, until it is stopped
+pub struct RunningServer {
+    listening: Listening<ServerAcceptor>,
+    dispatcher: Sender<DispatchMessage>
+}
+
+impl RunningServer {
+    // Stops taking requests, then waits for every session to be cleaned up
+    pub fn stop(self) {
+        let mut listening = self.listening;
+        match listening.close() {
+            Ok(_) => {},
+            Err(e) => error!("Failed to stop listening: {}", e)
+        }
+        let (send_done, recv_done) = channel();
+        let _ = self.dispatcher.send_opt(DispatchMessage::Quit(send_done));
+        let _ = recv_done.recv_opt();
+    }
+}
+
+pub fn serve(address: ListenAddress, settings: MarionetteSettings,
+             server_settings: ServerSettings,
+             new_backend: BackendFactory) -> Result<RunningServer, RunError> {
+    let mut builder = get_builder();
+    if settings.marionette_passthrough {
+        info!("Any marionette command can be sent through moz/marionette");
+        builder.add_extension(Post, "/session/{sessionId}/moz/marionette",
+                              PASSTHROUGH_EXTENSION, passthrough_parameters);
+    }
+    let idle_timeout = settings.idle_timeout;
+    let handler_settings = settings.clone();
+    let mut dispatcher = Dispatcher::new(settings, new_backend);
+
+    let (msg_send, msg_recv) = channel();
+
+    let dispatcher_send = msg_send.clone();
+    spawn(proc() {
+        logging::init();
+        dispatcher.run(msg_recv, dispatcher_send);
+    });
+    match idle_timeout {
+        Some(timeout) => {
+            let reaper_send = msg_send.clone();
+            spawn(proc() {
+                let mut timer = Timer::new().unwrap();
+                let ticks = timer.periodic(Duration::milliseconds(
+                    min(timeout, IDLE_CHECK_INTERVAL) as i64));
+                loop {
+                    ticks.recv();
+                    if reaper_send.send_opt(DispatchMessage::ReapIdle).is_err() {
+                        break
+                    }
+                }
+            });
+        },
+        None => {}
+    }
+    let address_name = match address {
+        ListenAddress::Tcp(x) => format!("http://{}", x),
+        ListenAddress::Tls(x, _, _) => format!("https://{}", x),
+        ListenAddress::Unix(ref path) => path.display().to_string()
+    };
+    let handler = MarionetteHandler::new(msg_send.clone(), handler_settings, address_name);
+    let unix_path = match address {
+        ListenAddress::Unix(ref path) => Some(path.clone()),
+        _ => None
+    };
+    let listening = match httpserver::start(address, server_settings, builder, handler) {
+        Ok(x) => x,
+        Err(ServerError::Address(x)) => return Err(RunError::Address(x)),
+        Err(ServerError::AddressInUse(x)) => return Err(RunError::AddressInUse(x)),
+        Err(ServerError::Internal(x)) => return Err(RunError::Internal(x))
+    };
+    match unix_path {
+        Some(ref path) => info!("Listening on {}", path.display()),
+        None => {}
+    }
+    Ok(RunningServer {
+        listening: listening,
+        dispatcher: msg_send
+    })
+}
@@ -1,51 +1,84 @@
+use std::collections::TreeMap;
 use serialize::json;
 use serialize::json::ToJson;
 
-use common::Nullable;
+use common::{Nullable, SameSite, Capabilities, WebElement};
 
 #[deriving(Show)]
 pub enum WebDriverResponse {
     NewSession(NewSessionResponse),
     DeleteSession,
-    WindowSize(WindowSizeResponse),
+    WindowRect(WindowRectResponse),
     ElementRect(ElementRectResponse),
-    Cookie(CookieResponse),
+    Cookies(CookiesResponse),
+    Element(ElementResponse),
     Generic(ValueResponse),
     Void
 }
 
 impl WebDriverResponse {
-    pub fn to_json_string(self) -> String {
+    // Every body is {"value": ...}, with null for commands that don't return anything
+    pub fn into_json(self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("value".to_string(), self.into_value());
+        json::Object(data)
+    }
+
+    // The JSON wire protocol envelope, for clients from before the spec. New Session
+    // puts its id in the envelope, with just the capabilities as the value.
+    pub fn into_jsonwire(self, session_id: Option<String>) -> json::Json {
+        let (session_id, value) = match self {
+            WebDriverResponse::NewSession(x) => (Some(x.session_id.clone()),
+                                                 x.capabilities.clone()),
+            x => (session_id, x.into_value())
+        };
+        let mut data = TreeMap::new();
+        data.insert("status".to_string(), 0u64.to_json());
+        data.insert("sessionId".to_string(), session_id.to_json());
+        data.insert("value".to_string(), value);
+        json::Object(data)
+    }
+
+    fn into_value(self) -> json::Json {
         match self {
-            WebDriverResponse::NewSession(x) => json::encode(&x),
-            WebDriverResponse::DeleteSession => "".into_string(),
-            WebDriverResponse::WindowSize(x) => json::encode(&x),
-            WebDriverResponse::ElementRect(x) => json::encode(&x),
-            WebDriverResponse::Cookie(x) => json::encode(&x),
-            WebDriverResponse::Generic(x) => json::encode(&x),
-            WebDriverResponse::Void => "".into_string()
+            WebDriverResponse::NewSession(x) => x.to_json(),
+            WebDriverResponse::WindowRect(x) => x.to_json(),
+            WebDriverResponse::ElementRect(x) => x.to_json(),
+            WebDriverResponse::Cookies(x) => x.to_json(),
+            WebDriverResponse::Element(x) => x.to_json(),
+            WebDriverResponse::Generic(x) => x.value,
+            WebDriverResponse::DeleteSession | WebDriverResponse::Void => json::Null
         }
     }
 }
 
-#[deriving(Encodable, Show)]
+#[deriving(Show)]
 pub struct NewSessionResponse {
-    sessionId: String,
-    value: json::Json
+    session_id: String,
+    capabilities: json::Json
 }
 
 impl NewSessionResponse {
-    pub fn new(session_id: String, value: json::Json) -> NewSessionResponse {
+    pub fn new(session_id: String, capabilities: &Capabilities) -> NewSessionResponse {
         NewSessionResponse {
-            value: value,
-            sessionId: session_id
+            session_id: session_id,
+            capabilities: capabilities.to_json()
         }
     }
 }
 
-#[deriving(Encodable, Show)]
+impl ToJson for NewSessionResponse {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("sessionId".to_string(), self.session_id.to_json());
+        data.insert("capabilities".to_string(), self.capabilities.clone());
+        json::Object(data)
+    }
+}
+
+#[deriving(Show)]
 pub struct ValueResponse {
-    value: json::Json
+    pub value: json::Json
 }
 
 impl ValueResponse {
@@ -56,22 +89,55 @@ impl ValueResponse {
     }
 }
 
-#[deriving(Encodable, Show)]
-pub struct WindowSizeResponse {
+impl ToJson for ValueResponse {
+    fn to_json(&self) -> json::Json {
+        self.value.clone()
+    }
+}
+
+// Get Window Size only has the size, Get Window Rect has the position as well
+#[deriving(Show)]
+pub struct WindowRectResponse {
+    position: Option<(i64, i64)>,
     width: u64,
     height: u64
 }
 
-impl WindowSizeResponse {
-    pub fn new(width: u64, height: u64) -> WindowSizeResponse {
-        WindowSizeResponse {
+impl WindowRectResponse {
+    pub fn new(width: u64, height: u64) -> WindowRectResponse {
+        WindowRectResponse {
+            position: None,
             width: width,
             height: height
         }
     }
+
+    pub fn with_position(x: i64, y: i64, width: u64, height: u64) -> WindowRectResponse {
+        WindowRectResponse {
+            position: Some((x, y)),
+            width: width,
+            height: height
+        }
+    }
+}
+
+impl ToJson for WindowRectResponse {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        match self.position {
+            Some((x, y)) => {
+                data.insert("x".to_string(), x.to_json());
+                data.insert("y".to_string(), y.to_json());
+            },
+            None => {}
+        }
+        data.insert("width".to_string(), self.width.to_json());
+        data.insert("height".to_string(), self.height.to_json());
+        json::Object(data)
+    }
 }
 
-#[deriving(Encodable, Show)]
+#[deriving(Show)]
 pub struct ElementRectResponse {
     x: u64,
     y: u64,
@@ -90,6 +156,36 @@ impl ElementRectResponse {
     }
 }
 
+impl ToJson for ElementRectResponse {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("x".to_string(), self.x.to_json());
+        data.insert("y".to_string(), self.y.to_json());
+        data.insert("width".to_string(), self.width.to_json());
+        data.insert("height".to_string(), self.height.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(Show)]
+pub struct ElementResponse {
+    element: WebElement
+}
+
+impl ElementResponse {
+    pub fn new(element: WebElement) -> ElementResponse {
+        ElementResponse {
+            element: element
+        }
+    }
+}
+
+impl ToJson for ElementResponse {
+    fn to_json(&self) -> json::Json {
+        self.element.to_json()
+    }
+}
+
 #[deriving(Encodable, PartialEq, Show)]
 pub struct Date(u64);
 
@@ -107,7 +203,7 @@ impl ToJson for Date {
 }
 
 //TODO: some of these fields are probably supposed to be optional
-#[deriving(Encodable, PartialEq, Show)]
+#[deriving(PartialEq, Show)]
 pub struct Cookie {
     name: String,
     value: String,
@@ -116,12 +212,14 @@ pub struct Cookie {
     expiry: Nullable<Date>,
     maxAge: Date,
     secure: bool,
-    httpOnly: bool
+    httpOnly: bool,
+    sameSite: Nullable<SameSite>
 }
 
 impl Cookie {
     pub fn new(name: String, value: String, path: Nullable<String>, domain: Nullable<String>,
-               expiry: Nullable<Date>, max_age: Date, secure: bool, http_only: bool) -> Cookie {
+               expiry: Nullable<Date>, max_age: Date, secure: bool, http_only: bool,
+               same_site: Nullable<SameSite>) -> Cookie {
         Cookie {
             name: name,
             value: value,
@@ -130,20 +228,43 @@ impl Cookie {
             expiry: expiry,
             maxAge: max_age,
             secure: secure,
-            httpOnly: http_only
+            httpOnly: http_only,
+            sameSite: same_site
         }
     }
 }
 
-#[deriving(Encodable, Show)]
-pub struct CookieResponse {
+impl ToJson for Cookie {
+    fn to_json(&self) -> json::Json {
+        let mut data = TreeMap::new();
+        data.insert("name".to_string(), self.name.to_json());
+        data.insert("value".to_string(), self.value.to_json());
+        data.insert("path".to_string(), self.path.to_json());
+        data.insert("domain".to_string(), self.domain.to_json());
+        data.insert("expiry".to_string(), self.expiry.to_json());
+        data.insert("maxAge".to_string(), self.maxAge.to_json());
+        data.insert("secure".to_string(), self.secure.to_json());
+        data.insert("httpOnly".to_string(), self.httpOnly.to_json());
+        data.insert("sameSite".to_string(), self.sameSite.to_json());
+        json::Object(data)
+    }
+}
+
+#[deriving(Show)]
+pub struct CookiesResponse {
     value: Vec<Cookie>
 }
 
-impl CookieResponse {
-    pub fn new(value: Vec<Cookie>) -> CookieResponse {
-        CookieResponse {
+impl CookiesResponse {
+    pub fn new(value: Vec<Cookie>) -> CookiesResponse {
+        CookiesResponse {
             value: value
         }
     }
 }
+
+impl ToJson for CookiesResponse {
+    fn to_json(&self) -> json::Json {
+        self.value.to_json()
+    }
+}
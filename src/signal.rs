@@ -0,0 +1,58 @@
+use libc::{c_int, size_t};
+use std::io::timer::sleep;
+use std::sync::atomic::{AtomicBool, AtomicUint, INIT_ATOMIC_BOOL, INIT_ATOMIC_UINT, SeqCst};
+use std::time::Duration;
+
+pub static SIGHUP: c_int = 1;
+pub static SIGINT: c_int = 2;
+pub static SIGTERM: c_int = 15;
+
+//Number of the last signal received, or 0
+static RECEIVED: AtomicUint = INIT_ATOMIC_UINT;
+static HANGUP: AtomicBool = INIT_ATOMIC_BOOL;
+
+extern {
+    fn signal(signum: c_int, handler: extern fn(c_int)) -> size_t;
+}
+
+extern fn record_signal(signum: c_int) {
+    RECEIVED.store(signum as uint, SeqCst);
+}
+
+extern fn record_hangup(_: c_int) {
+    HANGUP.store(true, SeqCst);
+}
+
+pub fn install_handlers() {
+    unsafe {
+        signal(SIGINT, record_signal);
+        signal(SIGTERM, record_signal);
+    }
+    install_hangup_handler();
+}
+
+#[cfg(unix)]
+fn install_hangup_handler() {
+    unsafe {
+        signal(SIGHUP, record_hangup);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_hangup_handler() {}
+
+// Whether SIGHUP arrived since the last call
+pub fn take_hangup() -> bool {
+    HANGUP.swap(false, SeqCst)
+}
+
+// Very little is safe to do inside a signal handler, so it only records the signal
+// and this polls for it
+pub fn wait_for_signal() -> c_int {
+    loop {
+        match RECEIVED.load(SeqCst) {
+            0 => sleep(Duration::milliseconds(100)),
+            x => return x as c_int
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use serialize::json;
+use serialize::json::{Json, ToJson};
+use std::collections::TreeMap;
+use std::io::{BufferedReader, TcpStream};
+
+use webdriver::client::Client;
+use webdriver::common::{ErrorStatus, LocatorStrategy};
+
+use embed::{WiresBuilder, Wires};
+use marionette::DEFAULT_HOST;
+use mock_marionette::{MockMarionette, MockResponse};
+
+// wires, connected to a mock marionette rather than a browser
+struct TestServer {
+    port: u16,
+    server: Wires,
+    marionette: MockMarionette
+}
+
+impl TestServer {
+    fn start(responses: TreeMap<String, MockResponse>) -> TestServer {
+        let marionette = MockMarionette::start(responses);
+        let server = WiresBuilder::new()
+            .connect_existing(DEFAULT_HOST, marionette.port)
+            .startup_timeout(5000)
+            .response_timeout(5000)
+            .start().ok().expect("Failed to start server");
+        let port = server.address().unwrap().port;
+        TestServer {
+            port: port,
+            server: server,
+            marionette: marionette
+        }
+    }
+
+    fn client(&self) -> Client {
+        Client::new("127.0.0.1", self.port)
+    }
+
+    fn stop(self) {
+        self.server.shutdown()
+    }
+}
+
+// Sends requests as they are, for looking at what the client doesn't show: headers,
+// HEAD responses and what happens to the connection
+fn connect(port: u16) -> (TcpStream, BufferedReader<TcpStream>) {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    (stream.clone(), BufferedReader::new(stream))
+}
+
+// Reads a response with a Content-Length, which isn't followed by a body for HEAD
+fn read_response<R: Buffer>(reader: &mut R,
+                            head: bool) -> (int, TreeMap<String, String>, String) {
+    let status_line = reader.read_line().unwrap();
+    let status = from_str::<int>(status_line[].split(' ').nth(1).unwrap()).unwrap();
+    let mut headers = TreeMap::new();
+    loop {
+        let line = reader.read_line().unwrap();
+        let line = line[].trim_right();
+        if line.is_empty() {
+            break
+        }
+        let index = line.find(':').unwrap();
+        headers.insert(line[..index].to_ascii_lower(), line[index + 1..].trim().to_string());
+    }
+    let len = match headers.get("content-length") {
+        Some(x) if !head => from_str::<uint>(x[]).unwrap(),
+        _ => 0
+    };
+    let body = String::from_utf8(reader.read_exact(len).unwrap()).unwrap();
+    (status, headers, body)
+}
+
+fn responses() -> TreeMap<String, MockResponse> {
+    let mut capabilities = TreeMap::new();
+    capabilities.insert("browserName".to_string(), "firefox".to_json());
+    capabilities.insert("browserVersion".to_string(), "99.0".to_json());
+    let mut new_session = TreeMap::new();
+    new_session.insert("sessionId".to_string(), "marionette-session".to_json());
+    new_session.insert("value".to_string(), Json::Object(capabilities));
+    let mut rv = TreeMap::new();
+    rv.insert("newSession".to_string(), MockResponse::Value(Json::Object(new_session)));
+    rv.insert("getCurrentUrl".to_string(),
+              MockResponse::Value("http://example.org/".to_json()));
+    rv.insert("findElement".to_string(),
+              MockResponse::Error("no such element".to_string(),
+                                  "Unable to locate element".to_string()));
+    rv
+}
+
+#[test]
+fn new_session_returns_capabilities() {
+    let server = TestServer::start(responses());
+    let mut client = server.client();
+    let capabilities = client.new_session(Json::Object(TreeMap::new())).unwrap();
+    assert_eq!(capabilities.find("browserName"), Some(&"firefox".to_json()));
+    //The client gets the id wires made up, not marionette's
+    assert!(client.session_id().unwrap() != "marionette-session");
+    assert_eq!(server.marionette.commands(), vec!["newSession".to_string()]);
+    server.stop();
+}
+
+#[test]
+fn commands_are_forwarded() {
+    let server = TestServer::start(responses());
+    let mut client = server.client();
+    client.new_session(Json::Object(TreeMap::new())).unwrap();
+    client.get("http://example.org/").unwrap();
+    assert_eq!(client.current_url().unwrap()[], "http://example.org/");
+    assert_eq!(server.marionette.commands(),
+               vec!["newSession".to_string(), "get".to_string(), "getCurrentUrl".to_string()]);
+    server.stop();
+}
+
+#[test]
+fn marionette_errors_are_returned() {
+    let server = TestServer::start(responses());
+    let mut client = server.client();
+    client.new_session(Json::Object(TreeMap::new())).unwrap();
+    let err = client.find_element(LocatorStrategy::CSSSelector, "#missing").err().unwrap();
+    assert_eq!(err.status, ErrorStatus::NoSuchElement);
+    assert_eq!(&*err.message, "Unable to locate element");
+    server.stop();
+}
+
+#[test]
+fn deleted_session_is_invalid() {
+    let server = TestServer::start(responses());
+    let mut client = server.client();
+    client.new_session(Json::Object(TreeMap::new())).unwrap();
+    let session_id = client.session_id().unwrap().to_string();
+    client.quit().unwrap();
+    let err = client.command("GET", format!("/session/{}/url", session_id)[], None).err().unwrap();
+    assert_eq!(err.status, ErrorStatus::InvalidSessionId);
+    server.stop();
+}
+
+#[test]
+fn legacy_window_routes_only_take_the_current_window() {
+    let mut responses = responses();
+    responses.insert("getWindowHandle".to_string(), MockResponse::Value("window-1".to_json()));
+    let server = TestServer::start(responses);
+    let mut client = server.client();
+    client.new_session(Json::Object(TreeMap::new())).unwrap();
+    let session_id = client.session_id().unwrap().to_string();
+    for handle in ["current", "window-1"].iter() {
+        let path = format!("/session/{}/window/{}/maximize", session_id, handle);
+        client.command("POST", path[], None).unwrap();
+    }
+    let path = format!("/session/{}/window/window-2/maximize", session_id);
+    let err = client.command("POST", path[], None).err().unwrap();
+    assert_eq!(err.status, ErrorStatus::NoSuchWindow);
+    assert_eq!(server.marionette.commands(),
+               vec!["newSession".to_string(), "maximizeWindow".to_string(),
+                    "getWindowHandle".to_string(), "maximizeWindow".to_string(),
+                    "getWindowHandle".to_string()]);
+    server.stop();
+}
+
+#[test]
+fn wheel_actions_are_forwarded() {
+    let server = TestServer::start(responses());
+    let mut client = server.client();
+    client.new_session(Json::Object(TreeMap::new())).unwrap();
+    let path = format!("/session/{}/actions", client.session_id().unwrap());
+    let scroll = json::from_str(r#"{"actions": [{"type": "wheel", "id": "wheel", "actions": [
+        {"type": "scroll", "x": 10, "y": 20, "deltaX": 0, "deltaY": 100, "duration": 50}]}]}"#);
+    client.command("POST", path[], Some(&scroll.unwrap())).unwrap();
+    //Scrolling isn't something pointers do
+    let pointer_scroll = json::from_str(r#"{"actions": [{"type": "pointer", "id": "mouse",
+        "actions": [{"type": "scroll", "x": 0, "y": 0, "deltaX": 0, "deltaY": 100}]}]}"#);
+    let err = client.command("POST", path[], Some(&pointer_scroll.unwrap())).err().unwrap();
+    assert_eq!(err.status, ErrorStatus::InvalidArgument);
+    client.command("DELETE", path[], None).unwrap();
+    assert_eq!(server.marionette.commands(),
+               vec!["newSession".to_string(), "performActions".to_string(),
+                    "releaseActions".to_string()]);
+    server.stop();
+}
+
+#[test]
+fn unknown_path() {
+    let server = TestServer::start(responses());
+    let (status, body) = server.client().request("GET", "/no/such/path", None).unwrap();
+    assert_eq!(status, 404);
+    assert_eq!(body.find_path(&["value", "error"]), Some(&"unknown command".to_json()));
+    assert!(server.marionette.commands().is_empty());
+    server.stop();
+}
+
+#[test]
+fn head_has_the_length_of_get() {
+    let server = TestServer::start(responses());
+    let (mut writer, mut reader) = connect(server.port);
+    writer.write_str("GET /status HTTP/1.0\r\nHost: localhost\r\n\r\n").unwrap();
+    let (_, get_headers, get_body) = read_response(&mut reader, false);
+    let (mut writer, mut reader) = connect(server.port);
+    writer.write_str("HEAD /status HTTP/1.0\r\nHost: localhost\r\n\r\n").unwrap();
+    let (status, headers, _) = read_response(&mut reader, true);
+    assert_eq!(status, 200);
+    assert_eq!(headers.get("content-length"), Some(&get_body.len().to_string()));
+    assert_eq!(headers.get("content-length"), get_headers.get("content-length"));
+    assert!(reader.read_to_end().unwrap().is_empty());
+    server.stop();
+}
+
+#[test]
+fn head_of_streamed_response_has_length() {
+    //Big enough that GET would stream it
+    let screenshot = String::from_char(2 * 1024 * 1024, 'a');
+    let mut responses = responses();
+    responses.insert("takeScreenshot".to_string(), MockResponse::Value(screenshot.to_json()));
+    let server = TestServer::start(responses);
+    let mut client = server.client();
+    client.new_session(Json::Object(TreeMap::new())).unwrap();
+    let (mut writer, mut reader) = connect(server.port);
+    writer.write_str(format!("HEAD /session/{}/screenshot HTTP/1.0\r\nHost: localhost\r\n\r\n",
+                             client.session_id().unwrap())[]).unwrap();
+    let (status, headers, _) = read_response(&mut reader, true);
+    let mut expected = TreeMap::new();
+    expected.insert("value".to_string(), screenshot.to_json());
+    assert_eq!(status, 200);
+    assert_eq!(headers.get("transfer-encoding"), None);
+    assert_eq!(headers.get("content-length"),
+               Some(&json::encode(&Json::Object(expected)).len().to_string()));
+    assert!(reader.read_to_end().unwrap().is_empty());
+    server.stop();
+}
+
+#[test]
+fn http_11_connections_are_kept_alive() {
+    let server = TestServer::start(responses());
+    let (mut writer, mut reader) = connect(server.port);
+    for _ in range(0u, 2) {
+        writer.write_str("GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let (status, headers, _) = read_response(&mut reader, false);
+        assert_eq!(status, 200);
+        assert_eq!(headers.get("connection").map(|x| x[].to_ascii_lower()),
+                   Some("keep-alive".to_string()));
+    }
+    server.stop();
+}
+
+#[test]
+fn connection_close_is_honoured() {
+    let server = TestServer::start(responses());
+    let (mut writer, mut reader) = connect(server.port);
+    writer.write_str("GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let (status, headers, _) = read_response(&mut reader, false);
+    assert_eq!(status, 200);
+    assert_eq!(headers.get("connection").map(|x| x[].to_ascii_lower()),
+               Some("close".to_string()));
+    //Nothing more comes, and the server hangs up
+    assert!(reader.read_to_end().unwrap().is_empty());
+    server.stop();
+}
+
+#[test]
+fn http_10_connections_are_closed() {
+    let server = TestServer::start(responses());
+    let (mut writer, mut reader) = connect(server.port);
+    writer.write_str("GET /status HTTP/1.0\r\nHost: localhost\r\n\r\n").unwrap();
+    let (status, headers, _) = read_response(&mut reader, false);
+    assert_eq!(status, 200);
+    assert_eq!(headers.get("connection").map(|x| x[].to_ascii_lower()),
+               Some("close".to_string()));
+    assert!(reader.read_to_end().unwrap().is_empty());
+    server.stop();
+}
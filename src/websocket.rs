@@ -0,0 +1,320 @@
+use serialize::base64::{ToBase64, STANDARD};
+use std::io::{Acceptor, BufferedReader, IoResult, IoError, IoErrorKind, Listener, TcpListener,
+              TcpStream};
+use std::io::net::ip::SocketAddr;
+
+use webdriver::httpserver::{check_host_and_origin, local_hosts};
+
+use events;
+use logging;
+
+static ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+//Clients only send control frames, which are small
+static MAX_FRAME_SIZE: u64 = 64 * 1024;
+//Constants rather than statics, so they can be matched on
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// The Host and Origin header values a handshake may have, as for the HTTP server
+#[deriving(Clone)]
+struct Allowed {
+    hosts: Vec<String>,
+    origins: Vec<String>
+}
+
+// Serves a WebSocket at /events on its own port, which streams every event as a text
+// message. Anything the client sends, other than pings and closing the connection,
+// is ignored for now.
+pub fn start(address: SocketAddr, allowed_hosts: Vec<String>,
+             allowed_origins: Vec<String>) -> IoResult<()> {
+    let listener = try!(TcpListener::bind(address));
+    let mut acceptor = try!(listener.listen());
+    let mut allowed = Allowed {
+        hosts: allowed_hosts,
+        origins: allowed_origins
+    };
+    allowed.hosts.push_all(local_hosts(address.ip)[]);
+    events::enable();
+    info!("Streaming events on ws://{}/events", address);
+    spawn(proc() {
+        for stream in acceptor.incoming() {
+            let allowed = allowed.clone();
+            match stream {
+                Ok(stream) => spawn(proc() {
+                    logging::init();
+                    match serve(stream, &allowed) {
+                        Ok(_) => {},
+                        Err(e) => debug!("Event stream ended: {}", e)
+                    }
+                }),
+                Err(e) => {
+                    error!("Failed to accept event stream connection: {}", e);
+                    break
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+enum Incoming {
+    Ping(Vec<u8>),
+    Close
+}
+
+fn serve(stream: TcpStream, allowed: &Allowed) -> IoResult<()> {
+    let mut reader = BufferedReader::new(stream.clone());
+    let mut writer = stream;
+    let key = match try!(read_handshake(&mut reader, allowed)) {
+        Ok(x) => x,
+        Err(status) => {
+            return writer.write_str(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n",
+                                            status)[])
+        }
+    };
+    try!(writer.write_str(format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key[]))[]));
+    let events = events::subscribe();
+
+    let (incoming_send, incoming) = channel();
+    spawn(proc() {
+        loop {
+            match read_frame(&mut reader) {
+                Ok((OPCODE_PING, data)) => {
+                    if incoming_send.send_opt(Incoming::Ping(data)).is_err() {
+                        break
+                    }
+                },
+                Ok((OPCODE_CLOSE, _)) | Err(_) => {
+                    let _ = incoming_send.send_opt(Incoming::Close);
+                    break
+                },
+                Ok(_) => {}
+            }
+        }
+    });
+
+    loop {
+        select! {
+            event = events.recv_opt() => match event {
+                Ok(x) => try!(write_frame(&mut writer, OPCODE_TEXT, x.as_bytes())),
+                Err(_) => break
+            },
+            msg = incoming.recv_opt() => match msg {
+                Ok(Incoming::Ping(data)) => try!(write_frame(&mut writer, OPCODE_PONG, data[])),
+                _ => break
+            }
+        }
+    }
+    write_frame(&mut writer, OPCODE_CLOSE, &[])
+}
+
+// Returns the client's key, or the status to refuse the handshake with: not found if
+// this isn't a WebSocket request for /events, and forbidden if it comes from a host or
+// page that isn't allowed, since any page the browser loads can try to connect
+fn read_handshake<R: Buffer>(reader: &mut R,
+                             allowed: &Allowed) -> IoResult<Result<String, &'static str>> {
+    let request_line = try!(reader.read_line());
+    let mut key = None;
+    let mut upgrade = false;
+    let mut hosts = vec![];
+    let mut origin = None;
+    loop {
+        let line = try!(reader.read_line());
+        let line = line.trim_right();
+        if line.is_empty() {
+            break
+        }
+        match line.find(':') {
+            Some(index) => {
+                let value = line[index + 1..].trim();
+                match line[..index].trim().to_ascii_lower()[] {
+                    "sec-websocket-key" => key = Some(value.to_string()),
+                    "upgrade" => upgrade = value.to_ascii_lower()[] == "websocket",
+                    "host" => hosts.push(value.to_string()),
+                    "origin" => origin = Some(value.to_string()),
+                    _ => {}
+                }
+            },
+            None => {}
+        }
+    }
+    let host = if hosts.len() == 1 { Some(hosts[0][]) } else { None };
+    match check_host_and_origin(host, origin.as_ref().map(|x| x[]),
+                                allowed.hosts[], allowed.origins[]) {
+        Ok(_) => {},
+        Err(e) => {
+            info!("Refused event stream connection: {}", e.message);
+            return Ok(Err("403 Forbidden"))
+        }
+    }
+    let mut parts = request_line[].split(' ');
+    if parts.next() != Some("GET") || parts.next() != Some("/events") || !upgrade {
+        return Ok(Err("404 Not Found"))
+    }
+    Ok(key.ok_or("400 Bad Request"))
+}
+
+fn accept_key(key: &str) -> String {
+    sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes())[].to_base64(STANDARD)
+}
+
+fn write_frame<W: Writer>(writer: &mut W, opcode: u8, data: &[u8]) -> IoResult<()> {
+    //Messages are never fragmented, and servers don't mask them
+    try!(writer.write_u8(0x80 | opcode));
+    let len = data.len();
+    if len < 126 {
+        try!(writer.write_u8(len as u8));
+    } else if len < 65536 {
+        try!(writer.write_u8(126));
+        try!(writer.write_be_u16(len as u16));
+    } else {
+        try!(writer.write_u8(127));
+        try!(writer.write_be_u64(len as u64));
+    }
+    try!(writer.write(data));
+    writer.flush()
+}
+
+fn read_frame<R: Reader>(reader: &mut R) -> IoResult<(u8, Vec<u8>)> {
+    let first = try!(reader.read_u8());
+    let second = try!(reader.read_u8());
+    let len = match second & 0x7F {
+        126 => try!(reader.read_be_u16()) as u64,
+        127 => try!(reader.read_be_u64()),
+        x => x as u64
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(IoError {
+            kind: IoErrorKind::InvalidInput,
+            desc: "WebSocket frame is too big",
+            detail: Some(format!("{} bytes", len))
+        })
+    }
+    let mask = if second & 0x80 != 0 {
+        try!(reader.read_exact(4))
+    } else {
+        vec![0, 0, 0, 0]
+    };
+    let mut data = try!(reader.read_exact(len as uint));
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((first & 0x0F, data))
+}
+
+// The handshake needs SHA-1, and nothing else here does, so it lives here too
+fn sha1(data: &[u8]) -> Vec<u8> {
+    let mut h = [0x67452301u32, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    let bits = data.len() as u64 * 8;
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    for i in range(0u, 8).rev() {
+        message.push((bits >> (i * 8)) as u8);
+    }
+    for chunk in message.chunks(64) {
+        let mut w = [0u32, ..80];
+        for i in range(0u, 16) {
+            w[i] = (chunk[i * 4] as u32 << 24) | (chunk[i * 4 + 1] as u32 << 16) |
+                (chunk[i * 4 + 2] as u32 << 8) | chunk[i * 4 + 3] as u32;
+        }
+        for i in range(16u, 80) {
+            w[i] = rotate_left(w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16], 1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in range(0u, 80) {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (!b & d), 0x5A827999u32),
+                20...39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp = rotate_left(a, 5) + f + e + k + w[i];
+            e = d;
+            d = c;
+            c = rotate_left(b, 30);
+            b = a;
+            a = temp;
+        }
+        h[0] += a;
+        h[1] += b;
+        h[2] += c;
+        h[3] += d;
+        h[4] += e;
+    }
+    let mut rv = vec![];
+    for x in h.iter() {
+        rv.push_all(&[(*x >> 24) as u8, (*x >> 16) as u8, (*x >> 8) as u8, *x as u8]);
+    }
+    rv
+}
+
+fn rotate_left(x: u32, n: uint) -> u32 {
+    (x << n) | (x >> (32 - n))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::MemReader;
+
+    use super::{Allowed, accept_key, read_handshake, sha1};
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|x| format!("{:02x}", *x)).collect::<Vec<String>>().concat()
+    }
+
+    fn allowed() -> Allowed {
+        Allowed {
+            hosts: vec!["localhost".to_string()],
+            origins: vec!["http://example.org".to_string()]
+        }
+    }
+
+    fn handshake(headers: &str) -> Result<String, &'static str> {
+        let request = format!("GET /events HTTP/1.1\r\n\
+                               Upgrade: websocket\r\n\
+                               Connection: Upgrade\r\n\
+                               Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                               {}\r\n", headers);
+        let mut reader = MemReader::new(request.into_bytes());
+        read_handshake(&mut reader, &allowed()).unwrap()
+    }
+
+    #[test]
+    fn sha1_digests() {
+        assert_eq!(hex(sha1(b"")[])[], "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(sha1(b"abc")[])[], "a9993e364706816aba3e25717850c26c9cd0d89d");
+        //Long enough to need a second block for the padding
+        assert_eq!(hex(sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")[])[],
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ==")[], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn handshake_from_allowed_host() {
+        assert_eq!(handshake("Host: localhost:4444\r\n"),
+                   Ok("dGhlIHNhbXBsZSBub25jZQ==".to_string()));
+        assert_eq!(handshake("Host: localhost\r\nOrigin: http://example.org\r\n"),
+                   Ok("dGhlIHNhbXBsZSBub25jZQ==".to_string()));
+    }
+
+    #[test]
+    fn handshake_from_other_host_or_origin_is_forbidden() {
+        assert_eq!(handshake("Host: example.com\r\n"), Err("403 Forbidden"));
+        assert_eq!(handshake(""), Err("403 Forbidden"));
+        assert_eq!(handshake("Host: localhost\r\nHost: localhost\r\n"), Err("403 Forbidden"));
+        assert_eq!(handshake("Host: localhost\r\nOrigin: http://example.com\r\n"),
+                   Err("403 Forbidden"));
+    }
+}
@@ -0,0 +1,338 @@
+use libc::{c_int, c_void, size_t};
+use std::io::fs;
+use std::io::{File, USER_DIR};
+use std::uint;
+
+use webdriver::common::{WebDriverResult, WebDriverError, ErrorStatus};
+
+// Just enough of a zip reader to unpack the archives clients upload: the central
+// directory is read for the entries and each one is either stored or deflated.
+// Anything fancier (zip64, encryption, spanning) is rejected.
+static END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+static CENTRAL_DIRECTORY_ENTRY: u32 = 0x02014b50;
+static LOCAL_FILE_HEADER: u32 = 0x04034b50;
+
+static STORED: u16 = 0;
+static DEFLATED: u16 = 8;
+
+//Limits on what an archive unpacks to, so a small upload can't fill the disk
+static MAX_ENTRY_SIZE: uint = 128 * 1024 * 1024;
+static MAX_TOTAL_SIZE: u64 = 256 * 1024 * 1024;
+
+// From miniz, which flate links in. Unlike flate's inflate_bytes, it inflates into a
+// buffer of a given size and fails rather than go past the end of it, so an entry
+// can't unpack to more than the size it declares.
+extern {
+    fn tinfl_decompress_mem_to_mem(out: *mut c_void, out_len: size_t, src: *const c_void,
+                                   src_len: size_t, flags: c_int) -> size_t;
+}
+static TINFL_FAILED: size_t = uint::MAX as size_t;
+
+struct Entry {
+    name: String,
+    method: u16,
+    compressed_size: uint,
+    size: uint,
+    offset: uint
+}
+
+fn zip_error(message: &str) -> WebDriverError {
+    wd_err!(ErrorStatus::InvalidArgument, "Invalid zip archive: {}", message)
+}
+
+fn read_u16(data: &[u8], offset: uint) -> WebDriverResult<u16> {
+    if offset + 2 > data.len() {
+        return Err(zip_error("unexpected end of data"))
+    }
+    Ok(data[offset] as u16 | (data[offset + 1] as u16 << 8))
+}
+
+fn read_u32(data: &[u8], offset: uint) -> WebDriverResult<u32> {
+    let low = try!(read_u16(data, offset)) as u32;
+    let high = try!(read_u16(data, offset + 2)) as u32;
+    Ok(low | (high << 16))
+}
+
+fn slice(data: &[u8], start: uint, len: uint) -> WebDriverResult<&[u8]> {
+    if start + len > data.len() {
+        return Err(zip_error("unexpected end of data"))
+    }
+    Ok(data[start..start + len])
+}
+
+fn find_end_of_central_directory(data: &[u8]) -> WebDriverResult<uint> {
+    //The record is 22 bytes, followed by a comment of up to 64k
+    if data.len() < 22 {
+        return Err(zip_error("too short"))
+    }
+    let mut offset = data.len() - 22;
+    loop {
+        if try!(read_u32(data, offset)) == END_OF_CENTRAL_DIRECTORY {
+            return Ok(offset)
+        }
+        if offset == 0 || data.len() - offset > 22 + 0xffff {
+            return Err(zip_error("end of central directory not found"))
+        }
+        offset -= 1;
+    }
+}
+
+fn read_entries(data: &[u8]) -> WebDriverResult<Vec<Entry>> {
+    let end = try!(find_end_of_central_directory(data));
+    let count = try!(read_u16(data, end + 10)) as uint;
+    let mut offset = try!(read_u32(data, end + 16)) as uint;
+    let mut rv = Vec::with_capacity(count);
+    for _ in range(0, count) {
+        if try!(read_u32(data, offset)) != CENTRAL_DIRECTORY_ENTRY {
+            return Err(zip_error("bad central directory entry"))
+        }
+        let flags = try!(read_u16(data, offset + 8));
+        if flags & 1 != 0 {
+            return Err(zip_error("encrypted entries are not supported"))
+        }
+        let name_len = try!(read_u16(data, offset + 28)) as uint;
+        let extra_len = try!(read_u16(data, offset + 30)) as uint;
+        let comment_len = try!(read_u16(data, offset + 32)) as uint;
+        let name = match String::from_utf8(try!(slice(data, offset + 46, name_len)).to_vec()) {
+            Ok(x) => x,
+            Err(_) => return Err(zip_error("entry name is not valid UTF-8"))
+        };
+        rv.push(Entry {
+            name: name,
+            method: try!(read_u16(data, offset + 10)),
+            compressed_size: try!(read_u32(data, offset + 20)) as uint,
+            size: try!(read_u32(data, offset + 24)) as uint,
+            offset: try!(read_u32(data, offset + 42)) as uint
+        });
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(rv)
+}
+
+// Inflates a raw deflate stream into at most size bytes
+fn inflate(compressed: &[u8], size: uint) -> Option<Vec<u8>> {
+    let mut rv: Vec<u8> = Vec::with_capacity(size);
+    let written = unsafe {
+        tinfl_decompress_mem_to_mem(rv.as_mut_ptr() as *mut c_void, size as size_t,
+                                    compressed.as_ptr() as *const c_void,
+                                    compressed.len() as size_t, 0)
+    };
+    if written == TINFL_FAILED {
+        return None
+    }
+    unsafe { rv.set_len(written as uint) };
+    Some(rv)
+}
+
+fn entry_data(data: &[u8], entry: &Entry) -> WebDriverResult<Vec<u8>> {
+    if try!(read_u32(data, entry.offset)) != LOCAL_FILE_HEADER {
+        return Err(zip_error("bad local file header"))
+    }
+    let name_len = try!(read_u16(data, entry.offset + 26)) as uint;
+    let extra_len = try!(read_u16(data, entry.offset + 28)) as uint;
+    let compressed = try!(slice(data, entry.offset + 30 + name_len + extra_len,
+                                entry.compressed_size));
+    let rv = if entry.method == STORED {
+        compressed.to_vec()
+    } else if entry.method == DEFLATED {
+        match inflate(compressed, entry.size) {
+            Some(x) => x,
+            None => return Err(zip_error("failed to inflate entry"))
+        }
+    } else {
+        return Err(wd_err!(ErrorStatus::InvalidArgument,
+                           "Invalid zip archive: unsupported compression method {} for {}",
+                           entry.method, entry.name))
+    };
+    if rv.len() != entry.size {
+        return Err(zip_error("entry size doesn't match the central directory"))
+    }
+    Ok(rv)
+}
+
+// Entries have to stay inside the directory they are extracted into
+fn entry_path(dir: &Path, name: &str) -> WebDriverResult<Path> {
+    if name.starts_with("/") || name.contains("\\") ||
+        name.split('/').any(|x| x == "..") {
+        return Err(wd_err!(ErrorStatus::InvalidArgument,
+                           "Invalid zip archive: unsafe entry name {}", name))
+    }
+    Ok(dir.join(name))
+}
+
+fn io_error(path: &Path, err: ::std::io::IoError) -> WebDriverError {
+    wd_err!(ErrorStatus::UnknownError, "Failed to write {}: {}", path.display(), err)
+}
+
+// Archives that would unpack to too much are rejected before anything is written.
+// Entries are then held to the sizes they declare while they are extracted.
+fn check_sizes(entries: &[Entry]) -> WebDriverResult<()> {
+    let mut total = 0u64;
+    for entry in entries.iter() {
+        if entry.size > MAX_ENTRY_SIZE {
+            return Err(wd_err!(ErrorStatus::InvalidArgument,
+                               "Invalid zip archive: {} is larger than {} bytes",
+                               entry.name, MAX_ENTRY_SIZE))
+        }
+        total += entry.size as u64;
+    }
+    if total > MAX_TOTAL_SIZE {
+        return Err(wd_err!(ErrorStatus::InvalidArgument,
+                           "Invalid zip archive: contents are larger than {} bytes",
+                           MAX_TOTAL_SIZE))
+    }
+    Ok(())
+}
+
+// Extracts the archive into dir, returning the paths of the files in it
+pub fn extract(data: &[u8], dir: &Path) -> WebDriverResult<Vec<Path>> {
+    let entries = try!(read_entries(data));
+    try!(check_sizes(entries[]));
+    let mut rv = vec![];
+    for entry in entries.iter() {
+        let path = try!(entry_path(dir, entry.name[]));
+        if entry.name.ends_with("/") {
+            try!(fs::mkdir_recursive(&path, USER_DIR).map_err(|e| io_error(&path, e)));
+            continue;
+        }
+        let contents = try!(entry_data(data, entry));
+        try!(fs::mkdir_recursive(&path.dir_path(), USER_DIR).map_err(|e| io_error(&path, e)));
+        try!(File::create(&path).write(contents[]).map_err(|e| io_error(&path, e)));
+        rv.push(path);
+    }
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use flate::deflate_bytes;
+    use std::io::{File, TempDir};
+
+    use super::{Entry, STORED, DEFLATED, MAX_ENTRY_SIZE, check_sizes, entry_path, extract};
+
+    fn push_u16(data: &mut Vec<u8>, value: u16) {
+        data.push(value as u8);
+        data.push((value >> 8) as u8);
+    }
+
+    fn push_u32(data: &mut Vec<u8>, value: u32) {
+        push_u16(data, value as u16);
+        push_u16(data, (value >> 16) as u16);
+    }
+
+    // An archive of (name, method, contents as stored, declared size) entries. The
+    // CRCs are left as 0, since they aren't checked.
+    fn archive(entries: &[(&str, u16, &[u8], uint)]) -> Vec<u8> {
+        let mut rv = vec![];
+        let mut central = vec![];
+        for &(name, method, contents, size) in entries.iter() {
+            let offset = rv.len() as u32;
+            push_u32(&mut rv, 0x04034b50);
+            push_u16(&mut rv, 20);
+            push_u16(&mut rv, 0);
+            push_u16(&mut rv, method);
+            push_u32(&mut rv, 0);
+            push_u32(&mut rv, 0);
+            push_u32(&mut rv, contents.len() as u32);
+            push_u32(&mut rv, size as u32);
+            push_u16(&mut rv, name.len() as u16);
+            push_u16(&mut rv, 0);
+            rv.push_all(name.as_bytes());
+            rv.push_all(contents);
+
+            push_u32(&mut central, 0x02014b50);
+            push_u16(&mut central, 20);
+            push_u16(&mut central, 20);
+            push_u16(&mut central, 0);
+            push_u16(&mut central, method);
+            push_u32(&mut central, 0);
+            push_u32(&mut central, 0);
+            push_u32(&mut central, contents.len() as u32);
+            push_u32(&mut central, size as u32);
+            push_u16(&mut central, name.len() as u16);
+            push_u16(&mut central, 0);
+            push_u16(&mut central, 0);
+            push_u16(&mut central, 0);
+            push_u16(&mut central, 0);
+            push_u32(&mut central, 0);
+            push_u32(&mut central, offset);
+            central.push_all(name.as_bytes());
+        }
+        let central_offset = rv.len() as u32;
+        rv.push_all(central[]);
+        push_u32(&mut rv, 0x06054b50);
+        push_u16(&mut rv, 0);
+        push_u16(&mut rv, 0);
+        push_u16(&mut rv, entries.len() as u16);
+        push_u16(&mut rv, entries.len() as u16);
+        push_u32(&mut rv, central.len() as u32);
+        push_u32(&mut rv, central_offset);
+        push_u16(&mut rv, 0);
+        rv
+    }
+
+    fn entry(name: &str, size: uint) -> Entry {
+        Entry {
+            name: name.to_string(),
+            method: STORED,
+            compressed_size: size,
+            size: size,
+            offset: 0
+        }
+    }
+
+    #[test]
+    fn stored_and_deflated_entries_round_trip() {
+        let text = "All work and no play makes Jack a dull boy. ".repeat(100);
+        let deflated = deflate_bytes(text.as_bytes()).unwrap();
+        let data = archive(&[("stored.txt", STORED, "stored".as_bytes(), 6),
+                             ("dir/deflated.txt", DEFLATED, deflated.as_slice(), text.len())]);
+        let dir = TempDir::new("wires-zip-test").unwrap();
+        let paths = extract(data[], dir.path()).unwrap();
+        assert_eq!(paths, vec![dir.path().join("stored.txt"),
+                               dir.path().join("dir/deflated.txt")]);
+        assert_eq!(File::open(&paths[0]).read_to_end().unwrap(), b"stored".to_vec());
+        assert_eq!(File::open(&paths[1]).read_to_end().unwrap(), text.into_bytes());
+    }
+
+    #[test]
+    fn unsafe_entry_names_are_rejected() {
+        let dir = Path::new("/tmp/upload");
+        for name in ["../x", "a/../../x", "/abs", "a\\b"].iter() {
+            assert!(entry_path(&dir, *name).is_err(), "{} was accepted", name);
+        }
+        assert_eq!(entry_path(&dir, "a/b").unwrap(), dir.join("a/b"));
+    }
+
+    #[test]
+    fn entries_must_have_the_size_they_declare() {
+        let dir = TempDir::new("wires-zip-test").unwrap();
+        let data = archive(&[("short.txt", STORED, "abc".as_bytes(), 5)]);
+        assert!(extract(data[], dir.path()).is_err());
+        //Inflating stops at the declared size, rather than unpacking all of it
+        let text = "x".repeat(10000);
+        let deflated = deflate_bytes(text.as_bytes()).unwrap();
+        let data = archive(&[("bomb.txt", DEFLATED, deflated.as_slice(), 100)]);
+        assert!(extract(data[], dir.path()).is_err());
+        assert!(!dir.path().join("short.txt").exists());
+        assert!(!dir.path().join("bomb.txt").exists());
+    }
+
+    #[test]
+    fn archives_over_the_size_limits_are_rejected() {
+        assert!(check_sizes(&[entry("a", MAX_ENTRY_SIZE)]).is_ok());
+        assert!(check_sizes(&[entry("a", MAX_ENTRY_SIZE + 1)]).is_err());
+        assert!(check_sizes(&[entry("a", MAX_ENTRY_SIZE), entry("b", MAX_ENTRY_SIZE),
+                              entry("c", 1)]).is_err());
+    }
+
+    #[test]
+    fn truncated_archives_are_errors() {
+        let data = archive(&[("a.txt", STORED, "hello".as_bytes(), 5),
+                             ("b.txt", STORED, "world".as_bytes(), 5)]);
+        let dir = TempDir::new("wires-zip-test").unwrap();
+        for len in range(0, data.len()) {
+            assert!(extract(data[..len], dir.path()).is_err(), "{} bytes were accepted", len);
+        }
+    }
+}